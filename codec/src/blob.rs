@@ -0,0 +1,171 @@
+//! A second logical channel for transferring large PDUs.
+//!
+//! A PDU such as the text of an exported scrollback can take long
+//! enough to transmit that it would hold up the latency sensitive
+//! PDUs that are queued behind it, such as the response to a key
+//! press.  Once the peer has agreed to `EnableBlobChannel`, the
+//! encoded frame of such a PDU is split into `BlobChunk`s which the
+//! sender interleaves with its other PDUs, and which the receiver
+//! reassembles into the original PDU.
+use crate::{BlobChunk, DecodedPdu, FrameTooLarge, Pdu};
+use anyhow::Context;
+use std::collections::HashMap;
+
+/// The maximum size of the data carried by a `BlobChunk`.
+/// PDUs whose frames are no larger than this are sent as they are.
+pub const BLOB_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Returns true if `pdu` is of a type that is potentially large
+/// enough to be worth sending on the blob channel
+pub fn is_bulk_pdu(pdu: &Pdu) -> bool {
+    matches!(
+        pdu,
+        Pdu::ExportScrollbackResponse(_) | Pdu::GetTabScreenshotResponse(_)
+    )
+}
+
+/// Split `decoded` into the chunks that carry it on the blob channel
+/// as `blob_id`.  Returns None if it fits in a single chunk, in which
+/// case it should be sent as it is.
+pub fn split_into_blob_chunks(
+    decoded: &DecodedPdu,
+    blob_id: u64,
+) -> anyhow::Result<Option<Vec<DecodedPdu>>> {
+    // The frame is checksummed and sealed as part of the chunks
+    // that carry it, so it doesn't need a checksum of its own
+    let frame = decoded.pdu.encode_as_vec(decoded.serial, false)?;
+    if frame.len() <= BLOB_CHUNK_SIZE {
+        return Ok(None);
+    }
+
+    let num_chunks = (frame.len() + BLOB_CHUNK_SIZE - 1) / BLOB_CHUNK_SIZE;
+    Ok(Some(
+        frame
+            .chunks(BLOB_CHUNK_SIZE)
+            .enumerate()
+            .map(|(idx, data)| DecodedPdu {
+                serial: decoded.serial,
+                pdu: Pdu::BlobChunk(BlobChunk {
+                    blob_id,
+                    data: data.to_vec(),
+                    last: idx + 1 == num_chunks,
+                }),
+            })
+            .collect(),
+    ))
+}
+
+/// Accumulates the chunks of the blobs that are being received
+#[derive(Default)]
+pub struct BlobAssembler {
+    partial: HashMap<u64, Vec<u8>>,
+}
+
+impl BlobAssembler {
+    /// Add `chunk` to its blob.  Returns the PDU that the blob
+    /// carries once its last chunk has been received.  The blob
+    /// is rejected if it grows beyond `max_frame_len` bytes.
+    pub fn receive(
+        &mut self,
+        chunk: BlobChunk,
+        max_frame_len: usize,
+    ) -> anyhow::Result<Option<DecodedPdu>> {
+        let buffer = self.partial.entry(chunk.blob_id).or_insert_with(Vec::new);
+        buffer.extend_from_slice(&chunk.data);
+
+        if buffer.len() > max_frame_len {
+            let len = buffer.len() as u64;
+            self.partial.remove(&chunk.blob_id);
+            return Err(FrameTooLarge {
+                len,
+                max: max_frame_len,
+            }
+            .into());
+        }
+
+        if !chunk.last {
+            return Ok(None);
+        }
+
+        let frame = self.partial.remove(&chunk.blob_id).unwrap_or_default();
+        Pdu::decode_with_max_len(frame.as_slice(), max_frame_len)
+            .with_context(|| format!("decoding blob {}", chunk.blob_id))
+            .map(Some)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{ExportScrollbackResponse, UnitResponse, DEFAULT_MAX_FRAME_LEN};
+
+    fn export(len: usize) -> DecodedPdu {
+        // Use data that doesn't compress well, so that the frame
+        // is larger than a single chunk
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let text: String = (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (b'!' + (state % 94) as u8) as char
+            })
+            .collect();
+        DecodedPdu {
+            serial: 42,
+            pdu: Pdu::ExportScrollbackResponse(ExportScrollbackResponse {
+                pane_id: 1,
+                range: 0..100,
+                text,
+            }),
+        }
+    }
+
+    #[test]
+    fn small_pdus_are_not_split() {
+        let decoded = DecodedPdu {
+            serial: 1,
+            pdu: Pdu::UnitResponse(UnitResponse {}),
+        };
+        assert!(split_into_blob_chunks(&decoded, 0).unwrap().is_none());
+    }
+
+    #[test]
+    fn interleaved_blobs_are_reassembled() {
+        let first = export(5 * BLOB_CHUNK_SIZE);
+        let second = export(2 * BLOB_CHUNK_SIZE);
+        let first_chunks = split_into_blob_chunks(&first, 1).unwrap().unwrap();
+        let second_chunks = split_into_blob_chunks(&second, 2).unwrap().unwrap();
+        assert!(first_chunks.len() > second_chunks.len());
+
+        let mut assembler = BlobAssembler::default();
+        let mut completed = vec![];
+        let mut first_chunks = first_chunks.into_iter();
+        let mut second_chunks = second_chunks.into_iter();
+        loop {
+            let chunks: Vec<DecodedPdu> = first_chunks
+                .next()
+                .into_iter()
+                .chain(second_chunks.next())
+                .collect();
+            if chunks.is_empty() {
+                break;
+            }
+            for chunk in chunks {
+                assert_eq!(chunk.pdu.pdu_name(), "BlobChunk");
+                let chunk = match chunk.pdu {
+                    Pdu::BlobChunk(chunk) => chunk,
+                    _ => unreachable!(),
+                };
+                assert!(chunk.data.len() <= BLOB_CHUNK_SIZE);
+                if let Some(decoded) = assembler.receive(chunk, DEFAULT_MAX_FRAME_LEN).unwrap() {
+                    completed.push(decoded);
+                }
+            }
+        }
+
+        // The smaller blob completes first, despite being queued second
+        assert_eq!(completed, vec![second, first]);
+        assert!(assembler.partial.is_empty());
+    }
+}
@@ -0,0 +1,263 @@
+//! Optional application level encryption of PDU frames.
+//!
+//! This is intended for transports that are not already protected
+//! by TLS, such as a unix domain socket that is forwarded through
+//! a host that we don't trust.  Both peers are configured with the
+//! same pre-shared key; each session exchanges random nonces via
+//! the `NegotiateEncryption` PDU and derives a session key from the
+//! pre-shared key and those nonces.
+//!
+//! Once negotiated, every frame is sealed with AES-256-GCM.  A sealed
+//! frame has the format:
+//!
+//! ```text
+//! len: leb128      (length of the remainder of the frame)
+//! counter: leb128
+//! ciphertext bytes (an encoded frame; see encode_raw())
+//! tag: 16 bytes
+//! ```
+//!
+//! The nonce for each frame is built from the direction of travel and
+//! a counter that starts at zero and increases by one for each frame.
+//! The receiver requires that the counter is exactly the next value
+//! that it expects, so a frame that is replayed, reordered or dropped
+//! causes the session to be torn down.
+use crate::{encoded_length, read_u64, read_u64_async};
+use anyhow::Context;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use smol::prelude::*;
+
+const TAG_LEN: usize = 16;
+
+/// The size of the nonce that each peer contributes to the session
+/// key derivation
+pub const SESSION_NONCE_LEN: usize = 16;
+
+/// The most that sealing adds to the size of a frame: the leb128
+/// encoded counter and the authentication tag.  A sealed frame may
+/// be this much larger than the maximum frame length, so that a frame of
+/// the maximum size can still be sent.
+const SEALING_OVERHEAD: u64 = 10 + TAG_LEN as u64;
+
+/// Which end of the connection a `FrameCipher` belongs to.
+/// The two directions use distinct nonces so that a frame
+/// cannot be reflected back to its sender.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CipherRole {
+    Client,
+    Server,
+}
+
+impl CipherRole {
+    fn send_direction(self) -> u32 {
+        match self {
+            CipherRole::Client => 0,
+            CipherRole::Server => 1,
+        }
+    }
+
+    fn recv_direction(self) -> u32 {
+        match self {
+            CipherRole::Client => 1,
+            CipherRole::Server => 0,
+        }
+    }
+}
+
+/// The pre-shared key from which session keys are derived
+pub struct EncryptionKey {
+    key: Vec<u8>,
+}
+
+impl EncryptionKey {
+    pub fn new(secret: &str) -> Self {
+        Self {
+            key: secret.as_bytes().to_vec(),
+        }
+    }
+
+    pub fn load(path: &std::path::Path) -> anyhow::Result<Self> {
+        let secret = config::read_auth_token(path)
+            .with_context(|| format!("loading encryption key from {}", path.display()))?;
+        Ok(Self::new(&secret))
+    }
+
+    /// Derive the cipher for a session from the nonces contributed
+    /// by the client and server.
+    pub fn session_cipher(
+        &self,
+        role: CipherRole,
+        client_nonce: &[u8],
+        server_nonce: &[u8],
+    ) -> anyhow::Result<FrameCipher> {
+        if client_nonce.len() != SESSION_NONCE_LEN || server_nonce.len() != SESSION_NONCE_LEN {
+            anyhow::bail!("session nonces must be {} bytes long", SESSION_NONCE_LEN);
+        }
+        let pkey = PKey::hmac(&self.key)?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &pkey)?;
+        signer.update(b"wezterm mux session key\0")?;
+        signer.update(client_nonce)?;
+        signer.update(server_nonce)?;
+        let digest = signer.sign_to_vec()?;
+
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&digest);
+        Ok(FrameCipher {
+            key,
+            role,
+            send_counter: 0,
+            recv_counter: 0,
+        })
+    }
+}
+
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("EncryptionKey").finish()
+    }
+}
+
+/// Generate a random nonce to contribute to the session key
+pub fn generate_session_nonce() -> anyhow::Result<Vec<u8>> {
+    let mut nonce = vec![0u8; SESSION_NONCE_LEN];
+    openssl::rand::rand_bytes(&mut nonce)?;
+    Ok(nonce)
+}
+
+/// Seals and opens the frames for one session
+pub struct FrameCipher {
+    key: [u8; 32],
+    role: CipherRole,
+    send_counter: u64,
+    recv_counter: u64,
+}
+
+impl std::fmt::Debug for FrameCipher {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("FrameCipher")
+            .field("role", &self.role)
+            .field("send_counter", &self.send_counter)
+            .field("recv_counter", &self.recv_counter)
+            .finish()
+    }
+}
+
+fn make_nonce(direction: u32, counter: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[0..4].copy_from_slice(&direction.to_be_bytes());
+    nonce[4..12].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+impl FrameCipher {
+    /// Encrypt an encoded frame, returning the sealed frame
+    /// ready to be written to the stream
+    pub fn seal(&mut self, frame: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let counter = self.send_counter;
+        self.send_counter = counter
+            .checked_add(1)
+            .ok_or_else(|| anyhow::anyhow!("frame counter exhausted; session must be restarted"))?;
+
+        let nonce = make_nonce(self.role.send_direction(), counter);
+        let mut tag = [0u8; TAG_LEN];
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(&nonce),
+            &[],
+            frame,
+            &mut tag,
+        )
+        .context("sealing frame")?;
+
+        let len = encoded_length(counter) + ciphertext.len() + TAG_LEN;
+        let mut buffer = Vec::with_capacity(len + encoded_length(len as u64));
+        leb128::write::unsigned(&mut buffer, len as u64).context("writing sealed frame len")?;
+        leb128::write::unsigned(&mut buffer, counter).context("writing frame counter")?;
+        buffer.extend_from_slice(&ciphertext);
+        buffer.extend_from_slice(&tag);
+        Ok(buffer)
+    }
+
+    /// Verify and decrypt the remainder of a sealed frame
+    /// whose counter has already been read
+    fn open(&mut self, counter: u64, sealed: &[u8]) -> anyhow::Result<Vec<u8>> {
+        if counter != self.recv_counter {
+            anyhow::bail!(
+                "frame counter {} does not match the expected {}; \
+                 the frame was replayed, reordered or lost",
+                counter,
+                self.recv_counter
+            );
+        }
+        if sealed.len() < TAG_LEN {
+            anyhow::bail!("sealed frame is too short");
+        }
+        let (ciphertext, tag) = sealed.split_at(sealed.len() - TAG_LEN);
+        let nonce = make_nonce(self.role.recv_direction(), counter);
+        let frame = decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(&nonce),
+            &[],
+            ciphertext,
+            tag,
+        )
+        .map_err(|_| anyhow::anyhow!("sealed frame failed authentication"))?;
+        self.recv_counter += 1;
+        Ok(frame)
+    }
+
+    fn data_len(len: u64, counter: u64, max: usize) -> anyhow::Result<usize> {
+        // Check the length before allocating, as the frame
+        // can't be authenticated until it has been read
+        if len > max as u64 + SEALING_OVERHEAD {
+            return Err(crate::FrameTooLarge { len, max }.into());
+        }
+        (len as usize)
+            .checked_sub(encoded_length(counter))
+            .ok_or_else(|| anyhow::anyhow!("sealed frame length {} makes no sense", len))
+    }
+
+    /// Read and open a sealed frame that contains a frame of no more
+    /// than `max_frame_len` bytes, returning the contained frame along
+    /// with the size of the sealed frame on the wire
+    pub fn read_sealed<R: std::io::Read>(
+        &mut self,
+        mut r: R,
+        max_frame_len: usize,
+    ) -> anyhow::Result<(Vec<u8>, usize)> {
+        let len = read_u64(r.by_ref()).context("reading sealed frame length")?;
+        let counter = read_u64(r.by_ref()).context("reading frame counter")?;
+        let mut sealed = vec![0u8; Self::data_len(len, counter, max_frame_len)?];
+        r.read_exact(&mut sealed).context("reading sealed frame")?;
+        let frame = self.open(counter, &sealed)?;
+        Ok((frame, encoded_length(len) + len as usize))
+    }
+
+    /// Read and open a sealed frame that contains a frame of no more
+    /// than `max_frame_len` bytes, returning the contained frame along
+    /// with the size of the sealed frame on the wire
+    pub async fn read_sealed_async<R>(
+        &mut self,
+        r: &mut R,
+        max_frame_len: usize,
+    ) -> anyhow::Result<(Vec<u8>, usize)>
+    where
+        R: Unpin + AsyncRead + std::fmt::Debug,
+    {
+        let len = read_u64_async(r)
+            .await
+            .context("reading sealed frame length")?;
+        let counter = read_u64_async(r).await.context("reading frame counter")?;
+        let mut sealed = vec![0u8; Self::data_len(len, counter, max_frame_len)?];
+        r.read_exact(&mut sealed)
+            .await
+            .context("reading sealed frame")?;
+        let frame = self.open(counter, &sealed)?;
+        Ok((frame, encoded_length(len) + len as usize))
+    }
+}
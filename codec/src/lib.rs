@@ -2,6 +2,10 @@
 //! The frames include the length of a PDU as well as an identifier
 //! that informs us how to decode it.  The length, ident and serial
 //! number are encoded using a variable length integer encoding.
+//! The PDU bodies are serialized using varbincode, which uses the same
+//! leb128 encoding for every integer, so the ids, serials and stable
+//! row indices that appear in almost every PDU typically occupy just
+//! one or two bytes each.
 //! Rather than rely solely on serde to serialize and deserialize an
 //! enum, we encode the enum variants with a version/identifier tag
 //! for ourselves.  This will make it a little easier to manage
@@ -12,26 +16,46 @@
 #![cfg_attr(feature = "cargo-clippy", allow(clippy::range_plus_one))]
 
 use anyhow::{bail, Context as _, Error};
+use chrono::serde::ts_seconds;
+use chrono::{DateTime, Utc};
 use mux::client::{ClientId, ClientInfo};
+use mux::domain::{DomainId, DomainState};
 use mux::pane::PaneId;
 use mux::renderable::{RenderableDimensions, StableCursorPosition};
-use mux::tab::{PaneNode, SerdeUrl, SplitDirection, TabId};
+use mux::tab::{PaneLayout, PaneNode, SerdeUrl, SplitDirection, TabId};
 use mux::window::WindowId;
 use portable_pty::{CommandBuilder, PtySize};
 use rangeset::*;
 use serde::{Deserialize, Serialize};
 use smol::io::AsyncWriteExt;
 use smol::prelude::*;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
+use std::hash::Hasher;
 use std::io::Cursor;
 use std::ops::Range;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use termwiz::cell::{Cell, CellAttributes};
+use termwiz::escape::osc::ProgressState;
 use termwiz::hyperlink::Hyperlink;
-use termwiz::surface::{Line, SequenceNo};
+use termwiz::image::{ImageCell, ImageData, TextureCoordinate};
+use termwiz::surface::{Line, SequenceNo, SEQ_ZERO};
 use varbincode;
 use wezterm_term::color::ColorPalette;
-use wezterm_term::{Alert, ClipboardSelection, StableRowIndex};
+use wezterm_term::{Alert, ClipboardSelection, StableRowIndex, TerminalModes};
+
+mod blob;
+mod crypt;
+mod serial;
+mod stream;
+pub use blob::{is_bulk_pdu, split_into_blob_chunks, BlobAssembler, BLOB_CHUNK_SIZE};
+pub use crypt::{
+    generate_session_nonce, CipherRole, EncryptionKey, FrameCipher, SESSION_NONCE_LEN,
+};
+pub use serial::{SerialSpace, SERIAL_EPOCH_BITS};
+pub use stream::{StreamCompressor, StreamDecompressor, StreamDesync};
 
 /// Returns the encoded length of the leb128 representation of value
 fn encoded_length(value: u64) -> usize {
@@ -49,19 +73,185 @@ fn encoded_length(value: u64) -> usize {
 }
 
 const COMPRESSED_MASK: u64 = 1 << 63;
+const CHECKSUM_MASK: u64 = 1 << 62;
+const CHECKSUM_LEN: usize = 4;
+
+/// Returned when the checksum carried by a frame doesn't match
+/// its contents.  Callers can downcast to this to distinguish
+/// corruption on the wire from other decoding errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChecksumMismatch {
+    pub expected: u32,
+    pub actual: u32,
+}
+
+impl std::fmt::Display for ChecksumMismatch {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            fmt,
+            "frame checksum mismatch: expected {:08x} but computed {:08x}",
+            self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
+/// Returned when a frame arrives without a checksum after checksums
+/// were negotiated for the session.  A peer that agreed to checksum
+/// its frames never sends one without, so this indicates corruption
+/// of the length prefix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MissingChecksum;
+
+impl std::fmt::Display for MissingChecksum {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            fmt,
+            "frame has no checksum although checksums were negotiated"
+        )
+    }
+}
+
+impl std::error::Error for MissingChecksum {}
+
+/// The default for the largest frame that we are prepared to decode.
+/// This is generous enough for any legitimate PDU, but bounded so
+/// that a corrupt or hostile length prefix can't make us allocate
+/// an arbitrary amount of memory.
+pub const DEFAULT_MAX_FRAME_LEN: usize = 256 * 1024 * 1024;
+
+/// Returned when the length prefix of a frame exceeds the maximum
+/// frame length of the connection, or when a PDU decompresses to
+/// more than that.
+/// The stream can't be resynchronized after this, so the connection
+/// should be torn down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameTooLarge {
+    /// The length of the frame; for a decompressed PDU, this is
+    /// the point at which decompression was abandoned
+    pub len: u64,
+    pub max: usize,
+}
+
+impl std::fmt::Display for FrameTooLarge {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            fmt,
+            "frame length {} exceeds the maximum of {}",
+            self.len, self.max
+        )
+    }
+}
+
+impl std::error::Error for FrameTooLarge {}
+
+/// Reject a frame length that exceeds `max` before anything
+/// is allocated to hold it
+fn check_frame_len(len: u64, max: usize) -> anyhow::Result<()> {
+    if len > max as u64 {
+        return Err(FrameTooLarge { len, max }.into());
+    }
+    Ok(())
+}
+
+/// Per-session framing options, negotiated when the session
+/// is established
+#[derive(Debug)]
+pub struct Framing {
+    /// Seal each frame with this cipher; see the crypt module
+    pub cipher: Option<FrameCipher>,
+    /// Append a checksum to each frame that we send.  This must only
+    /// be enabled once the peer has agreed to `EnableFrameChecksums`.
+    pub checksum: bool,
+    /// Compress the frames that we send with the compression stream
+    /// of the connection, rather than compressing each of them on
+    /// its own; see the stream module.  This must only be enabled
+    /// once the peer has agreed to `EnableStreamCompression`.
+    pub compressor: Option<StreamCompressor>,
+    /// Decompress the frames that we receive with the compression
+    /// stream of the connection
+    pub decompressor: Option<StreamDecompressor>,
+    /// The work done to compress the frames that we have sent
+    pub compression: CompressionStats,
+    /// The largest frame that we will decode; frames whose length
+    /// prefix exceeds this are rejected with `FrameTooLarge`.
+    /// This also bounds the size of a decompressed PDU.
+    pub max_frame_len: usize,
+}
+
+impl Default for Framing {
+    fn default() -> Self {
+        Self {
+            cipher: None,
+            checksum: false,
+            compressor: None,
+            decompressor: None,
+            compression: CompressionStats::default(),
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+        }
+    }
+}
+
+/// Counts the work done to compress the frames that are sent on a
+/// connection.  Only the frames that were large enough to be worth
+/// compressing are counted.
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Default, Clone, Copy)]
+pub struct CompressionStats {
+    /// The number of frames that were compressed
+    pub frames: u64,
+    /// The size of their bodies before they were compressed
+    pub bytes_in: u64,
+    /// The size of their bodies as they were sent, which is their
+    /// uncompressed size when compressing didn't make them smaller
+    pub bytes_out: u64,
+    /// The time that was spent compressing them
+    pub time: Duration,
+}
+
+impl CompressionStats {
+    fn record(&mut self, bytes_in: usize, bytes_out: usize, time: Duration) {
+        self.frames += 1;
+        self.bytes_in += bytes_in as u64;
+        self.bytes_out += bytes_out as u64;
+        self.time += time;
+    }
+
+    /// Add the counts of `other` to these
+    pub fn add(&mut self, other: &Self) {
+        self.frames += other.frames;
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+        self.time += other.time;
+    }
+
+    /// The size of the bodies before compression divided by their
+    /// size as they were sent, or 1 if nothing has been compressed
+    pub fn ratio(&self) -> f64 {
+        if self.bytes_out == 0 {
+            1.0
+        } else {
+            self.bytes_in as f64 / self.bytes_out as f64
+        }
+    }
+}
 
 fn encode_raw_as_vec(
     ident: u64,
     serial: u64,
     data: &[u8],
     is_compressed: bool,
+    checksum: bool,
 ) -> anyhow::Result<Vec<u8>> {
-    let len = data.len() + encoded_length(ident) + encoded_length(serial);
-    let masked_len = if is_compressed {
-        (len as u64) | COMPRESSED_MASK
-    } else {
-        len as u64
-    };
+    let checksum_len = if checksum { CHECKSUM_LEN } else { 0 };
+    let len = data.len() + encoded_length(ident) + encoded_length(serial) + checksum_len;
+    let mut masked_len = len as u64;
+    if is_compressed {
+        masked_len |= COMPRESSED_MASK;
+    }
+    if checksum {
+        masked_len |= CHECKSUM_MASK;
+    }
 
     // Double-buffer the data; since we run with nodelay enabled, it is
     // desirable for the write to be a single packet (or at least, for
@@ -69,10 +259,16 @@ fn encode_raw_as_vec(
     let mut buffer = Vec::with_capacity(len + encoded_length(masked_len));
 
     leb128::write::unsigned(&mut buffer, masked_len).context("writing pdu len")?;
+    let body_start = buffer.len();
     leb128::write::unsigned(&mut buffer, serial).context("writing pdu serial")?;
     leb128::write::unsigned(&mut buffer, ident).context("writing pdu ident")?;
     buffer.extend_from_slice(data);
 
+    if checksum {
+        let crc = crc32fast::hash(&buffer[body_start..]);
+        buffer.extend_from_slice(&crc.to_le_bytes());
+    }
+
     if is_compressed {
         metrics::histogram!("pdu.encode.compressed.size", buffer.len() as f64);
     } else {
@@ -84,10 +280,13 @@ fn encode_raw_as_vec(
 
 /// Encode a frame.  If the data is compressed, the high bit of the length
 /// is set to indicate that.  The data written out has the format:
-/// tagged_len: leb128  (u64 msb is set if data is compressed)
+/// tagged_len: leb128  (u64 msb is set if data is compressed,
+///                      the next bit is set if a checksum is present)
 /// serial: leb128
 /// ident: leb128
 /// data bytes
+/// checksum: u32 little endian CRC32 of serial, ident and data,
+///           present only if indicated by tagged_len
 fn encode_raw<W: std::io::Write>(
     ident: u64,
     serial: u64,
@@ -95,25 +294,11 @@ fn encode_raw<W: std::io::Write>(
     is_compressed: bool,
     mut w: W,
 ) -> anyhow::Result<usize> {
-    let buffer = encode_raw_as_vec(ident, serial, data, is_compressed)?;
+    let buffer = encode_raw_as_vec(ident, serial, data, is_compressed, false)?;
     w.write_all(&buffer).context("writing pdu data buffer")?;
     Ok(buffer.len())
 }
 
-async fn encode_raw_async<W: Unpin + AsyncWriteExt>(
-    ident: u64,
-    serial: u64,
-    data: &[u8],
-    is_compressed: bool,
-    w: &mut W,
-) -> anyhow::Result<usize> {
-    let buffer = encode_raw_as_vec(ident, serial, data, is_compressed)?;
-    w.write_all(&buffer)
-        .await
-        .context("writing pdu data buffer")?;
-    Ok(buffer.len())
-}
-
 /// Read a single leb128 encoded value from the stream
 async fn read_u64_async<R>(r: &mut R) -> anyhow::Result<u64>
 where
@@ -158,19 +343,77 @@ struct Decoded {
     serial: u64,
     data: Vec<u8>,
     is_compressed: bool,
+    /// The size of the frame on the wire, including its header
+    frame_len: usize,
+}
+
+/// Split a tagged length into the length and its compressed
+/// and checksum flags
+fn untag_len(tagged_len: u64) -> (u64, bool, bool) {
+    (
+        tagged_len & !(COMPRESSED_MASK | CHECKSUM_MASK),
+        (tagged_len & COMPRESSED_MASK) != 0,
+        (tagged_len & CHECKSUM_MASK) != 0,
+    )
+}
+
+/// Verify the checksum at the end of the body of a frame and
+/// then decode the serial, ident and data that it covers.
+fn decode_checksummed(
+    tagged_len: u64,
+    is_compressed: bool,
+    body: Vec<u8>,
+) -> anyhow::Result<Decoded> {
+    if body.len() < CHECKSUM_LEN {
+        bail!("frame of length {} is too short for a checksum", body.len());
+    }
+    let (covered, trailer) = body.split_at(body.len() - CHECKSUM_LEN);
+    let expected = u32::from_le_bytes(trailer.try_into().unwrap());
+    let actual = crc32fast::hash(covered);
+    if expected != actual {
+        return Err(ChecksumMismatch { expected, actual }.into());
+    }
+
+    let mut cursor = covered;
+    let serial = read_u64(&mut cursor).context("reading PDU serial")?;
+    let ident = read_u64(&mut cursor).context("reading PDU ident")?;
+
+    if is_compressed {
+        metrics::histogram!("pdu.decode.compressed.size", cursor.len() as f64);
+    } else {
+        metrics::histogram!("pdu.decode.size", cursor.len() as f64);
+    }
+
+    Ok(Decoded {
+        ident,
+        serial,
+        data: cursor.to_vec(),
+        is_compressed,
+        frame_len: encoded_length(tagged_len) + body.len(),
+    })
 }
 
 /// Decode a frame.
 /// See encode_raw() for the frame format.
+/// If `require_checksum` is set, a frame without a checksum is rejected.
 async fn decode_raw_async<R: Unpin + AsyncRead + std::fmt::Debug>(
     r: &mut R,
+    require_checksum: bool,
+    max_frame_len: usize,
 ) -> anyhow::Result<Decoded> {
-    let len = read_u64_async(r).await.context("reading PDU length")?;
-    let (len, is_compressed) = if (len & COMPRESSED_MASK) != 0 {
-        (len & !COMPRESSED_MASK, true)
-    } else {
-        (len, false)
-    };
+    let tagged_len = read_u64_async(r).await.context("reading PDU length")?;
+    let (len, is_compressed, has_checksum) = untag_len(tagged_len);
+    check_frame_len(len, max_frame_len)?;
+    if require_checksum && !has_checksum {
+        return Err(MissingChecksum.into());
+    }
+    if has_checksum {
+        let mut body = vec![0u8; len as usize];
+        r.read_exact(&mut body)
+            .await
+            .with_context(|| format!("reading {} bytes of checksummed PDU", len))?;
+        return decode_checksummed(tagged_len, is_compressed, body);
+    }
     let serial = read_u64_async(r).await.context("reading PDU serial")?;
     let ident = read_u64_async(r).await.context("reading PDU ident")?;
     let data_len =
@@ -206,18 +449,30 @@ async fn decode_raw_async<R: Unpin + AsyncRead + std::fmt::Debug>(
         serial,
         data,
         is_compressed,
+        frame_len: encoded_length(tagged_len) + len as usize,
     })
 }
 
 /// Decode a frame.
 /// See encode_raw() for the frame format.
-fn decode_raw<R: std::io::Read>(mut r: R) -> anyhow::Result<Decoded> {
-    let len = read_u64(r.by_ref()).context("reading PDU length")?;
-    let (len, is_compressed) = if (len & COMPRESSED_MASK) != 0 {
-        (len & !COMPRESSED_MASK, true)
-    } else {
-        (len, false)
-    };
+/// If `require_checksum` is set, a frame without a checksum is rejected.
+fn decode_raw<R: std::io::Read>(
+    mut r: R,
+    require_checksum: bool,
+    max_frame_len: usize,
+) -> anyhow::Result<Decoded> {
+    let tagged_len = read_u64(r.by_ref()).context("reading PDU length")?;
+    let (len, is_compressed, has_checksum) = untag_len(tagged_len);
+    check_frame_len(len, max_frame_len)?;
+    if require_checksum && !has_checksum {
+        return Err(MissingChecksum.into());
+    }
+    if has_checksum {
+        let mut body = vec![0u8; len as usize];
+        r.read_exact(&mut body)
+            .with_context(|| format!("reading {} bytes of checksummed PDU", len))?;
+        return decode_checksummed(tagged_len, is_compressed, body);
+    }
     let serial = read_u64(r.by_ref()).context("reading PDU serial")?;
     let ident = read_u64(r.by_ref()).context("reading PDU ident")?;
     let data_len =
@@ -253,6 +508,7 @@ fn decode_raw<R: std::io::Read>(mut r: R) -> anyhow::Result<Decoded> {
         serial,
         data,
         is_compressed,
+        frame_len: encoded_length(tagged_len) + len as usize,
     })
 }
 
@@ -265,15 +521,30 @@ pub struct DecodedPdu {
 /// If the serialized size is larger than this, then we'll consider compressing it
 const COMPRESS_THRESH: usize = 32;
 
-fn serialize<T: serde::Serialize>(t: &T) -> Result<(Vec<u8>, bool), Error> {
+fn serialize_uncompressed<T: serde::Serialize>(t: &T) -> Result<Vec<u8>, Error> {
     let mut uncompressed = Vec::new();
     let mut encode = varbincode::Serializer::new(&mut uncompressed);
     t.serialize(&mut encode)?;
+    Ok(uncompressed)
+}
+
+fn serialize<T: serde::Serialize>(t: &T) -> Result<(Vec<u8>, bool), Error> {
+    serialize_counted(t, &mut CompressionStats::default())
+}
+
+/// Serialize `t`, as `serialize` does, adding the work that was
+/// done to compress it to `stats`
+fn serialize_counted<T: serde::Serialize>(
+    t: &T,
+    stats: &mut CompressionStats,
+) -> Result<(Vec<u8>, bool), Error> {
+    let uncompressed = serialize_uncompressed(t)?;
 
     if uncompressed.len() <= COMPRESS_THRESH {
         return Ok((uncompressed, false));
     }
     // It's a little heavy; let's try compressing it
+    let start = Instant::now();
     let mut compressed = Vec::new();
     let mut compress = zstd::Encoder::new(&mut compressed, zstd::DEFAULT_COMPRESSION_LEVEL)?;
     let mut encode = varbincode::Serializer::new(&mut compress);
@@ -287,21 +558,49 @@ fn serialize<T: serde::Serialize>(t: &T) -> Result<(Vec<u8>, bool), Error> {
         uncompressed.len()
     );
 
+    let elapsed = start.elapsed();
     if compressed.len() < uncompressed.len() {
+        stats.record(uncompressed.len(), compressed.len(), elapsed);
         Ok((compressed, true))
     } else {
+        stats.record(uncompressed.len(), uncompressed.len(), elapsed);
         Ok((uncompressed, false))
     }
 }
 
 fn deserialize<T: serde::de::DeserializeOwned, R: std::io::Read>(
+    r: R,
+    is_compressed: bool,
+) -> Result<T, Error> {
+    deserialize_with_max_len(r, is_compressed, DEFAULT_MAX_FRAME_LEN)
+}
+
+/// Deserialize a PDU body, failing with `FrameTooLarge` if it
+/// decompresses to more than `max_frame_len` bytes
+fn deserialize_with_max_len<T: serde::de::DeserializeOwned, R: std::io::Read>(
     mut r: R,
     is_compressed: bool,
+    max_frame_len: usize,
 ) -> Result<T, Error> {
     if is_compressed {
-        let mut decompress = zstd::Decoder::new(r)?;
-        let mut decode = varbincode::Deserializer::new(&mut decompress);
-        serde::Deserialize::deserialize(&mut decode).map_err(Into::into)
+        // Bound the decompressed size too, so that a small frame
+        // can't expand into an unreasonable amount of memory.
+        // Allow one byte more than the limit, so that reaching it
+        // shows that the PDU is too large, rather than truncated.
+        let limit = max_frame_len as u64 + 1;
+        let mut decompress = std::io::Read::take(zstd::Decoder::new(r)?, limit);
+        let result = {
+            let mut decode = varbincode::Deserializer::new(&mut decompress);
+            serde::Deserialize::deserialize(&mut decode)
+        };
+        if decompress.limit() == 0 {
+            return Err(FrameTooLarge {
+                len: limit,
+                max: max_frame_len,
+            }
+            .into());
+        }
+        result.map_err(Into::into)
     } else {
         let mut decode = varbincode::Deserializer::new(&mut r);
         serde::Deserialize::deserialize(&mut decode).map_err(Into::into)
@@ -312,13 +611,28 @@ macro_rules! pdu {
     ($( $name:ident:$vers:expr),* $(,)?) => {
         #[derive(PartialEq, Debug)]
         pub enum Pdu {
-            Invalid{ident: u64},
+            /// A PDU with an ident that we don't recognize, most likely
+            /// sent by a newer peer.  Its `len` bytes of data have been
+            /// consumed from the stream so that decoding can continue
+            /// with the next frame.
+            Invalid{ident: u64, len: usize},
             $(
                 $name($name)
             ,)*
         }
 
         impl Pdu {
+            /// Returns the name of the type of this PDU, for use
+            /// in diagnostics
+            pub fn pdu_name(&self) -> &'static str {
+                match self {
+                    Pdu::Invalid{..} => "Invalid",
+                    $(
+                        Pdu::$name(_) => stringify!($name),
+                    )*
+                }
+            }
+
             pub fn encode<W: std::io::Write>(&self, w: W, serial: u64) -> Result<(), Error> {
                 match self {
                     Pdu::Invalid{..} => bail!("attempted to serialize Pdu::Invalid"),
@@ -334,23 +648,103 @@ macro_rules! pdu {
                 }
             }
 
-            pub async fn encode_async<W: Unpin + AsyncWriteExt>(&self, w: &mut W, serial: u64) -> Result<(), Error> {
+            fn encode_as_vec(&self, serial: u64, checksum: bool) -> Result<Vec<u8>, Error> {
+                self.encode_as_vec_counted(serial, checksum, &mut CompressionStats::default())
+            }
+
+            /// Encode the PDU, adding the work that was done to
+            /// compress it to `stats`
+            fn encode_as_vec_counted(
+                &self,
+                serial: u64,
+                checksum: bool,
+                stats: &mut CompressionStats,
+            ) -> Result<Vec<u8>, Error> {
                 match self {
                     Pdu::Invalid{..} => bail!("attempted to serialize Pdu::Invalid"),
                     $(
                         Pdu::$name(s) => {
-                            let (data, is_compressed) = serialize(s)?;
-                            let encoded_size = encode_raw_async($vers, serial, &data, is_compressed, w).await?;
-                            metrics::histogram!("pdu.size", encoded_size as f64, "pdu" => stringify!($name));
-                            metrics::histogram!("pdu.size.rate", encoded_size as f64, "pdu" => stringify!($name));
-                            Ok(())
+                            let (data, is_compressed) = serialize_counted(s, stats)?;
+                            let buffer = encode_raw_as_vec($vers, serial, &data, is_compressed, checksum)?;
+                            metrics::histogram!("pdu.size", buffer.len() as f64, "pdu" => stringify!($name));
+                            metrics::histogram!("pdu.size.rate", buffer.len() as f64, "pdu" => stringify!($name));
+                            Ok(buffer)
+                        }
+                    ,)*
+                }
+            }
+
+            /// Encode the PDU, compressing its body with the compression
+            /// stream of the connection
+            fn encode_as_vec_streamed(
+                &self,
+                serial: u64,
+                checksum: bool,
+                compressor: &mut StreamCompressor,
+                stats: &mut CompressionStats,
+            ) -> Result<Vec<u8>, Error> {
+                match self {
+                    Pdu::Invalid{..} => bail!("attempted to serialize Pdu::Invalid"),
+                    $(
+                        Pdu::$name(s) => {
+                            let uncompressed = serialize_uncompressed(s)?;
+                            let start = Instant::now();
+                            let data = compressor.compress(&uncompressed)?;
+                            stats.record(uncompressed.len(), data.len(), start.elapsed());
+                            let buffer = encode_raw_as_vec($vers, serial, &data, true, checksum)?;
+                            metrics::histogram!("pdu.size", buffer.len() as f64, "pdu" => stringify!($name));
+                            metrics::histogram!("pdu.size.rate", buffer.len() as f64, "pdu" => stringify!($name));
+                            Ok(buffer)
                         }
                     ,)*
                 }
             }
 
+            /// Encode the PDU to the stream, returning the number of
+            /// bytes that were written.
+            pub async fn encode_async<W: Unpin + AsyncWriteExt>(&self, w: &mut W, serial: u64) -> Result<usize, Error> {
+                self.encode_async_framed(w, serial, &mut Framing::default()).await
+            }
+
+            /// Encode the PDU to the stream using the framing options
+            /// that have been negotiated for the session.
+            /// Returns the number of bytes that were written.
+            pub async fn encode_async_framed<W: Unpin + AsyncWriteExt>(
+                &self,
+                w: &mut W,
+                serial: u64,
+                framing: &mut Framing,
+            ) -> Result<usize, Error> {
+                let mut buffer = match framing.compressor.as_mut() {
+                    Some(compressor) => self.encode_as_vec_streamed(
+                        serial,
+                        framing.checksum,
+                        compressor,
+                        &mut framing.compression,
+                    )?,
+                    None => self.encode_as_vec_counted(
+                        serial,
+                        framing.checksum,
+                        &mut framing.compression,
+                    )?,
+                };
+                if let Some(cipher) = framing.cipher.as_mut() {
+                    buffer = cipher.seal(&buffer)?;
+                }
+                w.write_all(&buffer)
+                    .await
+                    .context("writing pdu data buffer")?;
+                Ok(buffer.len())
+            }
+
             pub fn decode<R: std::io::Read>(r: R) -> Result<DecodedPdu, Error> {
-                let decoded = decode_raw(r).context("decoding a PDU")?;
+                Self::decode_with_max_len(r, DEFAULT_MAX_FRAME_LEN)
+            }
+
+            /// Decode a PDU, rejecting a frame that is larger than
+            /// `max_frame_len`, or that decompresses to more than that
+            pub fn decode_with_max_len<R: std::io::Read>(r: R, max_frame_len: usize) -> Result<DecodedPdu, Error> {
+                let decoded = decode_raw(r, false, max_frame_len).context("decoding a PDU")?;
                 match decoded.ident {
                     $(
                         $vers => {
@@ -358,7 +752,7 @@ macro_rules! pdu {
                             metrics::histogram!("pdu.size.rate", decoded.data.len() as f64, "pdu" => stringify!($name));
                             Ok(DecodedPdu {
                                 serial: decoded.serial,
-                                pdu: Pdu::$name(deserialize(decoded.data.as_slice(), decoded.is_compressed)?)
+                                pdu: Pdu::$name(deserialize_with_max_len(decoded.data.as_slice(), decoded.is_compressed, max_frame_len)?)
                             })
                         }
                     ,)*
@@ -367,7 +761,7 @@ macro_rules! pdu {
                         metrics::histogram!("pdu.size.rate", decoded.data.len() as f64, "pdu" => "??");
                         Ok(DecodedPdu {
                             serial: decoded.serial,
-                            pdu: Pdu::Invalid{ident:decoded.ident}
+                            pdu: Pdu::Invalid{ident:decoded.ident, len:decoded.data.len()}
                         })
                     }
                 }
@@ -378,14 +772,78 @@ macro_rules! pdu {
                       R: AsyncRead,
                       R: std::fmt::Debug
             {
-                let decoded = decode_raw_async(r).await.context("decoding a PDU")?;
+                Self::decode_async_with_size(r).await.map(|(decoded, _)| decoded)
+            }
+
+            /// Decode a PDU from the stream, returning it along with
+            /// the number of bytes that it occupied on the wire.
+            pub async fn decode_async_with_size<R>(r: &mut R) -> Result<(DecodedPdu, usize), Error>
+                where R: std::marker::Unpin,
+                      R: AsyncRead,
+                      R: std::fmt::Debug
+            {
+                let decoded = decode_raw_async(r, false, DEFAULT_MAX_FRAME_LEN)
+                    .await
+                    .context("decoding a PDU")?;
+                let frame_len = decoded.frame_len;
+                Ok((Self::from_decoded(decoded, DEFAULT_MAX_FRAME_LEN)?, frame_len))
+            }
+
+            /// Decode a PDU from the stream using the framing options
+            /// that have been negotiated for the session.
+            /// Returns the PDU along with the number of bytes that it
+            /// occupied on the wire.
+            pub async fn decode_async_framed<R>(
+                r: &mut R,
+                framing: &mut Framing,
+            ) -> Result<(DecodedPdu, usize), Error>
+                where R: std::marker::Unpin,
+                      R: AsyncRead,
+                      R: std::fmt::Debug
+            {
+                let max_frame_len = framing.max_frame_len;
+                let (mut decoded, frame_len) = match framing.cipher.as_mut() {
+                    None => {
+                        let decoded = decode_raw_async(r, framing.checksum, max_frame_len)
+                            .await
+                            .context("decoding a PDU")?;
+                        let frame_len = decoded.frame_len;
+                        (decoded, frame_len)
+                    }
+                    Some(cipher) => {
+                        let (frame, frame_len) = cipher.read_sealed_async(r, max_frame_len).await?;
+                        let decoded = decode_raw(frame.as_slice(), framing.checksum, max_frame_len)
+                            .context("decoding a sealed PDU")?;
+                        (decoded, frame_len)
+                    }
+                };
+                // Once the stream is enabled, a compressed frame has been
+                // compressed by the stream rather than on its own
+                if let Some(decompressor) = framing.decompressor.as_mut() {
+                    if decoded.is_compressed {
+                        decoded.data = decompressor.decompress(&decoded.data, max_frame_len)?;
+                        decoded.is_compressed = false;
+                    }
+                }
+                Ok((Self::from_decoded(decoded, max_frame_len)?, frame_len))
+            }
+
+            /// Decode a sealed PDU from the stream
+            pub fn decode_sealed<R: std::io::Read>(r: R, cipher: &mut FrameCipher) -> Result<DecodedPdu, Error> {
+                let (frame, _) = cipher.read_sealed(r, DEFAULT_MAX_FRAME_LEN)?;
+                let decoded = decode_raw(frame.as_slice(), false, DEFAULT_MAX_FRAME_LEN)
+                    .context("decoding a sealed PDU")?;
+                Self::from_decoded(decoded, DEFAULT_MAX_FRAME_LEN)
+            }
+
+            fn from_decoded(decoded: Decoded, max_frame_len: usize) -> Result<DecodedPdu, Error> {
                 match decoded.ident {
                     $(
                         $vers => {
                             metrics::histogram!("pdu.size", decoded.data.len() as f64, "pdu" => stringify!($name));
                             Ok(DecodedPdu {
                                 serial: decoded.serial,
-                                pdu: Pdu::$name(deserialize(decoded.data.as_slice(), decoded.is_compressed)?)
+                                pdu: Pdu::$name(deserialize_with_max_len(decoded.data.as_slice(), decoded.is_compressed, max_frame_len)?)
                             })
                         }
                     ,)*
@@ -393,7 +851,7 @@ macro_rules! pdu {
                         metrics::histogram!("pdu.size", decoded.data.len() as f64, "pdu" => "??");
                         Ok(DecodedPdu {
                             serial: decoded.serial,
-                            pdu: Pdu::Invalid{ident:decoded.ident}
+                            pdu: Pdu::Invalid{ident:decoded.ident, len:decoded.data.len()}
                         })
                     }
                 }
@@ -405,7 +863,24 @@ macro_rules! pdu {
 /// The overall version of the codec.
 /// This must be bumped when backwards incompatible changes
 /// are made to the types and protocol.
-pub const CODEC_VERSION: usize = 18;
+pub const CODEC_VERSION: usize = 58;
+
+/// The oldest codec version of a client that a server can serve.
+/// A server accepts clients whose codec version lies within
+/// `MIN_SUPPORTED_CODEC_VERSION..=CODEC_VERSION`, and responds to
+/// the `GetCodecVersion` of any other client with `IncompatibleCodec`.
+/// A client below that range must be upgraded; a client above it
+/// needs a newer server.
+/// Every version so far has changed the encoding of some PDU, so
+/// this is the same as CODEC_VERSION.  It may be lowered by a future
+/// version that retains the ability to talk to older clients.
+pub const MIN_SUPPORTED_CODEC_VERSION: usize = CODEC_VERSION;
+
+/// Returns true if a server can serve a client whose codec version
+/// is `codec_vers`
+pub fn is_supported_codec_version(codec_vers: usize) -> bool {
+    (MIN_SUPPORTED_CODEC_VERSION..=CODEC_VERSION).contains(&codec_vers)
+}
 
 // Defines the Pdu enum.
 // Each struct has an explicit identifying number.
@@ -448,6 +923,92 @@ pdu! {
     GetClientListResponse: 42,
     SetWindowWorkspace: 43,
     WindowWorkspaceChanged: 44,
+    Authenticate: 45,
+    GetServerStats: 47,
+    GetServerStatsResponse: 48,
+    SpawnSplit: 49,
+    SpawnSplitResponse: 50,
+    SendKeyDownBatch: 51,
+    GetWindows: 52,
+    GetWindowsResponse: 53,
+    WindowStructureChanged: 54,
+    NegotiateEncryption: 55,
+    NegotiateEncryptionResponse: 56,
+    EnableFrameChecksums: 57,
+    UnimplementedResponse: 58,
+    ResizeWindow: 59,
+    SetSelection: 60,
+    SetClientInfo: 61,
+    ListClients: 62,
+    ListClientsResponse: 63,
+    ListDomains: 64,
+    ListDomainsResponse: 65,
+    DetachDomain: 66,
+    AttachDomain: 67,
+    TrimScrollback: 68,
+    ExportScrollback: 69,
+    ExportScrollbackResponse: 70,
+    SetWriteAccess: 71,
+    ImageAck: 73,
+    InvalidateTab: 74,
+    Notification: 75,
+    SetProgress: 76,
+    SetFocusedTab: 77,
+    TryWriteToPane: 78,
+    WriteToPaneResponse: 79,
+    ReorderTab: 80,
+    GetTabScreenshot: 81,
+    GetTabScreenshotResponse: 82,
+    BlobChunk: 83,
+    EnableBlobChannel: 84,
+    CursorUpdate: 85,
+    WriteTextToPane: 86,
+    GetEnv: 87,
+    GetEnvResponse: 88,
+    CloseWindow: 89,
+    BeginPaste: 90,
+    BeginPasteResponse: 91,
+    PasteChunk: 92,
+    EndPaste: 93,
+    SetTabTitlePolicy: 94,
+    IncompatibleCodec: 95,
+    DrainTab: 96,
+    EnableStreamCompression: 97,
+    ListSessions: 98,
+    ListSessionsResponse: 99,
+    KillSession: 100,
+    SpawnFailed: 101,
+    GetPaneLayout: 102,
+    GetPaneLayoutResponse: 103,
+    GetTabStats: 104,
+    GetTabStatsResponse: 105,
+    FreezeTab: 106,
+    Barrier: 107,
+    BarrierResponse: 108,
+    SendText: 109,
+    NewWindow: 110,
+    NewWindowResponse: 111,
+    GetCapabilities: 112,
+    GetCapabilitiesResponse: 113,
+    SerializeTabState: 114,
+    SerializeTabStateResponse: 115,
+    RestoreTabState: 116,
+    TabExited: 117,
+    GetCellMetrics: 118,
+    GetCellMetricsResponse: 119,
+    SetCellMetrics: 120,
+    TrickleScrollback: 121,
+    ScrollbackChunk: 122,
+    TailTab: 123,
+    TailTabResponse: 124,
+    TailLines: 125,
+    StopTail: 126,
+    SubscribeClipboard: 127,
+    ResizeResponse: 128,
+    ValidateSpawn: 129,
+    ValidateSpawnResponse: 130,
+    GetTab: 131,
+    GetTabResponse: 132,
 }
 
 impl Pdu {
@@ -522,7 +1083,11 @@ impl Pdu {
             Pdu::GetPaneRenderChangesResponse(GetPaneRenderChangesResponse { pane_id, .. })
             | Pdu::SetPalette(SetPalette { pane_id, .. })
             | Pdu::NotifyAlert(NotifyAlert { pane_id, .. })
+            | Pdu::Notification(Notification { pane_id, .. })
+            | Pdu::SetProgress(SetProgress { pane_id, .. })
+            | Pdu::CursorUpdate(CursorUpdate { pane_id, .. })
             | Pdu::SetClipboard(SetClipboard { pane_id, .. })
+            | Pdu::ScrollbackChunk(ScrollbackChunk { pane_id, .. })
             | Pdu::PaneRemoved(PaneRemoved { pane_id }) => Some(*pane_id),
             _ => None,
         }
@@ -532,13 +1097,137 @@ impl Pdu {
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
 pub struct UnitResponse {}
 
+/// Sent in response to a request whose PDU type the peer
+/// doesn't recognize; this typically means that the peer is
+/// running an older version.  The unrecognized frame has been
+/// skipped and the session remains usable.
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct UnimplementedResponse {
+    /// The serial of the request that was not recognized
+    pub serial: u64,
+    /// The ident of the request that was not recognized
+    pub ident: u64,
+}
+
+/// Classifies the reason that a request failed
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ErrorCode {
+    NoSuchTab,
+    NoSuchPane,
+    NoSuchWindow,
+    NoSuchDomain,
+    /// The session isn't allowed to make the request
+    PermissionDenied,
+    /// The session has made too many requests of this kind
+    RateLimited,
+    /// The server doesn't support the request, or the way
+    /// that it was used
+    Unsupported,
+    /// Any other failure, including those reported by servers
+    /// that predate the codes
+    Internal,
+}
+
+/// Sent in response to a request that failed.  `code` allows the
+/// client to react to the kind of failure without having to match
+/// on `reason`, which is only intended to be shown to the user.
+/// The client surfaces this as an error that can be downcast to it.
+#[derive(Serialize, PartialEq, Debug, Clone)]
 pub struct ErrorResponse {
+    /// A human readable description of the failure
     pub reason: String,
+    pub code: ErrorCode,
 }
 
-#[derive(Deserialize, Serialize, PartialEq, Debug)]
-pub struct GetCodecVersion {}
+/// Servers that predate `code` send only the reason, which the
+/// derived implementation would fail to decode; such a response
+/// is decoded with the `Internal` code instead.
+impl<'de> Deserialize<'de> for ErrorResponse {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct ErrorResponseVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for ErrorResponseVisitor {
+            type Value = ErrorResponse;
+
+            fn expecting(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+                fmt.write_str("struct ErrorResponse")
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<ErrorResponse, A::Error>
+            where
+                A: serde::de::SeqAccess<'de>,
+            {
+                let reason = seq
+                    .next_element()?
+                    .ok_or_else(|| serde::de::Error::invalid_length(0, &self))?;
+                // Running out of data here means that the field is absent
+                let code = seq
+                    .next_element()
+                    .ok()
+                    .flatten()
+                    .unwrap_or(ErrorCode::Internal);
+                Ok(ErrorResponse { reason, code })
+            }
+        }
+
+        deserializer.deserialize_struct("ErrorResponse", &["reason", "code"], ErrorResponseVisitor)
+    }
+}
+
+impl std::fmt::Display for ErrorResponse {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "{}", self.reason)
+    }
+}
+
+impl std::error::Error for ErrorResponse {}
+
+/// The first request made by a client.  It carries the codec version
+/// of the client so that the server can reject an incompatible client
+/// with `IncompatibleCodec` rather than fail to decode its requests.
+/// Servers that predate this field ignore it and respond with their
+/// own version, leaving the client to detect the mismatch.
+#[derive(Serialize, PartialEq, Debug)]
+pub struct GetCodecVersion {
+    /// None if the client predates this field, which the server
+    /// treats as an unsupported version
+    pub codec_vers: Option<usize>,
+}
+
+/// Clients that predate `codec_vers` send an empty struct, which the
+/// derived implementation would fail to decode; such a request is
+/// decoded with `codec_vers` set to None instead, so that the server
+/// can still respond with `IncompatibleCodec`.
+impl<'de> Deserialize<'de> for GetCodecVersion {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct GetCodecVersionVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for GetCodecVersionVisitor {
+            type Value = GetCodecVersion;
+
+            fn expecting(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+                fmt.write_str("struct GetCodecVersion")
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<GetCodecVersion, A::Error>
+            where
+                A: serde::de::SeqAccess<'de>,
+            {
+                // Running out of data here means that the field is absent
+                let codec_vers = seq.next_element::<Option<usize>>().ok().flatten().flatten();
+                Ok(GetCodecVersion { codec_vers })
+            }
+        }
+
+        deserializer.deserialize_struct("GetCodecVersion", &["codec_vers"], GetCodecVersionVisitor)
+    }
+}
 
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
 pub struct GetCodecVersionResponse {
@@ -546,12 +1235,60 @@ pub struct GetCodecVersionResponse {
     pub version_string: String,
     pub executable_path: PathBuf,
     pub config_file_path: Option<PathBuf>,
+    /// The algorithm that the server uses to hash the rows named by
+    /// `GetLines::known_hashes`, or None if it doesn't compare them
+    pub row_hash: Option<RowHashAlgorithm>,
+}
+
+/// Sent in response to `GetCodecVersion` when the codec version of
+/// the client is outside of the range that the server supports
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct IncompatibleCodec {
+    /// The wezterm version of the server
+    pub server_vers: String,
+    pub min_supported: usize,
+    pub max_supported: usize,
+}
+
+/// A liveness check.  When `timestamp` is provided, the client is
+/// measuring the round trip latency; the server echoes it back in
+/// the `Pong` along with the depth of its write queue.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Default)]
+pub struct Ping {
+    /// An opaque value chosen by the client, typically the
+    /// time at which it sent the Ping
+    pub timestamp: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug, Default)]
+pub struct Pong {
+    /// The timestamp from the corresponding Ping
+    pub timestamp: Option<u64>,
+    /// The number of PDUs that were waiting to be written to the
+    /// client when the Ping was processed.  Only reported when
+    /// the Ping has a timestamp.
+    pub queue_depth: Option<usize>,
+    /// Set when the main thread of the server has so much work queued
+    /// up that it is putting off the work that can wait, such as the
+    /// pushes for background tabs, so that the client can tell that
+    /// the server rather than the link is the cause of any latency
+    pub busy: bool,
 }
 
+/// Answered with a `BarrierResponse` only once every request that the
+/// session sent before it has completed, and after any work that
+/// those requests queued for the main thread, giving scripted
+/// sequences of requests a happens-before relationship.
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
-pub struct Ping {}
+pub struct Barrier {
+    /// An opaque value that is echoed back in the response
+    pub token: u64,
+}
+
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
-pub struct Pong {}
+pub struct BarrierResponse {
+    pub token: u64,
+}
 
 /// Requests a client certificate to authenticate against
 /// the TLS based server
@@ -567,14 +1304,69 @@ pub struct GetTlsCredsResponse {
     pub client_cert_pem: String,
 }
 
+/// Lists the tabs of the server.
+/// A client that is attaching to a particular window, as when it
+/// reconnects, can name it in `initial_window_id`; the response then
+/// lists just the tabs of that window, and the server follows it with
+/// a `WindowStructureChanged` for the window and the render changes
+/// of its active tab, without waiting for the client to ask for them.
+/// All of the tabs are listed when it is None, or when there is no
+/// such window.
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
-pub struct ListPanes {}
+pub struct ListPanes {
+    pub initial_window_id: Option<WindowId>,
+}
 
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
 pub struct ListPanesResponse {
     pub tabs: Vec<PaneNode>,
 }
 
+/// Returns the panes of a single tab, as `ListPanes` would list it,
+/// for a client that wants to refresh what it knows of that tab
+/// without listing all of them.  Fails with `NoSuchTab` if there
+/// is no such tab.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetTab {
+    pub tab_id: TabId,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetTabResponse {
+    pub tab: PaneNode,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetWindows {}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct WindowEntry {
+    pub window_id: WindowId,
+    /// The title that was given to the window by `NewWindow`, or
+    /// else that of the active pane in the active tab
+    pub title: String,
+    pub workspace: String,
+    /// The tabs in this window, in the order that they are displayed
+    pub tab_ids: Vec<TabId>,
+    /// The activity of each tab, in the same order as `tab_ids`
+    pub tab_activity: Vec<TabActivity>,
+}
+
+/// What has happened in a tab since the client last viewed it,
+/// either by focusing it or by fetching the lines of one of its panes
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Default, Clone, Copy)]
+pub struct TabActivity {
+    /// A pane in the tab has produced output
+    pub output: bool,
+    /// A pane in the tab has rung the bell
+    pub bell: bool,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetWindowsResponse {
+    pub windows: Vec<WindowEntry>,
+}
+
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
 pub struct SplitPane {
     pub pane_id: PaneId,
@@ -584,6 +1376,26 @@ pub struct SplitPane {
     pub domain: config::keyassignment::SpawnTabDomain,
 }
 
+/// Split the active pane of an existing tab
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SpawnSplit {
+    pub tab_id: TabId,
+    pub direction: SplitDirection,
+    /// The proportion of the split pane to give to the new pane
+    pub size_percent: u8,
+    pub command: Option<CommandBuilder>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SpawnSplitResponse {
+    pub tab_id: TabId,
+    pub pane_id: PaneId,
+    pub window_id: WindowId,
+    pub size: PtySize,
+    /// The layout of the tab after the split was applied
+    pub layout: PaneNode,
+}
+
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
 pub struct SpawnV2 {
     pub domain: config::keyassignment::SpawnTabDomain,
@@ -593,6 +1405,9 @@ pub struct SpawnV2 {
     pub command_dir: Option<String>,
     pub size: PtySize,
     pub workspace: String,
+    /// If true, keep the pane open showing its final output after
+    /// the process exits, until it is explicitly killed
+    pub hold: bool,
 }
 
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
@@ -605,12 +1420,74 @@ pub struct KillPane {
     pub pane_id: PaneId,
 }
 
+/// Classifies the reason that a spawn failed
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub enum SpawnErrorKind {
+    /// The program doesn't exist, or wasn't found in the PATH
+    CommandNotFound,
+    /// The program, or a directory leading to it, isn't accessible
+    PermissionDenied,
+    /// The domain doesn't exist, or is detached
+    DomainUnavailable,
+    /// The session already has as many spawns as it is allowed;
+    /// see `mux_max_concurrent_spawns`
+    ResourceExhausted,
+    /// The directory to run the program in doesn't exist; this is only
+    /// reported by `ValidateSpawn`, as a spawn runs the program in the
+    /// home directory instead
+    DirectoryNotFound,
+    Other,
+}
+
+/// Sent in place of `ErrorResponse` when a request that spawns a
+/// program fails, so that the client can react to the kind of
+/// failure; for example, by suggesting that the PATH be fixed.
+/// The client surfaces this as an error that can be downcast to it.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct SpawnFailed {
+    pub kind: SpawnErrorKind,
+    /// A human readable description of the failure
+    pub reason: String,
+}
+
+impl std::fmt::Display for SpawnFailed {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "{}", self.reason)
+    }
+}
+
+impl std::error::Error for SpawnFailed {}
+
+/// Check whether a spawn of `command` in `command_dir` would succeed,
+/// without spawning anything: the domain must be available, the program
+/// must exist, in the PATH of the domain if it is relative, and
+/// `command_dir`, if any, must be a directory.  The response is a
+/// `ValidateSpawnResponse`.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ValidateSpawn {
+    pub domain: config::keyassignment::SpawnTabDomain,
+    pub command: Option<CommandBuilder>,
+    pub command_dir: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ValidateSpawnResponse {
+    /// The path of the program that would be run, when the domain can
+    /// tell without spawning it
+    pub program: Option<String>,
+    /// Why the spawn would fail, or None if it is expected to succeed
+    pub failure: Option<SpawnFailed>,
+}
+
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
 pub struct SpawnResponse {
     pub tab_id: TabId,
     pub pane_id: PaneId,
     pub window_id: WindowId,
     pub size: PtySize,
+    /// The dimensions of the new pane, so that the client can
+    /// render it without first asking for them
+    pub dimensions: RenderableDimensions,
 }
 
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
@@ -619,24 +1496,106 @@ pub struct WriteToPane {
     pub data: Vec<u8>,
 }
 
+/// Like `WriteToPane`, but the server verifies that `data` is well
+/// formed UTF-8 before writing any of it, and replies with an error
+/// rather than writing anything if it is not.  Intended for key text
+/// and pastes; raw binary data should be sent using `WriteToPane`.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct WriteTextToPane {
+    pub pane_id: PaneId,
+    pub data: Vec<u8>,
+}
+
+/// Like `WriteToPane`, but rather than blocking until all of `data`
+/// has been written, the server writes as much as the pty will
+/// accept and reports how much that was in a `WriteToPaneResponse`.
+/// A client can use this to pace a large paste.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct TryWriteToPane {
+    pub pane_id: PaneId,
+    pub data: Vec<u8>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct WriteToPaneResponse {
+    /// The number of bytes from the start of `data` that were written
+    pub written: usize,
+    /// True if the pty could not accept all of `data`; the client
+    /// should wait a little before sending the remainder
+    pub full: bool,
+}
+
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
 pub struct SendPaste {
     pub pane_id: PaneId,
     pub data: String,
 }
 
+/// Begin a paste whose text is too large to send as a single
+/// `SendPaste`.  The text is sent in a sequence of `PasteChunk`s
+/// using the `paste_id` from the `BeginPasteResponse`, and the paste
+/// is finished with `EndPaste`.  The server writes each chunk to the
+/// pane as it arrives, so a client that waits for the response to
+/// each chunk before sending the next is paced by the pane.
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
-pub struct SendKeyDown {
-    pub pane_id: TabId,
-    pub event: termwiz::input::KeyEvent,
-    pub input_serial: InputSerial,
+pub struct BeginPaste {
+    pub pane_id: PaneId,
 }
 
-/// InputSerial is used to sequence input requests with output events.
-/// It started life as a monotonic sequence number but evolved into
-/// the number of milliseconds since the unix epoch.
-#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone, Copy, PartialOrd, Ord)]
-pub struct InputSerial(u64);
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct BeginPasteResponse {
+    pub paste_id: u64,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct PasteChunk {
+    pub paste_id: u64,
+    pub data: String,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct EndPaste {
+    pub paste_id: u64,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SendKeyDown {
+    pub pane_id: TabId,
+    pub event: termwiz::input::KeyEvent,
+    pub input_serial: InputSerial,
+}
+
+/// A sequence of key presses that are applied to the pane in order.
+/// Render changes are computed once, after all of the events have
+/// been applied, and are tagged with `input_serial`, which should be
+/// the serial of the final key press.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SendKeyDownBatch {
+    pub pane_id: PaneId,
+    pub events: Vec<termwiz::input::KeyEvent>,
+    pub input_serial: InputSerial,
+}
+
+/// Text that has been composed by an input method or picked from
+/// an emoji picker, rather than typed as individual key presses.
+/// As for `WriteTextToPane`, the server verifies that `text` is well
+/// formed UTF-8 before writing any of it.  Text that spans several
+/// lines is written as a paste, so that it is bracketed if the
+/// application has asked for that.  The render changes that follow
+/// are pushed in the same way as for `SendKeyDown`, tagged with
+/// `input_serial`.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SendText {
+    pub pane_id: PaneId,
+    pub text: Vec<u8>,
+    pub input_serial: InputSerial,
+}
+
+/// InputSerial is used to sequence input requests with output events.
+/// It started life as a monotonic sequence number but evolved into
+/// the number of milliseconds since the unix epoch.
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone, Copy, PartialOrd, Ord)]
+pub struct InputSerial(u64);
 
 impl InputSerial {
     pub const fn empty() -> Self {
@@ -666,6 +1625,10 @@ impl Into<InputSerial> for std::time::SystemTime {
     }
 }
 
+/// The server may coalesce motion events: a motion event that is
+/// still waiting to be applied when a later motion event arrives for
+/// the same tab is dropped, and its request completes immediately.
+/// Button presses and releases are always applied, in order.
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
 pub struct SendMouseEvent {
     pub pane_id: PaneId,
@@ -675,8 +1638,26 @@ pub struct SendMouseEvent {
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
 pub struct SetClipboard {
     pub pane_id: PaneId,
+    /// The tab that contains the pane that set the clipboard
+    pub tab_id: Option<TabId>,
     pub clipboard: Option<String>,
     pub selection: ClipboardSelection,
+    /// True if the server truncated the clipboard because it was
+    /// larger than its `mux_clipboard_max_size`
+    pub truncated: bool,
+}
+
+/// Ask for the clipboard to be sent with `SetClipboard` whenever it
+/// is set by a pane in any tab, rather than only when the update is
+/// routed to this session by `mux_clipboard_routing`, or stop doing so.
+/// When the same value is set for a selection in more than one tab,
+/// it is only sent to a subscriber once, with the tab that set it
+/// first; an update is sent only if it differs from the last that
+/// was sent to the subscriber for the same selection.
+/// The response is a `UnitResponse`.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SubscribeClipboard {
+    pub subscribe: bool,
 }
 
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
@@ -697,6 +1678,78 @@ pub struct NotifyAlert {
     pub alert: Alert,
 }
 
+/// Pushed to clients when a program running in a pane requests a
+/// desktop notification, via OSC 9 or OSC 777;notify.
+/// It is up to the client whether the notification is displayed.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct Notification {
+    pub pane_id: PaneId,
+    pub title: Option<String>,
+    pub body: String,
+    /// Whether clicking on the notification should focus
+    /// the pane that generated it
+    pub focus: bool,
+}
+
+/// Pushed to clients when the progress reported by the program
+/// running in a pane changes, via the ConEmu OSC 9;4 sequence.
+/// The current progress is also pushed when a client attaches.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SetProgress {
+    pub pane_id: PaneId,
+    pub state: ProgressState,
+    /// The percentage complete, in the range 0-100
+    pub percent: u8,
+}
+
+/// Ask for the size of a cell of the panes in a tab, in pixels
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetCellMetrics {
+    pub tab_id: TabId,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetCellMetricsResponse {
+    pub tab_id: TabId,
+    pub cell_width_px: u16,
+    pub cell_height_px: u16,
+}
+
+/// Set the size of a cell of the panes in a tab, in pixels, keeping
+/// their size in cells.  The response is a `GetCellMetricsResponse`.
+///
+/// A tab has a single cell size, which is used to work out how many
+/// cells are covered by an image that is sized in pixels, such as a
+/// sixel image, when it is placed.  When the sessions viewing a tab
+/// have different cell sizes, the most recent `SetCellMetrics`, or
+/// `Resize` with pixel dimensions, decides it.  Each client draws the
+/// cells of a placed image at its own cell size, so the image keeps
+/// its place in the text, but appears scaled on the other clients by
+/// the ratio of their cell size to the one that it was placed with.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SetCellMetrics {
+    pub tab_id: TabId,
+    pub cell_width_px: u16,
+    pub cell_height_px: u16,
+}
+
+/// Pushed to clients when the server removes a tab that had been
+/// left behind; see `mux_stale_tab_sweep_interval_secs`.  The panes
+/// of the tab are reported as removed before this is sent.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct TabExited {
+    pub tab_id: TabId,
+}
+
+/// Pushed to clients when the set or order of tabs in a window
+/// changes, or when the window is created or removed.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct WindowStructureChanged {
+    pub window_id: WindowId,
+    /// The updated window, or None if it has been removed
+    pub window: Option<WindowEntry>,
+}
+
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
 pub struct WindowWorkspaceChanged {
     pub window_id: WindowId,
@@ -716,11 +1769,551 @@ pub struct GetClientListResponse {
     pub clients: Vec<ClientInfo>,
 }
 
+/// Describes the client to the server, so that it can be
+/// shown to the other clients that are attached to it.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct SetClientInfo {
+    pub label: Option<String>,
+    pub pid: u32,
+    pub hostname: String,
+    /// The client only intends to observe; the server will reject
+    /// any input or change to the mux that it subsequently sends.
+    /// This can't be cleared by sending the info again.
+    pub read_only: bool,
+}
+
+/// Returns an entry for each session that is attached to the server,
+/// including those that have yet to identify themselves
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ListClients {}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct AttachedClient {
+    /// Identifies the session on the server; see `SetWriteAccess`
+    pub session_id: usize,
+    /// The label provided by the client, or a default label for
+    /// a client that didn't provide one
+    pub label: String,
+    pub pid: Option<u32>,
+    pub hostname: Option<String>,
+    /// When the session was established
+    #[serde(with = "ts_seconds")]
+    pub connected_at: DateTime<Utc>,
+    pub read_only: bool,
+    /// The tab that most recently received input from the session
+    pub last_tab_id: Option<TabId>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ListClientsResponse {
+    pub clients: Vec<AttachedClient>,
+}
+
+/// Returns an entry for each session on the server, so that an
+/// operator can find those whose clients went away without detaching.
+/// A read-only session may not list the sessions.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ListSessions {}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct SessionEntry {
+    pub session_id: usize,
+    /// The label provided by the client, or a default label for
+    /// a client that didn't provide one
+    pub label: String,
+    #[serde(with = "ts_seconds")]
+    pub connected_at: DateTime<Utc>,
+    /// The time since the server last received a PDU from the client
+    pub idle: Duration,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ListSessionsResponse {
+    pub sessions: Vec<SessionEntry>,
+}
+
+/// Close the connection of the session `session_id`, releasing the
+/// state that the server holds for its panes and its subscriptions.
+/// A read-only session may not kill sessions.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct KillSession {
+    pub session_id: usize,
+}
+
+/// Grant (or revoke, when `allowed` is false) permission for the
+/// read-only session `session_id` to send input to `tab_id`.
+/// Only a session that is not itself read-only may change the
+/// access of another session.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SetWriteAccess {
+    pub session_id: usize,
+    pub tab_id: TabId,
+    pub allowed: bool,
+}
+
+/// Sent by the client to tell the server which images it holds in
+/// its cache, so that the server can send references to them in place
+/// of their data, and which images it has since evicted from its cache,
+/// so that the server will send their data again if needed.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ImageAck {
+    pub ids: Vec<ImageHash>,
+    pub evicted: Vec<ImageHash>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ListDomains {}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct DomainEntry {
+    pub domain_id: DomainId,
+    pub name: String,
+    pub label: String,
+    pub state: DomainState,
+    pub spawnable: bool,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ListDomainsResponse {
+    pub domains: Vec<DomainEntry>,
+}
+
+/// Detach the tabs of a domain, leaving their processes running.
+/// Detaching a domain that is already detached succeeds.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct DetachDomain {
+    pub domain_id: DomainId,
+}
+
+/// Re-attach to the tabs of a previously detached domain.
+/// The response is a `ListPanesResponse` reflecting the tabs that
+/// are present after attaching.  Attaching a domain that is already
+/// attached succeeds without attaching it a second time.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct AttachDomain {
+    pub domain_id: DomainId,
+}
+
+/// Ask what the server is able to do on the platform that it is
+/// running on, so that a client can leave out the parts of its UI
+/// that wouldn't work
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetCapabilities {}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetCapabilitiesResponse {
+    /// The names of the signals that the requests of a client can
+    /// cause to be delivered to the processes in a pane; for example,
+    /// `SIGHUP` by `KillPane` and `SIGWINCH` by `Resize`.  This is
+    /// empty on platforms that don't have signals.
+    pub signals: Vec<String>,
+    /// True if sixel and iTerm2 images are displayed
+    pub sixel_images: bool,
+    /// True if the kitty image protocol is enabled
+    pub kitty_images: bool,
+    /// True if panes may be split
+    pub splits: bool,
+    /// The selections that the server forwards to clients
+    /// with `SetClipboard`
+    pub clipboard_selections: Vec<ClipboardSelection>,
+    /// True if the server can respond to `GetTabScreenshot`
+    pub screenshots: bool,
+}
+
+/// Capture the display of the active pane of a tab, so that it can
+/// be recreated by `RestoreTabState`, most likely on another server.
+/// Unlike `ExportScrollback`, the state retains everything that is
+/// needed to redraw the pane as it was.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SerializeTabState {
+    pub tab_id: TabId,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SerializeTabStateResponse {
+    pub tab_id: TabId,
+    /// An encoded `TabState`, which is to be passed as-is to
+    /// `RestoreTabState`
+    pub state: Vec<u8>,
+}
+
+/// Create a tab showing the display that was captured by
+/// `SerializeTabState`.  The process that was running in the tab
+/// can't be moved with it, so `command` is spawned into the tab,
+/// as for `SpawnV2`, once the display has been restored.
+/// The response is a `SpawnResponse`.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct RestoreTabState {
+    pub domain: config::keyassignment::SpawnTabDomain,
+    /// If None, create a new window for the tab
+    pub window_id: Option<WindowId>,
+    pub command: Option<CommandBuilder>,
+    pub command_dir: Option<String>,
+    pub workspace: String,
+    pub state: Vec<u8>,
+}
+
+/// The version of the encoding of `TabState`.  It must be bumped
+/// whenever `TabState`, or anything that it contains, changes;
+/// a server refuses to restore a state with a different version.
+pub const TAB_STATE_VERSION: u64 = 1;
+
+/// The display of a pane, as captured by `SerializeTabState`
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct TabState {
+    pub title: String,
+    pub size: PtySize,
+    /// The lines of the pane, from the top of its scrollback
+    /// to the bottom of its viewport
+    pub lines: SerializedLines,
+    /// The row of the first line of the viewport; the rows of
+    /// `lines` and `cursor` are relative to the same origin
+    pub viewport_top: StableRowIndex,
+    pub cursor: StableCursorPosition,
+    pub modes: TerminalModes,
+}
+
+impl TabState {
+    /// Encode the state as a blob that starts with `TAB_STATE_VERSION`
+    pub fn encode(&self) -> anyhow::Result<Vec<u8>> {
+        let (data, is_compressed) = serialize(self)?;
+        let mut blob = vec![];
+        leb128::write::unsigned(&mut blob, TAB_STATE_VERSION)?;
+        blob.push(is_compressed as u8);
+        blob.extend_from_slice(&data);
+        Ok(blob)
+    }
+
+    /// Decode a blob produced by `encode`, failing if it was
+    /// produced by a version that used a different encoding
+    pub fn decode(blob: &[u8]) -> anyhow::Result<Self> {
+        let mut r = blob;
+        let version = read_u64(&mut r).context("reading tab state version")?;
+        if version != TAB_STATE_VERSION {
+            bail!(
+                "the tab state has version {}, but this server supports only version {}",
+                version,
+                TAB_STATE_VERSION
+            );
+        }
+        let (&is_compressed, data) = r
+            .split_first()
+            .ok_or_else(|| anyhow::anyhow!("the tab state is truncated"))?;
+        deserialize(data, is_compressed != 0).context("decoding tab state")
+    }
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetServerStats {
+    /// If true, include a breakdown of the statistics
+    /// for each connected session in the response
+    pub include_sessions: bool,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone, Default)]
+pub struct SessionStatistics {
+    /// The client id, if the session has declared one
+    pub client_id: Option<ClientId>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub pdus_processed: u64,
+    pub average_processing_time: Duration,
+    /// The work done to compress the frames sent to the client
+    pub compression: CompressionStats,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetServerStatsResponse {
+    pub num_sessions: usize,
+    pub num_windows: usize,
+    pub num_tabs: usize,
+    pub num_panes: usize,
+    /// Totals across all connected sessions
+    pub totals: SessionStatistics,
+    /// Populated only if `include_sessions` was requested
+    pub sessions: Vec<SessionStatistics>,
+    /// The number of tasks that the sessions have waiting for the
+    /// main thread, and the largest number since the server started
+    pub main_thread_queue_depth: usize,
+    pub peak_main_thread_queue_depth: usize,
+}
+
+/// Ask for the number of bytes that this session has been sent on
+/// behalf of the panes in a tab, for bandwidth accounting.
+/// This complements the server-wide totals of `GetServerStats`.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetTabStats {
+    pub tab_id: TabId,
+    /// If true, the counters are reset to zero once they have
+    /// been read
+    pub reset: bool,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetTabStatsResponse {
+    pub tab_id: TabId,
+    /// The encoded size of the render changes and cursor updates
+    pub render_changes_bytes: u64,
+    /// The encoded size of the `GetLinesResponse`s
+    pub get_lines_bytes: u64,
+    /// The number of key presses whose echo was measured, and the
+    /// average time from the arrival of each of them to the push
+    /// of the changes that followed it
+    pub echoes: u64,
+    pub average_echo_latency: Duration,
+}
+
+/// Sent in the clear by a client that has been configured with an
+/// encryption key.  Once the response has been received, all further
+/// frames in both directions are sealed using a session key derived
+/// from the pre-shared key and the two nonces; see the crypt module.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct NegotiateEncryption {
+    pub client_nonce: Vec<u8>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct NegotiateEncryptionResponse {
+    pub server_nonce: Vec<u8>,
+}
+
+/// Sent by a client that is able to verify frame checksums.
+/// A server that understands it replies with `UnitResponse` and
+/// appends a checksum to every subsequent frame that it sends; the
+/// client does likewise once it sees that response.  Decoding is
+/// driven by a flag in the frame length, so either side may start
+/// sending checksums independently of the other.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct EnableFrameChecksums {}
+
+/// Sent by a client that is able to decompress a connection level
+/// compression stream; see the stream module.  A server that
+/// understands it replies with `UnitResponse`, and then compresses
+/// every subsequent frame that it sends with its stream, and expects
+/// the same of the frames that it receives.  The client switches to
+/// the stream in both directions once it sees that response, so the
+/// client must not send anything else while it awaits the response.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct EnableStreamCompression {}
+
+/// Sent by the client to tell the server that it can reassemble
+/// large PDUs that are sent in `BlobChunk`s; see the blob module.
+/// The server responds with `UnitResponse`.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct EnableBlobChannel {}
+
+/// A portion of the encoded frame of a large PDU.  The chunks of
+/// a blob are sent in order, but may be interleaved with other PDUs,
+/// including the chunks of other blobs.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct BlobChunk {
+    pub blob_id: u64,
+    pub data: Vec<u8>,
+    /// True if this is the final chunk of the blob
+    pub last: bool,
+}
+
+/// Presents a shared secret to a server that has been configured
+/// with an `auth_token_file`.  It must be sent before any request
+/// other than `GetCodecVersion`.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct Authenticate {
+    pub token: String,
+}
+
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
 pub struct Resize {
     pub containing_tab_id: TabId,
     pub pane_id: PaneId,
     pub size: PtySize,
+    /// Chosen by the client, increasing with each resize of the
+    /// pane; echoed as the `last_resize_id` of the render changes
+    /// of the pane once the resize has been applied
+    pub resize_id: u64,
+    /// The row at the top of the client's view, when it is scrolled
+    /// back.  Rewrapping the lines to the new width can move the
+    /// content of that row to another row, which is returned as the
+    /// `anchor_row` of the `ResizeResponse`, so that the client can
+    /// keep showing the same content.
+    pub anchor_row: Option<StableRowIndex>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ResizeResponse {
+    /// The row that now holds the start of the content that was at
+    /// the start of the `anchor_row` of the `Resize`, or None if it
+    /// had no anchor, or the anchor isn't in the scrollback of the pane
+    pub anchor_row: Option<StableRowIndex>,
+}
+
+/// Returns the text of a range of rows of a pane as a single string,
+/// suitable for saving a transcript of the session.  Wrapped lines are
+/// joined together.  When `escapes` is true, the text includes SGR
+/// escape sequences to preserve colors and other attributes.
+/// The range is clamped to the rows that exist in the pane.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ExportScrollback {
+    pub pane_id: PaneId,
+    pub range: Range<StableRowIndex>,
+    pub escapes: bool,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ExportScrollbackResponse {
+    pub pane_id: PaneId,
+    /// The range that was exported, after clamping
+    pub range: Range<StableRowIndex>,
+    pub text: String,
+}
+
+/// Discard the oldest lines of a pane's scrollback so that it holds
+/// at most `max_lines` lines, including those in the viewport.
+/// The request is refused if `max_lines` is smaller than the viewport.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct TrimScrollback {
+    pub pane_id: PaneId,
+    pub max_lines: usize,
+}
+
+/// Ask the server to forget what it has sent about the panes in a tab
+/// and to push their complete state again, so that the client can
+/// recover from a rendering glitch without reconnecting
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct InvalidateTab {
+    pub tab_id: TabId,
+}
+
+/// Tells the server which tab the client is displaying, so that it
+/// can push changes for that tab eagerly and defer those for the
+/// tabs in the background
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SetFocusedTab {
+    pub tab_id: TabId,
+}
+
+/// Pause, or resume, the pushes of render changes for the panes in
+/// a tab, such as while the client has minimized or hidden it.
+/// The server continues to track what has changed, and catches the
+/// client up with one push for each pane when the tab is unfrozen.
+/// This only affects the session that sent it.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct FreezeTab {
+    pub tab_id: TabId,
+    pub frozen: bool,
+}
+
+/// Kill every tab in a window and remove the window.  This also
+/// removes a window that has already been emptied, for example, by
+/// moving its tabs to other windows.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct CloseWindow {
+    pub window_id: WindowId,
+}
+
+/// Create a window with no tabs, so that a client can set up the
+/// structure of its windows before spawning into them.  The window
+/// is listed by `GetWindows` with `title` until a tab is added to
+/// it, and is kept while it is empty until either a tab is added or
+/// it is removed by `CloseWindow`.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct NewWindow {
+    pub title: String,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct NewWindowResponse {
+    pub window_id: WindowId,
+}
+
+/// Control whether the programs running in the panes of a tab may
+/// change the titles that are reported for those panes via OSC 0/2.
+/// While they may not, each title remains as it was when the
+/// override was disabled.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SetTabTitlePolicy {
+    pub tab_id: TabId,
+    pub allow_process_override: bool,
+}
+
+/// Move a tab to position `new_index` within its window.
+/// An index beyond the end of the window moves the tab to the end.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ReorderTab {
+    pub tab_id: TabId,
+    pub new_index: usize,
+}
+
+/// Wait until the output that the server has already read from the
+/// ptys of the panes in a tab has been parsed and applied to those
+/// panes, then respond with `UnitResponse`.  A subsequent `GetLines`
+/// reflects that output.
+/// This does not wait for output that the programs produce later,
+/// nor for output that is still in the kernel's pty buffer waiting to
+/// be read, so it is only a synchronization point for output that
+/// the caller knows has already been written.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct DrainTab {
+    pub tab_id: TabId,
+}
+
+/// Ask the server for an image of the visible portion of a tab,
+/// rendered with its configured fonts and colors.
+/// This is only supported by servers that were built with the
+/// `screenshot` feature; others respond with an error.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetTabScreenshot {
+    pub tab_id: TabId,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetTabScreenshotResponse {
+    pub tab_id: TabId,
+    /// The image, encoded as a PNG
+    pub png: Vec<u8>,
+}
+
+/// Ask for the position and size of each of the panes in a tab,
+/// along with the splits that separate them.
+/// Once a client has asked for the layout of a tab, the server
+/// pushes a `GetPaneLayoutResponse` with serial 0 whenever the
+/// panes in that tab are resized, split, zoomed or closed.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetPaneLayout {
+    pub tab_id: TabId,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetPaneLayoutResponse {
+    pub tab_id: TabId,
+    /// The layout of the tab, or `PaneLayout::Empty` once the
+    /// tab has been closed
+    pub layout: PaneLayout,
+}
+
+/// Ask for the environment of the foreground process of the active
+/// pane of a tab.  The environment may hold secrets, so a read-only
+/// session must have been granted write access to the tab.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetEnv {
+    pub tab_id: TabId,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetEnvResponse {
+    pub tab_id: TabId,
+    /// The environment as (name, value) pairs, or None if it could
+    /// not be read, either because the platform doesn't support it
+    /// or because the process belongs to another user
+    pub env: Option<Vec<(String, String)>>,
+}
+
+/// Resize every tab in a window to `size` in a single step, so that
+/// the client doesn't observe a mixture of old and new layouts as it
+/// would when issuing a `Resize` for each pane in turn.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ResizeWindow {
+    pub window_id: WindowId,
+    pub size: PtySize,
 }
 
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
@@ -733,6 +2326,25 @@ pub struct SetPaneZoomed {
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
 pub struct GetPaneRenderChanges {
     pub pane_id: PaneId,
+    /// The most recent input serial carried by a render change that
+    /// the client has applied.  Rows that were sent in response to
+    /// input at or before this serial, and that haven't changed since,
+    /// are not sent again.
+    pub since_input_serial: Option<InputSerial>,
+    /// When false, the changes to the pane are sent without the
+    /// viewport rows in `bonus_lines`, leaving all of the changed rows
+    /// in `dirty_lines`; this suits a client whose viewport is already
+    /// accurate, such as one that has just fetched it with GetLines.
+    /// This applies to the pushes that follow the request, until a
+    /// later request changes it.
+    pub include_bonus_lines: bool,
+    /// When true, the next changes to be sent hold every row of the
+    /// viewport in `bonus_lines`, whether it has changed or not, and
+    /// no `dirty_lines`, after which the server considers the client
+    /// to hold all of the rows of the pane.  This gives a client that
+    /// has just attached its first frame as quickly as possible; it
+    /// can then fetch the scrollback with `TrickleScrollback`.
+    pub viewport_only: bool,
 }
 
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
@@ -745,35 +2357,284 @@ pub struct LivenessResponse {
 pub struct GetPaneRenderChangesResponse {
     pub pane_id: PaneId,
     pub mouse_grabbed: bool,
+    /// Whether the application has enabled bracketed paste mode;
+    /// clients may use this to warn before pasting multi-line text
+    /// into an application that will execute each line as it arrives
+    pub bracketed_paste: bool,
+    /// The complete set of modes that the application has enabled
+    pub terminal_modes: TerminalModes,
     pub cursor_position: StableCursorPosition,
     pub dimensions: RenderableDimensions,
     pub dirty_lines: Vec<Range<StableRowIndex>>,
     pub title: String,
+    /// The bytes of `title` as they were sent by the application,
+    /// when they were not valid UTF-8 and `title` holds a lossy
+    /// decoding of them.  A client may decode them differently,
+    /// according to its locale.
+    pub title_bytes: Option<Vec<u8>>,
     pub working_dir: Option<SerdeUrl>,
+    /// The bytes of `working_dir`, when they were not valid UTF-8
+    pub working_dir_bytes: Option<Vec<u8>>,
     /// Lines that the server thought we'd almost certainly
     /// want to fetch as soon as we received this response
     pub bonus_lines: SerializedLines,
+    /// Changes to rows that the client already holds, which are sent
+    /// in place of bonus lines when a few cells of a wide row changed
+    pub line_deltas: LineDeltas,
 
     pub input_serial: Option<InputSerial>,
     pub seqno: SequenceNo,
+    /// The selection that is shared by the clients viewing this pane
+    pub selection: Option<SelectionRange>,
+    /// Increases by one with each response that is sent to the
+    /// session for this pane, so that a gap reveals that the client
+    /// missed one, in which case it should send `InvalidateTab`
+    pub damage_seqno: u64,
+    /// The `resize_id` of the most recent `Resize` of this pane that
+    /// the session sent, once it has been applied; `dimensions`
+    /// reflect that resize.  A client that has sent a later resize
+    /// can tell that `dimensions` predate it.
+    pub last_resize_id: Option<u64>,
+    /// Set when the pane has switched between the primary and the
+    /// alternate screen since the previous response.  The rows that
+    /// the client holds are of the other screen, so it should discard
+    /// them; the rows of the viewport are all sent as dirty.
+    pub screen_switched: bool,
+    /// The SGR attributes with which the application's output is
+    /// drawn at the cursor, so that predictive echo can use them.
+    /// This is only sent when they have changed since the previous
+    /// response or `CursorUpdate` for the pane.
+    pub pen: Option<CellAttributes>,
+}
+
+/// Sent in place of `GetPaneRenderChangesResponse` in response to
+/// input that changed nothing other than the position of the cursor
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct CursorUpdate {
+    pub pane_id: PaneId,
+    pub cursor_position: StableCursorPosition,
+    pub input_serial: InputSerial,
+    /// As for `GetPaneRenderChangesResponse::pen`
+    pub pen: Option<CellAttributes>,
+}
+
+/// A cell position within a pane, as used to describe a selection
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub struct SelectionCoord {
+    pub x: usize,
+    pub y: StableRowIndex,
+}
+
+/// The span of a selection, from where it was started to where
+/// it was extended to.  `end` may precede `start`.
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub struct SelectionRange {
+    pub start: SelectionCoord,
+    pub end: SelectionCoord,
+}
+
+/// Set the selection for a pane, so that every client that is viewing
+/// it will highlight the same region.  A `None` selection clears it.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct SetSelection {
+    pub pane_id: PaneId,
+    pub selection: Option<SelectionRange>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct GetLines {
+    pub pane_id: PaneId,
+    pub lines: Vec<Range<StableRowIndex>>,
+    /// If true, the lines are sent as runs of text where possible;
+    /// see `SerializedLines::pack_text`
+    pub packed: bool,
+    /// The hashes of rows that the client already has, computed with
+    /// the `row_hash` algorithm of the server.  A row whose content
+    /// still has the same hash is left out of the response.
+    pub known_hashes: Vec<(StableRowIndex, u64)>,
+}
+
+/// The algorithm with which the content of a row is hashed, so that
+/// a client can tell the server which rows it already has.  The server
+/// names the algorithm that it uses in `GetCodecVersionResponse`.
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub enum RowHashAlgorithm {
+    /// 64 bit FNV-1a over the cells and attributes of the line, in
+    /// the order that their `Hash` implementations produce them
+    Fnv1a64,
+}
+
+impl RowHashAlgorithm {
+    /// Hash the content of `line`.  An image is identified by an id
+    /// that is local to the process that holds it, so a line with an
+    /// image has a different hash on each side of the connection
+    /// and is always sent.
+    pub fn hash_line(self, line: &Line) -> u64 {
+        match self {
+            Self::Fnv1a64 => {
+                let mut hasher = Fnv1a64::default();
+                line.hash_content(&mut hasher);
+                hasher.finish()
+            }
+        }
+    }
+}
+
+/// Unlike `DefaultHasher`, whose algorithm may change from one build
+/// to the next, this produces the same hash for the same data on any
+/// host, so integers are fed to it in little endian order
+struct Fnv1a64(u64);
+
+impl Default for Fnv1a64 {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1a64 {
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= b as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u128(&mut self, i: u128) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+impl GetLines {
+    /// Clamp the requested ranges to the rows that actually exist
+    /// in a pane with the specified dimensions, discarding any
+    /// ranges that fall entirely outside of the scrollback.
+    pub fn clamp_to_dimensions(&mut self, dims: &RenderableDimensions) {
+        let top = dims.scrollback_top;
+        let bottom = dims.physical_top + dims.viewport_rows as StableRowIndex;
+        self.lines = self
+            .lines
+            .drain(..)
+            .filter_map(|range| {
+                let start = range.start.max(top);
+                let end = range.end.min(bottom);
+                if start < end {
+                    Some(start..end)
+                } else {
+                    None
+                }
+            })
+            .collect();
+    }
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+struct CellCoordinates {
+    line_idx: usize,
+    cols: Range<usize>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+struct LineHyperlink {
+    link: Hyperlink,
+    coords: Vec<CellCoordinates>,
+}
+
+/// Identifies the data of an image by the sha256 hash of its content
+pub type ImageHash = [u8; 32];
+
+/// An image attached to a cell, with its data replaced by
+/// a reference to an entry in `SerializedLines::image_data`
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+struct LineImage {
+    line_idx: usize,
+    cell_idx: usize,
+    top_left: TextureCoordinate,
+    bottom_right: TextureCoordinate,
+    z_index: i32,
+    padding: (u16, u16, u16, u16),
+    image_id: Option<u32>,
+    placement_id: Option<u32>,
+    hash: ImageHash,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+struct LineImageData {
+    hash: ImageHash,
+    data: Arc<ImageData>,
 }
 
+/// A run of adjacent cells that share the same attributes,
+/// represented by the concatenation of their text.  `attrs` is the
+/// index of those attributes in `SerializedLines::attrs`.
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
-pub struct GetLines {
-    pub pane_id: PaneId,
-    pub lines: Vec<Range<StableRowIndex>>,
+struct TextRun {
+    text: String,
+    attrs: usize,
 }
 
+/// The cells of a line, sent as runs of text in their place
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
-struct CellCoordinates {
+struct PackedLine {
     line_idx: usize,
-    cols: Range<usize>,
+    runs: Vec<TextRun>,
 }
 
-#[derive(Deserialize, Serialize, PartialEq, Debug)]
-struct LineHyperlink {
-    link: Hyperlink,
-    coords: Vec<CellCoordinates>,
+/// Returns the index of `attrs` in `table`, adding them if needed
+fn attrs_index(table: &mut Vec<CellAttributes>, attrs: &CellAttributes) -> usize {
+    match table.iter().position(|a| a == attrs) {
+        Some(idx) => idx,
+        None => {
+            table.push(attrs.clone());
+            table.len() - 1
+        }
+    }
+}
+
+fn cells_to_runs(cells: &[Cell], table: &mut Vec<CellAttributes>) -> Vec<TextRun> {
+    let mut runs: Vec<TextRun> = vec![];
+    let mut x = 0;
+    while let Some(cell) = cells.get(x) {
+        match runs.last_mut() {
+            Some(run) if table[run.attrs] == *cell.attrs() => run.text.push_str(cell.str()),
+            _ => runs.push(TextRun {
+                text: cell.str().to_string(),
+                attrs: attrs_index(table, cell.attrs()),
+            }),
+        }
+        // The blank cells that follow a wide cell are implied by its width
+        x += cell.width().max(1);
+    }
+    runs
+}
+
+fn runs_to_cells(runs: &[TextRun], table: &[CellAttributes]) -> Vec<Cell> {
+    let default_attrs = CellAttributes::default();
+    let mut cells = vec![];
+    for run in runs {
+        let attrs = table.get(run.attrs).unwrap_or(&default_attrs);
+        cells.append(&mut Line::from_text(&run.text, attrs, SEQ_ZERO).take_cells());
+    }
+    cells
 }
 
 /// What's all this?
@@ -783,22 +2644,143 @@ struct LineHyperlink {
 /// This section of code extracts the hyperlinks from the cells and builds
 /// up a mapping that can be used to restore the identity when the `lines()`
 /// method is called.
+/// Images are treated similarly: an image typically spans many cells,
+/// and its data is sent just once regardless of how many cells reference
+/// it.  The data may be omitted entirely if the peer already has it;
+/// see `omit_image_data` and `resolve_image_data`.
+/// The attributes of packed lines are likewise sent once each, and
+/// referenced by index from their runs; see `pack_text`.
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
 pub struct SerializedLines {
     lines: Vec<(StableRowIndex, Line)>,
     hyperlinks: Vec<LineHyperlink>,
-    // TODO: image references
+    images: Vec<LineImage>,
+    image_data: Vec<LineImageData>,
+    packed: Vec<PackedLine>,
+    attrs: Vec<CellAttributes>,
 }
 
 impl SerializedLines {
     pub fn lines(self) -> Vec<(StableRowIndex, Line)> {
         self.into()
     }
+
+    /// Append the lines from `other` to self; this is used to
+    /// reassemble a `GetLinesResponse` that was sent in chunks.
+    pub fn append(&mut self, other: SerializedLines) {
+        let offset = self.lines.len();
+        self.lines.extend(other.lines);
+        for mut link in other.hyperlinks {
+            for coord in &mut link.coords {
+                coord.line_idx += offset;
+            }
+            self.hyperlinks.push(link);
+        }
+        for mut image in other.images {
+            image.line_idx += offset;
+            self.images.push(image);
+        }
+        for data in other.image_data {
+            if !self.image_data.iter().any(|d| d.hash == data.hash) {
+                self.image_data.push(data);
+            }
+        }
+        let attrs: Vec<usize> = other
+            .attrs
+            .iter()
+            .map(|attrs| attrs_index(&mut self.attrs, attrs))
+            .collect();
+        for mut packed in other.packed {
+            packed.line_idx += offset;
+            for run in &mut packed.runs {
+                // An index that was out of range remains so
+                run.attrs = attrs.get(run.attrs).copied().unwrap_or(usize::MAX);
+            }
+            self.packed.push(packed);
+        }
+    }
+
+    /// Replace the cells of each line with runs of text that share
+    /// the same attributes, which are much more compact than the
+    /// cells themselves.  This is only done for the lines whose cells
+    /// are reproduced exactly from those runs; lines with cells whose
+    /// width differs from the default for their text, for example,
+    /// are sent as they are.
+    /// Rather than repeating the attributes in each run, each distinct
+    /// set of attributes is sent once, so that colored output is
+    /// reduced to its text and a series of small indices, which
+    /// compresses far better.
+    pub fn pack_text(&mut self) {
+        for (line_idx, (_, line)) in self.lines.iter_mut().enumerate() {
+            if line.cells().is_empty() {
+                continue;
+            }
+            let num_attrs = self.attrs.len();
+            let runs = cells_to_runs(line.cells(), &mut self.attrs);
+            if runs_to_cells(&runs, &self.attrs) == line.cells() {
+                line.take_cells();
+                self.packed.push(PackedLine { line_idx, runs });
+            } else {
+                self.attrs.truncate(num_attrs);
+            }
+        }
+    }
+
+    /// Remove the data for the images for which `peer_has` returns
+    /// true, leaving just the references to them
+    pub fn omit_image_data<F: Fn(&ImageHash) -> bool>(&mut self, peer_has: F) {
+        self.image_data.retain(|d| !peer_has(&d.hash));
+    }
+
+    /// Returns the images whose data is included
+    pub fn image_data(&self) -> impl Iterator<Item = (&ImageHash, &Arc<ImageData>)> {
+        self.image_data.iter().map(|d| (&d.hash, &d.data))
+    }
+
+    /// Use `lookup` to supply the data for the referenced images
+    /// whose data was omitted by the sender.  Returns the hashes
+    /// that could not be resolved, along with the rows that reference
+    /// them; those images will be missing from the lines.
+    pub fn resolve_image_data<F>(&mut self, mut lookup: F) -> (Vec<ImageHash>, Vec<StableRowIndex>)
+    where
+        F: FnMut(&ImageHash) -> Option<Arc<ImageData>>,
+    {
+        let mut missing = vec![];
+        let mut missing_rows = vec![];
+        for image in &self.images {
+            if self.image_data.iter().any(|d| d.hash == image.hash) {
+                continue;
+            }
+            match lookup(&image.hash) {
+                Some(data) => self.image_data.push(LineImageData {
+                    hash: image.hash,
+                    data,
+                }),
+                None => {
+                    if !missing.contains(&image.hash) {
+                        missing.push(image.hash);
+                    }
+                    if let Some((row, _)) = self.lines.get(image.line_idx) {
+                        if !missing_rows.contains(row) {
+                            missing_rows.push(*row);
+                        }
+                    }
+                }
+            }
+        }
+        (missing, missing_rows)
+    }
 }
 
 impl From<Vec<(StableRowIndex, Line)>> for SerializedLines {
     fn from(mut lines: Vec<(StableRowIndex, Line)>) -> Self {
         let mut hyperlinks = vec![];
+        let mut images = vec![];
+        let mut image_data = vec![];
+        // Hashing the image data is relatively expensive, so only
+        // do it once per image
+        let mut hashes: HashMap<usize, ImageHash> = HashMap::new();
+        let mut seen_hashes = HashSet::new();
 
         for (line_idx, (_, line)) in lines.iter_mut().enumerate() {
             let mut current_link: Option<Arc<Hyperlink>> = None;
@@ -848,7 +2830,32 @@ impl From<Vec<(StableRowIndex, Line)>> for SerializedLines {
                     current_range = 0..0;
                 }
 
-                // TODO: something smart for image cells
+                // Move the images out of the cell, recording only
+                // a reference to their data
+                if let Some(cell_images) = cell.attrs().images() {
+                    cell.attrs_mut().clear_images();
+                    for image in cell_images {
+                        let data = image.image_data();
+                        let hash = *hashes.entry(data.id()).or_insert_with(|| data.hash());
+                        if seen_hashes.insert(hash) {
+                            image_data.push(LineImageData {
+                                hash,
+                                data: Arc::clone(data),
+                            });
+                        }
+                        images.push(LineImage {
+                            line_idx,
+                            cell_idx: x,
+                            top_left: image.top_left(),
+                            bottom_right: image.bottom_right(),
+                            z_index: image.z_index(),
+                            padding: image.padding(),
+                            image_id: image.image_id(),
+                            placement_id: image.placement_id(),
+                            hash,
+                        });
+                    }
+                }
             }
             if let Some(link) = current_link.take() {
                 // Wrap up final streak
@@ -862,7 +2869,14 @@ impl From<Vec<(StableRowIndex, Line)>> for SerializedLines {
             }
         }
 
-        Self { lines, hyperlinks }
+        Self {
+            lines,
+            hyperlinks,
+            images,
+            image_data,
+            packed: vec![],
+            attrs: vec![],
+        }
     }
 }
 
@@ -870,11 +2884,16 @@ impl From<Vec<(StableRowIndex, Line)>> for SerializedLines {
 /// serialization, and return the line data.
 impl Into<Vec<(StableRowIndex, Line)>> for SerializedLines {
     fn into(self) -> Vec<(StableRowIndex, Line)> {
-        if self.hyperlinks.is_empty() {
-            self.lines
-        } else {
-            let mut lines = self.lines;
+        let mut lines = self.lines;
+        for packed in self.packed {
+            if let Some((_, line)) = lines.get_mut(packed.line_idx) {
+                line.restore_cells(runs_to_cells(&packed.runs, &self.attrs));
+            }
+        }
 
+        if self.hyperlinks.is_empty() && self.images.is_empty() {
+            lines
+        } else {
             for link in self.hyperlinks {
                 let url = Arc::new(link.link);
 
@@ -891,15 +2910,245 @@ impl Into<Vec<(StableRowIndex, Line)>> for SerializedLines {
                 }
             }
 
+            let image_data: HashMap<ImageHash, Arc<ImageData>> = self
+                .image_data
+                .into_iter()
+                .map(|d| (d.hash, d.data))
+                .collect();
+            for image in self.images {
+                // An image whose data is unavailable is omitted
+                let data = match image_data.get(&image.hash) {
+                    Some(data) => data,
+                    None => continue,
+                };
+                if let Some((_, line)) = lines.get_mut(image.line_idx) {
+                    if let Some(cell) = line
+                        .cells_mut_for_attr_changes_only()
+                        .get_mut(image.cell_idx)
+                    {
+                        let (left, top, right, bottom) = image.padding;
+                        cell.attrs_mut()
+                            .attach_image(Box::new(ImageCell::with_z_index(
+                                image.top_left,
+                                image.bottom_right,
+                                Arc::clone(data),
+                                image.z_index,
+                                left,
+                                top,
+                                right,
+                                bottom,
+                                image.image_id,
+                                image.placement_id,
+                            )));
+                    }
+                }
+            }
+
             lines
         }
     }
 }
 
+/// A change to a row that the peer already holds, which is sent in
+/// place of the whole line when only a few of its cells have changed.
+/// The cells from `start` onwards are replaced by those that are sent
+/// along with the delta, and the line is resized to `len`.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct LineDelta {
+    pub row: StableRowIndex,
+    /// The `row_hash` of the line to which the delta applies.  A peer
+    /// that holds something else for the row has to fetch it instead.
+    pub base_hash: u64,
+    /// The `row_hash` of the line once the delta has been applied
+    pub hash: u64,
+    pub start: usize,
+    pub len: usize,
+}
+
+impl LineDelta {
+    /// Apply the delta to `line`, replacing the cells from `start`
+    /// with `cells`.  The implicit hyperlinks that were found in the
+    /// line are removed, as the text that they matched may have changed.
+    /// The cells are replaced as they are, rather than set one by one,
+    /// as setting the blank cell that follows a wide cell would blank
+    /// out the wide cell too.
+    pub fn apply(&self, line: &mut Line, cells: Vec<Cell>, seqno: SequenceNo) {
+        line.invalidate_implicit_hyperlinks(seqno);
+        line.resize(self.len, seqno);
+        let end = (self.start + cells.len()).min(self.len);
+        if let Some(dest) = line.cells_mut().get_mut(self.start..end) {
+            for (dest, cell) in dest.iter_mut().zip(cells) {
+                *dest = cell;
+            }
+        }
+    }
+}
+
+/// The `LineDelta`s of a response, along with their cells.  The cells
+/// of each delta are sent as a line of their own, so that they keep
+/// their hyperlinks as `SerializedLines` does.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct LineDeltas {
+    deltas: Vec<LineDelta>,
+    cells: SerializedLines,
+}
+
+impl Default for LineDeltas {
+    fn default() -> Self {
+        Self {
+            deltas: vec![],
+            cells: vec![].into(),
+        }
+    }
+}
+
+impl LineDeltas {
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    /// Add a delta that turns `base`, which the peer holds for `row`,
+    /// into `line`, as long as no more than `max_cells` cells need to
+    /// be sent.  Returns false if the whole line has to be sent instead,
+    /// which is always the case for lines with images, as their hashes
+    /// differ between peers.
+    pub fn push(
+        &mut self,
+        row: StableRowIndex,
+        base: &Line,
+        line: &Line,
+        algorithm: RowHashAlgorithm,
+        max_cells: usize,
+    ) -> bool {
+        let (old, new) = (base.cells(), line.cells());
+        if old
+            .iter()
+            .chain(new.iter())
+            .any(|cell| cell.attrs().images().is_some())
+        {
+            return false;
+        }
+        let start = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+        // Cells can't be moved, so unless the length is the same,
+        // everything from the first change onwards is sent
+        let end = if old.len() == new.len() {
+            let unchanged = old[start..]
+                .iter()
+                .rev()
+                .zip(new[start..].iter().rev())
+                .take_while(|(a, b)| a == b)
+                .count();
+            new.len() - unchanged
+        } else {
+            new.len()
+        };
+        if end - start > max_cells {
+            return false;
+        }
+
+        let delta = LineDelta {
+            row,
+            base_hash: algorithm.hash_line(base),
+            hash: algorithm.hash_line(line),
+            start,
+            len: new.len(),
+        };
+        // The attributes of the line itself aren't part of the delta,
+        // so check that it gives the peer the same line
+        let cells = new[start..end].to_vec();
+        let mut patched = base.clone();
+        delta.apply(&mut patched, cells.clone(), SEQ_ZERO);
+        if algorithm.hash_line(&patched) != delta.hash {
+            return false;
+        }
+
+        self.deltas.push(delta);
+        self.cells
+            .append(vec![(row, Line::from_cells(cells, SEQ_ZERO))].into());
+        true
+    }
+
+    /// Returns the deltas along with the cells that each of them sets
+    pub fn deltas(self) -> Vec<(LineDelta, Vec<Cell>)> {
+        self.deltas
+            .into_iter()
+            .zip(self.cells.lines())
+            .map(|(delta, (_, mut line))| (delta, line.take_cells()))
+            .collect()
+    }
+}
+
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
 pub struct GetLinesResponse {
     pub pane_id: PaneId,
     pub lines: SerializedLines,
+    /// Large responses are split into several chunks that share
+    /// the same serial.  This is true for all but the final chunk.
+    pub more: bool,
+}
+
+/// Ask the server to push the scrollback of a pane to the client in
+/// the background, as a series of `ScrollbackChunk`s that work upwards
+/// from the top of the viewport, until `max_rows` rows have been sent
+/// or the top of the scrollback is reached.  The chunks are only sent
+/// while the write queue of the session is idle, so they don't hold
+/// up the render changes and responses that the client is waiting for.
+///
+/// A later request for the same pane replaces the earlier one, and a
+/// `max_rows` of 0 stops it, for example once the client has enough
+/// of the scrollback.  The response is a `UnitResponse`.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct TrickleScrollback {
+    pub pane_id: PaneId,
+    pub max_rows: usize,
+}
+
+/// Follow the output of the active pane of a tab, like `tail -f`.
+/// The response is a `TailTabResponse` holding the last `context_lines`
+/// lines of output, after which each line that the pane outputs is
+/// pushed in a `TailLines` once the cursor has moved on from it.  A
+/// line is only sent once, even if it is changed later, which makes
+/// this much cheaper than render changes for following a log.
+///
+/// A later `TailTab` for the same tab replaces the earlier one.  The
+/// tail ends with `StopTail`, or when the pane is removed.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct TailTab {
+    pub tab_id: TabId,
+    pub context_lines: usize,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct TailTabResponse {
+    pub tab_id: TabId,
+    /// The pane that is being followed
+    pub pane_id: PaneId,
+    pub lines: SerializedLines,
+}
+
+/// Pushed to clients with the lines that a tab that they are
+/// tailing has output
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct TailLines {
+    pub tab_id: TabId,
+    pub lines: SerializedLines,
+}
+
+/// Stop following a tab that was being followed by `TailTab`.
+/// The response is a `UnitResponse`.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct StopTail {
+    pub tab_id: TabId,
+}
+
+/// Pushed to clients with rows of scrollback that were requested
+/// by `TrickleScrollback`
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ScrollbackChunk {
+    pub pane_id: PaneId,
+    pub lines: SerializedLines,
+    /// True if this is the last chunk that will be sent
+    pub done: bool,
 }
 
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
@@ -922,7 +3171,7 @@ mod test {
         let mut encoded = Vec::new();
         encode_raw(0x81, 0x42, b"hello", false, &mut encoded).unwrap();
         assert_eq!(&encoded, b"\x08\x42\x81\x01hello");
-        let decoded = decode_raw(encoded.as_slice()).unwrap();
+        let decoded = decode_raw(encoded.as_slice(), false, DEFAULT_MAX_FRAME_LEN).unwrap();
         assert_eq!(decoded.ident, 0x81);
         assert_eq!(decoded.serial, 0x42);
         assert_eq!(decoded.data, b"hello");
@@ -936,7 +3185,7 @@ mod test {
             payload.resize(*target_len, b'a');
             let mut encoded = Vec::new();
             encode_raw(0x42, serial, payload.as_slice(), false, &mut encoded).unwrap();
-            let decoded = decode_raw(encoded.as_slice()).unwrap();
+            let decoded = decode_raw(encoded.as_slice(), false, DEFAULT_MAX_FRAME_LEN).unwrap();
             assert_eq!(decoded.ident, 0x42);
             assert_eq!(decoded.serial, serial);
             assert_eq!(decoded.data, payload);
@@ -947,12 +3196,14 @@ mod test {
     #[test]
     fn test_pdu_ping() {
         let mut encoded = Vec::new();
-        Pdu::Ping(Ping {}).encode(&mut encoded, 0x40).unwrap();
-        assert_eq!(&encoded, &[2, 0x40, 1]);
+        Pdu::Ping(Ping::default())
+            .encode(&mut encoded, 0x40)
+            .unwrap();
+        assert_eq!(&encoded, &[3, 0x40, 1, 0]);
         assert_eq!(
             DecodedPdu {
                 serial: 0x40,
-                pdu: Pdu::Ping(Ping {})
+                pdu: Pdu::Ping(Ping::default())
             },
             Pdu::decode(encoded.as_slice()).unwrap()
         );
@@ -961,9 +3212,13 @@ mod test {
     #[test]
     fn stream_decode() {
         let mut encoded = Vec::new();
-        Pdu::Ping(Ping {}).encode(&mut encoded, 0x1).unwrap();
-        Pdu::Pong(Pong {}).encode(&mut encoded, 0x2).unwrap();
-        assert_eq!(encoded.len(), 6);
+        Pdu::Ping(Ping::default())
+            .encode(&mut encoded, 0x1)
+            .unwrap();
+        Pdu::Pong(Pong::default())
+            .encode(&mut encoded, 0x2)
+            .unwrap();
+        assert_eq!(encoded.len(), 9);
 
         let mut cursor = Cursor::new(encoded.as_slice());
         let mut read_buffer = Vec::new();
@@ -972,14 +3227,14 @@ mod test {
             Pdu::try_read_and_decode(&mut cursor, &mut read_buffer).unwrap(),
             Some(DecodedPdu {
                 serial: 1,
-                pdu: Pdu::Ping(Ping {})
+                pdu: Pdu::Ping(Ping::default())
             })
         );
         assert_eq!(
             Pdu::try_read_and_decode(&mut cursor, &mut read_buffer).unwrap(),
             Some(DecodedPdu {
                 serial: 2,
-                pdu: Pdu::Pong(Pong {})
+                pdu: Pdu::Pong(Pong::default())
             })
         );
         let err = Pdu::try_read_and_decode(&mut cursor, &mut read_buffer).unwrap_err();
@@ -994,14 +3249,16 @@ mod test {
         let mut encoded = Vec::new();
         {
             let mut encoder = base91::Base91Encoder::new(&mut encoded);
-            Pdu::Ping(Ping {}).encode(&mut encoder, 0x41).unwrap();
+            Pdu::Ping(Ping::default())
+                .encode(&mut encoder, 0x41)
+                .unwrap();
         }
-        assert_eq!(&encoded, &[60, 67, 75, 65]);
+        assert_eq!(&encoded, &[61, 67, 75, 65, 65]);
         let decoded = base91::decode(&encoded);
         assert_eq!(
             DecodedPdu {
                 serial: 0x41,
-                pdu: Pdu::Ping(Ping {})
+                pdu: Pdu::Ping(Ping::default())
             },
             Pdu::decode(decoded.as_slice()).unwrap()
         );
@@ -1010,12 +3267,14 @@ mod test {
     #[test]
     fn test_pdu_pong() {
         let mut encoded = Vec::new();
-        Pdu::Pong(Pong {}).encode(&mut encoded, 0x42).unwrap();
-        assert_eq!(&encoded, &[2, 0x42, 2]);
+        Pdu::Pong(Pong::default())
+            .encode(&mut encoded, 0x42)
+            .unwrap();
+        assert_eq!(&encoded, &[4, 0x42, 2, 0, 0]);
         assert_eq!(
             DecodedPdu {
                 serial: 0x42,
-                pdu: Pdu::Pong(Pong {})
+                pdu: Pdu::Pong(Pong::default())
             },
             Pdu::decode(encoded.as_slice()).unwrap()
         );
@@ -1028,9 +3287,671 @@ mod test {
         assert_eq!(
             DecodedPdu {
                 serial: 0x42,
-                pdu: Pdu::Invalid { ident: 0xdeadbeef }
+                pdu: Pdu::Invalid {
+                    ident: 0xdeadbeef,
+                    len: 5
+                }
             },
             Pdu::decode(encoded.as_slice()).unwrap()
         );
     }
+
+    #[test]
+    fn test_unknown_pdu_is_skipped() {
+        let mut encoded = Vec::new();
+        encode_raw(0xdeadbeef, 0x42, b"from the future", false, &mut encoded).unwrap();
+        Pdu::Ping(Ping::default())
+            .encode(&mut encoded, 0x43)
+            .unwrap();
+
+        let mut cursor = Cursor::new(encoded.as_slice());
+        assert_eq!(
+            Pdu::decode(&mut cursor).unwrap(),
+            DecodedPdu {
+                serial: 0x42,
+                pdu: Pdu::Invalid {
+                    ident: 0xdeadbeef,
+                    len: 15
+                }
+            }
+        );
+        assert_eq!(
+            Pdu::decode(&mut cursor).unwrap(),
+            DecodedPdu {
+                serial: 0x43,
+                pdu: Pdu::Ping(Ping::default())
+            }
+        );
+    }
+
+    #[test]
+    fn test_get_lines_clamped_to_scrollback() {
+        let dims = RenderableDimensions {
+            cols: 80,
+            viewport_rows: 24,
+            scrollback_rows: 100,
+            physical_top: 76,
+            scrollback_top: 0,
+        };
+        let mut request = GetLines {
+            pane_id: 0,
+            lines: vec![-50..1000, 2000..3000, 10..20],
+            packed: false,
+            known_hashes: vec![],
+        };
+        request.clamp_to_dimensions(&dims);
+        assert_eq!(request.lines, vec![0..100, 10..20]);
+    }
+
+    #[test]
+    fn test_row_hashes_agree_after_the_round_trip() {
+        // The published test vector for 64 bit FNV-1a
+        let mut hasher = Fnv1a64::default();
+        hasher.write(b"a");
+        assert_eq!(hasher.finish(), 0xaf63_dc4c_8601_ec8c);
+
+        let mut attrs = CellAttributes::default();
+        let mut line = Line::with_width(80, SEQ_ZERO);
+        line.overlay_text_with_attribute(0, "$ ", attrs.clone(), SEQ_ZERO);
+        attrs.set_hyperlink(Some(Arc::new(Hyperlink::new("https://wezfurlong.org"))));
+        line.overlay_text_with_attribute(2, "wezfurlong.org", attrs, SEQ_ZERO);
+
+        let algo = RowHashAlgorithm::Fnv1a64;
+        let hash = algo.hash_line(&line);
+        let mut lines: SerializedLines = vec![(0, line.clone())].into();
+        lines.pack_text();
+        let encoded = serialize_uncompressed(&lines).unwrap();
+        let decoded: SerializedLines = deserialize(encoded.as_slice(), false).unwrap();
+        let received = decoded.lines().remove(0).1;
+        assert_eq!(algo.hash_line(&received), hash);
+
+        line.overlay_text_with_attribute(0, "#", CellAttributes::default(), SEQ_ZERO);
+        assert_ne!(algo.hash_line(&line), hash);
+    }
+
+    #[test]
+    fn test_line_delta_round_trip() {
+        let algo = RowHashAlgorithm::Fnv1a64;
+        let attrs = CellAttributes::default();
+        // 500 columns, with wide cells
+        let text = format!("$ see https://wezfurlong.org {}", "\u{4e2d}x".repeat(157));
+        let base = Line::from_text(&text, &attrs, SEQ_ZERO);
+        assert_eq!(base.cells().len(), 500);
+        let mut line = base.clone();
+        let mut reverse = attrs.clone();
+        reverse.set_reverse(true);
+        line.set_cell(499, Cell::new(' ', reverse), SEQ_ZERO);
+
+        let mut deltas = LineDeltas::default();
+        assert!(deltas.push(3, &base, &line, algo, 1));
+        let encoded = serialize_uncompressed(&deltas).unwrap();
+        let full = serialize_uncompressed(&SerializedLines::from(vec![(3, line.clone())])).unwrap();
+        assert!(
+            encoded.len() * 20 < full.len(),
+            "{} bytes as a delta vs {} bytes as a line",
+            encoded.len(),
+            full.len()
+        );
+
+        // The peer applies it to its copy, in which it found a hyperlink
+        let mut held = base.clone();
+        held.scan_and_create_hyperlinks(&[
+            termwiz::hyperlink::Rule::new(r"\bhttps://\S+", "$0").unwrap()
+        ]);
+        let decoded: LineDeltas = deserialize(encoded.as_slice(), false).unwrap();
+        let (delta, cells) = decoded.deltas().remove(0);
+        assert_eq!(delta.row, 3);
+        assert_eq!(delta.base_hash, algo.hash_line(&base));
+        delta.apply(&mut held, cells, SEQ_ZERO);
+        assert_eq!(algo.hash_line(&held), delta.hash);
+        assert_eq!(held.cells(), line.cells());
+
+        // Replacing the wide cells keeps them intact
+        let mut line = base.clone();
+        line.overlay_text_with_attribute(30, "\u{4e8c}", attrs.clone(), SEQ_ZERO);
+        assert!(deltas.push(4, &base, &line, algo, 3));
+        let (delta, cells) = deltas.deltas().remove(1);
+        let mut held = base.clone();
+        delta.apply(&mut held, cells, SEQ_ZERO);
+        assert_eq!(held.cells(), line.cells());
+
+        // Too many changes are sent as the whole line
+        let line = Line::from_text(&"y".repeat(500), &attrs, SEQ_ZERO);
+        assert!(!LineDeltas::default().push(5, &base, &line, algo, 125));
+    }
+
+    #[test]
+    fn test_cell_widths_round_trip() {
+        use termwiz::cell::{Cell, CellAttributes};
+        use termwiz::surface::SEQ_ZERO;
+
+        let attrs = CellAttributes::default();
+        // ASCII, a double width CJK character and a ZWJ emoji sequence
+        let mut line = Line::from_text(
+            "a\u{4e2d}b\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}c",
+            &attrs,
+            SEQ_ZERO,
+        );
+        // A width that was explicitly specified, as it would be if the
+        // server used a different unicode version from the client
+        let explicit = line.cells().len();
+        line.set_cell(
+            explicit,
+            Cell::new_grapheme_with_width("\u{2764}", 2, attrs.clone()),
+            SEQ_ZERO,
+        );
+
+        let widths = |line: &Line| -> Vec<(String, usize)> {
+            line.visible_cells()
+                .map(|(_, cell)| (cell.str().to_string(), cell.width()))
+                .collect()
+        };
+        let expected = widths(&line);
+        assert_eq!(
+            expected,
+            vec![
+                ("a".to_string(), 1),
+                ("\u{4e2d}".to_string(), 2),
+                ("b".to_string(), 1),
+                ("\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}".to_string(), 2),
+                ("c".to_string(), 1),
+                ("\u{2764}".to_string(), 2),
+            ]
+        );
+
+        let response = DecodedPdu {
+            serial: 1,
+            pdu: Pdu::GetLinesResponse(GetLinesResponse {
+                pane_id: 0,
+                lines: vec![(0, line)].into(),
+                more: false,
+            }),
+        };
+        let mut encoded = Vec::new();
+        response.pdu.encode(&mut encoded, response.serial).unwrap();
+        let decoded = Pdu::decode(encoded.as_slice()).unwrap();
+        let lines = match decoded.pdu {
+            Pdu::GetLinesResponse(response) => response.lines.lines(),
+            pdu => panic!("unexpected {:?}", pdu),
+        };
+        assert_eq!(widths(&lines[0].1), expected);
+    }
+
+    #[test]
+    fn test_packed_lines() {
+        use termwiz::color::{AnsiColor, ColorAttribute};
+
+        // A typical screen: a colored prompt, a command and
+        // its output, padded out to the width of the terminal
+        let plain = CellAttributes::default();
+        let mut green = CellAttributes::default();
+        green.set_foreground(ColorAttribute::PaletteIndex(AnsiColor::Green as u8));
+        let mut blue = CellAttributes::default();
+        blue.set_foreground(ColorAttribute::PaletteIndex(AnsiColor::Blue as u8));
+        blue.set_intensity(termwiz::cell::Intensity::Bold);
+
+        let mut lines = vec![];
+        for row in 0..24 {
+            let mut line = Line::with_width(80, SEQ_ZERO);
+            if row % 4 == 0 {
+                line.overlay_text_with_attribute(0, "user@host", green.clone(), SEQ_ZERO);
+                line.overlay_text_with_attribute(9, ":", plain.clone(), SEQ_ZERO);
+                line.overlay_text_with_attribute(10, "~/src/wezterm", blue.clone(), SEQ_ZERO);
+                line.overlay_text_with_attribute(23, "$ ls -l", plain.clone(), SEQ_ZERO);
+            } else {
+                line.overlay_text_with_attribute(
+                    0,
+                    "-rw-r--r--  1 user user  4096 Jan  1 12:00 Cargo.toml",
+                    plain.clone(),
+                    SEQ_ZERO,
+                );
+            }
+            lines.push((row, line));
+        }
+        // A line with double width text is packed too
+        let mut line = Line::with_width(80, SEQ_ZERO);
+        line.overlay_text_with_attribute(0, "a\u{4e2d}b", plain.clone(), SEQ_ZERO);
+        lines.push((24, line));
+        // but not one whose width was explicitly specified
+        let mut line = Line::with_width(80, SEQ_ZERO);
+        line.set_cell(
+            0,
+            Cell::new_grapheme_with_width("\u{2764}", 2, plain.clone()),
+            SEQ_ZERO,
+        );
+        lines.push((25, line));
+
+        let unpacked: SerializedLines = lines.clone().into();
+        let mut packed: SerializedLines = lines.clone().into();
+        packed.pack_text();
+        assert_eq!(packed.packed.len(), 25);
+        assert_eq!(packed.attrs, vec![green, plain, blue]);
+
+        let unpacked_size = serialize_uncompressed(&unpacked).unwrap().len();
+        let packed_size = serialize_uncompressed(&packed).unwrap().len();
+        assert!(
+            packed_size * 3 < unpacked_size,
+            "packed {} bytes vs. {} bytes",
+            packed_size,
+            unpacked_size
+        );
+
+        let mut encoded = Vec::new();
+        Pdu::GetLinesResponse(GetLinesResponse {
+            pane_id: 0,
+            lines: packed,
+            more: false,
+        })
+        .encode(&mut encoded, 1)
+        .unwrap();
+        match Pdu::decode(encoded.as_slice()).unwrap().pdu {
+            Pdu::GetLinesResponse(response) => assert_eq!(response.lines.lines(), lines),
+            pdu => panic!("unexpected {:?}", pdu),
+        }
+    }
+
+    #[test]
+    fn test_tab_state_version_is_checked() {
+        let attrs = CellAttributes::default();
+        let state = TabState {
+            title: "vim".to_string(),
+            size: PtySize::default(),
+            lines: vec![(0, Line::from_text("hello", &attrs, SEQ_ZERO))].into(),
+            viewport_top: 0,
+            cursor: StableCursorPosition::default(),
+            modes: TerminalModes::BRACKETED_PASTE,
+        };
+        let mut blob = state.encode().unwrap();
+        assert_eq!(TabState::decode(&blob).unwrap(), state);
+
+        // A blob from another version is refused, rather than
+        // misinterpreted
+        blob[0] = (TAB_STATE_VERSION + 1) as u8;
+        let err = TabState::decode(&blob).unwrap_err().to_string();
+        assert!(err.contains("version"), "{}", err);
+
+        assert!(TabState::decode(&[]).is_err());
+    }
+
+    #[test]
+    fn test_packed_attrs_are_shared() {
+        use termwiz::color::{AnsiColor, ColorAttribute};
+
+        // Colored `ls -l` output, where each name is colored by the
+        // type of the file
+        let plain = CellAttributes::default();
+        let mut dir = CellAttributes::default();
+        dir.set_foreground(ColorAttribute::PaletteIndex(AnsiColor::Blue as u8));
+        dir.set_intensity(termwiz::cell::Intensity::Bold);
+        let mut exe = CellAttributes::default();
+        exe.set_foreground(ColorAttribute::PaletteIndex(AnsiColor::Green as u8));
+        exe.set_intensity(termwiz::cell::Intensity::Bold);
+        let mut link = CellAttributes::default();
+        link.set_foreground(ColorAttribute::PaletteIndex(AnsiColor::Teal as u8));
+
+        let mut lines = vec![];
+        for row in 0..48 {
+            let (mode, name, attrs) = match row % 3 {
+                0 => ("drwxr-xr-x", format!("dir{}", row), &dir),
+                1 => ("-rwxr-xr-x", format!("run{}.sh", row), &exe),
+                _ => ("lrwxrwxrwx", format!("link{}", row), &link),
+            };
+            let mut line = Line::with_width(80, SEQ_ZERO);
+            line.overlay_text_with_attribute(
+                0,
+                &format!("{}  1 user user  4096 Jan  1 12:00 ", mode),
+                plain.clone(),
+                SEQ_ZERO,
+            );
+            line.overlay_text_with_attribute(44, &name, attrs.clone(), SEQ_ZERO);
+            lines.push((row, line));
+        }
+
+        let mut packed: SerializedLines = lines.clone().into();
+        packed.pack_text();
+        assert_eq!(packed.attrs.len(), 4);
+
+        // The same runs with their attributes inline, as they were
+        // sent before the attributes were shared
+        let inline: Vec<Vec<(&str, &CellAttributes)>> = packed
+            .packed
+            .iter()
+            .map(|line| {
+                line.runs
+                    .iter()
+                    .map(|run| (run.text.as_str(), &packed.attrs[run.attrs]))
+                    .collect()
+            })
+            .collect();
+        let (inline, _) = serialize(&inline).unwrap();
+        let (shared, _) = serialize(&(&packed.packed, &packed.attrs)).unwrap();
+        assert!(
+            shared.len() < inline.len(),
+            "shared {} bytes vs. inline {} bytes",
+            shared.len(),
+            inline.len()
+        );
+
+        // Chunks with attributes of their own are reassembled
+        let mut first: SerializedLines = lines[..24].to_vec().into();
+        first.pack_text();
+        let mut second: SerializedLines = vec![
+            lines[24].clone(),
+            (48, Line::from_text("plain", &plain, SEQ_ZERO)),
+        ]
+        .into();
+        second.pack_text();
+        first.append(second);
+        assert_eq!(first.attrs.len(), 4);
+        let mut expected = lines[..25].to_vec();
+        expected.push((48, Line::from_text("plain", &plain, SEQ_ZERO)));
+        assert_eq!(first.lines(), expected);
+    }
+
+    #[test]
+    fn test_image_data_sent_once() {
+        use termwiz::cell::{Cell, CellAttributes};
+        use termwiz::surface::SEQ_ZERO;
+
+        let data = Arc::new(ImageData::with_raw_data(b"not really a png".to_vec()));
+        let mut line = Line::from_text("ab", &CellAttributes::default(), SEQ_ZERO);
+        for x in 0..2 {
+            let mut attrs = CellAttributes::default();
+            attrs.attach_image(Box::new(ImageCell::new(
+                TextureCoordinate::new_f32(x as f32 * 0.5, 0.),
+                TextureCoordinate::new_f32((x + 1) as f32 * 0.5, 1.),
+                Arc::clone(&data),
+            )));
+            line.set_cell(x, Cell::new('a', attrs), SEQ_ZERO);
+        }
+        let has_image = |lines: &[(StableRowIndex, Line)]| {
+            lines[0]
+                .1
+                .visible_cells()
+                .all(|(_, cell)| cell.attrs().images().is_some())
+        };
+
+        // Both cells reference the same image, so its data is only sent once
+        let mut serialized: SerializedLines = vec![(5, line.clone())].into();
+        assert_eq!(serialized.image_data().count(), 1);
+        let hash = *serialized.image_data().next().unwrap().0;
+
+        // When the peer has the image, the data is omitted and the
+        // peer supplies it from its own cache
+        serialized.omit_image_data(|h| *h == hash);
+        assert_eq!(serialized.image_data().count(), 0);
+        let (missing, rows) = serialized.resolve_image_data(|_| Some(Arc::clone(&data)));
+        assert!(missing.is_empty() && rows.is_empty());
+        assert!(has_image(&serialized.lines()));
+
+        // If the peer no longer has it, the image is dropped and the
+        // peer learns which row to fetch again
+        let mut serialized: SerializedLines = vec![(5, line)].into();
+        serialized.omit_image_data(|_| true);
+        let (missing, rows) = serialized.resolve_image_data(|_| None);
+        assert_eq!(missing, vec![hash]);
+        assert_eq!(rows, vec![5]);
+        let lines = serialized.lines();
+        assert!(lines[0]
+            .1
+            .visible_cells()
+            .all(|(_, cell)| cell.attrs().images().is_none()));
+    }
+
+    #[test]
+    fn test_sealed_frames() {
+        let key = EncryptionKey::new("secret");
+        let client_nonce = generate_session_nonce().unwrap();
+        let server_nonce = generate_session_nonce().unwrap();
+        let session = |key: &EncryptionKey, role| {
+            key.session_cipher(role, &client_nonce, &server_nonce)
+                .unwrap()
+        };
+        let mut client = session(&key, CipherRole::Client);
+        let mut server = session(&key, CipherRole::Server);
+
+        let frame = Pdu::Ping(Ping::default())
+            .encode_as_vec(0x40, false)
+            .unwrap();
+        let ping = DecodedPdu {
+            serial: 0x40,
+            pdu: Pdu::Ping(Ping::default()),
+        };
+
+        let sealed = client.seal(&frame).unwrap();
+        assert_eq!(
+            Pdu::decode_sealed(sealed.as_slice(), &mut server).unwrap(),
+            ping
+        );
+
+        // Replaying a frame is rejected
+        assert!(Pdu::decode_sealed(sealed.as_slice(), &mut server).is_err());
+
+        // Tampering with a frame is detected
+        let sealed = client.seal(&frame).unwrap();
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(Pdu::decode_sealed(tampered.as_slice(), &mut server).is_err());
+        assert_eq!(
+            Pdu::decode_sealed(sealed.as_slice(), &mut server).unwrap(),
+            ping
+        );
+
+        // A frame cannot be reflected back to its sender
+        let mut client = session(&key, CipherRole::Client);
+        let sealed = client.seal(&frame).unwrap();
+        assert!(Pdu::decode_sealed(sealed.as_slice(), &mut client).is_err());
+
+        // A peer with a different key cannot open the frame
+        let mut imposter = session(&EncryptionKey::new("guess"), CipherRole::Server);
+        assert!(Pdu::decode_sealed(sealed.as_slice(), &mut imposter).is_err());
+    }
+
+    #[test]
+    fn test_checksummed_frames() {
+        let write = DecodedPdu {
+            serial: 0x42,
+            pdu: Pdu::WriteToPane(WriteToPane {
+                pane_id: 1,
+                data: b"hello world".to_vec(),
+            }),
+        };
+        let frame = write.pdu.encode_as_vec(write.serial, true).unwrap();
+        assert_eq!(Pdu::decode(frame.as_slice()).unwrap(), write);
+
+        // Flip a bit in the payload; the checksum must catch it
+        let mut corrupt = frame.clone();
+        let idx = corrupt.len() - CHECKSUM_LEN - 3;
+        corrupt[idx] ^= 0x20;
+        let err = Pdu::decode(corrupt.as_slice()).unwrap_err();
+        assert!(
+            err.downcast_ref::<ChecksumMismatch>().is_some(),
+            "expected a checksum error, got {:#}",
+            err
+        );
+
+        // Frames without a checksum decode as before
+        let frame = write.pdu.encode_as_vec(write.serial, false).unwrap();
+        assert_eq!(Pdu::decode(frame.as_slice()).unwrap(), write);
+
+        // ...unless checksums were negotiated for the session
+        let mut framing = Framing {
+            checksum: true,
+            ..Framing::default()
+        };
+        let mut reader = smol::io::Cursor::new(frame);
+        let err = smol::block_on(Pdu::decode_async_framed(&mut reader, &mut framing)).unwrap_err();
+        assert!(
+            err.downcast_ref::<MissingChecksum>().is_some(),
+            "expected a missing checksum error, got {:#}",
+            err
+        );
+        let frame = write.pdu.encode_as_vec(write.serial, true).unwrap();
+        let mut reader = smol::io::Cursor::new(frame);
+        let (decoded, _) =
+            smol::block_on(Pdu::decode_async_framed(&mut reader, &mut framing)).unwrap();
+        assert_eq!(decoded, write);
+    }
+
+    #[test]
+    fn test_compression_is_counted() {
+        let mut stats = CompressionStats::default();
+        // Too small to be worth compressing
+        Pdu::Ping(Ping::default())
+            .encode_as_vec_counted(1, false, &mut stats)
+            .unwrap();
+        assert_eq!(stats, CompressionStats::default());
+        assert_eq!(stats.ratio(), 1.0);
+
+        let pdu = Pdu::WriteToPane(WriteToPane {
+            pane_id: 0,
+            data: vec![b'x'; 4096],
+        });
+        let frame = pdu.encode_as_vec_counted(2, false, &mut stats).unwrap();
+        assert_eq!(stats.frames, 1);
+        assert!(stats.bytes_in > 4096);
+        assert!(stats.bytes_out < frame.len() as u64);
+        assert!(stats.ratio() > 10.0);
+
+        let mut totals = CompressionStats::default();
+        totals.add(&stats);
+        totals.add(&stats);
+        assert_eq!(totals.frames, 2);
+        assert_eq!(totals.bytes_in, stats.bytes_in * 2);
+    }
+
+    #[test]
+    fn test_integers_are_compact() {
+        // A typical request from an interactive session: small pane
+        // id and serial, and rows near the top of the scrollback.
+        // Each integer occupies a single byte, where fixed width
+        // 64 bit integers would need 64 bytes for the same frame.
+        let frame = Pdu::GetLines(GetLines {
+            pane_id: 1,
+            lines: vec![10..20],
+            packed: true,
+            known_hashes: vec![],
+        })
+        .encode_as_vec(1, false)
+        .unwrap();
+        assert_eq!(frame.len(), 9);
+
+        // Rows deep into the scrollback take a couple more bytes each
+        let frame = Pdu::GetLines(GetLines {
+            pane_id: 1,
+            lines: vec![1_000_000..1_000_050],
+            packed: true,
+            known_hashes: vec![],
+        })
+        .encode_as_vec(1, false)
+        .unwrap();
+        assert_eq!(frame.len(), 13);
+    }
+
+    #[test]
+    fn test_oversized_frame_is_rejected() {
+        // A length prefix claiming a terabyte of data, followed by
+        // a few bytes; this must be rejected before the decoder
+        // tries to allocate a buffer to hold the frame
+        let len = 1u64 << 40;
+        for flags in &[0, CHECKSUM_MASK] {
+            let mut encoded = Vec::new();
+            leb128::write::unsigned(&mut encoded, len | flags).unwrap();
+            encoded.extend_from_slice(&[0x42, 0x01, 0x00]);
+
+            let err = Pdu::decode(encoded.as_slice()).unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<FrameTooLarge>(),
+                Some(&FrameTooLarge {
+                    len,
+                    max: DEFAULT_MAX_FRAME_LEN
+                }),
+                "expected a frame size error, got {:#}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_oversized_decompressed_pdu_is_rejected() {
+        // Compresses to a small frame, but expands beyond the limit
+        let pdu = Pdu::WriteToPane(WriteToPane {
+            pane_id: 0,
+            data: vec![0; 4096],
+        });
+        let mut encoded = Vec::new();
+        pdu.encode(&mut encoded, 1).unwrap();
+        assert!(encoded.len() < 1024);
+
+        let err = Pdu::decode_with_max_len(encoded.as_slice(), 1024).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<FrameTooLarge>(),
+            Some(&FrameTooLarge {
+                len: 1025,
+                max: 1024
+            }),
+            "expected a frame size error, got {:#}",
+            err
+        );
+
+        let decoded = Pdu::decode_with_max_len(encoded.as_slice(), 8192).unwrap();
+        assert_eq!(decoded.pdu, pdu);
+    }
+
+    #[test]
+    fn test_older_server_ignores_client_codec_version() {
+        // The GetCodecVersion of servers that predate the
+        // codec_vers field
+        #[derive(Deserialize, Serialize)]
+        struct LegacyGetCodecVersion {}
+
+        let (data, is_compressed) = serialize(&GetCodecVersion {
+            codec_vers: Some(CODEC_VERSION),
+        })
+        .unwrap();
+        let _: LegacyGetCodecVersion = deserialize(data.as_slice(), is_compressed).unwrap();
+        let request: GetCodecVersion = deserialize(data.as_slice(), is_compressed).unwrap();
+        assert_eq!(request.codec_vers, Some(CODEC_VERSION));
+
+        // ...and the GetCodecVersion of those clients decodes without it
+        let (data, is_compressed) = serialize(&LegacyGetCodecVersion {}).unwrap();
+        let request: GetCodecVersion = deserialize(data.as_slice(), is_compressed).unwrap();
+        assert_eq!(request.codec_vers, None);
+
+        assert!(is_supported_codec_version(CODEC_VERSION));
+        assert!(!is_supported_codec_version(CODEC_VERSION + 1));
+        assert!(!is_supported_codec_version(MIN_SUPPORTED_CODEC_VERSION - 1));
+    }
+
+    #[test]
+    fn test_error_response_from_older_server() {
+        // The ErrorResponse of servers that predate the code
+        #[derive(Deserialize, Serialize)]
+        struct LegacyErrorResponse {
+            reason: String,
+        }
+
+        let error = ErrorResponse {
+            reason: "no such pane 1".to_string(),
+            code: ErrorCode::NoSuchPane,
+        };
+        let (data, is_compressed) = serialize(&error).unwrap();
+        let legacy: LegacyErrorResponse = deserialize(data.as_slice(), is_compressed).unwrap();
+        assert_eq!(legacy.reason, error.reason);
+        let decoded: ErrorResponse = deserialize(data.as_slice(), is_compressed).unwrap();
+        assert_eq!(decoded, error);
+
+        let (data, is_compressed) = serialize(&LegacyErrorResponse {
+            reason: "oops".to_string(),
+        })
+        .unwrap();
+        let decoded: ErrorResponse = deserialize(data.as_slice(), is_compressed).unwrap();
+        assert_eq!(
+            decoded,
+            ErrorResponse {
+                reason: "oops".to_string(),
+                code: ErrorCode::Internal,
+            }
+        );
+    }
 }
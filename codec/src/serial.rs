@@ -0,0 +1,89 @@
+//! Request serials are namespaced by connection epoch.
+//!
+//! The client assigns the serial of each request and the server echoes
+//! it in the response.  The client bumps its epoch each time that it
+//! re-establishes its connection, and stores the epoch in the low bits
+//! of each serial, so that a response that arrives late from an
+//! earlier connection cannot be mistaken for the response to a request
+//! with the same sequence number on the current one.
+//! Serial 0 remains reserved for unilateral PDUs.
+use crate::DecodedPdu;
+
+/// The number of low bits of a serial that hold the epoch.  Keeping
+/// the epoch in the low bits keeps the leb128 encoded serials short.
+pub const SERIAL_EPOCH_BITS: u32 = 8;
+const SERIAL_EPOCH_MASK: u64 = (1 << SERIAL_EPOCH_BITS) - 1;
+
+/// Allocates the serials of the requests sent during an epoch
+#[derive(Debug)]
+pub struct SerialSpace {
+    epoch: u64,
+    next: u64,
+}
+
+impl SerialSpace {
+    /// The epoch wraps around once it exceeds SERIAL_EPOCH_BITS
+    pub fn new(epoch: u64) -> Self {
+        Self {
+            epoch: epoch & SERIAL_EPOCH_MASK,
+            next: 1,
+        }
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    pub fn next_serial(&mut self) -> u64 {
+        let serial = (self.next << SERIAL_EPOCH_BITS) | self.epoch;
+        self.next += 1;
+        serial
+    }
+
+    /// Returns true if `serial` was allocated during this epoch
+    pub fn is_current(&self, serial: u64) -> bool {
+        serial != 0 && serial & SERIAL_EPOCH_MASK == self.epoch
+    }
+}
+
+impl DecodedPdu {
+    /// The epoch in which the serial of this PDU was allocated.
+    /// This is meaningless for unilateral PDUs, whose serial is 0.
+    pub fn serial_epoch(&self) -> u64 {
+        self.serial & SERIAL_EPOCH_MASK
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Pdu, UnitResponse};
+
+    #[test]
+    fn stale_response_is_not_current() {
+        let mut old = SerialSpace::new(1);
+        let stale = DecodedPdu {
+            serial: old.next_serial(),
+            pdu: Pdu::UnitResponse(UnitResponse {}),
+        };
+        assert!(old.is_current(stale.serial));
+
+        // The first request of the new epoch has the same sequence
+        // number as the stale response, but a different serial
+        let mut current = SerialSpace::new(2);
+        let serial = current.next_serial();
+        assert_ne!(serial, stale.serial);
+        assert!(current.is_current(serial));
+        assert!(!current.is_current(stale.serial));
+        assert_eq!(stale.serial_epoch(), 1);
+
+        assert!(!current.is_current(0));
+    }
+
+    #[test]
+    fn epoch_wraps() {
+        let mut space = SerialSpace::new(1 << SERIAL_EPOCH_BITS);
+        assert_eq!(space.epoch(), 0);
+        assert!(space.next_serial() != 0);
+    }
+}
@@ -0,0 +1,287 @@
+//! Connection level compression.
+//!
+//! Compressing each frame on its own misses the redundancy between
+//! frames, such as the prompts and escape sequences that recur in
+//! most of the lines that are sent to the client.  Once the peer has
+//! agreed to `EnableStreamCompression`, the body of each frame that
+//! is sent on the connection is instead compressed by a single zstd
+//! stream.  The stream is flushed at the end of each frame, so that
+//! the receiver can decode the frame as soon as it arrives, while
+//! everything that was previously sent remains available as history
+//! against which later frames are compressed.
+//!
+//! The compressor and decompressor of a connection must remain in
+//! lockstep: every compressed frame must be decompressed, in the
+//! order in which it was compressed.  Each frame carries its sequence
+//! number in the stream so that a violation of this is detected as a
+//! `StreamDesync`, rather than as garbled PDUs.  The stream cannot be
+//! resynchronized after that, or after a frame fails to decompress,
+//! so the connection must be torn down.  Each connection starts with
+//! fresh contexts, so reconnecting restores the stream.
+use crate::{FrameTooLarge, DEFAULT_MAX_FRAME_LEN};
+use anyhow::Context;
+use std::io::Write;
+
+/// Returned when a frame doesn't carry the next sequence number
+/// of the compression stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamDesync {
+    pub expected: u64,
+    pub actual: u64,
+}
+
+impl std::fmt::Display for StreamDesync {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            fmt,
+            "compression stream out of sync: expected frame {} but received frame {}",
+            self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for StreamDesync {}
+
+/// Compresses the frames that are sent on a connection
+pub struct StreamCompressor {
+    encoder: zstd::stream::write::Encoder<Vec<u8>>,
+    seqno: u64,
+}
+
+impl std::fmt::Debug for StreamCompressor {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("StreamCompressor")
+            .field("seqno", &self.seqno)
+            .finish()
+    }
+}
+
+impl StreamCompressor {
+    pub fn new() -> anyhow::Result<Self> {
+        Ok(Self {
+            encoder: zstd::stream::write::Encoder::new(
+                Vec::new(),
+                zstd::DEFAULT_COMPRESSION_LEVEL,
+            )?,
+            seqno: 0,
+        })
+    }
+
+    /// Compress the body of the next frame.  An error leaves the
+    /// stream in an unknown state, so the connection must be closed.
+    pub(crate) fn compress(&mut self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut frame = Vec::new();
+        leb128::write::unsigned(&mut frame, self.seqno)
+            .context("writing compression stream sequence number")?;
+        self.seqno += 1;
+        self.encoder
+            .write_all(data)
+            .context("compressing frame into stream")?;
+        self.encoder
+            .flush()
+            .context("flushing compression stream")?;
+        frame.append(self.encoder.get_mut());
+        Ok(frame)
+    }
+}
+
+/// Collects the output of the decompressor, refusing to grow
+/// beyond `max` bytes, so that a small frame can't expand into
+/// an unreasonable amount of memory
+struct BoundedBuffer {
+    data: Vec<u8>,
+    max: usize,
+}
+
+impl Write for BoundedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = self.data.len() + buf.len();
+        if len > self.max {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                FrameTooLarge {
+                    len: len as u64,
+                    max: self.max,
+                },
+            ));
+        }
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Decompresses the frames that are received on a connection
+pub struct StreamDecompressor {
+    decoder: zstd::stream::write::Decoder<BoundedBuffer>,
+    seqno: u64,
+}
+
+impl std::fmt::Debug for StreamDecompressor {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("StreamDecompressor")
+            .field("seqno", &self.seqno)
+            .finish()
+    }
+}
+
+impl StreamDecompressor {
+    pub fn new() -> anyhow::Result<Self> {
+        Ok(Self {
+            decoder: zstd::stream::write::Decoder::new(BoundedBuffer {
+                data: vec![],
+                max: DEFAULT_MAX_FRAME_LEN,
+            })?,
+            seqno: 0,
+        })
+    }
+
+    /// Decompress the body of the next frame, which must not
+    /// expand to more than `max_frame_len` bytes
+    pub(crate) fn decompress(
+        &mut self,
+        frame: &[u8],
+        max_frame_len: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut cursor = frame;
+        let seqno = leb128::read::unsigned(&mut cursor)
+            .context("reading compression stream sequence number")?;
+        if seqno != self.seqno {
+            return Err(StreamDesync {
+                expected: self.seqno,
+                actual: seqno,
+            }
+            .into());
+        }
+        self.seqno += 1;
+
+        self.decoder.get_mut().max = max_frame_len;
+        self.decoder
+            .write_all(cursor)
+            .and_then(|_| self.decoder.flush())
+            .with_context(|| format!("decompressing frame {} of the stream", seqno))?;
+        Ok(std::mem::take(&mut self.decoder.get_mut().data))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Framing, GetLinesResponse, Pdu};
+    use termwiz::cell::CellAttributes;
+    use termwiz::color::AnsiColor;
+    use termwiz::surface::{Line, SEQ_ZERO};
+    use wezterm_term::StableRowIndex;
+
+    /// The frames that the server sends while a user runs a
+    /// series of short commands at a colorful prompt
+    fn prompt_heavy_session() -> Vec<Pdu> {
+        let mut prompt_attrs = CellAttributes::default();
+        prompt_attrs
+            .set_foreground(AnsiColor::Green)
+            .set_intensity(termwiz::cell::Intensity::Bold);
+        let output_attrs = CellAttributes::default();
+
+        let commands = ["ls", "git status", "cargo build", "cd src", "ls -l", "vim"];
+        (0..200)
+            .map(|idx| {
+                let command = commands[idx % commands.len()];
+                let row = idx as StableRowIndex * 2;
+                let prompt = format!("wez@localhost:~/src/wezterm (main) $ {}", command);
+                let output = format!("output of {} number {}", command, idx);
+                Pdu::GetLinesResponse(GetLinesResponse {
+                    pane_id: 1,
+                    lines: vec![
+                        (row, Line::from_text(&prompt, &prompt_attrs, SEQ_ZERO)),
+                        (row + 1, Line::from_text(&output, &output_attrs, SEQ_ZERO)),
+                    ]
+                    .into(),
+                    more: false,
+                })
+            })
+            .collect()
+    }
+
+    fn encoded_size(pdus: &[Pdu], framing: &mut Framing) -> usize {
+        pdus.iter()
+            .enumerate()
+            .map(|(serial, pdu)| {
+                smol::block_on(pdu.encode_async_framed(
+                    &mut smol::io::sink(),
+                    serial as u64 + 1,
+                    framing,
+                ))
+                .unwrap()
+            })
+            .sum()
+    }
+
+    #[test]
+    fn stream_beats_per_frame_compression() {
+        let pdus = prompt_heavy_session();
+        let per_frame = encoded_size(&pdus, &mut Framing::default());
+        let stream = encoded_size(
+            &pdus,
+            &mut Framing {
+                compressor: Some(StreamCompressor::new().unwrap()),
+                ..Framing::default()
+            },
+        );
+        assert!(
+            stream * 3 < per_frame * 2,
+            "streamed {} vs per-frame {}",
+            stream,
+            per_frame
+        );
+    }
+
+    #[test]
+    fn frames_round_trip_through_the_stream() {
+        let pdus = prompt_heavy_session();
+        let mut sender = Framing {
+            compressor: Some(StreamCompressor::new().unwrap()),
+            ..Framing::default()
+        };
+        let mut receiver = Framing {
+            decompressor: Some(StreamDecompressor::new().unwrap()),
+            ..Framing::default()
+        };
+        for (serial, pdu) in pdus.into_iter().enumerate() {
+            let mut wire = vec![];
+            smol::block_on(pdu.encode_async_framed(&mut wire, serial as u64, &mut sender)).unwrap();
+            let mut reader = smol::io::Cursor::new(wire);
+            let (decoded, _) =
+                smol::block_on(Pdu::decode_async_framed(&mut reader, &mut receiver)).unwrap();
+            assert_eq!(decoded.pdu, pdu);
+        }
+    }
+
+    #[test]
+    fn skipped_frame_is_detected() {
+        let mut compressor = StreamCompressor::new().unwrap();
+        let mut decompressor = StreamDecompressor::new().unwrap();
+        let first = compressor.compress(b"hello hello hello").unwrap();
+        let _second = compressor.compress(b"hello again").unwrap();
+        assert_eq!(
+            decompressor
+                .decompress(&first, DEFAULT_MAX_FRAME_LEN)
+                .unwrap(),
+            b"hello hello hello"
+        );
+
+        let third = compressor.compress(b"and again").unwrap();
+        let err = decompressor
+            .decompress(&third, DEFAULT_MAX_FRAME_LEN)
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<StreamDesync>(),
+            Some(&StreamDesync {
+                expected: 1,
+                actual: 2
+            })
+        );
+    }
+}
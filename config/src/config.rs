@@ -239,6 +239,15 @@ pub struct Config {
     #[serde(default = "default_ratelimit_line_prefetches_per_second")]
     pub ratelimit_mux_line_prefetches_per_second: u32,
 
+    /// Constrains the rate at which a multiplexer session may submit
+    /// input (key presses, mouse events, pastes and writes) to the
+    /// server, counting each chunk of a long paste separately.
+    /// Input in excess of this rate is rejected with a `RateLimited`
+    /// error, which prevents a misbehaving client from monopolizing
+    /// the server.
+    #[serde(default = "default_ratelimit_mux_input_pdus_per_second")]
+    pub ratelimit_mux_input_pdus_per_second: u32,
+
     /// The buffer size used by parse_buffered_data in the mux module.
     /// This should not be too large, otherwise the processing cost
     /// of applying a batch of actions to the terminal will be too
@@ -603,6 +612,84 @@ pub struct Config {
 
     #[serde(default)]
     pub default_workspace: Option<String>,
+
+    /// A label that identifies this client to the multiplexer
+    /// server, so that other attached clients can see who is
+    /// connected.  If not set, the server shows a default label.
+    #[serde(default)]
+    pub mux_client_label: Option<String>,
+
+    /// When set, this client tells the multiplexer server that it
+    /// only intends to observe, and the server will reject any
+    /// input that it sends, along with any request that spawns,
+    /// kills, resizes or rearranges panes, tabs and windows.
+    /// This guards against accidentally typing into a session
+    /// that is being watched.  The server keeps a session
+    /// read-only for as long as it is connected.
+    #[serde(default)]
+    pub mux_client_read_only: bool,
+
+    /// If set, the multiplexer server appends a record of each
+    /// request that changes its state (writes, pastes, spawns and
+    /// kills) to this file, along with the client that made it
+    #[serde(default)]
+    pub mux_server_audit_log: Option<PathBuf>,
+
+    /// The largest mux protocol frame, in bytes, that will be
+    /// accepted from a peer.  A frame that claims to be larger
+    /// than this, or whose PDU decompresses to more than this,
+    /// causes the connection to be closed.
+    #[serde(default = "default_mux_max_frame_size")]
+    pub mux_max_frame_size: usize,
+
+    /// When true, the multiplexer server hashes the content of the
+    /// rows that it sends for the viewport of each pane, and omits
+    /// rows that are reported as changed but whose content is the
+    /// same as what was last sent
+    #[serde(default)]
+    pub mux_skip_unchanged_rows: bool,
+
+    /// Which of the sessions attached to the multiplexer server
+    /// receive the clipboard contents set by a program in a pane
+    #[serde(default)]
+    pub mux_clipboard_routing: MuxClipboardRouting,
+
+    /// The largest clipboard, in bytes, that the multiplexer server
+    /// will send to its clients.  Larger clipboards are truncated.
+    #[serde(default = "default_mux_clipboard_max_size")]
+    pub mux_clipboard_max_size: usize,
+
+    /// How often, in seconds, the multiplexer server looks for tabs
+    /// that have been left behind and removes them.  A tab that isn't
+    /// in any window is removed once its processes have exited.
+    /// 0 disables it.
+    #[serde(default = "default_mux_stale_tab_sweep_interval_secs")]
+    pub mux_stale_tab_sweep_interval_secs: u64,
+
+    /// If set, the multiplexer server removes a tab whose processes
+    /// have all exited, but which is being held open, once no attached
+    /// session has shown an interest in it for this many seconds
+    #[serde(default)]
+    pub mux_reap_exited_tabs_after_secs: Option<u64>,
+
+    /// When attaching to a multiplexer server, ask it to push the
+    /// scrollback of each pane in the background, rather than waiting
+    /// for it to be fetched as it is scrolled into view
+    #[serde(default)]
+    pub mux_trickle_scrollback: bool,
+
+    /// The largest number of programs that a session of the multiplexer
+    /// server may have spawned at once; further spawns are refused until
+    /// some of their panes have gone away.  0 means that there is no limit.
+    #[serde(default = "default_mux_max_concurrent_spawns")]
+    pub mux_max_concurrent_spawns: usize,
+
+    /// The number of tasks that the sessions of the multiplexer server
+    /// may have waiting for its main thread before it is considered
+    /// to be busy, at which point it puts off the work that can wait,
+    /// such as pushing the changes of background tabs.  0 disables this.
+    #[serde(default = "default_mux_main_thread_busy_threshold")]
+    pub mux_main_thread_busy_threshold: usize,
 }
 impl_lua_conversion!(Config);
 
@@ -1062,10 +1149,34 @@ fn default_mux_output_parser_buffer_size() -> usize {
     128 * 1024
 }
 
+fn default_mux_max_frame_size() -> usize {
+    256 * 1024 * 1024
+}
+
+fn default_mux_clipboard_max_size() -> usize {
+    1024 * 1024
+}
+
+fn default_mux_stale_tab_sweep_interval_secs() -> u64 {
+    60
+}
+
+fn default_mux_max_concurrent_spawns() -> usize {
+    512
+}
+
+fn default_mux_main_thread_busy_threshold() -> usize {
+    256
+}
+
 fn default_ratelimit_line_prefetches_per_second() -> u32 {
     10
 }
 
+fn default_ratelimit_mux_input_pdus_per_second() -> u32 {
+    1000
+}
+
 fn default_cursor_blink_rate() -> u64 {
     800
 }
@@ -1411,3 +1522,19 @@ impl Default for ExitBehavior {
         ExitBehavior::CloseOnCleanExit
     }
 }
+
+/// Which sessions receive a clipboard update from a multiplexed pane
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum MuxClipboardRouting {
+    /// The session that most recently focused, sent input to or
+    /// spawned into the tab, or else the most recently connected session
+    MostRecentSession,
+    /// Every connected session
+    AllSessions,
+}
+
+impl Default for MuxClipboardRouting {
+    fn default() -> Self {
+        MuxClipboardRouting::MostRecentSession
+    }
+}
@@ -301,6 +301,19 @@ pub fn create_user_owned_dirs(p: &Path) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Read the shared secret used to authenticate mux sessions from
+/// the specified file.  Surrounding whitespace, such as a trailing
+/// newline, is not considered to be part of the token.
+pub fn read_auth_token(path: &Path) -> anyhow::Result<String> {
+    let token = std::fs::read_to_string(path)
+        .with_context(|| format!("reading auth token from {}", path.display()))?;
+    let token = token.trim();
+    if token.is_empty() {
+        anyhow::bail!("auth token file {} is empty", path.display());
+    }
+    Ok(token.to_string())
+}
+
 fn xdg_config_home() -> PathBuf {
     match std::env::var_os("XDG_CONFIG_HOME").map(|s| PathBuf::from(s).join("wezterm")) {
         Some(p) => p,
@@ -22,6 +22,11 @@ pub struct TlsDomainServer {
     /// to the trust store.
     #[serde(default)]
     pub pem_root_certs: Vec<PathBuf>,
+
+    /// The path to a file holding a shared secret token.
+    /// When set, clients must authenticate using that token
+    /// before the server will accept other requests.
+    pub auth_token_file: Option<PathBuf>,
 }
 impl_lua_conversion!(TlsDomainServer);
 
@@ -85,6 +90,10 @@ pub struct TlsDomainClient {
 
     /// The path to the wezterm binary on the remote host
     pub remote_wezterm_path: Option<String>,
+
+    /// The path to a file holding the shared secret token that
+    /// the server expects clients to present when connecting.
+    pub auth_token_file: Option<PathBuf>,
 }
 impl_lua_conversion!(TlsDomainClient);
 
@@ -52,6 +52,19 @@ pub struct UnixDomain {
     /// Don't use default_local_echo_threshold_ms() here to
     /// disable the predictive echo for Unix domains by default.
     pub local_echo_threshold_ms: Option<u64>,
+
+    /// The path to a file holding a shared secret token.
+    /// When set, the server will require that clients authenticate
+    /// using that token before accepting other requests, and the
+    /// client will present the token when connecting.
+    pub auth_token_file: Option<PathBuf>,
+
+    /// The path to a file holding a pre-shared key.  When set, the
+    /// client and server negotiate a per-session key derived from it
+    /// and encrypt all traffic after the initial handshake.  This is
+    /// useful when the socket is forwarded through a host that is
+    /// not trusted, and which doesn't terminate TLS.
+    pub encryption_key_file: Option<PathBuf>,
 }
 impl_lua_conversion!(UnixDomain);
 
@@ -68,6 +81,8 @@ impl Default for UnixDomain {
             write_timeout: default_write_timeout(),
             local_echo_threshold_ms: None,
             proxy_command: None,
+            auth_token_file: None,
+            encryption_key_file: None,
         }
     }
 }
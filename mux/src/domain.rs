@@ -15,13 +15,14 @@ use async_trait::async_trait;
 use config::{configuration, WslDomain};
 use downcast_rs::{impl_downcast, Downcast};
 use portable_pty::{native_pty_system, CommandBuilder, PtySize, PtySystem};
+use serde::{Deserialize, Serialize};
 use std::ffi::OsString;
 use std::rc::Rc;
 
 static DOMAIN_ID: ::std::sync::atomic::AtomicUsize = ::std::sync::atomic::AtomicUsize::new(0);
 pub type DomainId = usize;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
 pub enum DomainState {
     Detached,
     Attached,
@@ -96,6 +97,18 @@ pub trait Domain: Downcast {
         command_dir: Option<String>,
     ) -> anyhow::Result<Rc<dyn Pane>>;
 
+    /// Check whether `command` could be spawned in `command_dir`,
+    /// without spawning it, returning the path of the program that
+    /// would be run.  Domains that can't tell without spawning
+    /// return None.
+    fn validate_spawn(
+        &self,
+        _command: Option<CommandBuilder>,
+        _command_dir: Option<String>,
+    ) -> anyhow::Result<Option<OsString>> {
+        Ok(None)
+    }
+
     /// Returns false if the `spawn` method will never succeed.
     /// There are some internal placeholder domains that are
     /// pre-created with local UI that we do not want to allow
@@ -301,6 +314,22 @@ impl Domain for LocalDomain {
         Ok(pane)
     }
 
+    fn validate_spawn(
+        &self,
+        command: Option<CommandBuilder>,
+        command_dir: Option<String>,
+    ) -> anyhow::Result<Option<OsString>> {
+        // A spawn quietly falls back to the home directory when its
+        // directory doesn't exist, but that isn't what was asked for
+        if let Some(dir) = &command_dir {
+            if !std::path::Path::new(dir).is_dir() {
+                return Err(crate::DirectoryNotFound(dir.clone()).into());
+            }
+        }
+        let cmd = self.build_command(command, command_dir)?;
+        cmd.resolve_program().map(Some)
+    }
+
     fn domain_id(&self) -> DomainId {
         self.id
     }
@@ -15,12 +15,13 @@ use percent_encoding::percent_decode_str;
 use portable_pty::{CommandBuilder, ExitStatus, PtySize};
 use std::cell::{Ref, RefCell, RefMut};
 use std::collections::HashMap;
+use std::ffi::OsString;
 use std::io::{Read, Write};
 use std::rc::Rc;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use termwiz::escape::csi::{DecPrivateMode, DecPrivateModeCode, Device, Mode};
 use termwiz::escape::{Action, CSI};
 use thiserror::*;
@@ -54,6 +55,12 @@ pub enum MuxNotification {
     WindowCreated(WindowId),
     WindowRemoved(WindowId),
     WindowInvalidated(WindowId),
+    /// The size or arrangement of the panes in a tab has changed,
+    /// or one of them has been added, removed or (un)zoomed
+    TabResized(TabId),
+    /// A tab that had been left behind, either outside of any window
+    /// or held open after its processes exited, has been removed
+    TabExited(TabId),
     WindowWorkspaceChanged(WindowId),
     ActiveWorkspaceChanged(Arc<ClientId>),
     Alert {
@@ -77,10 +84,40 @@ pub struct Mux {
     clients: RefCell<HashMap<ClientId, ClientInfo>>,
     identity: RefCell<Option<Arc<ClientId>>>,
     num_panes_by_workspace: RefCell<HashMap<String, usize>>,
+    pty_output_progress: RefCell<HashMap<PaneId, Arc<PtyOutputProgress>>>,
 }
 
 const BUFSIZE: usize = 1024 * 1024;
 
+/// How often `PtyOutputProgress::drained` checks on the parser
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Tracks the output that has been read from the pty of a pane,
+/// and how much of it has been parsed and applied to the pane
+#[derive(Default, Debug)]
+pub struct PtyOutputProgress {
+    read: AtomicUsize,
+    applied: AtomicUsize,
+    /// Set when the parser has stopped, after which nothing
+    /// more will be applied
+    finished: AtomicBool,
+}
+
+impl PtyOutputProgress {
+    /// Wait until the output that has been read from the pty at the
+    /// time of the call has been parsed and applied to the pane.
+    /// Output that is read after the call is not waited for.
+    /// Output that is held back by synchronized output mode counts
+    /// as applied once it has been parsed.
+    pub async fn drained(self: Arc<Self>) {
+        let target = self.read.load(Ordering::SeqCst);
+        while self.applied.load(Ordering::SeqCst) < target && !self.finished.load(Ordering::SeqCst)
+        {
+            smol::Timer::after(DRAIN_POLL_INTERVAL).await;
+        }
+    }
+}
+
 /// This function bounces parsed actions over to the main thread to feed to
 /// the pty in the mux.
 /// It blocks until the mux has finished consuming the data, which provides
@@ -111,7 +148,12 @@ fn send_actions_to_mux(pane_id: PaneId, dead: &Arc<AtomicBool>, actions: Vec<Act
     histogram!("send_actions_to_mux.rate", 1.);
 }
 
-fn parse_buffered_data(pane_id: PaneId, dead: &Arc<AtomicBool>, mut rx: FileDescriptor) {
+fn parse_buffered_data(
+    pane_id: PaneId,
+    dead: &Arc<AtomicBool>,
+    mut rx: FileDescriptor,
+    progress: &PtyOutputProgress,
+) {
     let mut buf = vec![0; configuration().mux_output_parser_buffer_size];
     let mut parser = termwiz::escape::parser::Parser::new();
     let mut actions = vec![];
@@ -162,11 +204,13 @@ fn parse_buffered_data(pane_id: PaneId, dead: &Arc<AtomicBool>, mut rx: FileDesc
                 if !actions.is_empty() && !hold {
                     send_actions_to_mux(pane_id, dead, std::mem::take(&mut actions));
                 }
+                progress.applied.fetch_add(size, Ordering::SeqCst);
 
                 buf.resize(configuration().mux_output_parser_buffer_size, 0);
             }
         }
     }
+    progress.finished.store(true, Ordering::SeqCst);
 }
 
 fn set_socket_buffer(fd: &mut FileDescriptor, option: i32, size: usize) -> anyhow::Result<()> {
@@ -191,7 +235,12 @@ fn set_socket_buffer(fd: &mut FileDescriptor, option: i32, size: usize) -> anyho
 /// blocking reads from the pty (non-blocking reads are not portable to
 /// all platforms and pty/tty types), parse the escape sequences and
 /// relay the actions to the mux thread to apply them to the pane.
-fn read_from_pane_pty(pane_id: PaneId, banner: Option<String>, mut reader: Box<dyn std::io::Read>) {
+fn read_from_pane_pty(
+    pane_id: PaneId,
+    banner: Option<String>,
+    mut reader: Box<dyn std::io::Read>,
+    progress: Arc<PtyOutputProgress>,
+) {
     let mut buf = vec![0; BUFSIZE];
 
     // This is used to signal that an error occurred either in this thread,
@@ -204,10 +253,12 @@ fn read_from_pane_pty(pane_id: PaneId, banner: Option<String>, mut reader: Box<d
 
     std::thread::spawn({
         let dead = Arc::clone(&dead);
-        move || parse_buffered_data(pane_id, &dead, rx)
+        let progress = Arc::clone(&progress);
+        move || parse_buffered_data(pane_id, &dead, rx, &progress)
     });
 
     if let Some(banner) = banner {
+        progress.read.fetch_add(banner.len(), Ordering::SeqCst);
         tx.write_all(banner.as_bytes()).ok();
     }
 
@@ -223,6 +274,9 @@ fn read_from_pane_pty(pane_id: PaneId, banner: Option<String>, mut reader: Box<d
             }
             Ok(size) => {
                 histogram!("read_from_pane_pty.bytes.rate", size as f64);
+                // Count the bytes before handing them to the parser,
+                // so that they are never applied before being read
+                progress.read.fetch_add(size, Ordering::SeqCst);
                 if let Err(err) = tx.write_all(&buf[..size]) {
                     error!(
                         "read_pty failed to write to parser: pane {} {:?}",
@@ -234,25 +288,27 @@ fn read_from_pane_pty(pane_id: PaneId, banner: Option<String>, mut reader: Box<d
         }
     }
 
-    match configuration().exit_behavior {
-        ExitBehavior::Hold | ExitBehavior::CloseOnCleanExit => {
-            // We don't know if we can unilaterally close
-            // this pane right now, so don't!
-            promise::spawn::spawn_into_main_thread(async move {
-                let mux = Mux::get().unwrap();
+    promise::spawn::spawn_into_main_thread(async move {
+        let mux = Mux::get().unwrap();
+        // The pane may have been spawned with its own exit_behavior,
+        // which can only be inspected from the main thread
+        let exit_behavior = mux
+            .get_pane(pane_id)
+            .and_then(|pane| pane.get_exit_behavior())
+            .unwrap_or_else(|| configuration().exit_behavior);
+        match exit_behavior {
+            ExitBehavior::Hold | ExitBehavior::CloseOnCleanExit => {
+                // We don't know if we can unilaterally close
+                // this pane right now, so don't!
                 log::trace!("checking for dead windows after EOF on pane {}", pane_id);
                 mux.prune_dead_windows();
-            })
-            .detach();
-        }
-        ExitBehavior::Close => {
-            promise::spawn::spawn_into_main_thread(async move {
-                let mux = Mux::get().unwrap();
+            }
+            ExitBehavior::Close => {
                 mux.remove_pane(pane_id);
-            })
-            .detach();
+            }
         }
-    }
+    })
+    .detach();
 
     dead.store(true, Ordering::Relaxed);
 }
@@ -333,6 +389,7 @@ impl Mux {
             clients: RefCell::new(HashMap::new()),
             identity: RefCell::new(None),
             num_panes_by_workspace: RefCell::new(HashMap::new()),
+            pty_output_progress: RefCell::new(HashMap::new()),
         }
     }
 
@@ -530,6 +587,15 @@ impl Mux {
         self.panes.borrow().get(&pane_id).map(Rc::clone)
     }
 
+    /// Returns the progress of the parser for the pty output of
+    /// `pane_id`, or None if the mux doesn't read its output
+    pub fn get_pty_output_progress(&self, pane_id: PaneId) -> Option<Arc<PtyOutputProgress>> {
+        self.pty_output_progress
+            .borrow()
+            .get(&pane_id)
+            .map(Arc::clone)
+    }
+
     pub fn get_tab(&self, tab_id: TabId) -> Option<Rc<Tab>> {
         self.tabs.borrow().get(&tab_id).map(Rc::clone)
     }
@@ -545,7 +611,11 @@ impl Mux {
         let pane_id = pane.pane_id();
         if let Some(reader) = pane.reader()? {
             let banner = self.banner.borrow().clone();
-            thread::spawn(move || read_from_pane_pty(pane_id, banner, reader));
+            let progress = Arc::new(PtyOutputProgress::default());
+            self.pty_output_progress
+                .borrow_mut()
+                .insert(pane_id, Arc::clone(&progress));
+            thread::spawn(move || read_from_pane_pty(pane_id, banner, reader, progress));
         }
         self.recompute_pane_count();
         self.notify(MuxNotification::PaneAdded(pane_id));
@@ -567,6 +637,7 @@ impl Mux {
 
     fn remove_pane_internal(&self, pane_id: PaneId) {
         log::debug!("removing pane {}", pane_id);
+        self.pty_output_progress.borrow_mut().remove(&pane_id);
         if let Some(pane) = self.panes.borrow_mut().remove(&pane_id).clone() {
             log::debug!("killing pane {}", pane_id);
             pane.kill();
@@ -607,7 +678,9 @@ impl Mux {
         let window = self.windows.borrow_mut().remove(&window_id);
         if let Some(window) = window {
             for tab in window.iter() {
-                self.remove_tab_internal(tab.tab_id());
+                let tab_id = tab.tab_id();
+                self.remove_tab_internal(tab_id);
+                self.notify(MuxNotification::TabExited(tab_id));
             }
             self.notify(MuxNotification::WindowRemoved(window_id));
         }
@@ -645,7 +718,7 @@ impl Mux {
             };
             for (window_id, win) in windows.iter_mut() {
                 win.prune_dead_tabs(&live_tab_ids);
-                if win.is_empty() {
+                if win.is_empty() && !win.is_awaiting_first_tab() {
                     log::trace!("prune_dead_windows: window is now empty");
                     dead_windows.push(*window_id);
                 }
@@ -764,6 +837,14 @@ impl Mux {
             .collect()
     }
 
+    pub fn iter_tabs(&self) -> Vec<Rc<Tab>> {
+        self.tabs
+            .borrow()
+            .iter()
+            .map(|(_, v)| Rc::clone(v))
+            .collect()
+    }
+
     pub fn iter_windows_in_workspace(&self, workspace: &str) -> Vec<WindowId> {
         let mut windows: Vec<WindowId> = self
             .windows
@@ -853,13 +934,13 @@ impl Mux {
             }
             SpawnTabDomain::DomainId(domain_id) => self
                 .get_domain(*domain_id)
-                .ok_or_else(|| anyhow!("domain id {} is invalid", domain_id))?,
+                .ok_or_else(|| DomainUnavailable::NoSuchDomainId(*domain_id))?,
             SpawnTabDomain::DomainName(name) => self
                 .get_domain_by_name(&name)
-                .ok_or_else(|| anyhow!("domain name {} is invalid", name))?,
+                .ok_or_else(|| DomainUnavailable::NoSuchDomainName(name.to_string()))?,
         };
         if domain.state() == DomainState::Detached {
-            anyhow::bail!("Cannot spawn a tab into a Detached domain");
+            return Err(DomainUnavailable::Detached.into());
         }
         Ok(domain)
     }
@@ -940,6 +1021,79 @@ impl Mux {
         Ok((pane, size))
     }
 
+    /// Split the active pane of `tab_id`, giving the newly spawned pane
+    /// `size_percent` of the space.  The split is rejected up front if
+    /// it would leave either pane too small to be usable.
+    pub async fn spawn_split(
+        &self,
+        tab_id: TabId,
+        direction: SplitDirection,
+        size_percent: u8,
+        command: Option<CommandBuilder>,
+    ) -> anyhow::Result<(Rc<dyn Pane>, PtySize)> {
+        if size_percent == 0 || size_percent >= 100 {
+            anyhow::bail!("size_percent {} must be between 1 and 99", size_percent);
+        }
+
+        let tab = self
+            .get_tab(tab_id)
+            .ok_or_else(|| anyhow!("tab_id {} invalid", tab_id))?;
+        let current_pane = tab
+            .get_active_pane()
+            .ok_or_else(|| anyhow!("tab {} has no panes", tab_id))?;
+        let pane_index = tab.get_active_idx();
+
+        let domain = self
+            .resolve_spawn_tab_domain(
+                Some(current_pane.pane_id()),
+                &SpawnTabDomain::CurrentPaneDomain,
+            )
+            .context("resolve_spawn_tab_domain")?;
+
+        let split_size = tab
+            .compute_split_size_percent(pane_index, direction, size_percent)
+            .ok_or_else(|| anyhow!("invalid pane index {}", pane_index))?;
+        if !split_size.is_usable() {
+            anyhow::bail!(
+                "splitting tab {} by {}% would leave a pane smaller than {}x{}",
+                tab_id,
+                size_percent,
+                crate::tab::MIN_SPLIT_PANE_COLS,
+                crate::tab::MIN_SPLIT_PANE_ROWS
+            );
+        }
+
+        let term_config = current_pane.get_config();
+        let cwd = self.resolve_cwd(None, Some(Rc::clone(&current_pane)));
+
+        let pane = domain.spawn_pane(split_size.second, command, cwd).await?;
+        if let Some(config) = term_config {
+            pane.set_config(config);
+        }
+
+        if let Err(err) =
+            tab.split_and_insert_percent(pane_index, direction, size_percent, Rc::clone(&pane))
+        {
+            pane.kill();
+            self.remove_pane(pane.pane_id());
+            return Err(err);
+        }
+
+        Ok((pane, split_size.second))
+    }
+
+    /// Check whether `command` could be spawned in `command_dir` in
+    /// `domain`, without spawning it; see `Domain::validate_spawn`
+    pub fn validate_spawn(
+        &self,
+        domain: &SpawnTabDomain,
+        command: Option<CommandBuilder>,
+        command_dir: Option<String>,
+    ) -> anyhow::Result<Option<OsString>> {
+        let domain = self.resolve_spawn_tab_domain(None, domain)?;
+        domain.validate_spawn(command, command_dir)
+    }
+
     pub async fn spawn_tab_or_window(
         &self,
         window_id: Option<WindowId>,
@@ -1021,6 +1175,22 @@ impl Drop for IdentityHolder {
     }
 }
 
+/// Returned when a spawn names a domain that can't be spawned into
+#[derive(Debug, Error)]
+pub enum DomainUnavailable {
+    #[error("domain id {0} is invalid")]
+    NoSuchDomainId(DomainId),
+    #[error("domain name {0} is invalid")]
+    NoSuchDomainName(String),
+    #[error("Cannot spawn a tab into a Detached domain")]
+    Detached,
+}
+
+/// Returned when checking a spawn whose directory doesn't exist
+#[derive(Debug, Error)]
+#[error("{0} is not a directory")]
+pub struct DirectoryNotFound(pub String);
+
 #[derive(Debug, Error)]
 #[allow(dead_code)]
 pub enum SessionTerminated {
@@ -17,6 +17,7 @@ use std::io::Result as IoResult;
 use std::ops::Range;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
+use termwiz::escape::osc::Progress;
 use termwiz::escape::DeviceControlMode;
 use termwiz::input::KeyboardEncoding;
 use termwiz::surface::{Line, SequenceNo, SEQ_ZERO};
@@ -24,7 +25,7 @@ use url::Url;
 use wezterm_term::color::ColorPalette;
 use wezterm_term::{
     Alert, AlertHandler, CellAttributes, Clipboard, DownloadHandler, KeyCode, KeyModifiers,
-    MouseEvent, SemanticZone, StableRowIndex, Terminal, TerminalConfiguration,
+    MouseEvent, SemanticZone, StableRowIndex, Terminal, TerminalConfiguration, TerminalModes,
 };
 
 #[derive(Debug)]
@@ -38,6 +39,7 @@ enum ProcessState {
     },
     DeadPendingClose {
         killed: bool,
+        status: ExitStatus,
     },
     Dead,
 }
@@ -56,6 +58,7 @@ pub struct LocalPane {
     domain_id: DomainId,
     tmux_domain: RefCell<Option<Arc<TmuxDomainState>>>,
     proc_list: RefCell<Option<CachedProcInfo>>,
+    exit_behavior: RefCell<Option<ExitBehavior>>,
 }
 
 #[async_trait(?Send)]
@@ -131,7 +134,7 @@ impl Pane for LocalPane {
                 let _ = signaller.kill();
                 *killed = true;
             }
-            ProcessState::DeadPendingClose { killed } => {
+            ProcessState::DeadPendingClose { killed, .. } => {
                 *killed = true;
             }
             _ => {}
@@ -158,14 +161,21 @@ impl Pane for LocalPane {
                     _ => Some(ExitStatus::with_exit_code(1)),
                 };
                 if let Some(status) = status {
-                    match (configuration().exit_behavior, status.success(), killed) {
+                    let exit_behavior = self
+                        .exit_behavior
+                        .borrow()
+                        .unwrap_or_else(|| configuration().exit_behavior);
+                    match (exit_behavior, status.success(), killed) {
                         (ExitBehavior::Close, _, _) => *proc = ProcessState::Dead,
                         (ExitBehavior::CloseOnCleanExit, false, false) => {
                             notify = Some(format!(
                                 "\r\n[Process didn't exit cleanly. ({}=\"CloseOnCleanExit\")]\r\n",
                                 EXIT_BEHAVIOR
                             ));
-                            *proc = ProcessState::DeadPendingClose { killed: false }
+                            *proc = ProcessState::DeadPendingClose {
+                                killed: false,
+                                status,
+                            }
                         }
                         (ExitBehavior::CloseOnCleanExit, ..) => *proc = ProcessState::Dead,
                         (ExitBehavior::Hold, success, false) => {
@@ -180,14 +190,17 @@ impl Pane for LocalPane {
                                     EXIT_BEHAVIOR
                                 ));
                             }
-                            *proc = ProcessState::DeadPendingClose { killed: false }
+                            *proc = ProcessState::DeadPendingClose {
+                                killed: false,
+                                status,
+                            }
                         }
                         (ExitBehavior::Hold, _, true) => *proc = ProcessState::Dead,
                     }
                     log::debug!("child terminated, new state is {:?}", proc);
                 }
             }
-            ProcessState::DeadPendingClose { killed } => {
+            ProcessState::DeadPendingClose { killed, .. } => {
                 if *killed {
                     *proc = ProcessState::Dead;
                     log::debug!("child state -> {:?}", proc);
@@ -218,6 +231,13 @@ impl Pane for LocalPane {
         }
     }
 
+    fn exit_status(&self) -> Option<ExitStatus> {
+        match &*self.process.borrow() {
+            ProcessState::DeadPendingClose { status, .. } => Some(status.clone()),
+            ProcessState::Running { .. } | ProcessState::Dead => None,
+        }
+    }
+
     fn set_clipboard(&self, clipboard: &Arc<dyn Clipboard>) {
         self.terminal.borrow_mut().set_clipboard(clipboard);
     }
@@ -272,11 +292,31 @@ impl Pane for LocalPane {
         Ok(())
     }
 
+    fn resize_with_anchor(
+        &self,
+        size: PtySize,
+        anchor: StableRowIndex,
+    ) -> Result<Option<StableRowIndex>, Error> {
+        self.pty.borrow_mut().resize(size)?;
+        Ok(self.terminal.borrow_mut().resize_with_anchor(
+            size.rows as usize,
+            size.cols as usize,
+            size.pixel_width as usize,
+            size.pixel_height as usize,
+            Some(anchor),
+        ))
+    }
+
     fn writer(&self) -> RefMut<dyn std::io::Write> {
         Mux::get().unwrap().record_input_for_current_identity();
         self.pty.borrow_mut()
     }
 
+    fn try_write(&self, data: &[u8]) -> std::io::Result<usize> {
+        Mux::get().unwrap().record_input_for_current_identity();
+        self.pty.borrow_mut().try_write(data)
+    }
+
     fn reader(&self) -> anyhow::Result<Option<Box<dyn std::io::Read + Send>>> {
         Ok(Some(self.pty.borrow_mut().try_clone_reader()?))
     }
@@ -290,6 +330,31 @@ impl Pane for LocalPane {
         }
     }
 
+    fn begin_paste(&self) -> Result<bool, Error> {
+        Mux::get().unwrap().record_input_for_current_identity();
+        if self.tmux_domain.borrow().is_some() {
+            Ok(false)
+        } else {
+            self.terminal.borrow_mut().begin_paste()
+        }
+    }
+
+    fn send_paste_part(&self, text: &str, bracketed: bool) -> Result<(), Error> {
+        if self.tmux_domain.borrow().is_some() {
+            Ok(())
+        } else {
+            self.terminal.borrow_mut().send_paste_part(text, bracketed)
+        }
+    }
+
+    fn end_paste(&self, bracketed: bool) -> Result<(), Error> {
+        if self.tmux_domain.borrow().is_some() {
+            Ok(())
+        } else {
+            self.terminal.borrow_mut().end_paste(bracketed)
+        }
+    }
+
     fn get_title(&self) -> String {
         let title = self.terminal.borrow_mut().get_title().to_string();
         // If the title is the default pane title, then try to spice
@@ -325,6 +390,12 @@ impl Pane for LocalPane {
         }
     }
 
+    fn trim_scrollback(&self, max_scrollback_rows: usize) {
+        self.terminal
+            .borrow_mut()
+            .trim_scrollback(max_scrollback_rows);
+    }
+
     fn focus_changed(&self, focused: bool) {
         self.terminal.borrow_mut().focus_changed(focused);
     }
@@ -341,6 +412,10 @@ impl Pane for LocalPane {
         }
     }
 
+    fn is_bracketed_paste_enabled(&self) -> bool {
+        self.terminal.borrow().bracketed_paste_enabled()
+    }
+
     fn is_alt_screen_active(&self) -> bool {
         if self.tmux_domain.borrow().is_some() {
             false
@@ -349,6 +424,27 @@ impl Pane for LocalPane {
         }
     }
 
+    fn get_terminal_modes(&self) -> TerminalModes {
+        let mut modes = self.terminal.borrow().terminal_modes();
+        modes.set(TerminalModes::ALT_SCREEN, self.is_alt_screen_active());
+        modes
+    }
+
+    fn get_pen(&self) -> Option<CellAttributes> {
+        Some(self.terminal.borrow().pen())
+    }
+
+    fn get_title_bytes(&self) -> Option<Vec<u8>> {
+        self.terminal.borrow().get_title_bytes().map(<[u8]>::to_vec)
+    }
+
+    fn get_current_working_dir_bytes(&self) -> Option<Vec<u8>> {
+        self.terminal
+            .borrow()
+            .get_current_dir_bytes()
+            .map(<[u8]>::to_vec)
+    }
+
     fn get_current_working_dir(&self) -> Option<Url> {
         self.terminal
             .borrow()
@@ -357,6 +453,18 @@ impl Pane for LocalPane {
             .or_else(|| self.divine_current_working_dir())
     }
 
+    fn get_progress(&self) -> Progress {
+        self.terminal.borrow().get_progress()
+    }
+
+    fn get_exit_behavior(&self) -> Option<ExitBehavior> {
+        *self.exit_behavior.borrow()
+    }
+
+    fn set_exit_behavior(&self, behavior: ExitBehavior) {
+        self.exit_behavior.borrow_mut().replace(behavior);
+    }
+
     fn get_foreground_process_name(&self) -> Option<String> {
         #[cfg(unix)]
         if let Some(pid) = self.pty.borrow().process_group_leader() {
@@ -373,6 +481,20 @@ impl Pane for LocalPane {
         None
     }
 
+    fn get_foreground_process_environment(&self) -> Option<Vec<(String, String)>> {
+        #[cfg(unix)]
+        if let Some(pid) = self.pty.borrow().process_group_leader() {
+            return LocalProcessInfo::environment(pid as u32);
+        }
+
+        #[cfg(windows)]
+        if let Some(fg) = self.divine_foreground_process() {
+            return LocalProcessInfo::environment(fg.pid);
+        }
+
+        None
+    }
+
     fn can_close_without_prompting(&self, _reason: CloseReason) -> bool {
         if let Some(info) = self.divine_process_list(true) {
             log::trace!(
@@ -447,163 +569,186 @@ impl Pane for LocalPane {
         term.get_semantic_zones()
     }
 
-    async fn search(&self, mut pattern: Pattern) -> anyhow::Result<Vec<SearchResult>> {
+    async fn search(&self, pattern: Pattern) -> anyhow::Result<Vec<SearchResult>> {
         let term = self.terminal.borrow();
         let screen = term.screen();
+        let lines = screen
+            .lines
+            .iter()
+            .enumerate()
+            .map(|(idx, line)| (screen.phys_to_stable_row_index(idx), line));
+        Ok(search_lines(lines, pattern))
+    }
+}
 
-        if let Pattern::CaseInSensitiveString(s) = &mut pattern {
-            // normalize the case so we match everything lowercase
-            *s = s.to_lowercase()
-        }
-
-        let mut results = vec![];
-        let mut haystack = String::new();
-        let mut coords = vec![];
-        let mut uniq_matches: HashMap<String, usize> = HashMap::new();
-
-        #[derive(Copy, Clone)]
-        struct Coord {
-            byte_idx: usize,
-            grapheme_idx: usize,
-            stable_row: StableRowIndex,
-        }
-
-        fn haystack_idx_to_coord(idx: usize, coords: &[Coord]) -> (usize, StableRowIndex) {
-            let c = coords
-                .binary_search_by(|ele| ele.byte_idx.cmp(&idx))
-                .or_else(|i| -> Result<usize, usize> { Ok(i) })
-                .unwrap();
-            let coord = coords.get(c).or_else(|| coords.last()).unwrap();
-            (coord.grapheme_idx, coord.stable_row)
-        }
-
-        fn collect_matches(
-            results: &mut Vec<SearchResult>,
-            pattern: &Pattern,
-            haystack: &str,
-            coords: &[Coord],
-            uniq_matches: &mut HashMap<String, usize>,
-        ) {
-            if haystack.is_empty() {
-                return;
-            }
-            match pattern {
-                // Rust only provides a case sensitive match_indices function, so
-                // we have to pre-arrange to lowercase both the pattern and the
-                // haystack strings
-                Pattern::CaseInSensitiveString(s) | Pattern::CaseSensitiveString(s) => {
-                    for (idx, s) in haystack.match_indices(s) {
-                        let match_id = match uniq_matches.get(s).copied() {
-                            Some(id) => id,
-                            None => {
-                                let id = uniq_matches.len();
-                                uniq_matches.insert(s.to_owned(), id);
-                                id
-                            }
-                        };
-                        let (start_x, start_y) = haystack_idx_to_coord(idx, coords);
-                        let (end_x, end_y) = haystack_idx_to_coord(idx + s.len(), coords);
-                        results.push(SearchResult {
-                            start_x,
-                            start_y,
-                            end_x,
-                            end_y,
-                            match_id,
-                        });
-                    }
+/// Search `lines`, which are in order, for `pattern`.  The columns
+/// of each result are cell indices, so that a match that follows a
+/// wide character starts two cells after it, and the end of a match
+/// is the cell just past its last character, including the second
+/// cell of a wide character.
+fn search_lines<'a>(
+    lines: impl Iterator<Item = (StableRowIndex, &'a Line)>,
+    mut pattern: Pattern,
+) -> Vec<SearchResult> {
+    if let Pattern::CaseInSensitiveString(s) = &mut pattern {
+        // normalize the case so we match everything lowercase
+        *s = s.to_lowercase()
+    }
+
+    let mut results = vec![];
+    let mut haystack = String::new();
+    let mut coords = vec![];
+    let mut uniq_matches: HashMap<String, usize> = HashMap::new();
+
+    #[derive(Copy, Clone)]
+    struct Coord {
+        byte_idx: usize,
+        grapheme_idx: usize,
+        stable_row: StableRowIndex,
+    }
+
+    fn haystack_idx_to_coord(idx: usize, coords: &[Coord]) -> (usize, StableRowIndex) {
+        let c = coords
+            .binary_search_by(|ele| ele.byte_idx.cmp(&idx))
+            .or_else(|i| -> Result<usize, usize> { Ok(i) })
+            .unwrap();
+        let coord = coords.get(c).or_else(|| coords.last()).unwrap();
+        (coord.grapheme_idx, coord.stable_row)
+    }
+
+    fn collect_matches(
+        results: &mut Vec<SearchResult>,
+        pattern: &Pattern,
+        haystack: &str,
+        coords: &[Coord],
+        uniq_matches: &mut HashMap<String, usize>,
+    ) {
+        if haystack.is_empty() {
+            return;
+        }
+        match pattern {
+            // Rust only provides a case sensitive match_indices function, so
+            // we have to pre-arrange to lowercase both the pattern and the
+            // haystack strings
+            Pattern::CaseInSensitiveString(s) | Pattern::CaseSensitiveString(s) => {
+                for (idx, s) in haystack.match_indices(s) {
+                    let match_id = match uniq_matches.get(s).copied() {
+                        Some(id) => id,
+                        None => {
+                            let id = uniq_matches.len();
+                            uniq_matches.insert(s.to_owned(), id);
+                            id
+                        }
+                    };
+                    let (start_x, start_y) = haystack_idx_to_coord(idx, coords);
+                    let (end_x, end_y) = haystack_idx_to_coord(idx + s.len(), coords);
+                    results.push(SearchResult {
+                        start_x,
+                        start_y,
+                        end_x,
+                        end_y,
+                        match_id,
+                    });
                 }
-                Pattern::Regex(r) => {
-                    if let Ok(re) = regex::Regex::new(r) {
-                        // Allow for the regex to contain captures
-                        for c in re.captures_iter(haystack) {
-                            // Look for the captures in reverse order, as index==0 is
-                            // the whole matched string.  We can't just call
-                            // `c.iter().rev()` as the capture iterator isn't double-ended.
-                            for idx in (0..c.len()).rev() {
-                                if let Some(m) = c.get(idx) {
-                                    let s = m.as_str();
-                                    let match_id = match uniq_matches.get(s).copied() {
-                                        Some(id) => id,
-                                        None => {
-                                            let id = uniq_matches.len();
-                                            uniq_matches.insert(s.to_owned(), id);
-                                            id
-                                        }
-                                    };
-
-                                    let (start_x, start_y) =
-                                        haystack_idx_to_coord(m.start(), coords);
-                                    let (end_x, end_y) = haystack_idx_to_coord(m.end(), coords);
-                                    results.push(SearchResult {
-                                        start_x,
-                                        start_y,
-                                        end_x,
-                                        end_y,
-                                        match_id,
-                                    });
-                                    break;
-                                }
+            }
+            Pattern::Regex(r) => {
+                if let Ok(re) = regex::Regex::new(r) {
+                    // Allow for the regex to contain captures
+                    for c in re.captures_iter(haystack) {
+                        // Look for the captures in reverse order, as index==0 is
+                        // the whole matched string.  We can't just call
+                        // `c.iter().rev()` as the capture iterator isn't double-ended.
+                        for idx in (0..c.len()).rev() {
+                            if let Some(m) = c.get(idx) {
+                                let s = m.as_str();
+                                let match_id = match uniq_matches.get(s).copied() {
+                                    Some(id) => id,
+                                    None => {
+                                        let id = uniq_matches.len();
+                                        uniq_matches.insert(s.to_owned(), id);
+                                        id
+                                    }
+                                };
+
+                                let (start_x, start_y) = haystack_idx_to_coord(m.start(), coords);
+                                let (end_x, end_y) = haystack_idx_to_coord(m.end(), coords);
+                                results.push(SearchResult {
+                                    start_x,
+                                    start_y,
+                                    end_x,
+                                    end_y,
+                                    match_id,
+                                });
+                                break;
                             }
                         }
                     }
                 }
             }
         }
+    }
 
-        for (idx, line) in screen.lines.iter().enumerate() {
-            let stable_row = screen.phys_to_stable_row_index(idx);
-
-            let mut wrapped = false;
-            for (grapheme_idx, cell) in line.visible_cells() {
-                coords.push(Coord {
-                    byte_idx: haystack.len(),
-                    grapheme_idx,
-                    stable_row,
-                });
+    // The cell just past the end of the text of the most recent line;
+    // this is past both cells of a wide character at the end of it
+    let mut line_end = None;
+    for (stable_row, line) in lines {
+        let mut wrapped = false;
+        for (grapheme_idx, cell) in line.visible_cells() {
+            coords.push(Coord {
+                byte_idx: haystack.len(),
+                grapheme_idx,
+                stable_row,
+            });
 
-                let s = cell.str();
-                if let Pattern::CaseInSensitiveString(_) = &pattern {
-                    // normalize the case so we match everything lowercase
-                    haystack.push_str(&s.to_lowercase());
-                } else {
-                    haystack.push_str(cell.str());
-                }
-                wrapped = cell.attrs().wrapped();
+            let s = cell.str();
+            if let Pattern::CaseInSensitiveString(_) = &pattern {
+                // normalize the case so we match everything lowercase
+                haystack.push_str(&s.to_lowercase());
+            } else {
+                haystack.push_str(cell.str());
             }
+            wrapped = cell.attrs().wrapped();
+            line_end.replace(Coord {
+                byte_idx: haystack.len(),
+                grapheme_idx: grapheme_idx + cell.width().max(1),
+                stable_row,
+            });
+        }
 
-            if !wrapped {
-                if let Pattern::Regex(_) = &pattern {
-                    if let Some(coord) = coords.last().copied() {
-                        coords.push(Coord {
-                            byte_idx: haystack.len(),
-                            grapheme_idx: coord.grapheme_idx + 1,
-                            ..coord
-                        });
-                        haystack.push('\n');
-                    }
-                } else {
-                    collect_matches(
-                        &mut results,
-                        &pattern,
-                        &haystack,
-                        &coords,
-                        &mut uniq_matches,
-                    );
-                    haystack.clear();
-                    coords.clear();
+        if !wrapped {
+            if let Pattern::Regex(_) = &pattern {
+                if let Some(end) = line_end.take() {
+                    coords.push(end);
+                    haystack.push('\n');
                 }
+            } else {
+                if let Some(end) = line_end.take() {
+                    coords.push(end);
+                }
+                collect_matches(
+                    &mut results,
+                    &pattern,
+                    &haystack,
+                    &coords,
+                    &mut uniq_matches,
+                );
+                haystack.clear();
+                coords.clear();
             }
         }
+    }
 
-        collect_matches(
-            &mut results,
-            &pattern,
-            &haystack,
-            &coords,
-            &mut uniq_matches,
-        );
-        Ok(results)
+    if let Some(end) = line_end.take() {
+        coords.push(end);
     }
+    collect_matches(
+        &mut results,
+        &pattern,
+        &haystack,
+        &coords,
+        &mut uniq_matches,
+    );
+    results
 }
 
 struct LocalPaneDCSHandler {
@@ -753,6 +898,7 @@ impl LocalPane {
             domain_id,
             tmux_domain: RefCell::new(None),
             proc_list: RefCell::new(None),
+            exit_behavior: RefCell::new(None),
         }
     }
 
@@ -844,3 +990,41 @@ impl Drop for LocalPane {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use termwiz::cell::CellAttributes;
+
+    fn search(text: &str, pattern: Pattern) -> Vec<(usize, usize)> {
+        let line = Line::from_text(text, &CellAttributes::default(), SEQ_ZERO);
+        search_lines(std::iter::once((0, &line)), pattern)
+            .into_iter()
+            .map(|result| (result.start_x, result.end_x))
+            .collect()
+    }
+
+    #[test]
+    fn search_offsets_account_for_wide_characters() {
+        // Each of the CJK characters occupies two cells
+        let text = "中文 abc 中文";
+        assert_eq!(
+            search(text, Pattern::CaseSensitiveString("abc".to_string())),
+            vec![(5, 8)]
+        );
+        // A match at the end of the line ends after the second
+        // cell of its final character
+        assert_eq!(
+            search(text, Pattern::CaseSensitiveString("文".to_string())),
+            vec![(2, 4), (11, 13)]
+        );
+        assert_eq!(
+            search(text, Pattern::Regex("c 中+".to_string())),
+            vec![(7, 11)]
+        );
+        assert_eq!(
+            search(text, Pattern::Regex("(?m)中文$".to_string())),
+            vec![(9, 13)]
+        );
+    }
+}
@@ -3,25 +3,29 @@ use crate::renderable::*;
 use crate::Mux;
 use async_trait::async_trait;
 use config::keyassignment::ScrollbackEraseMode;
+use config::ExitBehavior;
 use downcast_rs::{impl_downcast, Downcast};
-use portable_pty::PtySize;
+use portable_pty::{ExitStatus, PtySize};
 use rangeset::RangeSet;
 use serde::{Deserialize, Serialize};
 use std::cell::RefMut;
 use std::collections::HashMap;
+use std::io::Write;
 use std::ops::Range;
 use std::sync::{Arc, Mutex};
+use termwiz::escape::osc::Progress;
 use termwiz::hyperlink::Rule;
 use termwiz::input::KeyboardEncoding;
 use termwiz::surface::{Line, SequenceNo, SEQ_ZERO};
 use url::Url;
 use wezterm_term::color::ColorPalette;
 use wezterm_term::{
-    Clipboard, DownloadHandler, KeyCode, KeyModifiers, MouseEvent, SemanticZone, StableRowIndex,
-    TerminalConfiguration,
+    CellAttributes, Clipboard, DownloadHandler, KeyCode, KeyModifiers, MouseEvent, SemanticZone,
+    StableRowIndex, TerminalConfiguration, TerminalModes,
 };
 
 static PANE_ID: ::std::sync::atomic::AtomicUsize = ::std::sync::atomic::AtomicUsize::new(0);
+/// Like `TabId`, pane ids are never reused while the process is running
 pub type PaneId = usize;
 
 pub fn alloc_pane_id() -> PaneId {
@@ -34,7 +38,9 @@ pub struct SearchResult {
     /// The cell index into the line of the start of the match
     pub start_x: usize,
     pub end_y: StableRowIndex,
-    /// The cell index into the line of the end of the match
+    /// The cell index into the line just past the end of the match.
+    /// A wide character at the end of the match occupies two cells,
+    /// both of which are included.
     pub end_x: usize,
     /// An identifier that can be used to group results that have
     /// the same textual content
@@ -315,10 +321,48 @@ pub trait Pane: Downcast {
     fn get_dimensions(&self) -> RenderableDimensions;
 
     fn get_title(&self) -> String;
+    /// Returns the bytes of the title as they were sent by the
+    /// application, if they weren't valid UTF-8, in which case
+    /// `get_title` returns a lossy decoding of them
+    fn get_title_bytes(&self) -> Option<Vec<u8>> {
+        None
+    }
     fn send_paste(&self, text: &str) -> anyhow::Result<()>;
+    /// Begin a paste whose text is sent in several parts using
+    /// `send_paste_part`, and which is finished by `end_paste`.
+    /// Returns true if the paste is bracketed.
+    /// Panes that don't support this send each part as a paste
+    /// of its own.
+    fn begin_paste(&self) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+    fn send_paste_part(&self, text: &str, _bracketed: bool) -> anyhow::Result<()> {
+        self.send_paste(text)
+    }
+    fn end_paste(&self, _bracketed: bool) -> anyhow::Result<()> {
+        Ok(())
+    }
     fn reader(&self) -> anyhow::Result<Option<Box<dyn std::io::Read + Send>>>;
     fn writer(&self) -> RefMut<dyn std::io::Write>;
+    /// Write as much of `data` as the pane can accept without blocking,
+    /// returning the number of bytes that were written.
+    /// Fails with `ErrorKind::WouldBlock` if no data could be written.
+    fn try_write(&self, data: &[u8]) -> std::io::Result<usize> {
+        self.writer().write_all(data)?;
+        Ok(data.len())
+    }
     fn resize(&self, size: PtySize) -> anyhow::Result<()>;
+    /// Resize the pane, returning the row that holds the start of the
+    /// line that was at `anchor` before its lines were rewrapped to the
+    /// new size, or None if that isn't known
+    fn resize_with_anchor(
+        &self,
+        size: PtySize,
+        _anchor: StableRowIndex,
+    ) -> anyhow::Result<Option<StableRowIndex>> {
+        self.resize(size)?;
+        Ok(None)
+    }
     /// Called as a hint that the pane is being resized as part of
     /// a zoom-to-fill-all-the-tab-space operation.
     fn set_zoomed(&self, _zoomed: bool) {}
@@ -327,6 +371,12 @@ pub trait Pane: Downcast {
     fn mouse_event(&self, event: MouseEvent) -> anyhow::Result<()>;
     fn perform_actions(&self, _actions: Vec<termwiz::escape::Action>) {}
     fn is_dead(&self) -> bool;
+    /// If the process has exited but the pane is being held open,
+    /// returns its exit status.  Returns None while the process is
+    /// still running.
+    fn exit_status(&self) -> Option<ExitStatus> {
+        None
+    }
     fn kill(&self) {}
     fn palette(&self) -> ColorPalette;
     fn domain_id(&self) -> DomainId;
@@ -341,6 +391,10 @@ pub trait Pane: Downcast {
 
     fn erase_scrollback(&self, _erase_mode: ScrollbackEraseMode) {}
 
+    /// Discard the oldest lines of the scrollback so that at most
+    /// `max_scrollback_rows` lines remain above the viewport
+    fn trim_scrollback(&self, _max_scrollback_rows: usize) {}
+
     /// Called to advise on whether this tab has focus
     fn focus_changed(&self, _focused: bool) {}
     fn has_unseen_output(&self) -> bool {
@@ -371,6 +425,39 @@ pub trait Pane: Downcast {
     fn is_mouse_grabbed(&self) -> bool;
     fn is_alt_screen_active(&self) -> bool;
 
+    /// Returns true if the application running in the terminal has
+    /// enabled bracketed paste mode.  Pasted text is then delimited
+    /// so that the application can tell it apart from typed input.
+    fn is_bracketed_paste_enabled(&self) -> bool {
+        false
+    }
+
+    /// Returns the set of modes that the application running in
+    /// the terminal has enabled
+    fn get_terminal_modes(&self) -> TerminalModes {
+        let mut modes = TerminalModes::default();
+        modes.set(TerminalModes::ALT_SCREEN, self.is_alt_screen_active());
+        modes.set(
+            TerminalModes::BRACKETED_PASTE,
+            self.is_bracketed_paste_enabled(),
+        );
+        modes
+    }
+
+    /// Returns the SGR attributes with which the application's output
+    /// is drawn at the cursor, or None if they aren't known
+    fn get_pen(&self) -> Option<CellAttributes> {
+        None
+    }
+
+    /// Returns the exit_behavior that overrides the configured
+    /// value for this pane, if any
+    fn get_exit_behavior(&self) -> Option<ExitBehavior> {
+        None
+    }
+    /// Override the configured exit_behavior for this pane
+    fn set_exit_behavior(&self, _behavior: ExitBehavior) {}
+
     fn set_clipboard(&self, _clipboard: &Arc<dyn Clipboard>) {}
     fn set_download_handler(&self, _handler: &Arc<dyn DownloadHandler>) {}
     fn set_config(&self, _config: Arc<dyn TerminalConfiguration>) {}
@@ -379,9 +466,24 @@ pub trait Pane: Downcast {
     }
 
     fn get_current_working_dir(&self) -> Option<Url>;
+    /// Returns the bytes of the working directory as they were
+    /// sent by the application, if they weren't valid UTF-8
+    fn get_current_working_dir_bytes(&self) -> Option<Vec<u8>> {
+        None
+    }
+    /// Returns the progress of a long running task, as reported
+    /// by the application running in the pane
+    fn get_progress(&self) -> Progress {
+        Progress::default()
+    }
     fn get_foreground_process_name(&self) -> Option<String> {
         None
     }
+    /// Returns the environment of the foreground process, if the
+    /// system allows it to be read
+    fn get_foreground_process_environment(&self) -> Option<Vec<(String, String)>> {
+        None
+    }
 
     fn trickle_paste(&self, text: String) -> anyhow::Result<()> {
         if text.len() <= PASTE_CHUNK_SIZE {
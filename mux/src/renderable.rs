@@ -106,3 +106,71 @@ pub fn terminal_get_dimensions(term: &mut Terminal) -> RenderableDimensions {
         scrollback_top: screen.phys_to_stable_row_index(0),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use wezterm_term::color::ColorPalette;
+    use wezterm_term::{TerminalConfiguration, TerminalSize};
+
+    #[derive(Debug)]
+    struct TestTermConfig {
+        scrollback: usize,
+    }
+
+    impl TerminalConfiguration for TestTermConfig {
+        fn scrollback_size(&self) -> usize {
+            self.scrollback
+        }
+
+        fn color_palette(&self) -> ColorPalette {
+            ColorPalette::default()
+        }
+    }
+
+    #[test]
+    fn dimensions_track_scrollback_trimming() {
+        let mut term = Terminal::new(
+            TerminalSize {
+                physical_rows: 4,
+                physical_cols: 10,
+                pixel_width: 80,
+                pixel_height: 64,
+            },
+            Arc::new(TestTermConfig { scrollback: 10 }),
+            "WezTerm",
+            "O_o",
+            Box::new(Vec::new()),
+        );
+
+        let initial = terminal_get_dimensions(&mut term);
+        assert_eq!(initial.scrollback_rows, 4);
+        assert_eq!(initial.physical_top, 0);
+        assert_eq!(initial.scrollback_top, 0);
+
+        for i in 0..6 {
+            term.advance_bytes(format!("line {}\r\n", i));
+        }
+        let grown = terminal_get_dimensions(&mut term);
+        assert_ne!(grown, initial);
+        assert_eq!(grown.scrollback_rows, 7);
+        assert_eq!(grown.physical_top, 3);
+        assert_eq!(grown.scrollback_top, 0);
+
+        // Write enough to exceed the scrollback size, so that the
+        // oldest lines are trimmed away
+        for i in 0..20 {
+            term.advance_bytes(format!("line {}\r\n", i));
+        }
+        let trimmed = terminal_get_dimensions(&mut term);
+        assert_ne!(trimmed, grown);
+        assert_eq!(trimmed.scrollback_rows, 14);
+        assert_eq!(trimmed.physical_top, 23);
+        assert_eq!(trimmed.scrollback_top, 13);
+        assert_eq!(
+            trimmed.physical_top + trimmed.viewport_rows as StableRowIndex - trimmed.scrollback_top,
+            trimmed.scrollback_rows as StableRowIndex
+        );
+    }
+}
@@ -1,6 +1,6 @@
 use crate::domain::DomainId;
 use crate::pane::*;
-use crate::{Mux, WindowId};
+use crate::{Mux, MuxNotification, WindowId};
 use bintree::PathBranch;
 use config::configuration;
 use config::keyassignment::PaneDirection;
@@ -8,6 +8,7 @@ use portable_pty::PtySize;
 use rangeset::range_intersection;
 use serde::{Deserialize, Serialize};
 use std::cell::{RefCell, RefMut};
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::rc::Rc;
 use url::Url;
@@ -16,6 +17,9 @@ pub type Tree = bintree::Tree<Rc<dyn Pane>, SplitDirectionAndSize>;
 pub type Cursor = bintree::Cursor<Rc<dyn Pane>, SplitDirectionAndSize>;
 
 static TAB_ID: ::std::sync::atomic::AtomicUsize = ::std::sync::atomic::AtomicUsize::new(0);
+/// Tab ids are allocated from a counter and are never reused while
+/// the process is running, so state that is keyed by the id of a tab
+/// that has been killed can't be mistaken for that of a later tab
 pub type TabId = usize;
 
 /// A Tab is a container of Panes
@@ -25,6 +29,9 @@ pub struct Tab {
     size: RefCell<PtySize>,
     active: RefCell<usize>,
     zoomed: RefCell<Option<Rc<dyn Pane>>>,
+    /// The titles that are reported for the panes in this tab while
+    /// programs are not allowed to change them, or None if they are
+    pinned_titles: RefCell<Option<HashMap<PaneId, String>>>,
 }
 
 #[derive(Clone)]
@@ -79,7 +86,22 @@ pub struct SplitDirectionAndSize {
     pub second: PtySize,
 }
 
+/// Splits that would produce a pane with fewer rows than this are
+/// rejected by `SplitDirectionAndSize::is_usable`
+pub const MIN_SPLIT_PANE_ROWS: u16 = 2;
+/// Splits that would produce a pane with fewer columns than this are
+/// rejected by `SplitDirectionAndSize::is_usable`
+pub const MIN_SPLIT_PANE_COLS: u16 = 8;
+
 impl SplitDirectionAndSize {
+    /// Returns true if both sides of the split are large enough
+    /// to be useful
+    pub fn is_usable(&self) -> bool {
+        [&self.first, &self.second]
+            .iter()
+            .all(|size| size.rows >= MIN_SPLIT_PANE_ROWS && size.cols >= MIN_SPLIT_PANE_COLS)
+    }
+
     fn top_of_second(&self) -> usize {
         match self.direction {
             SplitDirection::Horizontal => 0,
@@ -152,7 +174,7 @@ fn is_pane(pane: &Rc<dyn Pane>, other: &Option<&Rc<dyn Pane>>) -> bool {
 
 fn pane_tree(
     tree: &Tree,
-    tab_id: TabId,
+    tab: &Tab,
     window_id: WindowId,
     active: Option<&Rc<dyn Pane>>,
     zoomed: Option<&Rc<dyn Pane>>,
@@ -161,11 +183,9 @@ fn pane_tree(
     match tree {
         Tree::Empty => PaneNode::Empty,
         Tree::Node { left, right, data } => PaneNode::Split {
-            left: Box::new(pane_tree(
-                &*left, tab_id, window_id, active, zoomed, workspace,
-            )),
+            left: Box::new(pane_tree(&*left, tab, window_id, active, zoomed, workspace)),
             right: Box::new(pane_tree(
-                &*right, tab_id, window_id, active, zoomed, workspace,
+                &*right, tab, window_id, active, zoomed, workspace,
             )),
             node: data.unwrap(),
         },
@@ -175,9 +195,9 @@ fn pane_tree(
 
             PaneNode::Leaf(PaneEntry {
                 window_id,
-                tab_id,
+                tab_id: tab.tab_id(),
                 pane_id: pane.pane_id(),
-                title: pane.get_title(),
+                title: tab.pane_title(pane),
                 is_active_pane: is_pane(pane, &active),
                 is_zoomed_pane: is_pane(pane, &zoomed),
                 size: PtySize {
@@ -188,11 +208,53 @@ fn pane_tree(
                 },
                 working_dir: working_dir.map(Into::into),
                 workspace: workspace.to_string(),
+                exit_status: pane.exit_status().map(|status| status.exit_code() as i32),
             })
         }
     }
 }
 
+fn pane_layout(
+    tree: &Tree,
+    left: usize,
+    top: usize,
+    size: PtySize,
+    active: Option<&Rc<dyn Pane>>,
+    zoomed: Option<&Rc<dyn Pane>>,
+) -> PaneLayout {
+    match tree {
+        Tree::Empty => PaneLayout::Empty,
+        Tree::Node {
+            left: first,
+            right: second,
+            data,
+        } => {
+            let node = data.unwrap();
+            PaneLayout::Split {
+                first: Box::new(pane_layout(&*first, left, top, node.first, active, zoomed)),
+                second: Box::new(pane_layout(
+                    &*second,
+                    left + node.left_of_second(),
+                    top + node.top_of_second(),
+                    node.second,
+                    active,
+                    zoomed,
+                )),
+                node,
+            }
+        }
+        Tree::Leaf(pane) => PaneLayout::Pane(PaneGeometry {
+            pane_id: pane.pane_id(),
+            left,
+            top,
+            cols: size.cols as usize,
+            rows: size.rows as usize,
+            is_active_pane: is_pane(pane, &active),
+            is_zoomed_pane: is_pane(pane, &zoomed),
+        }),
+    }
+}
+
 fn build_from_pane_tree<F>(
     tree: bintree::Tree<PaneEntry, SplitDirectionAndSize>,
     active: &mut Option<Rc<dyn Pane>>,
@@ -398,6 +460,36 @@ fn apply_sizes_from_splits(tree: &Tree, size: &PtySize) {
     }
 }
 
+/// Returns `size` with its pixel dimensions recomputed from the
+/// pixel dimensions of a cell
+fn with_cell_dimensions(size: &PtySize, cell_dimensions: &PtySize) -> PtySize {
+    PtySize {
+        pixel_width: size.cols.saturating_mul(cell_dimensions.pixel_width),
+        pixel_height: size.rows.saturating_mul(cell_dimensions.pixel_height),
+        ..*size
+    }
+}
+
+fn apply_cell_dimensions(tree: &mut Tree, size: &PtySize, cell_dimensions: &PtySize) {
+    match tree {
+        Tree::Empty => return,
+        Tree::Node { data: None, .. } => return,
+        Tree::Node {
+            left,
+            right,
+            data: Some(data),
+        } => {
+            data.first = with_cell_dimensions(&data.first, cell_dimensions);
+            data.second = with_cell_dimensions(&data.second, cell_dimensions);
+            apply_cell_dimensions(&mut *left, &data.first, cell_dimensions);
+            apply_cell_dimensions(&mut *right, &data.second, cell_dimensions);
+        }
+        Tree::Leaf(pane) => {
+            pane.resize(*size).ok();
+        }
+    }
+}
+
 fn cell_dimensions(size: &PtySize) -> PtySize {
     PtySize {
         rows: 1,
@@ -415,6 +507,40 @@ impl Tab {
             size: RefCell::new(*size),
             active: RefCell::new(0),
             zoomed: RefCell::new(None),
+            pinned_titles: RefCell::new(None),
+        }
+    }
+
+    /// Control whether the programs running in the panes of this tab
+    /// may change the titles that are reported for them.  Disallowing
+    /// it pins the title of each pane to its current value; panes that
+    /// are added later are pinned when their title is first reported.
+    pub fn set_allow_process_title_override(&self, allow: bool) {
+        let mut pinned = self.pinned_titles.borrow_mut();
+        if allow {
+            pinned.take();
+        } else if pinned.is_none() {
+            pinned.replace(
+                self.iter_panes_ignoring_zoom()
+                    .into_iter()
+                    .map(|pos| (pos.pane.pane_id(), pos.pane.get_title()))
+                    .collect(),
+            );
+        }
+    }
+
+    pub fn allows_process_title_override(&self) -> bool {
+        self.pinned_titles.borrow().is_none()
+    }
+
+    /// Returns the title to report for `pane`, which is in this tab
+    pub fn pane_title(&self, pane: &Rc<dyn Pane>) -> String {
+        match self.pinned_titles.borrow_mut().as_mut() {
+            Some(pinned) => pinned
+                .entry(pane.pane_id())
+                .or_insert_with(|| pane.get_title())
+                .clone(),
+            None => pane.get_title(),
         }
     }
 
@@ -506,7 +632,7 @@ impl Tab {
         if let Some(root) = self.pane.borrow().as_ref() {
             pane_tree(
                 root,
-                tab_id,
+                self,
                 window_id,
                 active.as_ref(),
                 zoomed.as_ref(),
@@ -517,6 +643,27 @@ impl Tab {
         }
     }
 
+    /// Returns the geometry of the splits and panes in this tab.
+    /// The positions and sizes are those of the split layout even
+    /// while a pane is zoomed.
+    pub fn pane_layout(&self) -> PaneLayout {
+        let zoomed = self.zoomed.borrow();
+        let active = self.get_active_pane();
+        let size = *self.size.borrow();
+        match self.pane.borrow().as_ref() {
+            Some(root) => pane_layout(root, 0, 0, size, active.as_ref(), zoomed.as_ref()),
+            None => PaneLayout::Empty,
+        }
+    }
+
+    /// Lets subscribers know that the layout of this tab may
+    /// have changed
+    fn notify_layout_changed(&self) {
+        if let Some(mux) = Mux::get() {
+            mux.notify(MuxNotification::TabResized(self.id));
+        }
+    }
+
     /// Returns a count of how many panes are in this tab
     pub fn count_panes(&self) -> usize {
         let mut count = 0;
@@ -566,6 +713,7 @@ impl Tab {
                 self.zoomed.borrow_mut().replace(pane);
             }
         }
+        self.notify_layout_changed();
     }
 
     pub fn contains_pane(&self, pane: PaneId) -> bool {
@@ -789,6 +937,44 @@ impl Tab {
 
         // And finally restore the zoom, if appropriate
         self.set_zoomed(was_zoomed);
+        self.notify_layout_changed();
+    }
+
+    /// Returns the size of a cell of the panes in this tab, in pixels,
+    /// as a PtySize of a single cell
+    pub fn get_cell_dimensions(&self) -> PtySize {
+        let size = self.get_size();
+        PtySize {
+            rows: 1,
+            cols: 1,
+            pixel_width: size.pixel_width.checked_div(size.cols).unwrap_or(0),
+            pixel_height: size.pixel_height.checked_div(size.rows).unwrap_or(0),
+        }
+    }
+
+    /// Change the size of a cell of the panes in this tab, in pixels,
+    /// keeping their size in cells.  The size of a cell determines the
+    /// number of cells that are covered by an image that is sized in
+    /// pixels, such as a sixel image.
+    pub fn set_cell_dimensions(&self, pixel_width: u16, pixel_height: u16) {
+        let was_zoomed = self.zoomed.borrow().is_some();
+        self.set_zoomed(false);
+
+        {
+            let cell_dimensions = PtySize {
+                rows: 1,
+                cols: 1,
+                pixel_width,
+                pixel_height,
+            };
+            let size = with_cell_dimensions(&self.get_size(), &cell_dimensions);
+            *self.size.borrow_mut() = size;
+            let mut root = self.pane.borrow_mut();
+            apply_cell_dimensions(root.as_mut().unwrap(), &size, &cell_dimensions);
+        }
+
+        self.set_zoomed(was_zoomed);
+        self.notify_layout_changed();
     }
 
     fn apply_pane_size(&self, pane_size: PtySize, cursor: &mut Cursor) {
@@ -865,12 +1051,15 @@ impl Tab {
             }
         }
 
-        let mut root = self.pane.borrow_mut();
-        if let Some(root) = root.as_mut() {
-            if let Some(size) = compute_size(root) {
-                *self.size.borrow_mut() = size;
+        {
+            let mut root = self.pane.borrow_mut();
+            if let Some(root) = root.as_mut() {
+                if let Some(size) = compute_size(root) {
+                    *self.size.borrow_mut() = size;
+                }
             }
         }
+        self.notify_layout_changed();
     }
 
     /// Given split_index, the topological index of a split returned by
@@ -992,6 +1181,8 @@ impl Tab {
                 }
             }
         }
+        drop(root);
+        self.notify_layout_changed();
     }
 
     /// Adjusts the size of the active pane in the specified direction
@@ -1268,6 +1459,7 @@ impl Tab {
         }
 
         if !dead_panes.is_empty() {
+            self.notify_layout_changed();
             promise::spawn::spawn_into_main_thread(async move {
                 let mux = Mux::get().unwrap();
                 for pane_id in dead_panes.into_iter() {
@@ -1380,6 +1572,18 @@ impl Tab {
         &self,
         pane_index: usize,
         direction: SplitDirection,
+    ) -> Option<SplitDirectionAndSize> {
+        self.compute_split_size_percent(pane_index, direction, 50)
+    }
+
+    /// Like `compute_split_size`, but the newly created (second) pane
+    /// is sized to occupy `percent` of the space that is available
+    /// once the split UI has been accounted for.
+    pub fn compute_split_size_percent(
+        &self,
+        pane_index: usize,
+        direction: SplitDirection,
+        percent: u8,
     ) -> Option<SplitDirectionAndSize> {
         let cell_dims = self.cell_dimensions();
 
@@ -1387,18 +1591,17 @@ impl Tab {
         // a bogus split state (https://github.com/wez/wezterm/issues/723)
         self.set_zoomed(false);
 
+        let percent = percent.min(100) as usize;
+
         self.iter_panes().iter().nth(pane_index).map(|pos| {
-            fn split_dimension(dim: usize) -> (usize, usize) {
-                let halved = dim / 2;
-                if halved * 2 == dim {
-                    // Was an even size; we need to allow 1 cell to render
-                    // the split UI, so make the newly created leaf slightly
-                    // smaller
-                    (halved, halved.saturating_sub(1))
-                } else {
-                    (halved, halved)
-                }
-            }
+            let split_dimension = |dim: usize| -> (usize, usize) {
+                // We need to allow 1 cell to render the split UI;
+                // when there is an odd cell left over it goes to
+                // the existing (first) pane
+                let available = dim.saturating_sub(1);
+                let second = available * percent / 100;
+                (available - second, second)
+            };
 
             let ((width1, width2), (height1, height2)) = match direction {
                 SplitDirection::Horizontal => {
@@ -1434,6 +1637,18 @@ impl Tab {
         pane_index: usize,
         direction: SplitDirection,
         pane: Rc<dyn Pane>,
+    ) -> anyhow::Result<usize> {
+        self.split_and_insert_percent(pane_index, direction, 50, pane)
+    }
+
+    /// Like `split_and_insert`, but the inserted pane occupies
+    /// `percent` of the space; see `compute_split_size_percent`.
+    pub fn split_and_insert_percent(
+        &self,
+        pane_index: usize,
+        direction: SplitDirection,
+        percent: u8,
+        pane: Rc<dyn Pane>,
     ) -> anyhow::Result<usize> {
         if self.zoomed.borrow().is_some() {
             anyhow::bail!("cannot split while zoomed");
@@ -1441,7 +1656,7 @@ impl Tab {
 
         {
             let split_info = self
-                .compute_split_size(pane_index, direction)
+                .compute_split_size_percent(pane_index, direction, percent)
                 .ok_or_else(|| {
                     anyhow::anyhow!("invalid pane_index {}; cannot split!", pane_index)
                 })?;
@@ -1500,6 +1715,7 @@ impl Tab {
 
         log::debug!("split info after split: {:#?}", self.iter_splits());
         log::debug!("pane info after split: {:#?}", self.iter_panes());
+        self.notify_layout_changed();
 
         Ok(pane_index + 1)
     }
@@ -1564,6 +1780,39 @@ pub struct PaneEntry {
     pub is_active_pane: bool,
     pub is_zoomed_pane: bool,
     pub workspace: String,
+    /// The exit status of the process in this pane if it has
+    /// exited but the pane is being held open.  None while the
+    /// process is still running.
+    pub exit_status: Option<i32>,
+}
+
+/// The geometry of the panes in a tab, as returned by `Tab::pane_layout`.
+/// This type is used directly by the codec, take care to bump
+/// the codec version if you change this
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone)]
+pub enum PaneLayout {
+    Empty,
+    /// A split whose `first` child is to the left of, or above,
+    /// its `second` child, with the divider between them
+    Split {
+        first: Box<PaneLayout>,
+        second: Box<PaneLayout>,
+        node: SplitDirectionAndSize,
+    },
+    Pane(PaneGeometry),
+}
+
+/// The position and size of a pane, in cells, relative to the
+/// top left of its tab
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub struct PaneGeometry {
+    pub pane_id: PaneId,
+    pub left: usize,
+    pub top: usize,
+    pub cols: usize,
+    pub rows: usize,
+    pub is_active_pane: bool,
+    pub is_zoomed_pane: bool,
 }
 
 #[derive(Deserialize, Clone, Serialize, PartialEq, Debug)]
@@ -1857,4 +2106,157 @@ mod test {
         assert_eq!(390, panes[2].pixel_width);
         assert_eq!(600, panes[2].pixel_height);
     }
+
+    #[test]
+    fn tab_splitting_percent() {
+        let size = PtySize {
+            rows: 24,
+            cols: 80,
+            pixel_width: 800,
+            pixel_height: 600,
+        };
+
+        let tab = Tab::new(&size);
+        tab.assign_pane(&FakePane::new(1, size));
+
+        let horz_size = tab
+            .compute_split_size_percent(0, SplitDirection::Horizontal, 25)
+            .unwrap();
+        assert_eq!(60, horz_size.first.cols);
+        assert_eq!(19, horz_size.second.cols);
+        assert!(horz_size.is_usable());
+
+        let tiny = tab
+            .compute_split_size_percent(0, SplitDirection::Vertical, 1)
+            .unwrap();
+        assert_eq!(0, tiny.second.rows);
+        assert!(!tiny.is_usable());
+
+        let new_index = tab
+            .split_and_insert_percent(
+                0,
+                SplitDirection::Horizontal,
+                25,
+                FakePane::new(2, horz_size.second),
+            )
+            .unwrap();
+        assert_eq!(new_index, 1);
+
+        let panes = tab.iter_panes();
+        assert_eq!(2, panes.len());
+        assert_eq!(60, panes[0].width);
+        assert_eq!(61, panes[1].left);
+        assert_eq!(19, panes[1].width);
+        assert_eq!(190, panes[1].pixel_width);
+    }
+
+    #[test]
+    fn pane_layout_describes_nested_splits() {
+        let size = PtySize {
+            rows: 24,
+            cols: 80,
+            pixel_width: 800,
+            pixel_height: 600,
+        };
+
+        let tab = Tab::new(&size);
+        tab.assign_pane(&FakePane::new(1, size));
+
+        let horz_size = tab
+            .compute_split_size(0, SplitDirection::Horizontal)
+            .unwrap();
+        tab.split_and_insert(
+            0,
+            SplitDirection::Horizontal,
+            FakePane::new(2, horz_size.second),
+        )
+        .unwrap();
+        let vert_size = tab.compute_split_size(1, SplitDirection::Vertical).unwrap();
+        tab.split_and_insert(
+            1,
+            SplitDirection::Vertical,
+            FakePane::new(3, vert_size.second),
+        )
+        .unwrap();
+
+        let geometry = |pane_id, left, top, cols, rows, is_active_pane| {
+            Box::new(PaneLayout::Pane(PaneGeometry {
+                pane_id,
+                left,
+                top,
+                cols,
+                rows,
+                is_active_pane,
+                is_zoomed_pane: false,
+            }))
+        };
+
+        assert_eq!(
+            tab.pane_layout(),
+            PaneLayout::Split {
+                first: geometry(1, 0, 0, 40, 24, false),
+                second: Box::new(PaneLayout::Split {
+                    first: geometry(2, 41, 0, 39, 12, false),
+                    second: geometry(3, 41, 13, 39, 11, true),
+                    node: vert_size,
+                }),
+                node: horz_size,
+            }
+        );
+    }
+
+    #[test]
+    fn cell_dimensions_apply_to_every_pane() {
+        let size = PtySize {
+            rows: 24,
+            cols: 80,
+            pixel_width: 800,
+            pixel_height: 600,
+        };
+        let tab = Tab::new(&size);
+        tab.assign_pane(&FakePane::new(1, size));
+        let horz_size = tab
+            .compute_split_size(0, SplitDirection::Horizontal)
+            .unwrap();
+        tab.split_and_insert(
+            0,
+            SplitDirection::Horizontal,
+            FakePane::new(2, horz_size.second),
+        )
+        .unwrap();
+
+        tab.set_cell_dimensions(20, 50);
+        assert_eq!(tab.get_cell_dimensions().pixel_width, 20);
+        assert_eq!(tab.get_cell_dimensions().pixel_height, 50);
+        assert_eq!(tab.get_size().pixel_width, 1600);
+
+        let panes = tab.iter_panes();
+        assert_eq!(40, panes[0].width);
+        assert_eq!(800, panes[0].pixel_width);
+        assert_eq!(1200, panes[0].pixel_height);
+        assert_eq!(39, panes[1].width);
+        assert_eq!(780, panes[1].pixel_width);
+        assert_eq!(1200, panes[1].pixel_height);
+    }
+
+    #[test]
+    fn tab_ids_are_not_reused() {
+        let size = PtySize {
+            rows: 24,
+            cols: 80,
+            pixel_width: 800,
+            pixel_height: 600,
+        };
+
+        let tab = Tab::new(&size);
+        tab.assign_pane(&FakePane::new(alloc_pane_id(), size));
+        let killed_tab_id = tab.tab_id();
+        let killed_pane_id = tab.get_active_pane().unwrap().pane_id();
+        drop(tab);
+
+        let tab = Tab::new(&size);
+        tab.assign_pane(&FakePane::new(alloc_pane_id(), size));
+        assert_ne!(tab.tab_id(), killed_tab_id);
+        assert_ne!(tab.get_active_pane().unwrap().pane_id(), killed_pane_id);
+    }
 }
@@ -246,6 +246,10 @@ impl Pane for TermWizTerminalPane {
         self.terminal.borrow().is_alt_screen_active()
     }
 
+    fn is_bracketed_paste_enabled(&self) -> bool {
+        self.terminal.borrow().bracketed_paste_enabled()
+    }
+
     fn get_current_working_dir(&self) -> Option<Url> {
         self.terminal.borrow().get_current_dir().cloned()
     }
@@ -14,6 +14,10 @@ pub struct Window {
     last_active: Option<TabId>,
     clipboard: Option<Arc<dyn Clipboard>>,
     workspace: String,
+    title: String,
+    /// Set for a window that was created ahead of its first tab,
+    /// so that it isn't pruned as dead in the meantime
+    awaiting_first_tab: bool,
 }
 
 impl Window {
@@ -29,9 +33,36 @@ impl Window {
                     .expect("Window::new to be called on mux thread")
                     .active_workspace()
             }),
+            title: String::new(),
+            awaiting_first_tab: false,
         }
     }
 
+    /// The title that was explicitly given to the window, if any
+    pub fn get_title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn set_title(&mut self, title: &str) {
+        if title == self.title {
+            return;
+        }
+        self.title = title.to_string();
+        self.invalidate();
+    }
+
+    /// Keep the window, even though it is empty, until a tab is
+    /// added to it
+    pub fn set_awaiting_first_tab(&mut self) {
+        self.awaiting_first_tab = true;
+    }
+
+    /// Returns true if the window is empty because it has yet to
+    /// be given its first tab, rather than because its tabs are gone
+    pub fn is_awaiting_first_tab(&self) -> bool {
+        self.awaiting_first_tab && self.tabs.is_empty()
+    }
+
     pub fn get_workspace(&self) -> &str {
         &self.workspace
     }
@@ -77,6 +108,7 @@ impl Window {
         self.check_that_tab_isnt_already_in_window(tab);
         self.assign_clipboard_to_tab(tab);
         self.tabs.insert(index, Rc::clone(tab));
+        self.awaiting_first_tab = false;
         self.invalidate();
     }
 
@@ -84,6 +116,7 @@ impl Window {
         self.check_that_tab_isnt_already_in_window(tab);
         self.assign_clipboard_to_tab(tab);
         self.tabs.push(Rc::clone(tab));
+        self.awaiting_first_tab = false;
         self.invalidate();
     }
 
@@ -145,10 +178,30 @@ impl Window {
         let active = self.get_active().map(Rc::clone);
         if let Some(idx) = self.idx_by_id(id) {
             self.tabs.remove(idx);
+            self.invalidate();
         }
         self.fixup_active_tab_after_removal(active);
     }
 
+    /// Move the tab identified by `id` to position `index`, which is
+    /// clamped to the positions that exist in the window.  The active
+    /// tab remains active.  Returns the new position of the tab, or
+    /// None if the tab is not in this window.
+    pub fn move_tab(&mut self, id: TabId, index: usize) -> Option<usize> {
+        let idx = self.idx_by_id(id)?;
+        let index = index.min(self.tabs.len() - 1);
+        if idx != index {
+            let active = self.get_active().map(|tab| tab.tab_id());
+            let tab = self.tabs.remove(idx);
+            self.tabs.insert(index, tab);
+            if let Some(active) = active.and_then(|active| self.idx_by_id(active)) {
+                self.active = active;
+            }
+            self.invalidate();
+        }
+        Some(index)
+    }
+
     pub fn get_active(&self) -> Option<&Rc<Tab>> {
         self.get_by_idx(self.active)
     }
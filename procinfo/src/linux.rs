@@ -28,6 +28,25 @@ impl LocalProcessInfo {
         std::fs::read_link(format!("/proc/{}/exe", pid)).ok()
     }
 
+    /// Returns the environment that the process was started with.
+    /// Changes that the process has made to its own environment
+    /// since then are not reflected.
+    pub fn environment(pid: u32) -> Option<Vec<(String, String)>> {
+        let data = std::fs::read(format!("/proc/{}/environ", pid)).ok()?;
+        Some(
+            data.split(|&c| c == 0)
+                .filter(|entry| !entry.is_empty())
+                .map(|entry| {
+                    let entry = String::from_utf8_lossy(entry);
+                    match entry.split_once('=') {
+                        Some((name, value)) => (name.to_string(), value.to_string()),
+                        None => (entry.to_string(), String::new()),
+                    }
+                })
+                .collect(),
+        )
+    }
+
     pub fn with_root_pid(pid: u32) -> Option<Self> {
         use libc::pid_t;
 
@@ -7,6 +7,30 @@ use std::ffi::{OsStr, OsString};
 #[cfg(windows)]
 use std::os::windows::ffi::OsStrExt;
 
+/// Returned when the program that is to be spawned doesn't exist
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandNotFound {
+    pub command: std::path::PathBuf,
+    /// True if the command was relative, and was searched for in the PATH
+    pub searched_path: bool,
+}
+
+impl std::fmt::Display for CommandNotFound {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            fmt,
+            "Unable to spawn {} because it doesn't exist on the filesystem",
+            self.command.display()
+        )?;
+        if self.searched_path {
+            write!(fmt, " and was not found in PATH")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CommandNotFound {}
+
 /// Used to deal with Windows having case-insensitive environment variables.
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
 #[cfg_attr(feature = "serde_support", derive(Serialize, Deserialize))]
@@ -254,34 +278,53 @@ impl CommandBuilder {
                     }
                 }
             }
-            anyhow::bail!(
-                "Unable to spawn {} because it doesn't exist on the filesystem \
-                and was not found in PATH",
-                exe_path.display()
-            );
+            Err(CommandNotFound {
+                command: exe_path.to_path_buf(),
+                searched_path: true,
+            }
+            .into())
         } else {
             if !exe_path.exists() {
-                anyhow::bail!(
-                    "Unable to spawn {} because it doesn't exist on the filesystem",
-                    exe_path.display()
-                );
+                return Err(CommandNotFound {
+                    command: exe_path.to_path_buf(),
+                    searched_path: false,
+                }
+                .into());
             }
 
             Ok(exe.to_owned())
         }
     }
 
+    /// Returns the directory that the command is run in: its cwd,
+    /// if that is a directory, or else the home directory
+    fn resolve_cwd(&self) -> anyhow::Result<OsString> {
+        match self
+            .cwd
+            .as_ref()
+            .filter(|dir| std::path::Path::new(dir).is_dir())
+        {
+            Some(dir) => Ok(dir.clone()),
+            None => Ok(self.get_home_dir()?.into()),
+        }
+    }
+
+    /// Returns the path of the program that would be run, without
+    /// running it.  Fails with `CommandNotFound` if the program
+    /// doesn't exist.  The default program is the shell.
+    pub fn resolve_program(&self) -> anyhow::Result<OsString> {
+        if self.is_default_prog() {
+            return Ok(self.get_shell()?.into());
+        }
+        let dir = self.resolve_cwd()?;
+        self.search_path(&self.args[0], &dir)
+    }
+
     /// Convert the CommandBuilder to a `std::process::Command` instance.
     pub(crate) fn as_command(&self) -> anyhow::Result<std::process::Command> {
         use std::os::unix::process::CommandExt;
 
-        let home = self.get_home_dir()?;
-        let dir: &OsStr = self
-            .cwd
-            .as_ref()
-            .map(|dir| dir.as_os_str())
-            .filter(|dir| std::path::Path::new(dir).is_dir())
-            .unwrap_or(home.as_ref());
+        let dir = self.resolve_cwd()?;
 
         let mut cmd = if self.is_default_prog() {
             let shell = self.get_shell()?;
@@ -294,14 +337,14 @@ impl CommandBuilder {
             cmd.arg0(&format!("-{}", basename));
             cmd
         } else {
-            let resolved = self.search_path(&self.args[0], dir)?;
+            let resolved = self.search_path(&self.args[0], &dir)?;
             let mut cmd = std::process::Command::new(&resolved);
             cmd.arg0(&self.args[0]);
             cmd.args(&self.args[1..]);
             cmd
         };
 
-        cmd.current_dir(dir);
+        cmd.current_dir(&dir);
 
         cmd.env_clear();
         cmd.envs(self.envs.values().map(
@@ -386,6 +429,19 @@ impl CommandBuilder {
         exe.to_owned()
     }
 
+    /// Returns the path of the program that would be run, without
+    /// running it.  A program that isn't found in the PATH is left
+    /// for CreateProcessW to find, so this doesn't fail on Windows.
+    pub fn resolve_program(&self) -> anyhow::Result<OsString> {
+        if self.is_default_prog() {
+            return Ok(self
+                .get_env("ComSpec")
+                .unwrap_or(OsStr::new("cmd.exe"))
+                .into());
+        }
+        Ok(self.search_path(&self.args[0]))
+    }
+
     pub(crate) fn current_directory(&self) -> Option<Vec<u16>> {
         use std::path::Path;
 
@@ -564,6 +620,32 @@ mod tests {
         }
     }
 
+    #[cfg(unix)]
+    #[test]
+    fn test_resolve_program() {
+        let mut cmd = CommandBuilder::new("sh");
+        cmd.env("PATH", "/nonexistent:/bin");
+        assert_eq!(cmd.resolve_program().unwrap(), OsString::from("/bin/sh"));
+
+        let cmd = CommandBuilder::new("/nonexistent/sh");
+        let err = cmd.resolve_program().unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<CommandNotFound>(),
+            Some(&CommandNotFound {
+                command: "/nonexistent/sh".into(),
+                searched_path: false,
+            })
+        );
+
+        let mut cmd = CommandBuilder::new("no-such-program");
+        cmd.env("PATH", "/nonexistent");
+        assert!(cmd
+            .resolve_program()
+            .unwrap_err()
+            .downcast_ref::<CommandNotFound>()
+            .map_or(false, |err| err.searched_path));
+    }
+
     #[cfg(windows)]
     #[test]
     fn test_env_case_insensitive_override() {
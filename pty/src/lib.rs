@@ -54,7 +54,7 @@ use std::io::Result as IoResult;
 use std::os::windows::prelude::{AsRawHandle, RawHandle};
 
 pub mod cmdbuilder;
-pub use cmdbuilder::CommandBuilder;
+pub use cmdbuilder::{CommandBuilder, CommandNotFound};
 
 #[cfg(unix)]
 pub mod unix;
@@ -109,6 +109,15 @@ pub trait MasterPty: std::io::Write {
     /// itself, but allows splitting it off into a separate object.
     fn try_clone_writer(&self) -> Result<Box<dyn std::io::Write + Send>, Error>;
 
+    /// Write as much of `buf` as the pty can accept without blocking,
+    /// returning the number of bytes that were written.  If the pty
+    /// can't accept any data, fails with `ErrorKind::WouldBlock`.
+    /// The default implementation performs a regular, potentially
+    /// blocking, write.
+    fn try_write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.write(buf)
+    }
+
     /// If applicable to the type of the tty, return the local process id
     /// of the process group or session leader
     #[cfg(unix)]
@@ -154,31 +163,46 @@ pub trait SlavePty {
 }
 
 /// Represents the exit status of a child process.
-/// This is rather anemic in the current version of this crate,
-/// holding only an indicator of success or failure.
 #[derive(Debug, Clone)]
 pub struct ExitStatus {
-    successful: bool,
+    code: u32,
 }
 
 impl ExitStatus {
     /// Construct an ExitStatus from a process return code
     pub fn with_exit_code(code: u32) -> Self {
-        Self {
-            successful: code == 0,
-        }
+        Self { code }
     }
 
     pub fn success(&self) -> bool {
-        self.successful
+        self.code == 0
+    }
+
+    /// Returns the process return code.  On unix systems, a process
+    /// that was terminated by a signal reports `128 + signal`, matching
+    /// the convention used by most shells.
+    pub fn exit_code(&self) -> u32 {
+        self.code
     }
 }
 
 impl From<std::process::ExitStatus> for ExitStatus {
     fn from(status: std::process::ExitStatus) -> ExitStatus {
-        ExitStatus {
-            successful: status.success(),
-        }
+        let code = match status.code() {
+            Some(code) => code as u32,
+            None => {
+                #[cfg(unix)]
+                {
+                    use std::os::unix::process::ExitStatusExt;
+                    status.signal().map(|sig| 128 + sig as u32).unwrap_or(1)
+                }
+                #[cfg(not(unix))]
+                {
+                    1
+                }
+            }
+        };
+        ExitStatus { code }
     }
 }
 
@@ -296,11 +296,32 @@ impl SlavePty for UnixSlavePty {
     }
 }
 
+/// The most that `try_write` passes to a single write(2).  Writing to
+/// a blocking fd can still block if we write more than the pty has
+/// room for, so keep each write small enough to fit once poll(2) has
+/// reported that the pty is writable.
+const MAX_TRY_WRITE_LEN: usize = 1024;
+
 impl MasterPty for UnixMasterPty {
     fn resize(&self, size: PtySize) -> Result<(), Error> {
         self.fd.resize(size)
     }
 
+    fn try_write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut pfd = [filedescriptor::pollfd {
+            fd: self.fd.0.as_raw_fd(),
+            events: filedescriptor::POLLOUT,
+            revents: 0,
+        }];
+        let ready = filedescriptor::poll(&mut pfd, Some(std::time::Duration::from_millis(0)))
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        if ready == 0 || pfd[0].revents & filedescriptor::POLLOUT == 0 {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let len = buf.len().min(MAX_TRY_WRITE_LEN);
+        self.fd.write(&buf[..len])
+    }
+
     fn get_size(&self) -> Result<PtySize, Error> {
         self.fd.get_size()
     }
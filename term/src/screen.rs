@@ -85,18 +85,24 @@ impl Screen {
         scrollback_size(&self.config, self.allow_scrollback)
     }
 
+    /// Rewrap the lines to `physical_cols`, returning the adjusted
+    /// cursor position, along with the row that now holds the start
+    /// of the content that was at the start of the `anchor` row
     fn rewrap_lines(
         &mut self,
         physical_cols: usize,
         physical_rows: usize,
         cursor_x: usize,
         cursor_y: PhysRowIndex,
+        anchor: Option<PhysRowIndex>,
         seqno: SequenceNo,
-    ) -> (usize, PhysRowIndex) {
+    ) -> ((usize, PhysRowIndex), Option<PhysRowIndex>) {
         let mut rewrapped = VecDeque::new();
         let mut logical_line: Option<Line> = None;
         let mut logical_cursor_x: Option<usize> = None;
         let mut adjusted_cursor = (cursor_y, cursor_y);
+        let mut logical_anchor_x: Option<usize> = None;
+        let mut adjusted_anchor = None;
 
         for (phys_idx, mut line) in self.lines.drain(..).enumerate() {
             line.invalidate_implicit_hyperlinks(seqno);
@@ -112,12 +118,18 @@ impl Screen {
                     if phys_idx == cursor_y {
                         logical_cursor_x = Some(cursor_x);
                     }
+                    if Some(phys_idx) == anchor {
+                        logical_anchor_x = Some(0);
+                    }
                     line
                 }
                 Some(mut prior) => {
                     if phys_idx == cursor_y {
                         logical_cursor_x = Some(cursor_x + prior.cells().len());
                     }
+                    if Some(phys_idx) == anchor {
+                        logical_anchor_x = Some(prior.cells().len());
+                    }
                     prior.append_line(line, seqno);
                     prior
                 }
@@ -133,6 +145,9 @@ impl Screen {
                 let last_x = x - (num_lines * physical_cols);
                 adjusted_cursor = (last_x, rewrapped.len() + num_lines);
             }
+            if let Some(x) = logical_anchor_x.take() {
+                adjusted_anchor = Some(rewrapped.len() + x / physical_cols);
+            }
 
             if line.cells().len() <= physical_cols {
                 rewrapped.push_back(line);
@@ -156,7 +171,7 @@ impl Screen {
             self.lines.pop_back();
         }
 
-        adjusted_cursor
+        (adjusted_cursor, adjusted_anchor)
     }
 
     /// Resize the physical, viewable portion of the screen
@@ -167,10 +182,32 @@ impl Screen {
         cursor: CursorPosition,
         seqno: SequenceNo,
     ) -> CursorPosition {
+        self.resize_with_anchor(physical_rows, physical_cols, cursor, None, seqno)
+            .0
+    }
+
+    /// Resize the screen, as `resize` does, additionally returning the
+    /// row that holds the start of the line that was at `anchor` before
+    /// the lines were rewrapped to the new width.  A view of the
+    /// scrollback whose top was at `anchor` can move to that row to
+    /// keep showing the same content.  The row is None if `anchor` is
+    /// None or isn't in the screen.
+    pub fn resize_with_anchor(
+        &mut self,
+        physical_rows: usize,
+        physical_cols: usize,
+        cursor: CursorPosition,
+        anchor: Option<StableRowIndex>,
+        seqno: SequenceNo,
+    ) -> (CursorPosition, Option<StableRowIndex>) {
+        let anchor = anchor.and_then(|row| self.stable_row_to_phys(row));
         let physical_rows = physical_rows.max(1);
         let physical_cols = physical_cols.max(1);
         if physical_rows == self.physical_rows && physical_cols == self.physical_cols {
-            return cursor;
+            return (
+                cursor,
+                anchor.map(|phys| self.phys_to_stable_row_index(phys)),
+            );
         }
         log::debug!("resize screen to {}x{}", physical_cols, physical_rows);
 
@@ -184,7 +221,7 @@ impl Screen {
             }
         }
 
-        let (cursor_x, cursor_y) = if physical_cols != self.physical_cols {
+        let ((cursor_x, cursor_y), anchor) = if physical_cols != self.physical_cols {
             // Check to see if we need to rewrap lines that were
             // wrapped due to reaching the right hand side of the terminal.
             // For each one that we find, we need to join it with its
@@ -193,7 +230,14 @@ impl Screen {
             // screen (hence the check for allow_scrollback), to avoid
             // conflicting screen updates with full screen apps.
             if self.allow_scrollback {
-                self.rewrap_lines(physical_cols, physical_rows, cursor.x, cursor_phys, seqno)
+                self.rewrap_lines(
+                    physical_cols,
+                    physical_rows,
+                    cursor.x,
+                    cursor_phys,
+                    anchor,
+                    seqno,
+                )
             } else {
                 for line in &mut self.lines {
                     if physical_cols < self.physical_cols {
@@ -204,10 +248,10 @@ impl Screen {
                         line.update_last_change_seqno(seqno);
                     }
                 }
-                (cursor.x, cursor_phys)
+                ((cursor.x, cursor_phys), anchor)
             }
         } else {
-            (cursor.x, cursor_phys)
+            ((cursor.x, cursor_phys), anchor)
         };
 
         let capacity = physical_rows + self.scrollback_size();
@@ -258,13 +302,16 @@ impl Screen {
 
         self.physical_rows = physical_rows;
         self.physical_cols = physical_cols;
-        CursorPosition {
-            x: cursor_x,
-            y: new_cursor_y,
-            shape: cursor.shape,
-            visibility: cursor.visibility,
-            seqno,
-        }
+        (
+            CursorPosition {
+                x: cursor_x,
+                y: new_cursor_y,
+                shape: cursor.shape,
+                visibility: cursor.visibility,
+                seqno,
+            },
+            anchor.map(|phys| self.phys_to_stable_row_index(phys)),
+        )
     }
 
     /// Get mutable reference to a line, relative to start of scrollback.
@@ -657,8 +704,14 @@ impl Screen {
     }
 
     pub fn erase_scrollback(&mut self) {
-        let len = self.lines.len();
-        let to_clear = len - self.physical_rows;
+        self.trim_scrollback(0);
+    }
+
+    /// Discard the oldest lines of the scrollback so that at most
+    /// `max_scrollback_rows` lines remain above the viewport
+    pub fn trim_scrollback(&mut self, max_scrollback_rows: usize) {
+        let scrollback_rows = self.lines.len() - self.physical_rows;
+        let to_clear = scrollback_rows.saturating_sub(max_scrollback_rows);
         for _ in 0..to_clear {
             self.lines.pop_front();
             self.stable_row_index_offset += 1;
@@ -32,6 +32,20 @@ pub trait DeviceControlHandler {
     fn handle_device_control(&mut self, _control: termwiz::escape::DeviceControlMode);
 }
 
+bitflags::bitflags! {
+    /// Modes enabled by the application that affect how the hosting
+    /// application should handle input and scrollback
+    #[cfg_attr(feature = "use_serde", derive(Serialize, Deserialize))]
+    #[derive(Default)]
+    pub struct TerminalModes: u8 {
+        const ALT_SCREEN = 1;
+        /// Mouse events are reported using the SGR encoding
+        const MOUSE_SGR = 2;
+        const APPLICATION_CURSOR_KEYS = 4;
+        const BRACKETED_PASTE = 8;
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 #[cfg_attr(feature = "use_serde", derive(Serialize, Deserialize))]
 pub enum Alert {
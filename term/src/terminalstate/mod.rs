@@ -16,6 +16,7 @@ use termwiz::escape::csi::{
     EraseInLine, Mode, Sgr, TabulationClear, TerminalMode, TerminalModeCode, Window, XtSmGraphics,
     XtSmGraphicsAction, XtSmGraphicsItem, XtSmGraphicsStatus,
 };
+use termwiz::escape::osc::Progress;
 use termwiz::escape::{OneBased, OperatingSystemCommand, CSI};
 use termwiz::image::ImageData;
 use termwiz::input::KeyboardEncoding;
@@ -194,21 +195,38 @@ impl ScreenOrAlt {
         }
     }
 
+    /// Resize both screens, returning their adjusted cursor positions
+    /// along with the row that `anchor`, a row of the active screen,
+    /// was moved to; see `Screen::resize_with_anchor`
     pub fn resize(
         &mut self,
         physical_rows: usize,
         physical_cols: usize,
         cursor_main: CursorPosition,
         cursor_alt: CursorPosition,
+        anchor: Option<StableRowIndex>,
         seqno: SequenceNo,
-    ) -> (CursorPosition, CursorPosition) {
-        let cursor_main = self
-            .screen
-            .resize(physical_rows, physical_cols, cursor_main, seqno);
-        let cursor_alt = self
-            .alt_screen
-            .resize(physical_rows, physical_cols, cursor_alt, seqno);
-        (cursor_main, cursor_alt)
+    ) -> (CursorPosition, CursorPosition, Option<StableRowIndex>) {
+        let (main_anchor, alt_anchor) = if self.alt_screen_is_active {
+            (None, anchor)
+        } else {
+            (anchor, None)
+        };
+        let (cursor_main, main_anchor) = self.screen.resize_with_anchor(
+            physical_rows,
+            physical_cols,
+            cursor_main,
+            main_anchor,
+            seqno,
+        );
+        let (cursor_alt, alt_anchor) = self.alt_screen.resize_with_anchor(
+            physical_rows,
+            physical_cols,
+            cursor_alt,
+            alt_anchor,
+            seqno,
+        );
+        (cursor_main, cursor_alt, main_anchor.or(alt_anchor))
     }
 
     pub fn activate_alt_screen(&mut self, seqno: SequenceNo) {
@@ -338,6 +356,12 @@ pub struct TerminalState {
     title: String,
     /// The icon title string (OSC 1)
     icon_title: Option<String>,
+    /// The bytes of `title`, `icon_title` and `current_dir` as
+    /// sent by the application, when they were not valid UTF-8 and
+    /// were decoded lossily
+    title_bytes: Option<Vec<u8>>,
+    icon_title_bytes: Option<Vec<u8>>,
+    current_dir_bytes: Option<Vec<u8>>,
 
     palette: Option<ColorPalette>,
 
@@ -351,6 +375,10 @@ pub struct TerminalState {
 
     current_dir: Option<Url>,
 
+    /// The progress most recently reported by the application
+    /// using the ConEmu OSC 9;4 sequence
+    progress: Progress,
+
     term_program: String,
     term_version: String,
 
@@ -538,6 +566,9 @@ impl TerminalState {
             tabs: TabStop::new(size.physical_cols, 8),
             title: "wezterm".to_string(),
             icon_title: None,
+            title_bytes: None,
+            icon_title_bytes: None,
+            current_dir_bytes: None,
             palette: None,
             pixel_height: size.pixel_height,
             pixel_width: size.pixel_width,
@@ -546,6 +577,7 @@ impl TerminalState {
             alert_handler: None,
             download_handler: None,
             current_dir: None,
+            progress: Progress::default(),
             term_program: term_program.to_string(),
             term_version: term_version.to_string(),
             writer: Box::new(std::io::BufWriter::new(writer)),
@@ -621,6 +653,23 @@ impl TerminalState {
         self.icon_title.as_ref().unwrap_or(&self.title)
     }
 
+    /// Returns the bytes of the title returned by `get_title` as
+    /// they were sent by the application, if they were not valid
+    /// UTF-8.  `get_title` returns a lossy decoding of them.
+    pub fn get_title_bytes(&self) -> Option<&[u8]> {
+        if self.icon_title.is_some() {
+            self.icon_title_bytes.as_deref()
+        } else {
+            self.title_bytes.as_deref()
+        }
+    }
+
+    /// Returns the bytes of the OSC 7 working directory as they were
+    /// sent by the application, if they were not valid UTF-8.
+    pub fn get_current_dir_bytes(&self) -> Option<&[u8]> {
+        self.current_dir_bytes.as_deref()
+    }
+
     /// Returns the current working directory associated with the
     /// terminal session.  The working directory can be changed by
     /// the applicaiton using the OSC 7 escape sequence.
@@ -628,6 +677,12 @@ impl TerminalState {
         self.current_dir.as_ref()
     }
 
+    /// Returns the progress of a long running task, as most recently
+    /// reported by the application using the OSC 9;4 escape sequence.
+    pub fn get_progress(&self) -> Progress {
+        self.progress
+    }
+
     /// Returns a copy of the palette.
     /// By default we don't keep a copy in the terminal state,
     /// preferring to take the config values from the users
@@ -694,6 +749,12 @@ impl TerminalState {
         self.screen_mut().erase_scrollback();
     }
 
+    /// Discards the oldest lines of the scrollback, retaining
+    /// at most `max_scrollback_rows` lines above the viewport.
+    pub fn trim_scrollback(&mut self, max_scrollback_rows: usize) {
+        self.screen_mut().trim_scrollback(max_scrollback_rows);
+    }
+
     /// Returns true if the associated application has enabled any of the
     /// supported mouse reporting modes.
     /// This is useful for the hosting GUI application to decide how best
@@ -713,6 +774,31 @@ impl TerminalState {
         self.bracketed_paste
     }
 
+    /// Returns the SGR attributes, such as the colors and intensity,
+    /// with which text that is printed at the cursor is drawn
+    pub fn pen(&self) -> CellAttributes {
+        self.pen.clone_sgr_only()
+    }
+
+    /// Returns the set of modes that the application has enabled
+    pub fn terminal_modes(&self) -> TerminalModes {
+        let mut modes = TerminalModes::default();
+        modes.set(TerminalModes::ALT_SCREEN, self.is_alt_screen_active());
+        modes.set(
+            TerminalModes::MOUSE_SGR,
+            matches!(
+                self.mouse_encoding,
+                MouseEncoding::SGR | MouseEncoding::SgrPixels
+            ),
+        );
+        modes.set(
+            TerminalModes::APPLICATION_CURSOR_KEYS,
+            self.application_cursor_keys,
+        );
+        modes.set(TerminalModes::BRACKETED_PASTE, self.bracketed_paste);
+        modes
+    }
+
     /// Advise the terminal about a change in its focus state
     pub fn focus_changed(&mut self, focused: bool) {
         if !focused {
@@ -782,6 +868,39 @@ impl TerminalState {
         Ok(())
     }
 
+    /// Begin a paste whose text is sent in several parts using
+    /// `send_paste_part`, and which is finished by `end_paste`.
+    /// Returns true if the paste is bracketed; the caller passes that
+    /// to the other methods, so that the whole paste is treated the
+    /// same way even if the application changes the mode part way
+    /// through it.
+    pub fn begin_paste(&mut self) -> Result<bool, Error> {
+        let bracketed = self.bracketed_paste;
+        if bracketed {
+            self.writer.write_all(b"\x1b[200~")?;
+        }
+        Ok(bracketed)
+    }
+
+    pub fn send_paste_part(&mut self, text: &str, bracketed: bool) -> Result<(), Error> {
+        let canon = if bracketed {
+            NewlineCanon::None
+        } else {
+            self.config.canonicalize_pasted_newlines()
+        };
+        self.writer.write_all(canon.canonicalize(text).as_bytes())?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    pub fn end_paste(&mut self, bracketed: bool) -> Result<(), Error> {
+        if bracketed {
+            self.writer.write_all(b"\x1b[201~")?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+
     /// Informs the terminal that the viewport of the window has resized to the
     /// specified dimensions.
     /// We need to resize both the primary and alt screens, adjusting
@@ -793,6 +912,29 @@ impl TerminalState {
         pixel_width: usize,
         pixel_height: usize,
     ) {
+        self.resize_with_anchor(
+            physical_rows,
+            physical_cols,
+            pixel_width,
+            pixel_height,
+            None,
+        );
+    }
+
+    /// Resize the terminal, as `resize` does, returning the row that
+    /// holds the start of the line that was at `anchor` before the
+    /// lines were rewrapped to the new width.  A client that is viewing
+    /// the scrollback from `anchor` can move its view to that row so
+    /// that it keeps showing the same content.  Returns None if `anchor`
+    /// is None, or isn't in the scrollback.
+    pub fn resize_with_anchor(
+        &mut self,
+        physical_rows: usize,
+        physical_cols: usize,
+        pixel_width: usize,
+        pixel_height: usize,
+        anchor: Option<StableRowIndex>,
+    ) -> Option<StableRowIndex> {
         let (cursor_main, cursor_alt) = if self.screen.alt_screen_is_active {
             (
                 self.screen
@@ -813,11 +955,12 @@ impl TerminalState {
             )
         };
 
-        let (adjusted_cursor_main, adjusted_cursor_alt) = self.screen.resize(
+        let (adjusted_cursor_main, adjusted_cursor_alt, anchor) = self.screen.resize(
             physical_rows,
             physical_cols,
             cursor_main,
             cursor_alt,
+            anchor,
             self.seqno,
         );
         self.top_and_bottom_margins = 0..physical_rows as i64;
@@ -850,6 +993,7 @@ impl TerminalState {
                 saved.wrap_next = false;
             }
         }
+        anchor
     }
 
     /// When dealing with selection, mark a range of lines as dirty
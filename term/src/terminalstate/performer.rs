@@ -561,6 +561,7 @@ impl<'a> Performer<'a> {
                 self.unicode_version_stack.clear();
                 self.suppress_initial_title_change = false;
                 self.accumulating_title.take();
+                self.progress = Default::default();
 
                 self.screen.activate_primary_screen(seqno);
                 self.erase_in_display(EraseInDisplay::EraseScrollback);
@@ -574,11 +575,44 @@ impl<'a> Performer<'a> {
         }
     }
 
+    /// termwiz leaves titles and working directories that aren't
+    /// valid UTF-8, or that contain a `;`, as Unspecified.  Rather
+    /// than dropping them, apply them using a lossy decoding, and
+    /// retain the bytes that the application sent so that they can
+    /// be decoded differently by the consumer.
+    /// Returns false if `unspec` isn't one of those.
+    fn osc_undecodable_string(&mut self, unspec: &[Vec<u8>]) -> bool {
+        if unspec.len() < 2 {
+            return false;
+        }
+        let bytes = unspec[1..].join(&b';');
+        let text = String::from_utf8_lossy(&bytes).into_owned();
+        let code = unspec[0].as_slice();
+        let osc = match code {
+            b"0" => OperatingSystemCommand::SetIconNameAndWindowTitle(text),
+            b"1" => OperatingSystemCommand::SetIconName(text),
+            b"2" => OperatingSystemCommand::SetWindowTitle(text),
+            b"7" => OperatingSystemCommand::CurrentWorkingDirectory(text),
+            _ => return false,
+        };
+        self.osc_dispatch(osc);
+
+        if std::str::from_utf8(&bytes).is_err() {
+            match code {
+                b"0" | b"2" => self.title_bytes = Some(bytes),
+                b"1" => self.icon_title_bytes = Some(bytes),
+                _ => self.current_dir_bytes = Some(bytes),
+            }
+        }
+        true
+    }
+
     fn osc_dispatch(&mut self, osc: OperatingSystemCommand) {
         self.flush_print();
         match osc {
             OperatingSystemCommand::SetIconNameSun(title)
             | OperatingSystemCommand::SetIconName(title) => {
+                self.icon_title_bytes.take();
                 if title.is_empty() {
                     self.icon_title = None;
                 } else {
@@ -590,6 +624,8 @@ impl<'a> Performer<'a> {
             }
             OperatingSystemCommand::SetIconNameAndWindowTitle(title) => {
                 self.icon_title.take();
+                self.icon_title_bytes.take();
+                self.title_bytes.take();
                 self.title = title.clone();
                 if let Some(handler) = self.alert_handler.as_mut() {
                     handler.alert(Alert::TitleMaybeChanged);
@@ -598,6 +634,7 @@ impl<'a> Performer<'a> {
 
             OperatingSystemCommand::SetWindowTitleSun(title)
             | OperatingSystemCommand::SetWindowTitle(title) => {
+                self.title_bytes.take();
                 self.title = title.clone();
                 if let Some(handler) = self.alert_handler.as_mut() {
                     handler.alert(Alert::TitleMaybeChanged);
@@ -607,6 +644,9 @@ impl<'a> Performer<'a> {
                 self.set_hyperlink(link);
             }
             OperatingSystemCommand::Unspecified(unspec) => {
+                if self.osc_undecodable_string(&unspec) {
+                    return;
+                }
                 let mut output = String::new();
                 write!(&mut output, "Unhandled OSC ").ok();
                 for item in unspec {
@@ -711,6 +751,9 @@ impl<'a> Performer<'a> {
                     log::info!("Application sends SystemNotification: {}", message);
                 }
             }
+            OperatingSystemCommand::ConEmuProgress(progress) => {
+                self.progress = progress;
+            }
             OperatingSystemCommand::RxvtExtension(params) => {
                 if let Some("notify") = params.get(0).map(String::as_str) {
                     let title = params.get(1);
@@ -733,6 +776,7 @@ impl<'a> Performer<'a> {
                 }
             }
             OperatingSystemCommand::CurrentWorkingDirectory(url) => {
+                self.current_dir_bytes.take();
                 self.current_dir = Url::parse(&url).ok();
                 if let Some(handler) = self.alert_handler.as_mut() {
                     handler.alert(Alert::TitleMaybeChanged);
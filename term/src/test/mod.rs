@@ -528,6 +528,22 @@ fn issue_1161() {
     );
 }
 
+#[test]
+fn test_pen() {
+    use termwiz::color::ColorAttribute;
+    let mut term = TestTerm::new(1, 10, 0);
+    term.print("\x1b[1;31m$ ");
+    assert_eq!(term.pen().intensity(), Intensity::Bold);
+    assert_eq!(term.pen().foreground(), ColorAttribute::PaletteIndex(1));
+
+    // The pen holds only the SGR attributes
+    term.print("\x1b]8;;https://wezfurlong.org\x1b\\");
+    assert!(term.pen().hyperlink().is_none());
+
+    term.print("\x1b[0m");
+    assert_eq!(term.pen(), CellAttributes::default());
+}
+
 #[test]
 fn basic_output() {
     let mut term = TestTerm::new(5, 10, 0);
@@ -988,6 +1004,41 @@ fn test_resize_wrap_escape_code_issue_978() {
     assert_visible_contents(&term, file!(), line!(), &["====", "SS  ", "    ", "    "]);
 }
 
+#[test]
+fn test_resize_anchor_while_scrolled_back() {
+    let mut term = TestTerm::new(3, 4, 10);
+    term.print("aa\r\nbbbbbb\r\ncc\r\ndd\r\nee\r\nff");
+
+    fn text_at(term: &Terminal, row: StableRowIndex) -> String {
+        let screen = term.screen();
+        let phys = screen.stable_row_to_phys(row).unwrap();
+        screen.lines[phys].as_str().trim_end().to_string()
+    }
+    assert_eq!(text_at(&term, 2), "bb");
+    assert_eq!(text_at(&term, 3), "cc");
+
+    // The view is scrolled back so that "cc" is at the top; the
+    // wrapped line above it unwraps, so it moves up a row
+    let anchor = term.resize_with_anchor(3, 8, 0, 0, Some(3));
+    assert_eq!(anchor, Some(2));
+    assert_eq!(text_at(&term, 2), "cc");
+
+    // Narrowing wraps the line above it over three rows
+    let anchor = term.resize_with_anchor(3, 2, 0, 0, Some(2));
+    assert_eq!(anchor, Some(4));
+    assert_eq!(text_at(&term, 4), "cc");
+
+    // An anchor in the middle of a wrapped line stays with its content
+    assert_eq!(text_at(&term, 2), "bb");
+    let anchor = term.resize_with_anchor(3, 3, 0, 0, Some(3));
+    assert_eq!(anchor, Some(2));
+    assert_eq!(text_at(&term, 2), "bbb");
+
+    // Rows that are not in the scrollback have no anchor
+    assert_eq!(term.resize_with_anchor(3, 4, 0, 0, Some(100)), None);
+    assert_eq!(term.resize_with_anchor(3, 5, 0, 0, None), None);
+}
+
 #[test]
 fn test_scrollup() {
     let mut term = TestTerm::new(2, 1, 4);
@@ -1024,6 +1075,25 @@ fn test_scrollup() {
     assert_eq!(term.screen().visible_row_to_stable_row(0), 7);
 }
 
+#[test]
+fn test_trim_scrollback() {
+    let mut term = TestTerm::new(2, 1, 10);
+    term.print("1\n2\n3\n4\n5\n");
+    assert_all_contents(&term, file!(), line!(), &["1", "2", "3", "4", "5", " "]);
+
+    term.trim_scrollback(2);
+    assert_all_contents(&term, file!(), line!(), &["3", "4", "5", " "]);
+    assert_eq!(term.screen().visible_row_to_stable_row(0), 4);
+
+    // Asking to retain more than is present has no effect
+    term.trim_scrollback(10);
+    assert_all_contents(&term, file!(), line!(), &["3", "4", "5", " "]);
+
+    term.trim_scrollback(0);
+    assert_all_contents(&term, file!(), line!(), &["5", " "]);
+    assert_eq!(term.screen().visible_row_to_stable_row(0), 4);
+}
+
 #[test]
 fn test_ri() {
     let mut term = TestTerm::new(3, 1, 10);
@@ -1189,3 +1259,42 @@ fn test_hyperlinks() {
         Compare::TEXT | Compare::ATTRS,
     );
 }
+
+#[test]
+fn test_terminal_modes() {
+    let mut term = TestTerm::new(3, 10, 0);
+    assert_eq!(term.terminal_modes(), TerminalModes::empty());
+
+    term.set_mode("?1049", true);
+    term.set_mode("?1006", true);
+    term.set_mode("?1", true);
+    term.set_mode("?2004", true);
+    assert_eq!(term.terminal_modes(), TerminalModes::all());
+
+    term.set_mode("?1049", false);
+    term.set_mode("?1006", false);
+    assert_eq!(
+        term.terminal_modes(),
+        TerminalModes::APPLICATION_CURSOR_KEYS | TerminalModes::BRACKETED_PASTE
+    );
+}
+
+#[test]
+fn test_non_utf8_title() {
+    let mut term = TestTerm::new(3, 10, 0);
+    term.print(b"\x1b]2;caf\xe9\x07");
+    assert_eq!(term.get_title(), "caf\u{fffd}");
+    assert_eq!(term.get_title_bytes(), Some(&b"caf\xe9"[..]));
+
+    // A title containing a semicolon is kept intact
+    term.print(b"\x1b]0;one;two\x07");
+    assert_eq!(term.get_title(), "one;two");
+    assert_eq!(term.get_title_bytes(), None);
+
+    term.print(b"\x1b]7;file://host/tmp/\xff\x07");
+    assert!(term.get_current_dir().is_some());
+    assert_eq!(
+        term.get_current_dir_bytes(),
+        Some(&b"file://host/tmp/\xff"[..])
+    );
+}
@@ -10,7 +10,7 @@ use std::mem;
 use std::sync::Arc;
 
 #[cfg_attr(feature = "use_serde", derive(Serialize, Deserialize))]
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 enum SmallColor {
     Default,
     PaletteIndex(PaletteIndex),
@@ -37,7 +37,7 @@ impl Into<ColorAttribute> for SmallColor {
 /// The setter methods return a mutable self reference so that they can
 /// be chained together.
 #[cfg_attr(feature = "use_serde", derive(Serialize, Deserialize))]
-#[derive(Clone, Eq, PartialEq)]
+#[derive(Clone, Eq, PartialEq, Hash)]
 pub struct CellAttributes {
     attributes: u16,
     /// The foreground color
@@ -72,7 +72,7 @@ impl std::fmt::Debug for CellAttributes {
 }
 
 #[cfg_attr(feature = "use_serde", derive(Serialize, Deserialize))]
-#[derive(Debug, Default, Clone, Eq, PartialEq)]
+#[derive(Debug, Default, Clone, Eq, PartialEq, Hash)]
 struct FatAttributes {
     /// The hyperlink content, if any
     hyperlink: Option<Arc<Hyperlink>>,
@@ -483,13 +483,22 @@ impl CellAttributes {
     }
 }
 
+/// The text is serialized along with its width, rather than having
+/// the width recomputed when it is deserialized, because the width
+/// depends on the unicode version that was in effect when the cell
+/// was created, and may have been explicitly specified.
 #[cfg(feature = "use_serde")]
 fn deserialize_teenystring<'de, D>(deserializer: D) -> Result<TeenyString, D::Error>
 where
     D: Deserializer<'de>,
 {
-    let text = String::deserialize(deserializer)?;
-    Ok(TeenyString::from_str(&text, None))
+    let (text, width) = <(String, u8)>::deserialize(deserializer)?;
+    let mut width = width as usize;
+    if text.len() < std::mem::size_of::<usize>() {
+        // Inline storage can only represent a width of 1 or 2
+        width = width.clamp(1, 2);
+    }
+    Ok(TeenyString::from_str(&text, Some(width)))
 }
 
 #[cfg(feature = "use_serde")]
@@ -500,7 +509,7 @@ where
     // unsafety: this is safe because the Cell constructor guarantees
     // that the storage is valid utf8
     let s = unsafe { std::str::from_utf8_unchecked(value.as_bytes()) };
-    s.serialize(serializer)
+    (s, value.width().min(u8::MAX as usize) as u8).serialize(serializer)
 }
 
 /// TeenyString encodes string storage in a single machine word.
@@ -717,9 +726,15 @@ impl std::cmp::PartialEq for TeenyString {
 }
 impl std::cmp::Eq for TeenyString {}
 
+impl std::hash::Hash for TeenyString {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.as_bytes().hash(state)
+    }
+}
+
 /// Models the contents of a cell on the terminal display
 #[cfg_attr(feature = "use_serde", derive(Serialize, Deserialize))]
-#[derive(Clone, Eq, PartialEq)]
+#[derive(Clone, Eq, PartialEq, Hash)]
 pub struct Cell {
     #[cfg_attr(
         feature = "use_serde",
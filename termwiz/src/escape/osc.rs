@@ -5,6 +5,8 @@ use bitflags::bitflags;
 use num_derive::*;
 use num_traits::FromPrimitive;
 use ordered_float::NotNan;
+#[cfg(feature = "use_serde")]
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fmt::{Display, Error as FmtError, Formatter, Result as FmtResult};
 use std::str;
@@ -44,10 +46,51 @@ pub enum OperatingSystemCommand {
     CurrentWorkingDirectory(String),
     ResetColors(Vec<u8>),
     RxvtExtension(Vec<String>),
+    ConEmuProgress(Progress),
 
     Unspecified(Vec<Vec<u8>>),
 }
 
+/// The state of a long running task, as reported by ConEmu's
+/// `OSC 9 ; 4 ; st ; pr` sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "use_serde", derive(Serialize, Deserialize))]
+pub enum ProgressState {
+    /// No progress is being reported
+    None,
+    Normal,
+    Error,
+    /// The task is busy but cannot tell how far along it is
+    Indeterminate,
+    Paused,
+}
+
+impl Default for ProgressState {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+impl ProgressState {
+    fn as_code(self) -> u8 {
+        match self {
+            Self::None => 0,
+            Self::Normal => 1,
+            Self::Error => 2,
+            Self::Indeterminate => 3,
+            Self::Paused => 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "use_serde", derive(Serialize, Deserialize))]
+pub struct Progress {
+    pub state: ProgressState,
+    /// The percentage complete, in the range 0-100
+    pub percent: u8,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive)]
 #[repr(u8)]
 pub enum DynamicColorNumber {
@@ -175,6 +218,32 @@ impl OperatingSystemCommand {
         }
     }
 
+    fn parse_conemu_progress(osc: &[&[u8]]) -> Result<Self> {
+        fn param(osc: &[&[u8]], idx: usize) -> Result<u8> {
+            match osc.get(idx) {
+                Some(p) if !p.is_empty() => Ok(str::from_utf8(p)?.parse()?),
+                _ => Ok(0),
+            }
+        }
+
+        if osc.len() > 4 {
+            bail!("too many params for ConEmu progress: {:?}", osc);
+        }
+        let state = match param(osc, 2)? {
+            0 => ProgressState::None,
+            1 => ProgressState::Normal,
+            2 => ProgressState::Error,
+            3 => ProgressState::Indeterminate,
+            4 => ProgressState::Paused,
+            state => bail!("invalid ConEmu progress state {}", state),
+        };
+        let percent = param(osc, 3)?.min(100);
+        Ok(OperatingSystemCommand::ConEmuProgress(Progress {
+            state,
+            percent,
+        }))
+    }
+
     fn parse_reset_colors(osc: &[&[u8]]) -> Result<Self> {
         let mut colors = vec![];
         let mut iter = osc.iter();
@@ -295,6 +364,9 @@ impl OperatingSystemCommand {
             )),
             SetHyperlink => Ok(OperatingSystemCommand::SetHyperlink(Hyperlink::parse(osc)?)),
             ManipulateSelectionData => Self::parse_selection(osc),
+            SystemNotification if osc.len() > 1 && osc[1] == b"4" => {
+                Self::parse_conemu_progress(osc)
+            }
             SystemNotification => single_string!(SystemNotification),
             SetCurrentWorkingDirectory => single_string!(CurrentWorkingDirectory),
             ITermProprietary => {
@@ -493,6 +565,9 @@ impl Display for OperatingSystemCommand {
             QuerySelection(s) => write!(f, "52;{};?", s)?,
             SetSelection(s, val) => write!(f, "52;{};{}", s, base64::encode(val))?,
             SystemNotification(s) => write!(f, "9;{}", s)?,
+            ConEmuProgress(Progress { state, percent }) => {
+                write!(f, "9;4;{};{}", state.as_code(), percent)?
+            }
             ITermProprietary(i) => i.fmt(f)?,
             FinalTermSemanticPrompt(i) => i.fmt(f)?,
             ResetColors(colors) => {
@@ -1271,6 +1346,41 @@ mod test {
         );
     }
 
+    #[test]
+    fn conemu_progress() {
+        assert_eq!(
+            parse(&["9", "4", "1", "42"], "\x1b]9;4;1;42\x1b\\"),
+            OperatingSystemCommand::ConEmuProgress(Progress {
+                state: ProgressState::Normal,
+                percent: 42
+            })
+        );
+        assert_eq!(
+            parse(&["9", "4", "3"], "\x1b]9;4;3;0\x1b\\"),
+            OperatingSystemCommand::ConEmuProgress(Progress {
+                state: ProgressState::Indeterminate,
+                percent: 0
+            })
+        );
+        assert_eq!(
+            parse(&["9", "4", "0"], "\x1b]9;4;0;0\x1b\\"),
+            OperatingSystemCommand::ConEmuProgress(Progress::default())
+        );
+        // The percentage is clamped
+        assert_eq!(
+            parse(&["9", "4", "2", "200"], "\x1b]9;4;2;100\x1b\\"),
+            OperatingSystemCommand::ConEmuProgress(Progress {
+                state: ProgressState::Error,
+                percent: 100
+            })
+        );
+        // Other OSC 9 sequences are notifications
+        assert_eq!(
+            parse(&["9", "hello"], "\x1b]9;hello\x1b\\"),
+            OperatingSystemCommand::SystemNotification("hello".into())
+        );
+    }
+
     #[test]
     fn title() {
         assert_eq!(
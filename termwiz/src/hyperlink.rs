@@ -23,6 +23,18 @@ pub struct Hyperlink {
     implicit: bool,
 }
 
+impl std::hash::Hash for Hyperlink {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        // The iteration order of the params is unspecified,
+        // so hash them in a stable order
+        let mut params: Vec<_> = self.params.iter().collect();
+        params.sort();
+        params.hash(state);
+        self.uri.hash(state);
+        self.implicit.hash(state);
+    }
+}
+
 impl Hyperlink {
     pub fn uri(&self) -> &str {
         &self.uri
@@ -36,7 +36,7 @@ where
 }
 
 #[cfg_attr(feature = "use_serde", derive(Serialize, Deserialize))]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct TextureCoordinate {
     #[cfg_attr(
         feature = "use_serde",
@@ -74,7 +74,7 @@ impl TextureCoordinate {
 /// its "texture coordinates" within that image so that we can render the
 /// right slice.
 #[cfg_attr(feature = "use_serde", derive(Serialize, Deserialize))]
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ImageCell {
     /// Texture coordinate for the top left of this cell.
     /// (0,0) is the top left of the ImageData. (1, 1) is
@@ -139,6 +139,14 @@ impl ImageCell {
         self.placement_id.is_some()
     }
 
+    pub fn image_id(&self) -> Option<u32> {
+        self.image_id
+    }
+
+    pub fn placement_id(&self) -> Option<u32> {
+        self.placement_id
+    }
+
     pub fn top_left(&self) -> TextureCoordinate {
         self.top_left
     }
@@ -381,6 +389,11 @@ pub struct ImageData {
 }
 
 impl Eq for ImageData {}
+impl std::hash::Hash for ImageData {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.id.hash(state)
+    }
+}
 impl PartialEq for ImageData {
     fn eq(&self, rhs: &Self) -> bool {
         self.id == rhs.id
@@ -218,6 +218,26 @@ impl Line {
         self.seqno
     }
 
+    /// Returns a hash of the cells and display attributes of the line.
+    /// Unlike comparing lines, this ignores the sequence number, so
+    /// it can tell whether a line that was marked as changed actually
+    /// has different content.
+    pub fn content_hash(&self) -> u64 {
+        use std::hash::Hasher;
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        self.hash_content(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Feed the cells and display attributes of the line to `hasher`,
+    /// as `content_hash` does, for callers that need a hash whose
+    /// algorithm they control
+    pub fn hash_content<H: std::hash::Hasher>(&self, hasher: &mut H) {
+        use std::hash::Hash;
+        self.cells.hash(hasher);
+        self.bits.hash(hasher);
+    }
+
     /// Annotate the line with the sequence number of a change.
     /// This can be used together with Line::changed_since to
     /// manage caching and rendering
@@ -807,6 +827,20 @@ impl Line {
         &self.cells
     }
 
+    /// Remove and return the cells of the line, leaving its other
+    /// properties as they are.  This is intended for serializers that
+    /// encode the cells separately from the line; see `restore_cells`.
+    pub fn take_cells(&mut self) -> Vec<Cell> {
+        std::mem::take(&mut self.cells)
+    }
+
+    /// Put back the cells that were removed by `take_cells`.
+    /// Unlike `set_cell`, this doesn't invalidate any of the state
+    /// that is derived from the cells, nor update the seqno.
+    pub fn restore_cells(&mut self, cells: Vec<Cell>) {
+        self.cells = cells;
+    }
+
     pub fn cells_mut(&mut self) -> &mut [Cell] {
         &mut self.cells
     }
@@ -1014,4 +1048,17 @@ mod test {
         let r = line.compute_double_click_range(200, |_| true);
         assert_eq!(r, DoubleClickRange::Range(200..200));
     }
+
+    #[test]
+    fn content_hash_ignores_seqno() {
+        let line = Line::from_text("hello", &CellAttributes::default(), 1);
+        let repainted = Line::from_text("hello", &CellAttributes::default(), 2);
+        assert_ne!(line, repainted);
+        assert_eq!(line.content_hash(), repainted.content_hash());
+
+        let mut bold = CellAttributes::default();
+        bold.set_intensity(crate::cell::Intensity::Bold);
+        let restyled = Line::from_text("hello", &bold, 2);
+        assert_ne!(line.content_hash(), restyled.content_hash());
+    }
 }
@@ -27,8 +27,9 @@ use std::net::TcpStream;
 #[cfg(unix)]
 use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
 
 enum ReaderMessage {
@@ -46,6 +47,21 @@ pub struct Client {
     client_id: ClientId,
     pub is_reconnectable: bool,
     pub is_local: bool,
+    last_latency: Arc<Mutex<Option<Latency>>>,
+    row_hash: Arc<Mutex<Option<RowHashAlgorithm>>>,
+}
+
+/// The latency of the connection to the server, as measured
+/// by `Client::measure_latency`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Latency {
+    pub round_trip: Duration,
+    /// The number of PDUs that the server had queued up to send
+    /// to us when it processed the measurement
+    pub server_queue_depth: Option<usize>,
+    /// Set when the server reported that its main thread is busy,
+    /// in which case it is slow to respond whatever the link
+    pub server_busy: bool,
 }
 
 #[derive(Error, Debug, Clone, PartialEq, Eq)]
@@ -64,6 +80,37 @@ pub struct IncompatibleVersionError {
     pub codec_vers: usize,
 }
 
+/// The server rejected our codec version as being outside of
+/// the range that it supports
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnsupportedCodecError {
+    pub version: String,
+    pub min_supported: usize,
+    pub max_supported: usize,
+}
+
+impl std::fmt::Display for UnsupportedCodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "The server version is {}, which supports codec versions {} through {},\n\
+             but our version is {} (codec version {}).\n",
+            self.version,
+            self.min_supported,
+            self.max_supported,
+            config::wezterm_version(),
+            CODEC_VERSION
+        )?;
+        if CODEC_VERSION < self.min_supported {
+            write!(f, "Please upgrade wezterm on this machine!")
+        } else {
+            write!(f, "Please upgrade wezterm on the server!")
+        }
+    }
+}
+
+impl std::error::Error for UnsupportedCodecError {}
+
 macro_rules! rpc {
     ($method_name:ident, $request_type:ident, $response_type:ident) => {
         pub async fn $method_name(&self, pdu: $request_type) -> anyhow::Result<$response_type> {
@@ -74,6 +121,11 @@ macro_rules! rpc {
             metrics::counter!("rpc.count", 1, "method" => stringify!($method_name));
             match result {
                 Ok(Pdu::$response_type(res)) => Ok(res),
+                Ok(Pdu::UnimplementedResponse(_)) => {
+                    bail!("server does not implement {}", stringify!($request_type))
+                }
+                Ok(Pdu::SpawnFailed(res)) => Err(res.into()),
+                Ok(Pdu::ErrorResponse(res)) => Err(res.into()),
                 Ok(_) => bail!("unexpected response {:?}", result),
                 Err(err) => Err(err),
             }
@@ -93,6 +145,11 @@ macro_rules! rpc {
             metrics::counter!("rpc.count", 1, "method" => stringify!($method_name));
             match result {
                 Ok(Pdu::$response_type(res)) => Ok(res),
+                Ok(Pdu::UnimplementedResponse(_)) => {
+                    bail!("server does not implement {}", stringify!($request_type))
+                }
+                Ok(Pdu::SpawnFailed(res)) => Err(res.into()),
+                Ok(Pdu::ErrorResponse(res)) => Err(res.into()),
                 Ok(_) => bail!("unexpected response {:?}", result),
                 Err(err) => Err(err),
             }
@@ -163,6 +220,22 @@ async fn process_unilateral_inner_async(
     client_pane.process_unilateral(decoded.pdu)
 }
 
+/// Some other client has changed the window/tab topology;
+/// refresh our view of it.
+fn resync_domain(local_domain_id: DomainId) {
+    promise::spawn::spawn(async move {
+        let mux = Mux::get().ok_or_else(|| anyhow!("no more mux"))?;
+        let client_domain = mux
+            .get_domain(local_domain_id)
+            .ok_or_else(|| anyhow!("no such domain {}", local_domain_id))?;
+        let client_domain = client_domain
+            .downcast_ref::<ClientDomain>()
+            .ok_or_else(|| anyhow!("domain {} is not a ClientDomain instance", local_domain_id))?;
+        client_domain.resync().await
+    })
+    .detach();
+}
+
 fn process_unilateral(
     local_domain_id: Option<DomainId>,
     decoded: DecodedPdu,
@@ -181,6 +254,14 @@ fn process_unilateral(
         }
     };
     match &decoded.pdu {
+        Pdu::WindowStructureChanged(WindowStructureChanged { window_id, .. }) => {
+            log::trace!("remote window {} structure changed", window_id);
+            promise::spawn::spawn_into_main_thread(async move {
+                resync_domain(local_domain_id);
+            })
+            .detach();
+            return Ok(());
+        }
         Pdu::WindowWorkspaceChanged(WindowWorkspaceChanged {
             window_id,
             workspace,
@@ -212,6 +293,32 @@ fn process_unilateral(
 
             return Ok(());
         }
+        Pdu::TabExited(TabExited { tab_id }) => {
+            log::trace!("remote tab {} exited", tab_id);
+            promise::spawn::spawn_into_main_thread(async move {
+                resync_domain(local_domain_id);
+            })
+            .detach();
+            return Ok(());
+        }
+        Pdu::GetPaneLayoutResponse(GetPaneLayoutResponse { tab_id, .. }) => {
+            // We learn of layout changes through WindowStructureChanged
+            // and the resync that it triggers
+            log::trace!("remote tab {} layout changed", tab_id);
+            return Ok(());
+        }
+        Pdu::TailLines(TailLines { tab_id, .. }) => {
+            // The domain doesn't follow tabs; the lines are for
+            // whoever asked for them with TailTab
+            log::trace!("remote tab {} output lines", tab_id);
+            return Ok(());
+        }
+        Pdu::Invalid { ident, .. } => {
+            // Most likely a newer server; skip it rather than
+            // tearing down the connection
+            log::debug!("ignoring unknown unilateral PDU ident {}", ident);
+            return Ok(());
+        }
         _ => {}
     }
 
@@ -230,22 +337,309 @@ fn process_unilateral(
 enum NotReconnectableError {
     #[error("Client was destroyed")]
     ClientWasDestroyed,
+    #[error("Authentication failed: {0}")]
+    AuthenticationFailed(String),
+}
+
+/// The server may split a large GetLinesResponse into several chunks
+/// that share the same serial.  Accumulate those chunks in `partial`
+/// and return the complete response once the final chunk arrives.
+/// Other responses are returned as-is.
+fn reassemble_chunks(
+    partial: &mut HashMap<u64, GetLinesResponse>,
+    serial: u64,
+    pdu: Pdu,
+) -> Option<Pdu> {
+    match pdu {
+        Pdu::GetLinesResponse(mut chunk) => {
+            if let Some(mut prior) = partial.remove(&serial) {
+                prior.lines.append(chunk.lines);
+                prior.more = chunk.more;
+                chunk = prior;
+            }
+            if chunk.more {
+                partial.insert(serial, chunk);
+                None
+            } else {
+                Some(Pdu::GetLinesResponse(chunk))
+            }
+        }
+        pdu => {
+            // Most likely an error response part way through
+            // a chunked response; discard any partial data
+            partial.remove(&serial);
+            Some(pdu)
+        }
+    }
+}
+
+/// Exchange nonces with the server and derive the session cipher.
+/// This happens straight after the codec version check on a new or
+/// re-established connection, so that everything else, including
+/// the auth token, is encrypted.
+async fn negotiate_encryption(
+    stream: &mut Box<dyn AsyncReadAndWrite>,
+    serial: u64,
+    key: &EncryptionKey,
+) -> anyhow::Result<FrameCipher> {
+    let client_nonce = generate_session_nonce()?;
+    Pdu::NegotiateEncryption(NegotiateEncryption {
+        client_nonce: client_nonce.clone(),
+    })
+    .encode_async(stream, serial)
+    .await
+    .context("sending NegotiateEncryption to server")?;
+    stream.flush().await.context("flushing PDU to server")?;
+    let decoded = Pdu::decode_async(stream)
+        .await
+        .context("reading NegotiateEncryption response")?;
+    match decoded.pdu {
+        Pdu::NegotiateEncryptionResponse(NegotiateEncryptionResponse { server_nonce })
+            if decoded.serial == serial =>
+        {
+            key.session_cipher(CipherRole::Client, &client_nonce, &server_nonce)
+        }
+        Pdu::ErrorResponse(ErrorResponse { reason, .. }) => {
+            bail!("server refused to negotiate encryption: {}", reason)
+        }
+        pdu => bail!("unexpected response to NegotiateEncryption: {:?}", pdu),
+    }
+}
+
+/// Read the response to a request that is made while the connection
+/// is being set up.  The server pushes changes to the client as soon
+/// as the session is usable, which may be before the client has
+/// finished setting it up, so the PDUs that it sends unprompted in
+/// the meantime are processed just as they are once connected.
+async fn read_setup_response(
+    stream: &mut Box<dyn AsyncReadAndWrite>,
+    framing: &mut Framing,
+    blobs: &mut BlobAssembler,
+    local_domain_id: Option<DomainId>,
+) -> anyhow::Result<DecodedPdu> {
+    loop {
+        let (decoded, _) = Pdu::decode_async_framed(stream, framing).await?;
+        let decoded = match decoded.pdu {
+            Pdu::BlobChunk(chunk) => match blobs
+                .receive(chunk, framing.max_frame_len)
+                .context("reassembling a blob from the server")?
+            {
+                Some(decoded) => decoded,
+                None => continue,
+            },
+            pdu => DecodedPdu {
+                pdu,
+                serial: decoded.serial,
+            },
+        };
+        if decoded.serial != 0 {
+            return Ok(decoded);
+        }
+        process_unilateral(local_domain_id, decoded)
+            .context("processing unilateral PDU from server")?;
+    }
+}
+
+/// Ask the server for its codec version, telling it ours.  This is
+/// the first request on a new or re-established connection, as the
+/// rest of the setup is only understood by a compatible server.
+/// Returns false if the server is incompatible, which is reported
+/// to the user by `verify_version_compat` once connected.
+async fn check_codec_version(
+    stream: &mut Box<dyn AsyncReadAndWrite>,
+    framing: &mut Framing,
+    blobs: &mut BlobAssembler,
+    local_domain_id: Option<DomainId>,
+    serial: u64,
+) -> anyhow::Result<bool> {
+    Pdu::GetCodecVersion(GetCodecVersion {
+        codec_vers: Some(CODEC_VERSION),
+    })
+    .encode_async_framed(stream, serial, framing)
+    .await
+    .context("sending GetCodecVersion to server")?;
+    stream.flush().await.context("flushing PDU to server")?;
+    let decoded = read_setup_response(stream, framing, blobs, local_domain_id)
+        .await
+        .context("reading GetCodecVersion response")?;
+    match decoded.pdu {
+        Pdu::GetCodecVersionResponse(info) if decoded.serial == serial => {
+            Ok(info.codec_vers == CODEC_VERSION)
+        }
+        Pdu::IncompatibleCodec(_) | Pdu::ErrorResponse(_) if decoded.serial == serial => Ok(false),
+        pdu => bail!("unexpected response to GetCodecVersion: {:?}", pdu),
+    }
+}
+
+/// Present our auth token to the server.  This happens before any
+/// request other than the version check and encryption setup is
+/// sent on a new or re-established connection.
+async fn authenticate(
+    stream: &mut Box<dyn AsyncReadAndWrite>,
+    framing: &mut Framing,
+    blobs: &mut BlobAssembler,
+    local_domain_id: Option<DomainId>,
+    serial: u64,
+    token: String,
+) -> anyhow::Result<()> {
+    Pdu::Authenticate(Authenticate { token })
+        .encode_async_framed(stream, serial, framing)
+        .await
+        .context("sending Authenticate to server")?;
+    stream.flush().await.context("flushing PDU to server")?;
+    let decoded = read_setup_response(stream, framing, blobs, local_domain_id)
+        .await
+        .context("reading Authenticate response")?;
+    match decoded.pdu {
+        Pdu::UnitResponse(_) if decoded.serial == serial => Ok(()),
+        Pdu::ErrorResponse(ErrorResponse { reason, .. }) => {
+            Err(NotReconnectableError::AuthenticationFailed(reason).into())
+        }
+        pdu => bail!("unexpected response to Authenticate: {:?}", pdu),
+    }
+}
+
+/// Ask the server to enable an optional feature of the protocol.
+/// Returns true if it agreed; an older server will reject the
+/// request, in which case the feature remains disabled.
+async fn request_protocol_feature(
+    stream: &mut Box<dyn AsyncReadAndWrite>,
+    framing: &mut Framing,
+    blobs: &mut BlobAssembler,
+    local_domain_id: Option<DomainId>,
+    serial: u64,
+    request: Pdu,
+) -> anyhow::Result<bool> {
+    let name = request.pdu_name();
+    request
+        .encode_async_framed(stream, serial, framing)
+        .await
+        .with_context(|| format!("sending {} to server", name))?;
+    stream.flush().await.context("flushing PDU to server")?;
+    let decoded = read_setup_response(stream, framing, blobs, local_domain_id)
+        .await
+        .with_context(|| format!("reading {} response", name))?;
+    match decoded.pdu {
+        Pdu::UnitResponse(_) if decoded.serial == serial => Ok(true),
+        Pdu::ErrorResponse(ErrorResponse { reason, .. }) if decoded.serial == serial => {
+            log::debug!("server does not support {}: {}", name, reason);
+            Ok(false)
+        }
+        Pdu::UnimplementedResponse(_) if decoded.serial == serial => {
+            log::debug!("server does not support {}", name);
+            Ok(false)
+        }
+        pdu => bail!("unexpected response to {}: {:?}", name, pdu),
+    }
+}
+
+/// Ask the server to checksum the frames that it sends, and if it
+/// agrees, start checksumming the frames that we send.  An older
+/// server will reject the request, in which case neither side
+/// checksums its frames.
+async fn negotiate_checksums(
+    stream: &mut Box<dyn AsyncReadAndWrite>,
+    framing: &mut Framing,
+    blobs: &mut BlobAssembler,
+    local_domain_id: Option<DomainId>,
+    serial: u64,
+) -> anyhow::Result<()> {
+    let request = Pdu::EnableFrameChecksums(EnableFrameChecksums {});
+    if request_protocol_feature(stream, framing, blobs, local_domain_id, serial, request).await? {
+        framing.checksum = true;
+    }
+    Ok(())
+}
+
+/// Negotiate the encryption, authentication and optional protocol
+/// features of a new or re-established connection to a compatible
+/// server, in that order
+async fn set_up_session(
+    reconnectable: &mut Reconnectable,
+    stream: &mut Box<dyn AsyncReadAndWrite>,
+    framing: &mut Framing,
+    blobs: &mut BlobAssembler,
+    local_domain_id: Option<DomainId>,
+    serials: &mut SerialSpace,
+) -> anyhow::Result<()> {
+    if let Some(key) = reconnectable.encryption_key()? {
+        framing.cipher = Some(negotiate_encryption(stream, serials.next_serial(), &key).await?);
+    }
+
+    if let Some(token) = reconnectable.auth_token()? {
+        authenticate(
+            stream,
+            framing,
+            blobs,
+            local_domain_id,
+            serials.next_serial(),
+            token,
+        )
+        .await?;
+    }
+
+    negotiate_checksums(
+        stream,
+        framing,
+        blobs,
+        local_domain_id,
+        serials.next_serial(),
+    )
+    .await?;
+
+    let request = Pdu::EnableBlobChannel(EnableBlobChannel {});
+    request_protocol_feature(
+        stream,
+        framing,
+        blobs,
+        local_domain_id,
+        serials.next_serial(),
+        request,
+    )
+    .await?;
+
+    // Compress the frames in both directions with a stream that spans
+    // the connection, which captures the redundancy between frames
+    let request = Pdu::EnableStreamCompression(EnableStreamCompression {});
+    if request_protocol_feature(
+        stream,
+        framing,
+        blobs,
+        local_domain_id,
+        serials.next_serial(),
+        request,
+    )
+    .await?
+    {
+        framing.compressor = Some(StreamCompressor::new()?);
+        framing.decompressor = Some(StreamDecompressor::new()?);
+    }
+    Ok(())
 }
 
 fn client_thread(
     reconnectable: &mut Reconnectable,
     local_domain_id: Option<DomainId>,
     rx: &mut Receiver<ReaderMessage>,
+    epoch: u64,
 ) -> anyhow::Result<()> {
-    block_on(client_thread_async(reconnectable, local_domain_id, rx))
+    block_on(client_thread_async(
+        reconnectable,
+        local_domain_id,
+        rx,
+        epoch,
+    ))
 }
 
 async fn client_thread_async(
     reconnectable: &mut Reconnectable,
     local_domain_id: Option<DomainId>,
     rx: &mut Receiver<ReaderMessage>,
+    epoch: u64,
 ) -> anyhow::Result<()> {
-    let mut next_serial = 1u64;
+    // The epoch is bumped for each connection, so that a response
+    // to a request from a prior connection can be recognized
+    let mut serials = SerialSpace::new(epoch);
 
     struct Promises {
         map: HashMap<u64, Sender<anyhow::Result<Pdu>>>,
@@ -268,9 +662,38 @@ async fn client_thread_async(
     let mut promises = Promises {
         map: HashMap::new(),
     };
+    let mut partial_lines = HashMap::new();
 
     let mut stream = reconnectable.take_stream().unwrap();
 
+    let mut framing = Framing::default();
+    framing.max_frame_len = configuration().mux_max_frame_size;
+    // Large responses are sent in chunks, interleaved with the other
+    // PDUs, once the blob channel has been enabled below
+    let mut blobs = BlobAssembler::default();
+    // An incompatible server wouldn't understand the rest of the
+    // setup, so skip it; verify_version_compat asks for the version
+    // again once connected, and reports the mismatch to the user
+    if check_codec_version(
+        &mut stream,
+        &mut framing,
+        &mut blobs,
+        local_domain_id,
+        serials.next_serial(),
+    )
+    .await?
+    {
+        set_up_session(
+            reconnectable,
+            &mut stream,
+            &mut framing,
+            &mut blobs,
+            local_domain_id,
+            &mut serials,
+        )
+        .await?;
+    }
+
     loop {
         let rx_msg = rx.recv();
         let wait_for_read = stream
@@ -279,43 +702,77 @@ async fn client_thread_async(
 
         match smol::future::or(rx_msg, wait_for_read).await {
             Ok(ReaderMessage::SendPdu { pdu, promise }) => {
-                let serial = next_serial;
-                next_serial += 1;
+                let serial = serials.next_serial();
                 promises.map.insert(serial, promise);
 
-                pdu.encode_async(&mut stream, serial)
+                pdu.encode_async_framed(&mut stream, serial, &mut framing)
                     .await
                     .context("encoding a PDU to send to the server")?;
                 stream.flush().await.context("flushing PDU to server")?;
             }
-            Ok(ReaderMessage::Readable) => match Pdu::decode_async(&mut stream).await {
-                Ok(decoded) => {
-                    log::trace!("decoded serial {}", decoded.serial);
-                    if decoded.serial == 0 {
-                        process_unilateral(local_domain_id, decoded)
-                            .context("processing unilateral PDU from server")
-                            .map_err(|e| {
-                                log::error!("process_unilateral: {:?}", e);
-                                e
-                            })?;
-                    } else if let Some(promise) = promises.map.remove(&decoded.serial) {
-                        if promise.try_send(Ok(decoded.pdu)).is_err() {
-                            return Err(NotReconnectableError::ClientWasDestroyed.into());
+            Ok(ReaderMessage::Readable) => {
+                match Pdu::decode_async_framed(&mut stream, &mut framing).await {
+                    Ok((decoded, _)) => {
+                        let decoded = match decoded.pdu {
+                            Pdu::BlobChunk(chunk) => match blobs
+                                .receive(chunk, framing.max_frame_len)
+                                .context("reassembling a blob from the server")?
+                            {
+                                Some(decoded) => decoded,
+                                None => continue,
+                            },
+                            pdu => DecodedPdu {
+                                pdu,
+                                serial: decoded.serial,
+                            },
+                        };
+                        log::trace!("decoded serial {}", decoded.serial);
+                        if decoded.serial == 0 {
+                            process_unilateral(local_domain_id, decoded)
+                                .context("processing unilateral PDU from server")
+                                .map_err(|e| {
+                                    log::error!("process_unilateral: {:?}", e);
+                                    e
+                                })?;
+                        } else if !serials.is_current(decoded.serial) {
+                            // Its request was sent on an earlier connection,
+                            // and its promise was failed when that was lost
+                            log::debug!(
+                                "discarding response with serial {} from epoch {}; \
+                                 current epoch is {}",
+                                decoded.serial,
+                                decoded.serial_epoch(),
+                                serials.epoch()
+                            );
+                        } else if promises.map.contains_key(&decoded.serial) {
+                            if let Some(pdu) =
+                                reassemble_chunks(&mut partial_lines, decoded.serial, decoded.pdu)
+                            {
+                                let promise = promises.map.remove(&decoded.serial).unwrap();
+                                if promise.try_send(Ok(pdu)).is_err() {
+                                    return Err(NotReconnectableError::ClientWasDestroyed.into());
+                                }
+                            }
+                        } else {
+                            let reason =
+                                format!("got serial {:?} without a corresponding promise", decoded);
+                            promises.fail_all(&reason);
+                            anyhow::bail!("{}", reason);
                         }
-                    } else {
-                        let reason =
-                            format!("got serial {:?} without a corresponding promise", decoded);
+                    }
+                    Err(err) => {
+                        if err.downcast_ref::<ChecksumMismatch>().is_some()
+                            || err.downcast_ref::<MissingChecksum>().is_some()
+                        {
+                            log::error!("frame from server failed its checksum; disconnecting");
+                        }
+                        let reason = format!("Error while decoding response pdu: {:#}", err);
+                        log::error!("{}", reason);
                         promises.fail_all(&reason);
-                        anyhow::bail!("{}", reason);
+                        return Err(err).context("Error while decoding response pdu");
                     }
                 }
-                Err(err) => {
-                    let reason = format!("Error while decoding response pdu: {:#}", err);
-                    log::error!("{}", reason);
-                    promises.fail_all(&reason);
-                    return Err(err).context("Error while decoding response pdu");
-                }
-            },
+            }
             Err(_) => {
                 return Err(NotReconnectableError::ClientWasDestroyed.into());
             }
@@ -493,6 +950,20 @@ impl Reconnectable {
         Ok(self.tls_creds_path()?.join("cert.pem"))
     }
 
+    fn auth_token(&self) -> anyhow::Result<Option<String>> {
+        match self.config.auth_token_file() {
+            Some(path) => Ok(Some(config::read_auth_token(path)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn encryption_key(&self) -> anyhow::Result<Option<EncryptionKey>> {
+        match self.config.encryption_key_file() {
+            Some(path) => Ok(Some(EncryptionKey::load(path)?)),
+            None => Ok(None),
+        }
+    }
+
     fn take_stream(&mut self) -> Option<Box<dyn AsyncReadAndWrite>> {
         self.stream.take()
     }
@@ -920,8 +1391,12 @@ impl Client {
             const MAX_INTERVAL: Duration = Duration::from_secs(10);
 
             let mut backoff = BASE_INTERVAL;
+            let mut epoch = 0;
             loop {
-                if let Err(e) = client_thread(&mut reconnectable, local_domain_id, &mut receiver) {
+                epoch += 1;
+                if let Err(e) =
+                    client_thread(&mut reconnectable, local_domain_id, &mut receiver, epoch)
+                {
                     if !reconnectable.reconnectable() || local_domain_id.is_none() {
                         log::debug!("client thread ended: {}", e);
                         break;
@@ -1007,6 +1482,8 @@ impl Client {
             is_reconnectable,
             is_local,
             client_id,
+            last_latency: Arc::new(Mutex::new(None)),
+            row_hash: Arc::new(Mutex::new(None)),
         }
     }
 
@@ -1014,20 +1491,33 @@ impl Client {
         &self,
         ui: &ConnectionUI,
     ) -> anyhow::Result<GetCodecVersionResponse> {
-        match self.get_codec_version(GetCodecVersion {}).await {
-            Ok(info) if info.codec_vers == CODEC_VERSION => {
+        let result = self
+            .send_pdu(Pdu::GetCodecVersion(GetCodecVersion {
+                codec_vers: Some(CODEC_VERSION),
+            }))
+            .await;
+        match result {
+            Ok(Pdu::GetCodecVersionResponse(info)) if info.codec_vers == CODEC_VERSION => {
                 log::trace!(
                     "Server version is {} (codec version {})",
                     info.version_string,
                     info.codec_vers
                 );
+                *self.row_hash.lock().unwrap() = info.row_hash;
                 self.set_client_id(SetClientId {
                     client_id: self.client_id.clone(),
                 })
                 .await?;
+                self.set_client_info(SetClientInfo {
+                    label: configuration().mux_client_label.clone(),
+                    pid: self.client_id.pid,
+                    hostname: self.client_id.hostname.clone(),
+                    read_only: configuration().mux_client_read_only,
+                })
+                .await?;
                 Ok(info)
             }
-            Ok(info) => {
+            Ok(Pdu::GetCodecVersionResponse(info)) => {
                 let err = IncompatibleVersionError {
                     version: info.version_string,
                     codec_vers: info.codec_vers,
@@ -1036,6 +1526,17 @@ impl Client {
                 log::error!("{:?}", err);
                 return Err(err.into());
             }
+            Ok(Pdu::IncompatibleCodec(info)) => {
+                let err = UnsupportedCodecError {
+                    version: info.server_vers,
+                    min_supported: info.min_supported,
+                    max_supported: info.max_supported,
+                };
+                ui.output_str(&err.to_string());
+                log::error!("{:?}", err);
+                return Err(err.into());
+            }
+            Ok(pdu) => bail!("unexpected response {:?}", pdu),
             Err(err) => {
                 let msg = format!(
                     "Please install the same version of wezterm on both \
@@ -1051,6 +1552,47 @@ impl Client {
         }
     }
 
+    /// Ping the server to measure the round trip latency of the
+    /// connection, recording the result so that it can later be
+    /// retrieved via `last_latency`
+    pub async fn measure_latency(&self) -> anyhow::Result<Latency> {
+        let start = Instant::now();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let pong = self
+            .ping(Ping {
+                timestamp: Some(timestamp),
+            })
+            .await?;
+        if pong.timestamp != Some(timestamp) {
+            bail!(
+                "server echoed ping timestamp {:?} rather than {}",
+                pong.timestamp,
+                timestamp
+            );
+        }
+        let latency = Latency {
+            round_trip: start.elapsed(),
+            server_queue_depth: pong.queue_depth,
+            server_busy: pong.busy,
+        };
+        self.last_latency.lock().unwrap().replace(latency);
+        Ok(latency)
+    }
+
+    /// Returns the most recent result from `measure_latency`
+    pub fn last_latency(&self) -> Option<Latency> {
+        *self.last_latency.lock().unwrap()
+    }
+
+    /// Returns the algorithm with which the server compares the rows
+    /// that we already have, as it told us in `verify_version_compat`
+    pub fn row_hash(&self) -> Option<RowHashAlgorithm> {
+        *self.row_hash.lock().unwrap()
+    }
+
     #[allow(dead_code)]
     pub fn local_domain_id(&self) -> Option<DomainId> {
         self.local_domain_id
@@ -1146,15 +1688,32 @@ impl Client {
         rx.recv().await?
     }
 
-    rpc!(ping, Ping = (), Pong);
-    rpc!(list_panes, ListPanes = (), ListPanesResponse);
+    rpc!(ping, Ping, Pong);
+    rpc!(list_panes, ListPanes, ListPanesResponse);
+    rpc!(get_tab, GetTab, GetTabResponse);
+    rpc!(get_windows, GetWindows = (), GetWindowsResponse);
     rpc!(spawn_v2, SpawnV2, SpawnResponse);
+    rpc!(validate_spawn, ValidateSpawn, ValidateSpawnResponse);
+    rpc!(restore_tab_state, RestoreTabState, SpawnResponse);
     rpc!(split_pane, SplitPane, SpawnResponse);
+    rpc!(spawn_split, SpawnSplit, SpawnSplitResponse);
     rpc!(write_to_pane, WriteToPane, UnitResponse);
+    rpc!(write_text_to_pane, WriteTextToPane, UnitResponse);
+    rpc!(try_write_to_pane, TryWriteToPane, WriteToPaneResponse);
     rpc!(send_paste, SendPaste, UnitResponse);
     rpc!(key_down, SendKeyDown, UnitResponse);
+    rpc!(key_down_batch, SendKeyDownBatch, UnitResponse);
+    rpc!(send_text, SendText, UnitResponse);
     rpc!(mouse_event, SendMouseEvent, UnitResponse);
-    rpc!(resize, Resize, UnitResponse);
+    rpc!(resize, Resize, ResizeResponse);
+    rpc!(resize_window, ResizeWindow, UnitResponse);
+    rpc!(trim_scrollback, TrimScrollback, UnitResponse);
+    rpc!(
+        export_scrollback,
+        ExportScrollback,
+        ExportScrollbackResponse
+    );
+    rpc!(set_selection, SetSelection, UnitResponse);
     rpc!(set_zoomed, SetPaneZoomed, UnitResponse);
     rpc!(
         get_tab_render_changes,
@@ -1172,5 +1731,51 @@ impl Client {
     rpc!(kill_pane, KillPane, UnitResponse);
     rpc!(set_client_id, SetClientId, UnitResponse);
     rpc!(list_clients, GetClientList, GetClientListResponse);
+    rpc!(set_client_info, SetClientInfo, UnitResponse);
+    rpc!(list_domains, ListDomains = (), ListDomainsResponse);
+    rpc!(detach_domain, DetachDomain, UnitResponse);
+    rpc!(attach_domain, AttachDomain, ListPanesResponse);
+    rpc!(list_attached_clients, ListClients = (), ListClientsResponse);
+    rpc!(list_sessions, ListSessions = (), ListSessionsResponse);
+    rpc!(kill_session, KillSession, UnitResponse);
+    rpc!(set_write_access, SetWriteAccess, UnitResponse);
+    rpc!(image_ack, ImageAck, UnitResponse);
+    rpc!(invalidate_tab, InvalidateTab, UnitResponse);
+    rpc!(set_focused_tab, SetFocusedTab, UnitResponse);
+    rpc!(freeze_tab, FreezeTab, UnitResponse);
+    rpc!(barrier, Barrier, BarrierResponse);
+    rpc!(reorder_tab, ReorderTab, UnitResponse);
+    rpc!(set_tab_title_policy, SetTabTitlePolicy, UnitResponse);
+    rpc!(drain_tab, DrainTab, UnitResponse);
+    rpc!(get_pane_layout, GetPaneLayout, GetPaneLayoutResponse);
+    rpc!(close_window, CloseWindow, UnitResponse);
+    rpc!(new_window, NewWindow, NewWindowResponse);
+    rpc!(begin_paste, BeginPaste, BeginPasteResponse);
+    rpc!(paste_chunk, PasteChunk, UnitResponse);
+    rpc!(end_paste, EndPaste, UnitResponse);
+    rpc!(
+        get_tab_screenshot,
+        GetTabScreenshot,
+        GetTabScreenshotResponse
+    );
+    rpc!(get_env, GetEnv, GetEnvResponse);
     rpc!(set_window_workspace, SetWindowWorkspace, UnitResponse);
+    rpc!(get_server_stats, GetServerStats, GetServerStatsResponse);
+    rpc!(
+        get_capabilities,
+        GetCapabilities = (),
+        GetCapabilitiesResponse
+    );
+    rpc!(get_tab_stats, GetTabStats, GetTabStatsResponse);
+    rpc!(get_cell_metrics, GetCellMetrics, GetCellMetricsResponse);
+    rpc!(set_cell_metrics, SetCellMetrics, GetCellMetricsResponse);
+    rpc!(trickle_scrollback, TrickleScrollback, UnitResponse);
+    rpc!(tail_tab, TailTab, TailTabResponse);
+    rpc!(stop_tail, StopTail, UnitResponse);
+    rpc!(subscribe_clipboard, SubscribeClipboard, UnitResponse);
+    rpc!(
+        serialize_tab_state,
+        SerializeTabState,
+        SerializeTabStateResponse
+    );
 }
@@ -1,8 +1,9 @@
 use crate::client::Client;
+use crate::imagecache::ImageCache;
 use crate::pane::ClientPane;
 use anyhow::{anyhow, bail};
 use async_trait::async_trait;
-use codec::{ListPanesResponse, SpawnV2, SplitPane};
+use codec::{ListPanes, ListPanesResponse, SpawnV2, SplitPane};
 use config::keyassignment::SpawnTabDomain;
 use config::{SshDomain, TlsDomainClient, UnixDomain};
 use mux::connui::ConnectionUI;
@@ -15,6 +16,7 @@ use portable_pty::{CommandBuilder, PtySize};
 use promise::spawn::spawn_into_new_thread;
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::path::Path;
 use std::rc::Rc;
 use std::sync::{Arc, Mutex};
 
@@ -26,6 +28,7 @@ pub struct ClientInner {
     remote_to_local_window: Mutex<HashMap<WindowId, WindowId>>,
     remote_to_local_tab: Mutex<HashMap<TabId, TabId>>,
     remote_to_local_pane: Mutex<HashMap<PaneId, PaneId>>,
+    pub images: ImageCache,
 }
 
 impl ClientInner {
@@ -151,6 +154,22 @@ impl ClientDomainConfig {
         }
     }
 
+    pub fn auth_token_file(&self) -> Option<&Path> {
+        match self {
+            ClientDomainConfig::Unix(unix) => unix.auth_token_file.as_deref(),
+            ClientDomainConfig::Tls(tls) => tls.auth_token_file.as_deref(),
+            ClientDomainConfig::Ssh(_) => None,
+        }
+    }
+
+    pub fn encryption_key_file(&self) -> Option<&Path> {
+        match self {
+            ClientDomainConfig::Unix(unix) => unix.encryption_key_file.as_deref(),
+            // These are already encrypted by their transport
+            ClientDomainConfig::Tls(_) | ClientDomainConfig::Ssh(_) => None,
+        }
+    }
+
     pub fn connect_automatically(&self) -> bool {
         match self {
             ClientDomainConfig::Unix(unix) => unix.connect_automatically,
@@ -179,6 +198,7 @@ impl ClientInner {
             remote_to_local_window: Mutex::new(HashMap::new()),
             remote_to_local_tab: Mutex::new(HashMap::new()),
             remote_to_local_pane: Mutex::new(HashMap::new()),
+            images: ImageCache::default(),
         }
     }
 }
@@ -303,7 +323,12 @@ impl ClientDomain {
     pub async fn reattach(domain_id: DomainId, ui: ConnectionUI) -> anyhow::Result<()> {
         let inner = Self::get_client_inner_for_domain(domain_id)?;
 
-        let panes = inner.client.list_panes().await?;
+        let panes = inner
+            .client
+            .list_panes(ListPanes {
+                initial_window_id: None,
+            })
+            .await?;
         Self::process_pane_list(inner, panes)?;
 
         ui.close();
@@ -312,7 +337,12 @@ impl ClientDomain {
 
     pub async fn resync(&self) -> anyhow::Result<()> {
         if let Some(inner) = self.inner.borrow().as_ref() {
-            let panes = inner.client.list_panes().await?;
+            let panes = inner
+                .client
+                .list_panes(ListPanes {
+                    initial_window_id: None,
+                })
+                .await?;
             Self::process_pane_list(Arc::clone(inner), panes)?;
         }
         Ok(())
@@ -374,13 +404,16 @@ impl ClientDomain {
                             }
                         }
                     } else {
-                        let pane: Rc<dyn Pane> = Rc::new(ClientPane::new(
+                        let pane = ClientPane::new(
                             &inner,
                             entry.tab_id,
                             entry.pane_id,
                             entry.size,
                             &entry.title,
-                        ));
+                        );
+                        pane.request_viewport();
+                        pane.start_scrollback_trickle();
+                        let pane: Rc<dyn Pane> = Rc::new(pane);
                         log::debug!(
                             "attaching to remote pane {:?} -> local pane_id {}",
                             entry,
@@ -477,18 +510,15 @@ impl Domain for ClientDomain {
                 command,
                 command_dir,
                 workspace,
+                hold: false,
             })
             .await?;
 
         inner.record_remote_to_local_window_mapping(result.window_id, window);
 
-        let pane: Rc<dyn Pane> = Rc::new(ClientPane::new(
-            &inner,
-            result.tab_id,
-            result.pane_id,
-            size,
-            "wezterm",
-        ));
+        let pane = ClientPane::new(&inner, result.tab_id, result.pane_id, size, "wezterm");
+        pane.set_initial_dimensions(result.dimensions);
+        let pane: Rc<dyn Pane> = Rc::new(pane);
         let tab = Rc::new(Tab::new(&size));
         tab.assign_pane(&pane);
         inner.remove_old_tab_mapping(result.tab_id);
@@ -536,13 +566,15 @@ impl Domain for ClientDomain {
             })
             .await?;
 
-        let pane: Rc<dyn Pane> = Rc::new(ClientPane::new(
+        let pane = ClientPane::new(
             &inner,
             result.tab_id,
             result.pane_id,
             result.size,
             "wezterm",
-        ));
+        );
+        pane.set_initial_dimensions(result.dimensions);
+        let pane: Rc<dyn Pane> = Rc::new(pane);
 
         let pane_index = match tab
             .iter_panes()
@@ -599,7 +631,11 @@ impl Domain for ClientDomain {
                 client.verify_version_compat(&ui).await?;
 
                 ui.output_str("Version check OK!  Requesting pane list...\n");
-                let panes = client.list_panes().await?;
+                let panes = client
+                    .list_panes(ListPanes {
+                        initial_window_id: None,
+                    })
+                    .await?;
                 ui.output_str(&format!(
                     "Server has {} tabs.  Attaching to local UI...\n",
                     panes.tabs.len()
@@ -0,0 +1,62 @@
+use codec::{ImageAck, ImageHash, SerializedLines};
+use lru::LruCache;
+use std::sync::{Arc, Mutex};
+use termwiz::image::ImageData;
+use wezterm_term::StableRowIndex;
+
+/// The maximum number of images that are retained in the cache
+const MAX_CACHED_IMAGES: usize = 256;
+
+/// Holds the images that the server has sent to us, keyed by
+/// the hash of their data.  The server is told which images we
+/// hold so that it can send references to them rather than their
+/// data; when we evict an image the server is told about that too,
+/// so that it will send the data again if it is needed.
+pub struct ImageCache {
+    images: Mutex<LruCache<ImageHash, Arc<ImageData>>>,
+}
+
+impl Default for ImageCache {
+    fn default() -> Self {
+        Self {
+            images: Mutex::new(LruCache::new(MAX_CACHED_IMAGES)),
+        }
+    }
+}
+
+impl ImageCache {
+    /// Cache the image data contained in `lines` and fill in the
+    /// data for the images that the server omitted because we
+    /// had previously acknowledged them.
+    /// Returns the acknowledgement to send to the server, if any,
+    /// along with the rows that reference images that we no longer
+    /// have; those rows need to be fetched again.
+    pub fn receive(&self, lines: &mut SerializedLines) -> (Option<ImageAck>, Vec<StableRowIndex>) {
+        let mut images = self.images.lock().unwrap();
+        let mut ack = ImageAck {
+            ids: vec![],
+            evicted: vec![],
+        };
+
+        for (hash, data) in lines.image_data() {
+            if images.get(hash).is_some() {
+                continue;
+            }
+            ack.ids.push(*hash);
+            if let Some((evicted, _)) = images.push(*hash, Arc::clone(data)) {
+                ack.evicted.push(evicted);
+            }
+        }
+
+        let (missing, rows) = lines.resolve_image_data(|hash| images.get(hash).cloned());
+        ack.evicted.extend(missing);
+        let evicted = &ack.evicted;
+        ack.ids.retain(|hash| !evicted.contains(hash));
+
+        if ack.ids.is_empty() && ack.evicted.is_empty() {
+            (None, rows)
+        } else {
+            (Some(ack), rows)
+        }
+    }
+}
@@ -6,4 +6,5 @@ use uds_windows::UnixStream;
 pub mod client;
 pub mod discovery;
 pub mod domain;
+pub mod imagecache;
 pub mod pane;
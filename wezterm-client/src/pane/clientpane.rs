@@ -18,11 +18,18 @@ use std::collections::HashMap;
 use std::ops::Range;
 use std::rc::Rc;
 use std::sync::Arc;
+use termwiz::escape::osc::Progress;
 use termwiz::input::KeyEvent;
 use termwiz::surface::SequenceNo;
 use url::Url;
 use wezterm_term::color::ColorPalette;
-use wezterm_term::{Alert, Clipboard, KeyCode, KeyModifiers, Line, MouseEvent, StableRowIndex};
+use wezterm_term::{
+    Alert, CellAttributes, Clipboard, KeyCode, KeyModifiers, Line, MouseEvent, StableRowIndex,
+    TerminalModes,
+};
+
+/// Pastes that are larger than this are sent in chunks of this size
+const PASTE_CHUNK_SIZE: usize = 64 * 1024;
 
 pub struct ClientPane {
     client: Arc<ClientInner>,
@@ -31,12 +38,80 @@ pub struct ClientPane {
     pub remote_tab_id: TabId,
     pub renderable: RefCell<RenderableState>,
     palette: RefCell<ColorPalette>,
+    progress: RefCell<Progress>,
     writer: RefCell<PaneWriter>,
     mouse: Rc<RefCell<MouseState>>,
     clipboard: RefCell<Option<Arc<dyn Clipboard>>>,
     mouse_grabbed: RefCell<bool>,
+    bracketed_paste: RefCell<bool>,
+    terminal_modes: RefCell<TerminalModes>,
+    selection: RefCell<Option<SelectionRange>>,
+    pending_keys: Rc<RefCell<PendingKeys>>,
     ignore_next_kill: RefCell<bool>,
     user_vars: RefCell<HashMap<String, String>>,
+    /// The damage_seqno of the most recent render changes
+    damage_seqno: RefCell<Option<u64>>,
+}
+
+/// Key presses that have not yet been sent to the server.
+/// While a send is in flight, further key presses accumulate here
+/// and are then sent together in a single SendKeyDownBatch, which
+/// keeps the number of round trips down when typing quickly over
+/// a high latency link.
+struct PendingKeys {
+    events: Vec<KeyEvent>,
+    input_serial: InputSerial,
+    sending: bool,
+}
+
+impl Default for PendingKeys {
+    fn default() -> Self {
+        Self {
+            events: vec![],
+            input_serial: InputSerial::empty(),
+            sending: false,
+        }
+    }
+}
+
+async fn send_pending_keys(
+    client: Arc<ClientInner>,
+    remote_pane_id: PaneId,
+    pending: Rc<RefCell<PendingKeys>>,
+) {
+    loop {
+        let (mut events, input_serial) = {
+            let mut pending = pending.borrow_mut();
+            if pending.events.is_empty() {
+                pending.sending = false;
+                return;
+            }
+            (std::mem::take(&mut pending.events), pending.input_serial)
+        };
+
+        let result = if events.len() == 1 {
+            client
+                .client
+                .key_down(SendKeyDown {
+                    pane_id: remote_pane_id,
+                    event: events.pop().unwrap(),
+                    input_serial,
+                })
+                .await
+        } else {
+            client
+                .client
+                .key_down_batch(SendKeyDownBatch {
+                    pane_id: remote_pane_id,
+                    events,
+                    input_serial,
+                })
+                .await
+        };
+        if let Err(err) = result {
+            log::debug!("failed to send keys to pane {}: {:#}", remote_pane_id, err);
+        }
+    }
 }
 
 impl ClientPane {
@@ -90,26 +165,115 @@ impl ClientPane {
             renderable: RefCell::new(render),
             writer: RefCell::new(writer),
             palette: RefCell::new(palette),
+            progress: RefCell::new(Progress::default()),
             clipboard: RefCell::new(None),
             mouse_grabbed: RefCell::new(false),
+            bracketed_paste: RefCell::new(false),
+            terminal_modes: RefCell::new(TerminalModes::default()),
+            selection: RefCell::new(None),
+            pending_keys: Rc::new(RefCell::new(PendingKeys::default())),
             ignore_next_kill: RefCell::new(false),
             user_vars: RefCell::new(HashMap::new()),
+            damage_seqno: RefCell::new(None),
         }
     }
 
+    /// Replace the dimensions that `new` guessed from the pty size
+    /// with those that the server reported when it spawned the pane
+    pub fn set_initial_dimensions(&self, dimensions: RenderableDimensions) {
+        self.renderable.borrow().inner.borrow_mut().dimensions = dimensions;
+    }
+
+    /// Ask the server to push the complete state of the tab again
+    fn resync_tab(&self) {
+        let client = Arc::clone(&self.client);
+        let remote_tab_id = self.remote_tab_id;
+        promise::spawn::spawn(async move {
+            client
+                .client
+                .invalidate_tab(InvalidateTab {
+                    tab_id: remote_tab_id,
+                })
+                .await
+        })
+        .detach();
+    }
+
+    /// Ask the server to push the viewport of the pane to us, without
+    /// any of the scrollback, so that we can paint it right away
+    pub fn request_viewport(&self) {
+        let client = Arc::clone(&self.client);
+        let request = GetPaneRenderChanges {
+            pane_id: self.remote_pane_id,
+            since_input_serial: None,
+            include_bonus_lines: true,
+            viewport_only: true,
+        };
+        promise::spawn::spawn(async move { client.client.get_tab_render_changes(request).await })
+            .detach();
+    }
+
+    /// Ask the server to push the scrollback of the pane to us in the
+    /// background, if `mux_trickle_scrollback` is enabled
+    pub fn start_scrollback_trickle(&self) {
+        let config = configuration();
+        if !config.mux_trickle_scrollback {
+            return;
+        }
+        let client = Arc::clone(&self.client);
+        let request = TrickleScrollback {
+            pane_id: self.remote_pane_id,
+            // Any more than this would evict rows from our cache
+            max_rows: config.scrollback_lines,
+        };
+        promise::spawn::spawn(async move { client.client.trickle_scrollback(request).await })
+            .detach();
+    }
+
     pub fn process_unilateral(&self, pdu: Pdu) -> anyhow::Result<()> {
         match pdu {
             Pdu::GetPaneRenderChangesResponse(delta) => {
+                let last_seqno = self.damage_seqno.borrow_mut().replace(delta.damage_seqno);
+                if let Some(last_seqno) = last_seqno {
+                    if delta.damage_seqno != last_seqno + 1 {
+                        log::warn!(
+                            "pane {} missed render changes: expected damage seqno {} \
+                             but received {}; resyncing its tab",
+                            self.local_pane_id,
+                            last_seqno + 1,
+                            delta.damage_seqno
+                        );
+                        self.resync_tab();
+                    }
+                }
                 *self.mouse_grabbed.borrow_mut() = delta.mouse_grabbed;
+                *self.bracketed_paste.borrow_mut() = delta.bracketed_paste;
+                *self.terminal_modes.borrow_mut() = delta.terminal_modes;
+                *self.selection.borrow_mut() = delta.selection;
                 self.renderable
                     .borrow()
                     .inner
                     .borrow_mut()
                     .apply_changes_to_surface(delta);
             }
+            Pdu::CursorUpdate(update) => {
+                self.renderable
+                    .borrow()
+                    .inner
+                    .borrow_mut()
+                    .apply_cursor_update(update);
+            }
+            Pdu::ScrollbackChunk(chunk) => {
+                self.renderable
+                    .borrow()
+                    .inner
+                    .borrow_mut()
+                    .apply_scrollback_chunk(chunk);
+            }
             Pdu::SetClipboard(SetClipboard {
                 clipboard,
                 selection,
+                truncated,
                 ..
             }) => match self.clipboard.borrow().as_ref() {
                 Some(clip) => {
@@ -120,6 +284,12 @@ impl ClientPane {
                         selection,
                         clipboard
                     );
+                    if truncated {
+                        log::warn!(
+                            "The clipboard set by pane {} was truncated by the server",
+                            self.local_pane_id
+                        );
+                    }
                     clip.set_contents(selection, clipboard)?;
                 }
                 None => {
@@ -149,6 +319,23 @@ impl ClientPane {
                     alert,
                 });
             }
+            Pdu::Notification(Notification {
+                title, body, focus, ..
+            }) => {
+                let mux = Mux::get().unwrap();
+                mux.notify(MuxNotification::Alert {
+                    pane_id: self.local_pane_id,
+                    alert: Alert::ToastNotification { title, body, focus },
+                });
+            }
+            Pdu::SetProgress(SetProgress { state, percent, .. }) => {
+                *self.progress.borrow_mut() = Progress { state, percent };
+                let mux = Mux::get().unwrap();
+                mux.notify(MuxNotification::Alert {
+                    pane_id: self.local_pane_id,
+                    alert: Alert::TitleMaybeChanged,
+                });
+            }
             Pdu::PaneRemoved(PaneRemoved { pane_id }) => {
                 log::trace!("remote pane {} has been removed", pane_id);
                 self.renderable.borrow().inner.borrow_mut().dead = true;
@@ -164,6 +351,30 @@ impl ClientPane {
         self.remote_pane_id
     }
 
+    /// Returns the selection that is shared by the clients
+    /// that are viewing this pane
+    pub fn shared_selection(&self) -> Option<SelectionRange> {
+        *self.selection.borrow()
+    }
+
+    /// Share `selection` with the other clients that are viewing
+    /// this pane; `None` clears the shared selection.
+    pub fn share_selection(&self, selection: Option<SelectionRange>) {
+        *self.selection.borrow_mut() = selection;
+        let client = Arc::clone(&self.client);
+        let remote_pane_id = self.remote_pane_id;
+        promise::spawn::spawn(async move {
+            client
+                .client
+                .set_selection(SetSelection {
+                    pane_id: remote_pane_id,
+                    selection,
+                })
+                .await
+        })
+        .detach();
+    }
+
     /// Arrange to suppress the next Pane::kill call.
     /// This is a bit of a hack that we use when closing a window;
     /// our Domain::local_window_is_closing impl calls this for each
@@ -215,6 +426,12 @@ impl Pane for ClientPane {
         inner.title.clone()
     }
 
+    fn get_title_bytes(&self) -> Option<Vec<u8>> {
+        let renderable = self.renderable.borrow();
+        let inner = renderable.inner.borrow();
+        inner.title_bytes.clone()
+    }
+
     fn send_paste(&self, text: &str) -> anyhow::Result<()> {
         let client = Arc::clone(&self.client);
         let remote_pane_id = self.remote_pane_id;
@@ -226,13 +443,44 @@ impl Pane for ClientPane {
 
         let data = text.to_owned();
         promise::spawn::spawn(async move {
-            client
+            if data.len() <= PASTE_CHUNK_SIZE {
+                client
+                    .client
+                    .send_paste(SendPaste {
+                        pane_id: remote_pane_id,
+                        data,
+                    })
+                    .await?;
+                return Ok(());
+            }
+
+            // Send a large paste in pieces, so that it doesn't need to
+            // fit in a single frame and doesn't hold up other PDUs
+            let paste_id = client
                 .client
-                .send_paste(SendPaste {
+                .begin_paste(BeginPaste {
                     pane_id: remote_pane_id,
-                    data,
                 })
-                .await
+                .await?
+                .paste_id;
+            let mut remaining = data.as_str();
+            while !remaining.is_empty() {
+                let mut len = remaining.len().min(PASTE_CHUNK_SIZE);
+                while !remaining.is_char_boundary(len) {
+                    len -= 1;
+                }
+                let (chunk, rest) = remaining.split_at(len);
+                client
+                    .client
+                    .paste_chunk(PasteChunk {
+                        paste_id,
+                        data: chunk.to_string(),
+                    })
+                    .await?;
+                remaining = rest;
+            }
+            client.client.end_paste(EndPaste { paste_id }).await?;
+            anyhow::Result::<()>::Ok(())
         })
         .detach();
         self.renderable
@@ -287,18 +535,37 @@ impl Pane for ClientPane {
             // Invalidate any cached rows on a resize
             inner.make_all_stale();
 
+            inner.resize_id += 1;
+            inner.resize_pending = true;
+            let resize_id = inner.resize_id;
             let client = Arc::clone(&self.client);
             let remote_pane_id = self.remote_pane_id;
             let remote_tab_id = self.remote_tab_id;
+            let local_pane_id = self.local_pane_id;
             promise::spawn::spawn(async move {
-                client
+                let result = client
                     .client
                     .resize(Resize {
                         containing_tab_id: remote_tab_id,
                         pane_id: remote_pane_id,
                         size,
+                        resize_id,
+                        anchor_row: None,
                     })
-                    .await
+                    .await;
+                if result.is_err() {
+                    // The server won't confirm this resize, so don't
+                    // hold on to our size waiting for it to do so
+                    let pane = Mux::get().unwrap().get_pane(local_pane_id);
+                    if let Some(pane) = pane.as_ref().and_then(|p| p.downcast_ref::<ClientPane>()) {
+                        let renderable = pane.renderable.borrow();
+                        let mut inner = renderable.inner.borrow_mut();
+                        if inner.resize_id == resize_id {
+                            inner.resize_pending = false;
+                        }
+                    }
+                }
+                result
             })
             .detach();
             inner.update_last_send();
@@ -330,22 +597,23 @@ impl Pane for ClientPane {
             input_serial = inner.input_serial;
             inner.predict_from_key_event(key, mods);
         }
-        let client = Arc::clone(&self.client);
-        let remote_pane_id = self.remote_pane_id;
-        promise::spawn::spawn(async move {
-            client
-                .client
-                .key_down(SendKeyDown {
-                    pane_id: remote_pane_id,
-                    event: KeyEvent {
-                        key,
-                        modifiers: mods,
-                    },
-                    input_serial,
-                })
-                .await
-        })
-        .detach();
+        let start_sending = {
+            let mut pending = self.pending_keys.borrow_mut();
+            pending.events.push(KeyEvent {
+                key,
+                modifiers: mods,
+            });
+            pending.input_serial = input_serial;
+            !std::mem::replace(&mut pending.sending, true)
+        };
+        if start_sending {
+            promise::spawn::spawn(send_pending_keys(
+                Arc::clone(&self.client),
+                self.remote_pane_id,
+                Rc::clone(&self.pending_keys),
+            ))
+            .detach();
+        }
         self.renderable
             .borrow()
             .inner
@@ -398,6 +666,29 @@ impl Pane for ClientPane {
         self.palette.borrow().clone()
     }
 
+    fn focus_changed(&self, focused: bool) {
+        if !focused {
+            return;
+        }
+        // Let the server know which tab we're displaying, so that
+        // it can prioritize the changes for it
+        let client = Arc::clone(&self.client);
+        let remote_tab_id = self.remote_tab_id;
+        promise::spawn::spawn(async move {
+            client
+                .client
+                .set_focused_tab(SetFocusedTab {
+                    tab_id: remote_tab_id,
+                })
+                .await
+        })
+        .detach();
+    }
+
+    fn get_progress(&self) -> Progress {
+        *self.progress.borrow()
+    }
+
     fn domain_id(&self) -> DomainId {
         self.client.local_domain_id
     }
@@ -406,15 +697,37 @@ impl Pane for ClientPane {
         *self.mouse_grabbed.borrow()
     }
 
+    fn is_bracketed_paste_enabled(&self) -> bool {
+        *self.bracketed_paste.borrow()
+    }
+
     fn is_alt_screen_active(&self) -> bool {
-        // FIXME: retrieve this from the remote
-        false
+        self.terminal_modes
+            .borrow()
+            .contains(TerminalModes::ALT_SCREEN)
+    }
+
+    fn get_terminal_modes(&self) -> TerminalModes {
+        *self.terminal_modes.borrow()
+    }
+
+    fn get_pen(&self) -> Option<CellAttributes> {
+        Some(self.renderable.borrow().inner.borrow().pen.clone())
     }
 
     fn get_current_working_dir(&self) -> Option<Url> {
         self.renderable.borrow().inner.borrow().working_dir.clone()
     }
 
+    fn get_current_working_dir_bytes(&self) -> Option<Vec<u8>> {
+        self.renderable
+            .borrow()
+            .inner
+            .borrow()
+            .working_dir_bytes
+            .clone()
+    }
+
     fn can_close_without_prompting(&self, reason: CloseReason) -> bool {
         match reason {
             CloseReason::Window => true,
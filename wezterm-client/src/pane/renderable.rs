@@ -50,6 +50,24 @@ impl LineEntry {
     }
 }
 
+/// Fill in the images referenced by `lines` from the image cache,
+/// and let the server know about any changes to the cache.
+/// Returns the rows that need to be fetched again because they
+/// reference images that are no longer in the cache.
+fn receive_images(client: &Arc<ClientInner>, lines: &mut SerializedLines) -> Vec<StableRowIndex> {
+    let (ack, refetch) = client.images.receive(lines);
+    if let Some(ack) = ack {
+        let client = Arc::clone(client);
+        promise::spawn::spawn(async move {
+            if let Err(err) = client.client.image_ack(ack).await {
+                log::debug!("image_ack failed: {:#}", err);
+            }
+        })
+        .detach();
+    }
+    refetch
+}
+
 pub struct RenderableInner {
     client: Arc<ClientInner>,
     remote_pane_id: TabId,
@@ -63,8 +81,21 @@ pub struct RenderableInner {
     pub dimensions: RenderableDimensions,
 
     lines: LruCache<StableRowIndex, LineEntry>,
+    /// The hashes of the lines in `lines` as the server sent them,
+    /// before our hyperlink rules were applied, so that we can tell
+    /// the server which rows we already have when we fetch them again
+    row_hashes: LruCache<StableRowIndex, u64>,
+    /// Rows that we've modified via predictive echo; these need
+    /// to be refreshed from the server as they may not match the
+    /// actual remote state
+    predicted_rows: RangeSet<StableRowIndex>,
     pub title: String,
+    pub title_bytes: Option<Vec<u8>>,
     pub working_dir: Option<Url>,
+    pub working_dir_bytes: Option<Vec<u8>>,
+    /// The attributes with which the server draws the output of the
+    /// application, which we also use for the text that we predict
+    pub pen: CellAttributes,
     pub seqno: SequenceNo,
 
     fetch_limiter: RateLimiter,
@@ -75,6 +106,13 @@ pub struct RenderableInner {
     last_input_rtt: u64,
 
     pub input_serial: InputSerial,
+    /// The most recent input serial carried by a delta that we
+    /// have applied
+    acked_input_serial: InputSerial,
+    /// The resize_id of the most recent resize that we sent, and
+    /// whether the server has yet to confirm that it applied it
+    pub resize_id: u64,
+    pub resize_pending: bool,
 }
 
 pub struct RenderableState {
@@ -103,15 +141,23 @@ impl RenderableInner {
             cursor_position: StableCursorPosition::default(),
             dimensions,
             lines: LruCache::new(configuration().scrollback_lines),
+            row_hashes: LruCache::new(configuration().scrollback_lines),
+            predicted_rows: RangeSet::new(),
             title: title.to_string(),
+            title_bytes: None,
             working_dir: None,
+            working_dir_bytes: None,
+            pen: CellAttributes::default(),
             fetch_limiter,
             last_send_time: now,
             last_recv_time: now,
             last_late_dirty: now,
             last_input_rtt: 0,
             input_serial: InputSerial::empty(),
+            acked_input_serial: InputSerial::empty(),
             seqno: SEQ_ZERO,
+            resize_id: 0,
+            resize_pending: false,
         }
     }
 
@@ -137,6 +183,13 @@ impl RenderableInner {
             .unwrap_or(false)
     }
 
+    /// The attributes of the text that we predict: those that the
+    /// application would draw it with, underlined so that it can be
+    /// told apart from the text that the server has confirmed
+    fn prediction_attrs(&self) -> CellAttributes {
+        self.pen.clone().set_underline(Underline::Double).clone()
+    }
+
     /// Compute a "prediction" and apply it to the line data that we
     /// have available, marking it as dirty so that it gets rendered.
     /// The prediction is basically just local echo.
@@ -184,12 +237,7 @@ impl RenderableInner {
                 }
             }
             KeyCode::Char(c) => {
-                let cell = Cell::new(
-                    c,
-                    CellAttributes::default()
-                        .set_underline(Underline::Double)
-                        .clone(),
-                );
+                let cell = Cell::new(c, self.prediction_attrs());
 
                 let cell = line.set_cell(self.cursor_position.x, cell, SEQ_ZERO);
                 // Adjust the cursor to reflect the width of this new cell
@@ -224,6 +272,8 @@ impl RenderableInner {
         }
 
         let row = self.cursor_position.y;
+        self.predicted_rows.add(row);
+        self.row_hashes.pop(&row);
         match self.lines.pop(&row) {
             Some(LineEntry::Stale(mut line)) | Some(LineEntry::Line(mut line)) => {
                 self.apply_prediction(c, &mut line);
@@ -242,11 +292,7 @@ impl RenderableInner {
     }
 
     fn apply_paste_prediction(&mut self, row: usize, text: &str, line: &mut Line) {
-        let attrs = CellAttributes::default()
-            .set_underline(Underline::Double)
-            .clone();
-
-        let text_line = Line::from_text(text, &attrs, SEQ_ZERO);
+        let text_line = Line::from_text(text, &self.prediction_attrs(), SEQ_ZERO);
 
         if row == 0 {
             for cell in text_line.cells() {
@@ -271,6 +317,8 @@ impl RenderableInner {
 
         for (idx, paste_line) in lines.iter().enumerate() {
             let row = self.cursor_position.y + idx as StableRowIndex;
+            self.predicted_rows.add(row);
+            self.row_hashes.pop(&row);
 
             match self.lines.pop(&row) {
                 Some(LineEntry::Stale(mut line)) | Some(LineEntry::Line(mut line)) => {
@@ -301,21 +349,31 @@ impl RenderableInner {
         self.poll_interval = BASE_POLL_INTERVAL;
         self.last_recv_time = now;
 
+        // The lines that we hold are of the screen that is no longer
+        // active, and would briefly be shown in place of the new one
+        if delta.screen_switched {
+            self.lines.clear();
+            self.row_hashes.clear();
+        }
+
+        // The server tracks which lines have changed since its prior
+        // push, and only sends those, so there is no need to refetch
+        // the cursor rows merely because the cursor has moved.
+        // Any lines that we've predicted may not have changed on the
+        // server side, so we need to refresh those to be sure that
+        // we're not showing an incorrect prediction.
         let mut dirty = RangeSet::new();
         for r in delta.dirty_lines {
             dirty.add_range(r.clone());
         }
-        if delta.cursor_position != self.cursor_position {
-            dirty.add(self.cursor_position.y);
-            // But note that the server may have sent this in bonus_lines;
-            // we'll address that below
-            dirty.add(delta.cursor_position.y);
-        }
+        dirty.add_set(&self.predicted_rows);
+        self.predicted_rows = RangeSet::new();
 
         // Keep track of the approximate round trip time by recording how
         // long it took for this response to come back
         if let Some(serial) = delta.input_serial {
             self.last_input_rtt = serial.elapsed_millis();
+            self.acked_input_serial = self.acked_input_serial.max(serial);
         }
 
         // When it comes to updating the cursor position, if the update was tagged
@@ -334,32 +392,147 @@ impl RenderableInner {
         {
             self.cursor_position = delta.cursor_position;
         }
-        self.dimensions = delta.dimensions;
+        if delta.dimensions.scrollback_top > self.dimensions.scrollback_top {
+            // The scrollback was trimmed on the server; the rows above
+            // the new scrollback_top no longer exist, so evict them
+            // rather than continuing to render stale content
+            let trimmed: Vec<StableRowIndex> = self
+                .lines
+                .iter()
+                .map(|(stable_row, _)| *stable_row)
+                .filter(|stable_row| *stable_row < delta.dimensions.scrollback_top)
+                .collect();
+            for stable_row in trimmed {
+                self.lines.pop(&stable_row);
+            }
+        }
+        // A delta that was computed before the server applied our
+        // latest resize would take us back to the old size
+        if delta
+            .last_resize_id
+            .map_or(false, |resize_id| resize_id >= self.resize_id)
+        {
+            self.resize_pending = false;
+        }
+        if self.resize_pending {
+            let (cols, viewport_rows) = (self.dimensions.cols, self.dimensions.viewport_rows);
+            self.dimensions = delta.dimensions;
+            self.dimensions.cols = cols;
+            self.dimensions.viewport_rows = viewport_rows;
+        } else {
+            self.dimensions = delta.dimensions;
+        }
         self.title = delta.title;
+        self.title_bytes = delta.title_bytes;
         self.working_dir = delta.working_dir.map(Into::into);
+        self.working_dir_bytes = delta.working_dir_bytes;
+        if let Some(pen) = delta.pen {
+            self.pen = pen;
+        }
         log::trace!("server says: seqno from {} -> {}", self.seqno, delta.seqno);
         self.seqno = delta.seqno;
 
         let config = configuration();
-        for (stable_row, line) in delta.bonus_lines.lines() {
+        let mut bonus_lines = delta.bonus_lines;
+        let refetch = receive_images(&self.client, &mut bonus_lines);
+        for (stable_row, line) in bonus_lines.lines() {
             log::trace!("bonus line {} seqno={}", stable_row, line.current_seqno());
             self.put_line(stable_row, line, &config, None);
             dirty.remove(stable_row);
         }
+        for (line_delta, cells) in delta.line_deltas.deltas() {
+            let stable_row = line_delta.row;
+            if self.apply_line_delta(line_delta, cells, &config) {
+                dirty.remove(stable_row);
+            } else {
+                dirty.add(stable_row);
+            }
+        }
+        for stable_row in refetch {
+            dirty.add(stable_row);
+        }
 
         Mux::get()
             .unwrap()
             .notify(mux::MuxNotification::PaneOutput(self.local_pane_id));
 
-        let mut to_fetch = RangeSet::new();
         log::trace!("dirty as of seq {} -> {:?}", delta.seqno, dirty);
+        self.fetch_dirty_lines(dirty, delta.dimensions.physical_top, now);
+    }
+
+    /// Applies a `CursorUpdate`, which the server sends in place of
+    /// a full set of changes when our input only moved the cursor
+    pub fn apply_cursor_update(&mut self, update: CursorUpdate) {
+        let now = Instant::now();
+        self.poll_interval = BASE_POLL_INTERVAL;
+        self.last_recv_time = now;
+
+        self.last_input_rtt = update.input_serial.elapsed_millis();
+        self.acked_input_serial = self.acked_input_serial.max(update.input_serial);
+
+        // As in apply_changes_to_surface, only take the position if it
+        // is the response to the most recent key event
+        if update.input_serial >= self.input_serial {
+            self.cursor_position = update.cursor_position;
+        }
+        if let Some(pen) = update.pen {
+            self.pen = pen;
+        }
+
+        // Nothing changed on the server, so any rows that we predicted
+        // are now incorrect and need to be fetched again
+        let dirty = std::mem::replace(&mut self.predicted_rows, RangeSet::new());
+
+        Mux::get()
+            .unwrap()
+            .notify(mux::MuxNotification::PaneOutput(self.local_pane_id));
+
+        let physical_top = self.dimensions.physical_top;
+        self.fetch_dirty_lines(dirty, physical_top, now);
+    }
+
+    /// Applies a `ScrollbackChunk` that was pushed in response to
+    /// `TrickleScrollback`.  Rows that we already hold, or are already
+    /// fetching, are more recent than the chunk, and are left alone.
+    pub fn apply_scrollback_chunk(&mut self, mut chunk: ScrollbackChunk) {
+        let config = configuration();
+        let refetch = receive_images(&self.client, &mut chunk.lines);
+        for (stable_row, line) in chunk.lines.lines() {
+            if !self.lines.contains(&stable_row) {
+                self.put_line(stable_row, line, &config, None);
+            }
+        }
+        for stable_row in refetch {
+            self.make_stale(stable_row);
+        }
+        if chunk.done {
+            log::debug!(
+                "finished receiving the scrollback of pane {}",
+                self.local_pane_id
+            );
+        }
+
+        Mux::get()
+            .unwrap()
+            .notify(mux::MuxNotification::PaneOutput(self.local_pane_id));
+    }
+
+    /// Schedule a fetch of the `dirty` rows that are at or below
+    /// `physical_top`, and mark the rest as stale
+    fn fetch_dirty_lines(
+        &mut self,
+        dirty: RangeSet<StableRowIndex>,
+        physical_top: StableRowIndex,
+        now: Instant,
+    ) {
+        let mut to_fetch = RangeSet::new();
         for r in dirty.iter() {
             for stable_row in r.clone() {
                 // If a line is in the (probable) viewport region,
                 // then we'll likely want to fetch it.
                 // If it is outside that region, remove it from our cache
                 // so that we'll fetch it on demand later.
-                let fetchable = stable_row >= delta.dimensions.physical_top;
+                let fetchable = stable_row >= physical_top;
                 let prior = self.lines.pop(&stable_row);
                 let prior_kind = prior.as_ref().map(|e| e.kind());
                 if !fetchable {
@@ -420,10 +593,50 @@ impl RenderableInner {
         }
     }
 
+    /// Apply a delta from the server to the line that we hold for its
+    /// row.  Returns false if we don't hold the line that it applies
+    /// to, in which case the row has to be fetched.
+    fn apply_line_delta(
+        &mut self,
+        delta: LineDelta,
+        cells: Vec<Cell>,
+        config: &ConfigHandle,
+    ) -> bool {
+        if self.row_hashes.peek(&delta.row) != Some(&delta.base_hash) {
+            return false;
+        }
+        let mut line = match self.lines.peek(&delta.row) {
+            Some(LineEntry::Line(line)) | Some(LineEntry::LineAndFetching(line, _)) => line.clone(),
+            _ => return false,
+        };
+        log::trace!("line delta {} cols {}..", delta.row, delta.start);
+        delta.apply(&mut line, cells, self.seqno);
+        self.put_hashed_line(delta.row, line, Some(delta.hash), config, None);
+        true
+    }
+
     fn put_line(
+        &mut self,
+        stable_row: StableRowIndex,
+        line: Line,
+        config: &ConfigHandle,
+        fetch_start: Option<Instant>,
+    ) {
+        let hash = self
+            .client
+            .client
+            .row_hash()
+            .map(|algo| algo.hash_line(&line));
+        self.put_hashed_line(stable_row, line, hash, config, fetch_start);
+    }
+
+    /// Store `line`, whose content has the server's row hash `hash`,
+    /// as the content of `stable_row`
+    fn put_hashed_line(
         &mut self,
         stable_row: StableRowIndex,
         mut line: Line,
+        hash: Option<u64>,
         config: &ConfigHandle,
         fetch_start: Option<Instant>,
     ) {
@@ -465,6 +678,14 @@ impl RenderableInner {
             LineEntry::Line(line)
         };
         self.lines.put(stable_row, entry);
+        match hash {
+            Some(hash) => {
+                self.row_hashes.put(stable_row, hash);
+            }
+            None => {
+                self.row_hashes.pop(&stable_row);
+            }
+        }
     }
 
     fn schedule_fetch_lines(&mut self, to_fetch: RangeSet<StableRowIndex>, now: Instant) {
@@ -480,6 +701,18 @@ impl RenderableInner {
             now,
         );
 
+        // Let the server leave out the rows that we already have
+        let mut known_hashes = vec![];
+        for r in to_fetch.iter() {
+            for stable_row in r.clone() {
+                if let Some(LineEntry::LineAndFetching(..)) = self.lines.peek(&stable_row) {
+                    if let Some(&hash) = self.row_hashes.peek(&stable_row) {
+                        known_hashes.push((stable_row, hash));
+                    }
+                }
+            }
+        }
+
         let client = Arc::clone(&self.client);
         let remote_pane_id = self.remote_pane_id;
 
@@ -489,6 +722,8 @@ impl RenderableInner {
                 .get_lines(GetLines {
                     pane_id: remote_pane_id,
                     lines: to_fetch.clone().into(),
+                    packed: true,
+                    known_hashes,
                 })
                 .await;
             Self::apply_lines(local_pane_id, result, to_fetch, now)
@@ -511,14 +746,33 @@ impl RenderableInner {
             let mut inner = renderable.inner.borrow_mut();
 
             match result {
-                Ok(result) => {
+                Ok(mut result) => {
                     let config = configuration();
+                    let refetch = receive_images(&inner.client, &mut result.lines);
                     let lines = result.lines.lines();
 
                     log::trace!("fetch complete for {:?} at {:?}", to_fetch, now);
                     for (stable_row, line) in lines.into_iter() {
                         inner.put_line(stable_row, line, &config, Some(now));
                     }
+                    // The server left out the rows that haven't changed
+                    // from the copies that we already have
+                    for r in to_fetch.iter() {
+                        for stable_row in r.clone() {
+                            match inner.lines.pop(&stable_row) {
+                                Some(LineEntry::LineAndFetching(line, then)) if then == now => {
+                                    inner.lines.put(stable_row, LineEntry::Line(line));
+                                }
+                                Some(entry) => {
+                                    inner.lines.put(stable_row, entry);
+                                }
+                                None => {}
+                            }
+                        }
+                    }
+                    for stable_row in refetch {
+                        inner.make_stale(stable_row);
+                    }
                 }
                 Err(err) => {
                     log::error!("get_lines failed: {}", err);
@@ -564,12 +818,20 @@ impl RenderableInner {
         self.poll_in_progress.store(true, Ordering::SeqCst);
         let remote_pane_id = self.remote_pane_id;
         let local_pane_id = self.local_pane_id;
+        let since_input_serial = if self.acked_input_serial == InputSerial::empty() {
+            None
+        } else {
+            Some(self.acked_input_serial)
+        };
         let client = Arc::clone(&self.client);
         promise::spawn::spawn(async move {
             let alive = match client
                 .client
                 .get_tab_render_changes(GetPaneRenderChanges {
                     pane_id: remote_pane_id,
+                    since_input_serial,
+                    include_bonus_lines: true,
+                    viewport_only: false,
                 })
                 .await
             {
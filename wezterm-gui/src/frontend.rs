@@ -56,6 +56,8 @@ impl GuiFrontEnd {
                     }
                     MuxNotification::PaneRemoved(_) => {}
                     MuxNotification::WindowInvalidated(_) => {}
+                    MuxNotification::TabResized(_) => {}
+                    MuxNotification::TabExited(_) => {}
                     MuxNotification::PaneOutput(_) => {}
                     MuxNotification::PaneAdded(_) => {}
                     MuxNotification::Alert {
@@ -459,6 +459,7 @@ impl Publish {
                                         .as_deref()
                                         .unwrap_or(mux::DEFAULT_WORKSPACE)
                                 ).to_string(),
+                                hold: false,
                             })
                             .await
                     }));
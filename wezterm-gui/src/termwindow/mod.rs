@@ -1009,6 +1009,8 @@ impl TermWindow {
                 | MuxNotification::PaneRemoved(_)
                 | MuxNotification::WindowWorkspaceChanged(_)
                 | MuxNotification::ActiveWorkspaceChanged(_)
+                | MuxNotification::TabResized(_)
+                | MuxNotification::TabExited(_)
                 | MuxNotification::Empty
                 | MuxNotification::WindowCreated(_) => {}
             },
@@ -1165,6 +1167,8 @@ impl TermWindow {
             | MuxNotification::PaneRemoved(_)
             | MuxNotification::WindowCreated(_)
             | MuxNotification::ActiveWorkspaceChanged(_)
+            | MuxNotification::TabResized(_)
+            | MuxNotification::TabExited(_)
             | MuxNotification::Empty
             | MuxNotification::WindowWorkspaceChanged(_) => return true,
         }
@@ -0,0 +1,34 @@
+use mux::tab::TabId;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+lazy_static::lazy_static! {
+    /// The tabs to which an otherwise read-only session may send
+    /// input, keyed by (session_id, tab_id)
+    static ref WRITE_ACCESS: Mutex<HashSet<(usize, TabId)>> = Mutex::new(HashSet::new());
+}
+
+/// Allow (or disallow, when `allowed` is false) the read-only
+/// session `session_id` to send input to `tab_id`
+pub fn set_write_access(session_id: usize, tab_id: TabId, allowed: bool) {
+    let mut access = WRITE_ACCESS.lock().unwrap();
+    if allowed {
+        access.insert((session_id, tab_id));
+    } else {
+        access.remove(&(session_id, tab_id));
+    }
+}
+
+/// Returns true if the read-only session `session_id` has been
+/// granted permission to send input to `tab_id`
+pub fn can_write(session_id: usize, tab_id: TabId) -> bool {
+    WRITE_ACCESS.lock().unwrap().contains(&(session_id, tab_id))
+}
+
+/// Discard the grants made to a session that has ended
+pub fn forget_session(session_id: usize) {
+    WRITE_ACCESS
+        .lock()
+        .unwrap()
+        .retain(|(id, _)| *id != session_id);
+}
@@ -0,0 +1,299 @@
+//! A record of the requests that change the state of the server,
+//! along with the session that made them.
+//!
+//! Each mutating request is described by an `AuditRecord` and passed
+//! to the installed `AuditSink` before the request is dispatched.
+//! If `mux_server_audit_log` is configured, records are appended to
+//! that file; an embedding application may install its own sink via
+//! `set_audit_sink` to forward them elsewhere.
+use chrono::{DateTime, Utc};
+use codec::{
+    BeginPaste, CloseWindow, KillPane, NewWindow, PasteChunk, Pdu, ReorderTab, RestoreTabState,
+    SendPaste, SendText, SetTabTitlePolicy, SpawnSplit, SpawnV2, SplitPane, TryWriteToPane,
+    WriteTextToPane, WriteToPane,
+};
+use mux::pane::PaneId;
+use mux::tab::TabId;
+use portable_pty::CommandBuilder;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// The maximum number of bytes of a payload that are included
+/// in the preview, so that a huge paste doesn't bloat the log
+pub const MAX_PREVIEW_BYTES: usize = 64;
+
+lazy_static::lazy_static! {
+    static ref SINK: Mutex<Option<Arc<dyn AuditSink>>> = Mutex::new(configured_sink());
+}
+
+/// Receives the audit records produced by all sessions
+pub trait AuditSink: Send + Sync {
+    fn record(&self, record: &AuditRecord) -> anyhow::Result<()>;
+}
+
+/// Summarizes the data carried by a request without
+/// retaining all of it
+#[derive(Debug, Clone, PartialEq)]
+pub struct PayloadSummary {
+    /// The length of the complete payload, in bytes
+    pub len: usize,
+    /// The hex encoded sha256 digest of the complete payload
+    pub sha256: String,
+    /// Up to MAX_PREVIEW_BYTES of the payload, with any control
+    /// characters escaped.  None for payloads that may hold secrets.
+    pub preview: Option<String>,
+}
+
+impl PayloadSummary {
+    pub fn new(data: &[u8]) -> Self {
+        let truncated = data.len() > MAX_PREVIEW_BYTES;
+        let head = &data[..data.len().min(MAX_PREVIEW_BYTES)];
+        let mut preview: String = String::from_utf8_lossy(head).escape_debug().collect();
+        if truncated {
+            preview.push_str("...");
+        }
+
+        Self {
+            preview: Some(preview),
+            ..Self::without_preview(data)
+        }
+    }
+
+    /// Summarize input that was typed or pasted into a pane.
+    /// Passwords and tokens pass through as input, so none of
+    /// its content is recorded; only its length and digest.
+    pub fn without_preview(data: &[u8]) -> Self {
+        let digest = openssl::sha::sha256(data);
+        let sha256 = digest.iter().map(|b| format!("{:02x}", b)).collect();
+
+        Self {
+            len: data.len(),
+            sha256,
+            preview: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditRecord {
+    pub timestamp: DateTime<Utc>,
+    pub session_id: usize,
+    pub client_label: String,
+    /// The name of the request, eg: "WriteToPane"
+    pub request: &'static str,
+    pub pane_id: Option<PaneId>,
+    pub tab_id: Option<TabId>,
+    pub payload: Option<PayloadSummary>,
+}
+
+impl std::fmt::Display for AuditRecord {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            fmt,
+            "{} session={} client={:?} request={}",
+            self.timestamp.to_rfc3339(),
+            self.session_id,
+            self.client_label,
+            self.request
+        )?;
+        if let Some(tab_id) = self.tab_id {
+            write!(fmt, " tab={}", tab_id)?;
+        }
+        if let Some(pane_id) = self.pane_id {
+            write!(fmt, " pane={}", pane_id)?;
+        }
+        if let Some(payload) = &self.payload {
+            write!(fmt, " len={} sha256={}", payload.len, payload.sha256)?;
+            if let Some(preview) = &payload.preview {
+                write!(fmt, " preview=\"{}\"", preview)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The parts of a request that are recorded in the audit log
+pub struct AuditEvent {
+    pub request: &'static str,
+    pub pane_id: Option<PaneId>,
+    pub tab_id: Option<TabId>,
+    pub payload: Option<PayloadSummary>,
+}
+
+fn command_payload(command: &Option<CommandBuilder>) -> Option<PayloadSummary> {
+    let command = command.as_ref()?;
+    let argv: Vec<_> = command
+        .get_argv()
+        .iter()
+        .map(|arg| arg.to_string_lossy())
+        .collect();
+    Some(PayloadSummary::new(argv.join(" ").as_bytes()))
+}
+
+/// Returns the event to record for `pdu`, or None if it is not
+/// a request that mutates the state of the server.  The tab_id
+/// is filled in by the caller for requests that target a pane.
+pub fn describe(pdu: &Pdu) -> Option<AuditEvent> {
+    let (request, pane_id, tab_id, payload) = match pdu {
+        Pdu::WriteToPane(WriteToPane { pane_id, data }) => (
+            "WriteToPane",
+            Some(*pane_id),
+            None,
+            Some(PayloadSummary::without_preview(data)),
+        ),
+        Pdu::WriteTextToPane(WriteTextToPane { pane_id, data }) => (
+            "WriteTextToPane",
+            Some(*pane_id),
+            None,
+            Some(PayloadSummary::without_preview(data)),
+        ),
+        Pdu::TryWriteToPane(TryWriteToPane { pane_id, data }) => (
+            "TryWriteToPane",
+            Some(*pane_id),
+            None,
+            Some(PayloadSummary::without_preview(data)),
+        ),
+        Pdu::BeginPaste(BeginPaste { pane_id }) => ("BeginPaste", Some(*pane_id), None, None),
+        Pdu::PasteChunk(PasteChunk { data, .. }) => (
+            "PasteChunk",
+            None,
+            None,
+            Some(PayloadSummary::without_preview(data.as_bytes())),
+        ),
+        Pdu::SendPaste(SendPaste { pane_id, data }) => (
+            "SendPaste",
+            Some(*pane_id),
+            None,
+            Some(PayloadSummary::without_preview(data.as_bytes())),
+        ),
+        Pdu::SendText(SendText { pane_id, text, .. }) => (
+            "SendText",
+            Some(*pane_id),
+            None,
+            Some(PayloadSummary::without_preview(text)),
+        ),
+        Pdu::KillPane(KillPane { pane_id }) => ("KillPane", Some(*pane_id), None, None),
+        Pdu::CloseWindow(CloseWindow { .. }) => ("CloseWindow", None, None, None),
+        Pdu::NewWindow(NewWindow { .. }) => ("NewWindow", None, None, None),
+        Pdu::SpawnV2(SpawnV2 { command, .. }) => ("SpawnV2", None, None, command_payload(command)),
+        Pdu::RestoreTabState(RestoreTabState { command, .. }) => {
+            ("RestoreTabState", None, None, command_payload(command))
+        }
+        Pdu::SplitPane(SplitPane {
+            pane_id, command, ..
+        }) => ("SplitPane", Some(*pane_id), None, command_payload(command)),
+        Pdu::SpawnSplit(SpawnSplit {
+            tab_id, command, ..
+        }) => ("SpawnSplit", None, Some(*tab_id), command_payload(command)),
+        Pdu::ReorderTab(ReorderTab { tab_id, .. }) => ("ReorderTab", None, Some(*tab_id), None),
+        Pdu::SetTabTitlePolicy(SetTabTitlePolicy { tab_id, .. }) => {
+            ("SetTabTitlePolicy", None, Some(*tab_id), None)
+        }
+        _ => return None,
+    };
+    Some(AuditEvent {
+        request,
+        pane_id,
+        tab_id,
+        payload,
+    })
+}
+
+/// Appends each record as a line of text to a file
+pub struct FileAuditSink {
+    file: Mutex<File>,
+}
+
+impl FileAuditSink {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl AuditSink for FileAuditSink {
+    fn record(&self, record: &AuditRecord) -> anyhow::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        writeln!(file, "{}", record)?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+fn configured_sink() -> Option<Arc<dyn AuditSink>> {
+    let path = config::configuration().mux_server_audit_log.clone()?;
+    match FileAuditSink::open(&path) {
+        Ok(sink) => Some(Arc::new(sink)),
+        Err(err) => {
+            log::error!("failed to open audit log {}: {:#}", path.display(), err);
+            None
+        }
+    }
+}
+
+/// Replace the sink that receives audit records.  Passing None
+/// disables auditing.
+pub fn set_audit_sink(sink: Option<Arc<dyn AuditSink>>) {
+    *SINK.lock().unwrap() = sink;
+}
+
+/// Returns true if there is a sink to receive audit records
+pub fn is_enabled() -> bool {
+    SINK.lock().unwrap().is_some()
+}
+
+/// Pass `record` to the installed sink, if any
+pub fn record(record: AuditRecord) {
+    let sink = SINK.lock().unwrap().clone();
+    if let Some(sink) = sink {
+        if let Err(err) = sink.record(&record) {
+            log::error!("failed to write audit record {}: {:#}", record, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn preview_is_bounded() {
+        let data = vec![b'a'; MAX_PREVIEW_BYTES * 4];
+        let summary = PayloadSummary::new(&data);
+        assert_eq!(summary.len, data.len());
+        let preview = summary.preview.unwrap();
+        assert_eq!(preview.len(), MAX_PREVIEW_BYTES + 3);
+        assert!(preview.ends_with("..."));
+    }
+
+    #[test]
+    fn preview_escapes_controls() {
+        let summary = PayloadSummary::new(b"ls\r\n");
+        assert_eq!(summary.len, 4);
+        assert_eq!(summary.preview.as_deref(), Some("ls\\r\\n"));
+    }
+
+    #[test]
+    fn input_is_not_previewed() {
+        let event = describe(&Pdu::WriteToPane(WriteToPane {
+            pane_id: 1,
+            data: b"hunter2\r".to_vec(),
+        }))
+        .unwrap();
+        let payload = event.payload.unwrap();
+        assert_eq!(payload.len, 8);
+        assert_eq!(payload.preview, None);
+    }
+
+    #[test]
+    fn digest_is_hex_sha256() {
+        assert_eq!(
+            PayloadSummary::new(b"").sha256,
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+}
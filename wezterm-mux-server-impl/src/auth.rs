@@ -0,0 +1,33 @@
+use openssl::sha::sha256;
+use std::path::Path;
+
+/// Holds the shared secret that clients must present via the
+/// `Authenticate` PDU before the session will accept other requests.
+/// Only a digest of the token is retained, and comparisons are
+/// made in constant time.
+pub struct AuthToken {
+    digest: [u8; 32],
+}
+
+impl AuthToken {
+    pub fn new(token: &str) -> Self {
+        Self {
+            digest: sha256(token.as_bytes()),
+        }
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let token = config::read_auth_token(path)?;
+        Ok(Self::new(&token))
+    }
+
+    pub fn verify(&self, token: &str) -> bool {
+        openssl::memcmp::eq(&self.digest, &sha256(token.as_bytes()))
+    }
+}
+
+impl std::fmt::Debug for AuthToken {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("AuthToken").finish()
+    }
+}
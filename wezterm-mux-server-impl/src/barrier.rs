@@ -0,0 +1,111 @@
+//! Tracks the requests of a session that have yet to be answered,
+//! so that a `Barrier` can be answered once every request that was
+//! received before it has completed.
+//!
+//! Requests are numbered in the order in which they arrive.  A
+//! barrier waits for all of the requests with a lower number,
+//! including earlier barriers, so barriers are released in order.
+use std::collections::BTreeSet;
+
+pub struct RequestTracker<T> {
+    next_id: u64,
+    pending: BTreeSet<u64>,
+    /// The barriers that are waiting, in the order in which
+    /// they arrived, along with the id of each
+    barriers: Vec<(u64, T)>,
+}
+
+impl<T> Default for RequestTracker<T> {
+    fn default() -> Self {
+        Self {
+            next_id: 0,
+            pending: BTreeSet::new(),
+            barriers: vec![],
+        }
+    }
+}
+
+impl<T> RequestTracker<T> {
+    /// Note that a request has arrived, returning the id by which
+    /// it is to be completed
+    pub fn begin(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending.insert(id);
+        id
+    }
+
+    /// Wait for the requests that arrived before the barrier `id`.
+    /// `reply` is returned if they have already completed, and
+    /// is otherwise returned by the `complete` call that releases it.
+    pub fn wait(&mut self, id: u64, reply: T) -> Option<T> {
+        if self.is_clear(id) {
+            Some(reply)
+        } else {
+            self.barriers.push((id, reply));
+            None
+        }
+    }
+
+    /// Note that the request `id` has completed, returning the
+    /// replies of the barriers that no longer need to wait
+    pub fn complete(&mut self, id: u64) -> Vec<T> {
+        if !self.pending.remove(&id) {
+            return vec![];
+        }
+        let mut released = vec![];
+        while let Some((barrier, _)) = self.barriers.first() {
+            if !self.is_clear(*barrier) {
+                break;
+            }
+            released.push(self.barriers.remove(0).1);
+        }
+        released
+    }
+
+    /// Returns true if none of the requests that arrived
+    /// before `id` are pending
+    fn is_clear(&self, id: u64) -> bool {
+        self.pending.range(..id).next().is_none()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn barrier_waits_for_earlier_requests() {
+        let mut tracker = RequestTracker::default();
+        let first = tracker.begin();
+        let second = tracker.begin();
+        let barrier = tracker.begin();
+        let after = tracker.begin();
+
+        assert_eq!(tracker.wait(barrier, "barrier"), None);
+        assert!(tracker.complete(second).is_empty());
+        // A request that arrived after the barrier doesn't hold it up
+        assert!(tracker.complete(after).is_empty());
+        assert_eq!(tracker.complete(first), vec!["barrier"]);
+        assert!(tracker.complete(first).is_empty());
+    }
+
+    #[test]
+    fn barriers_are_released_in_order() {
+        let mut tracker = RequestTracker::default();
+        let request = tracker.begin();
+        let first = tracker.begin();
+        let second = tracker.begin();
+
+        assert_eq!(tracker.wait(first, 1), None);
+        assert_eq!(tracker.wait(second, 2), None);
+        // The second barrier waits for the first to be answered
+        assert_eq!(tracker.complete(request), vec![1]);
+        assert_eq!(tracker.complete(first), vec![2]);
+        assert!(tracker.complete(second).is_empty());
+
+        // With nothing outstanding, a barrier is released at once
+        let third = tracker.begin();
+        assert_eq!(tracker.wait(third, 3), Some(3));
+    }
+}
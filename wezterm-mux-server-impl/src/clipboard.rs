@@ -0,0 +1,478 @@
+//! Decides which of the connected sessions receive the clipboard
+//! contents that are set by the programs running in the panes.
+//!
+//! With several clients attached it is ambiguous which of them
+//! should receive an OSC 52 clipboard update.  By default it is sent
+//! to the session that most recently focused or sent input to the tab
+//! that contains the pane, or spawned it.  When no session has done
+//! so, it is sent to the session that connected most recently.
+//! `mux_clipboard_routing` can instead broadcast it to every session.
+//!
+//! A session that wants to mirror the clipboard activity of every tab
+//! can subscribe with `SubscribeClipboard`, and is then sent each update
+//! as well, unless it is the same as the last update that it was sent
+//! for that selection.  When the same value is set in two tabs, the
+//! subscriber receives it once, with the tab that set it first.  That
+//! doesn't change what is sent to the session that the update is routed
+//! to, which may receive the same value more than once.
+//!
+//! A program that sets the clipboard in a loop would otherwise flood
+//! the clients, so updates from a pane are coalesced such that at most
+//! one is sent per `COALESCE_INTERVAL`, and the contents are truncated
+//! to `mux_clipboard_max_size`.
+use crate::sessionhandler::PduSender;
+use codec::{DecodedPdu, Pdu, SetClipboard};
+use config::{configuration, MuxClipboardRouting};
+use mux::pane::{Pane, PaneId};
+use mux::tab::TabId;
+use mux::Mux;
+use promise::spawn::spawn_into_main_thread;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, Once};
+use std::time::{Duration, Instant};
+use wezterm_term::terminal::{Clipboard, ClipboardSelection};
+
+/// The minimum time between the clipboard updates sent for a pane.
+/// Updates made within this interval replace any that are pending.
+const COALESCE_INTERVAL: Duration = Duration::from_millis(100);
+
+lazy_static::lazy_static! {
+    static ref REGISTRY: Mutex<Registry> = Mutex::new(Registry::default());
+}
+
+#[derive(Default)]
+struct Registry {
+    /// The connected sessions, keyed by session id.  Session ids
+    /// are allocated in increasing order, so the last entry is the
+    /// most recently connected session.
+    sessions: BTreeMap<usize, PduSender>,
+    /// The session that most recently interacted with each tab
+    owners: HashMap<TabId, usize>,
+    /// The sessions that receive the updates from every tab
+    subscribers: HashMap<usize, Subscription>,
+}
+
+/// The clipboard that a subscribed session was last sent
+#[derive(Default)]
+struct Subscription {
+    /// The hash of the contents last sent for each selection
+    sent: Vec<(ClipboardSelection, u64)>,
+}
+
+impl Subscription {
+    /// Record that `update` is to be sent, returning false if the
+    /// subscriber was already sent the same contents
+    fn record(&mut self, update: &ClipboardUpdate) -> bool {
+        let hash = update.content_hash();
+        if self.sent.contains(&(update.selection, hash)) {
+            return false;
+        }
+        self.sent
+            .retain(|(selection, _)| *selection != update.selection);
+        self.sent.push((update.selection, hash));
+        true
+    }
+}
+
+impl Registry {
+    /// Returns the ids of the sessions that should receive a
+    /// clipboard update from a pane in `tab_id`
+    fn recipients(&self, tab_id: Option<TabId>, routing: MuxClipboardRouting) -> Vec<usize> {
+        match routing {
+            MuxClipboardRouting::AllSessions => self.sessions.keys().copied().collect(),
+            MuxClipboardRouting::MostRecentSession => tab_id
+                .and_then(|tab_id| self.owners.get(&tab_id))
+                .filter(|session_id| self.sessions.contains_key(session_id))
+                .or_else(|| self.sessions.keys().next_back())
+                .copied()
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    /// Returns the ids of the sessions that should receive `update`
+    /// from a pane in `tab_id`: those that it is routed to, and the
+    /// subscribers that haven't already been sent the same contents
+    fn recipients_of_update(
+        &mut self,
+        tab_id: Option<TabId>,
+        routing: MuxClipboardRouting,
+        update: &ClipboardUpdate,
+    ) -> Vec<usize> {
+        let mut recipients = self.recipients(tab_id, routing);
+        for (session_id, subscription) in &mut self.subscribers {
+            if subscription.record(update) && !recipients.contains(session_id) {
+                recipients.push(*session_id);
+            }
+        }
+        recipients.sort_unstable();
+        recipients
+    }
+}
+
+/// Make `sender` eligible to receive clipboard updates
+pub fn register_session(session_id: usize, sender: PduSender) {
+    REGISTRY.lock().unwrap().sessions.insert(session_id, sender);
+}
+
+/// Stop sending clipboard updates to a session that has disconnected,
+/// and release the tabs that it owned
+pub fn forget_session(session_id: usize) {
+    let mut registry = REGISTRY.lock().unwrap();
+    registry.sessions.remove(&session_id);
+    registry.owners.retain(|_, owner| *owner != session_id);
+    registry.subscribers.remove(&session_id);
+}
+
+/// Send a session the clipboard updates from every tab, or stop
+/// doing so.  A new subscription starts afresh, so the first update
+/// for each selection is always sent.
+pub fn subscribe(session_id: usize, subscribe: bool) {
+    let mut registry = REGISTRY.lock().unwrap();
+    if subscribe {
+        registry
+            .subscribers
+            .insert(session_id, Subscription::default());
+    } else {
+        registry.subscribers.remove(&session_id);
+    }
+}
+
+/// Note that `session_id` has focused, sent input to or spawned
+/// into `tab_id`, making it the recipient of its clipboard updates
+pub fn record_interaction(session_id: usize, tab_id: TabId) {
+    REGISTRY.lock().unwrap().owners.insert(tab_id, session_id);
+}
+
+/// Returns true if a connected session has focused, sent input to
+/// or spawned into `tab_id`
+pub fn has_interested_session(tab_id: TabId) -> bool {
+    REGISTRY.lock().unwrap().owners.contains_key(&tab_id)
+}
+
+/// Route the clipboard updates of the panes that exist when the first
+/// session connects, such as the initial pane that is spawned into the
+/// mux server.  Panes spawned later are routed by `install` when they
+/// are spawned through the mux protocol; those spawned by the GUI keep
+/// its clipboard.  Only the first call has any effect.
+pub fn start() {
+    static START: Once = Once::new();
+    START.call_once(|| {
+        let mux = Mux::get().unwrap();
+        for pane in mux.iter_panes() {
+            install(&pane);
+        }
+    });
+}
+
+/// Route clipboard updates from `pane` to the connected sessions
+pub fn install(pane: &Rc<dyn Pane>) {
+    let clip: Arc<dyn Clipboard> = Arc::new(RoutedClipboard {
+        pane_id: pane.pane_id(),
+        throttle: Arc::new(Mutex::new(Throttle::default())),
+    });
+    pane.set_clipboard(&clip);
+}
+
+#[derive(Debug, PartialEq)]
+struct ClipboardUpdate {
+    selection: ClipboardSelection,
+    clipboard: Option<String>,
+    truncated: bool,
+}
+
+impl ClipboardUpdate {
+    fn new(selection: ClipboardSelection, clipboard: Option<String>, max_size: usize) -> Self {
+        let mut truncated = false;
+        let clipboard = clipboard.map(|mut text| {
+            if text.len() > max_size {
+                let mut len = max_size;
+                while !text.is_char_boundary(len) {
+                    len -= 1;
+                }
+                text.truncate(len);
+                truncated = true;
+            }
+            text
+        });
+        Self {
+            selection,
+            clipboard,
+            truncated,
+        }
+    }
+
+    fn content_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.clipboard.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Offer {
+    /// Send the update immediately
+    SendNow(ClipboardUpdate),
+    /// The update is being held; call `take_pending` after
+    /// this delay to obtain it
+    FlushAfter(Duration),
+    /// The update replaced one that was already being held
+    Held,
+}
+
+/// Limits the rate at which the clipboard updates of a pane are sent
+#[derive(Default)]
+struct Throttle {
+    last_sent: Option<Instant>,
+    /// The most recent held update for each selection
+    pending: Vec<ClipboardUpdate>,
+    flush_scheduled: bool,
+}
+
+impl Throttle {
+    fn offer(&mut self, update: ClipboardUpdate, now: Instant) -> Offer {
+        let elapsed = self
+            .last_sent
+            .map(|last_sent| now.saturating_duration_since(last_sent))
+            .unwrap_or(COALESCE_INTERVAL);
+        if !self.flush_scheduled && elapsed >= COALESCE_INTERVAL {
+            self.last_sent.replace(now);
+            return Offer::SendNow(update);
+        }
+
+        self.pending.retain(|p| p.selection != update.selection);
+        self.pending.push(update);
+        if self.flush_scheduled {
+            Offer::Held
+        } else {
+            self.flush_scheduled = true;
+            Offer::FlushAfter(COALESCE_INTERVAL - elapsed)
+        }
+    }
+
+    fn take_pending(&mut self, now: Instant) -> Vec<ClipboardUpdate> {
+        self.flush_scheduled = false;
+        self.last_sent.replace(now);
+        std::mem::take(&mut self.pending)
+    }
+}
+
+struct RoutedClipboard {
+    pane_id: PaneId,
+    throttle: Arc<Mutex<Throttle>>,
+}
+
+impl Clipboard for RoutedClipboard {
+    fn set_contents(
+        &self,
+        selection: ClipboardSelection,
+        clipboard: Option<String>,
+    ) -> anyhow::Result<()> {
+        let update =
+            ClipboardUpdate::new(selection, clipboard, configuration().mux_clipboard_max_size);
+        if update.truncated {
+            log::warn!(
+                "clipboard set by pane {} was truncated to {} bytes",
+                self.pane_id,
+                configuration().mux_clipboard_max_size
+            );
+        }
+
+        let offer = self.throttle.lock().unwrap().offer(update, Instant::now());
+        match offer {
+            Offer::SendNow(update) => send_update(self.pane_id, update),
+            Offer::FlushAfter(delay) => {
+                let pane_id = self.pane_id;
+                let throttle = Arc::clone(&self.throttle);
+                smol::spawn(async move {
+                    smol::Timer::after(delay).await;
+                    spawn_into_main_thread(async move {
+                        let pending = throttle.lock().unwrap().take_pending(Instant::now());
+                        for update in pending {
+                            send_update(pane_id, update);
+                        }
+                    })
+                    .detach();
+                })
+                .detach();
+            }
+            Offer::Held => {}
+        }
+        Ok(())
+    }
+}
+
+fn send_update(pane_id: PaneId, update: ClipboardUpdate) {
+    let tab_id = Mux::get()
+        .and_then(|mux| mux.resolve_pane_id(pane_id))
+        .map(|(_domain_id, _window_id, tab_id)| tab_id);
+    let routing = configuration().mux_clipboard_routing;
+
+    let mut registry = REGISTRY.lock().unwrap();
+    for session_id in registry.recipients_of_update(tab_id, routing, &update) {
+        let sender = match registry.sessions.get(&session_id) {
+            Some(sender) => sender,
+            None => continue,
+        };
+        if let Err(err) = sender.send(DecodedPdu {
+            serial: 0,
+            pdu: Pdu::SetClipboard(SetClipboard {
+                pane_id,
+                tab_id,
+                clipboard: update.clipboard.clone(),
+                selection: update.selection,
+                truncated: update.truncated,
+            }),
+        }) {
+            log::error!(
+                "failed to send clipboard for pane {} to session {}: {:#}",
+                pane_id,
+                session_id,
+                err
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn registry(session_ids: &[usize]) -> Registry {
+        let mut registry = Registry::default();
+        for &session_id in session_ids {
+            registry
+                .sessions
+                .insert(session_id, PduSender::new(|_| Ok(())));
+        }
+        registry
+    }
+
+    #[test]
+    fn routes_to_the_session_that_last_used_the_tab() {
+        let mut registry = registry(&[1, 2, 3]);
+        registry.owners.insert(10, 2);
+        assert_eq!(
+            registry.recipients(Some(10), MuxClipboardRouting::MostRecentSession),
+            vec![2]
+        );
+
+        // Without an owner, or when the owner has gone away, fall
+        // back to the most recently connected session
+        assert_eq!(
+            registry.recipients(Some(11), MuxClipboardRouting::MostRecentSession),
+            vec![3]
+        );
+        registry.owners.insert(11, 4);
+        assert_eq!(
+            registry.recipients(Some(11), MuxClipboardRouting::MostRecentSession),
+            vec![3]
+        );
+        assert_eq!(
+            registry.recipients(None, MuxClipboardRouting::MostRecentSession),
+            vec![3]
+        );
+    }
+
+    fn update(text: &str) -> ClipboardUpdate {
+        ClipboardUpdate::new(ClipboardSelection::Clipboard, Some(text.to_string()), 1024)
+    }
+
+    #[test]
+    fn oversized_clipboard_is_truncated() {
+        assert!(!update("hello").truncated);
+
+        // Truncation doesn't split the multibyte character
+        let truncated =
+            ClipboardUpdate::new(ClipboardSelection::Clipboard, Some("aé€".to_string()), 4);
+        assert_eq!(truncated.clipboard.as_deref(), Some("aé"));
+        assert!(truncated.truncated);
+    }
+
+    #[test]
+    fn rapid_updates_are_coalesced() {
+        let mut throttle = Throttle::default();
+        let start = Instant::now();
+        assert_eq!(
+            throttle.offer(update("one"), start),
+            Offer::SendNow(update("one"))
+        );
+
+        let soon = start + Duration::from_millis(10);
+        assert_eq!(
+            throttle.offer(update("two"), soon),
+            Offer::FlushAfter(COALESCE_INTERVAL - Duration::from_millis(10))
+        );
+        assert_eq!(throttle.offer(update("three"), soon), Offer::Held);
+        assert_eq!(
+            throttle.take_pending(start + COALESCE_INTERVAL),
+            vec![update("three")]
+        );
+
+        let later = start + COALESCE_INTERVAL * 3;
+        assert_eq!(
+            throttle.offer(update("four"), later),
+            Offer::SendNow(update("four"))
+        );
+    }
+
+    #[test]
+    fn subscribers_receive_each_value_once() {
+        let mut registry = registry(&[1, 2, 3]);
+        registry.owners.insert(10, 2);
+        registry.owners.insert(11, 2);
+        registry.subscribers.insert(3, Subscription::default());
+        let routing = MuxClipboardRouting::MostRecentSession;
+
+        assert_eq!(
+            registry.recipients_of_update(Some(10), routing, &update("one")),
+            vec![2, 3]
+        );
+        // The same value set in another tab isn't sent to the
+        // subscriber again, but is still routed to the owner
+        assert_eq!(
+            registry.recipients_of_update(Some(11), routing, &update("one")),
+            vec![2]
+        );
+        let primary = ClipboardUpdate::new(
+            ClipboardSelection::PrimarySelection,
+            Some("one".into()),
+            1024,
+        );
+        assert_eq!(
+            registry.recipients_of_update(Some(11), routing, &primary),
+            vec![2, 3]
+        );
+        assert_eq!(
+            registry.recipients_of_update(Some(11), routing, &update("two")),
+            vec![2, 3]
+        );
+        assert_eq!(
+            registry.recipients_of_update(Some(10), routing, &update("one")),
+            vec![2, 3]
+        );
+
+        // A subscriber that owns the tab is sent the update once
+        registry.subscribers.insert(2, Subscription::default());
+        assert_eq!(
+            registry.recipients_of_update(Some(10), routing, &update("three")),
+            vec![2, 3]
+        );
+    }
+
+    #[test]
+    fn broadcasts_to_all_sessions() {
+        let mut registry = registry(&[1, 2, 3]);
+        registry.owners.insert(10, 2);
+        assert_eq!(
+            registry.recipients(Some(10), MuxClipboardRouting::AllSessions),
+            vec![1, 2, 3]
+        );
+        assert!(Registry::default()
+            .recipients(None, MuxClipboardRouting::MostRecentSession)
+            .is_empty());
+    }
+}
@@ -1,12 +1,21 @@
-use crate::sessionhandler::{PduSender, SessionHandler};
+use crate::auth::AuthToken;
+use crate::sessionhandler::{error_response, request_error, PduSender, SessionHandler};
+use crate::stats::KillSwitch;
+use crate::writequeue::WriteQueue;
 use crate::UnixStream;
 use anyhow::Context;
 use async_ossl::AsyncSslStream;
-use codec::{DecodedPdu, Pdu};
+use codec::{
+    generate_session_nonce, ChecksumMismatch, CipherRole, EncryptionKey, ErrorCode, FrameCipher,
+    Framing, MissingChecksum, NegotiateEncryption, NegotiateEncryptionResponse, Pdu,
+    StreamCompressor, StreamDecompressor, StreamDesync, UnitResponse,
+};
 use futures::FutureExt;
 use mux::{Mux, MuxNotification};
 use smol::prelude::*;
 use smol::Async;
+use std::sync::{Arc, Mutex};
+use wezterm_term::terminal::Alert;
 
 #[cfg(unix)]
 pub trait AsRawDesc: std::os::unix::io::AsRawFd {}
@@ -19,11 +28,18 @@ impl AsRawDesc for AsyncSslStream {}
 #[derive(Debug)]
 enum Item {
     Notif(MuxNotification),
-    WritePdu(DecodedPdu),
+    /// A PDU has been added to the write queue
+    WritePdu,
     Readable,
+    /// The session was killed using `KillSession`
+    Kill,
 }
 
-pub async fn process<T>(stream: T) -> anyhow::Result<()>
+pub async fn process<T>(
+    stream: T,
+    auth_token: Option<Arc<AuthToken>>,
+    encryption_key: Option<Arc<EncryptionKey>>,
+) -> anyhow::Result<()>
 where
     T: 'static,
     T: std::io::Read,
@@ -32,10 +48,36 @@ where
     T: std::fmt::Debug,
 {
     let stream = smol::Async::new(stream)?;
-    process_async(stream).await
+    process_async(stream, auth_token, encryption_key).await
 }
 
-pub async fn process_async<T>(mut stream: Async<T>) -> anyhow::Result<()>
+/// Derive the session cipher for a client's request to negotiate
+/// encryption, returning the nonce to send back to the client in
+/// the clear along with the cipher to use for all subsequent frames
+fn negotiate_encryption(
+    request: &NegotiateEncryption,
+    encryption_key: &Option<Arc<EncryptionKey>>,
+    cipher: &Option<FrameCipher>,
+) -> anyhow::Result<(Vec<u8>, FrameCipher)> {
+    let key = encryption_key.as_ref().ok_or_else(|| {
+        request_error(
+            ErrorCode::Unsupported,
+            "encryption is not configured for this domain".to_string(),
+        )
+    })?;
+    if cipher.is_some() {
+        anyhow::bail!("encryption has already been negotiated");
+    }
+    let server_nonce = generate_session_nonce()?;
+    let cipher = key.session_cipher(CipherRole::Server, &request.client_nonce, &server_nonce)?;
+    Ok((server_nonce, cipher))
+}
+
+pub async fn process_async<T>(
+    mut stream: Async<T>,
+    auth_token: Option<Arc<AuthToken>>,
+    encryption_key: Option<Arc<EncryptionKey>>,
+) -> anyhow::Result<()>
 where
     T: 'static,
     T: std::io::Read,
@@ -46,15 +88,34 @@ where
 
     let (item_tx, item_rx) = smol::channel::unbounded::<Item>();
 
+    // PDUs are held in the write queue, rather than in item_tx,
+    // so that the panes can take turns at being written
+    let write_queue = Arc::new(Mutex::new(WriteQueue::default()));
+
     let pdu_sender = PduSender::new({
         let item_tx = item_tx.clone();
+        let write_queue = Arc::clone(&write_queue);
         move |pdu| {
-            item_tx
-                .try_send(Item::WritePdu(pdu))
-                .map_err(|e| anyhow::anyhow!("{:?}", e))
+            let queued = write_queue.lock().unwrap().push(pdu);
+            for _ in 0..queued {
+                item_tx
+                    .try_send(Item::WritePdu)
+                    .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+            }
+            Ok(())
         }
+    })
+    .with_queue_len({
+        let write_queue = Arc::clone(&write_queue);
+        move || write_queue.lock().unwrap().len()
     });
-    let mut handler = SessionHandler::new(pdu_sender);
+    let mut handler = SessionHandler::new(pdu_sender, auth_token);
+    handler.stats().set_kill_switch(KillSwitch::new({
+        let item_tx = item_tx.clone();
+        move || {
+            item_tx.try_send(Item::Kill).ok();
+        }
+    }));
 
     {
         let mux = Mux::get().expect("to be running on gui thread");
@@ -62,33 +123,152 @@ where
         mux.subscribe(move |n| tx.try_send(Item::Notif(n)).is_ok());
     }
 
+    // When an encryption key is configured, everything other than the
+    // negotiation itself must be sealed with the session cipher
+    let mut framing = Framing::default();
+    framing.max_frame_len = config::configuration().mux_max_frame_size;
+
     loop {
         let rx_msg = item_rx.recv();
         let wait_for_read = stream.readable().map(|_| Ok(Item::Readable));
 
         match smol::future::or(rx_msg, wait_for_read).await {
             Ok(Item::Readable) => {
-                let decoded = Pdu::decode_async(&mut stream).await?;
-                handler.process_one(decoded);
+                let (decoded, size) =
+                    match Pdu::decode_async_framed(&mut stream, &mut framing).await {
+                        Ok(result) => result,
+                        Err(err)
+                            if err.downcast_ref::<ChecksumMismatch>().is_some()
+                                || err.downcast_ref::<MissingChecksum>().is_some()
+                                || err.downcast_ref::<StreamDesync>().is_some() =>
+                        {
+                            log::error!("{:#}; closing session", err);
+                            return Ok(());
+                        }
+                        Err(err) => return Err(err),
+                    };
+                handler.stats().record_received(size);
+                match &decoded.pdu {
+                    Pdu::NegotiateEncryption(request) => {
+                        let (response, new_cipher) =
+                            match negotiate_encryption(request, &encryption_key, &framing.cipher) {
+                                Ok((server_nonce, new_cipher)) => (
+                                    Pdu::NegotiateEncryptionResponse(NegotiateEncryptionResponse {
+                                        server_nonce,
+                                    }),
+                                    Some(new_cipher),
+                                ),
+                                Err(err) => (error_response(err), None),
+                            };
+                        let size = response
+                            .encode_async_framed(&mut stream, decoded.serial, &mut framing)
+                            .await?;
+                        handler.stats().record_sent(size, &framing.compression);
+                        stream.flush().await.context("flushing PDU to client")?;
+                        if new_cipher.is_some() {
+                            framing.cipher = new_cipher;
+                        }
+                    }
+                    Pdu::GetCodecVersion(_) => handler.process_one(decoded),
+                    _ if encryption_key.is_some() && framing.cipher.is_none() => {
+                        log::error!("client did not negotiate encryption; closing session");
+                        return Ok(());
+                    }
+                    // Changing the framing is reserved for authenticated
+                    // sessions; process_one rejects the request and counts
+                    // it as a failed attempt
+                    Pdu::EnableFrameChecksums(_)
+                    | Pdu::EnableBlobChannel(_)
+                    | Pdu::EnableStreamCompression(_)
+                        if !handler.is_authenticated() =>
+                    {
+                        handler.process_one(decoded)
+                    }
+                    Pdu::EnableFrameChecksums(_) => {
+                        let size = Pdu::UnitResponse(UnitResponse {})
+                            .encode_async_framed(&mut stream, decoded.serial, &mut framing)
+                            .await?;
+                        handler.stats().record_sent(size, &framing.compression);
+                        stream.flush().await.context("flushing PDU to client")?;
+                        framing.checksum = true;
+                    }
+                    Pdu::EnableBlobChannel(_) => {
+                        let size = Pdu::UnitResponse(UnitResponse {})
+                            .encode_async_framed(&mut stream, decoded.serial, &mut framing)
+                            .await?;
+                        handler.stats().record_sent(size, &framing.compression);
+                        stream.flush().await.context("flushing PDU to client")?;
+                        write_queue.lock().unwrap().enable_blob_channel();
+                    }
+                    Pdu::EnableStreamCompression(_) => {
+                        let size = Pdu::UnitResponse(UnitResponse {})
+                            .encode_async_framed(&mut stream, decoded.serial, &mut framing)
+                            .await?;
+                        handler.stats().record_sent(size, &framing.compression);
+                        stream.flush().await.context("flushing PDU to client")?;
+                        framing.compressor = Some(StreamCompressor::new()?);
+                        framing.decompressor = Some(StreamDecompressor::new()?);
+                    }
+                    _ => handler.process_one(decoded),
+                }
             }
-            Ok(Item::WritePdu(decoded)) => {
-                decoded
+            Ok(Item::WritePdu) => {
+                let decoded = match write_queue.lock().unwrap().pop() {
+                    Some(decoded) => decoded,
+                    None => continue,
+                };
+                let size = decoded
                     .pdu
-                    .encode_async(&mut stream, decoded.serial)
+                    .encode_async_framed(&mut stream, decoded.serial, &mut framing)
                     .await?;
+                handler.stats().record_sent(size, &framing.compression);
+                handler.record_pane_bytes_sent(&decoded.pdu, size);
                 stream.flush().await.context("flushing PDU to client")?;
+                // Don't hold back the deferred pushes until the
+                // chunks of the blob channel have been written.
+                // The scrollback trickle only resumes after them.
+                if write_queue.lock().unwrap().len() == 0 {
+                    handler.schedule_deferred_pushes();
+                    handler.continue_trickle();
+                }
+                if handler.should_disconnect() {
+                    log::error!("too many failed authentication attempts; closing session");
+                    return Ok(());
+                }
+            }
+            Ok(Item::Kill) => {
+                log::warn!(
+                    "session {} was killed; closing session",
+                    handler.stats().session_id()
+                );
+                return Ok(());
+            }
+            Ok(Item::Notif(_))
+                if !handler.is_authenticated()
+                    || (encryption_key.is_some() && framing.cipher.is_none()) =>
+            {
+                // Don't leak any information to a session that has
+                // yet to authenticate or to establish encryption
             }
             Ok(Item::Notif(MuxNotification::PaneOutput(pane_id))) => {
+                handler.record_tab_activity(pane_id, |activity| activity.output = true);
                 handler.schedule_pane_push(pane_id);
+                handler.push_tail_lines(pane_id);
             }
             Ok(Item::Notif(MuxNotification::PaneAdded(_pane_id))) => {}
             Ok(Item::Notif(MuxNotification::PaneRemoved(pane_id))) => {
-                Pdu::PaneRemoved(codec::PaneRemoved { pane_id })
-                    .encode_async(&mut stream, 0)
+                crate::selection::forget_pane(pane_id);
+                handler.forget_pane(pane_id);
+                let size = Pdu::PaneRemoved(codec::PaneRemoved { pane_id })
+                    .encode_async_framed(&mut stream, 0, &mut framing)
                     .await?;
+                handler.stats().record_sent(size, &framing.compression);
                 stream.flush().await.context("flushing PDU to client")?;
             }
             Ok(Item::Notif(MuxNotification::Alert { pane_id, alert })) => {
+                if alert == Alert::Bell {
+                    handler.record_tab_activity(pane_id, |activity| activity.bell = true);
+                }
                 {
                     let per_pane = handler.per_pane(pane_id);
                     let mut per_pane = per_pane.lock().unwrap();
@@ -96,9 +276,22 @@ where
                 }
                 handler.schedule_pane_push(pane_id);
             }
-            Ok(Item::Notif(MuxNotification::WindowRemoved(_window_id))) => {}
-            Ok(Item::Notif(MuxNotification::WindowCreated(_window_id))) => {}
-            Ok(Item::Notif(MuxNotification::WindowInvalidated(_window_id))) => {}
+            Ok(Item::Notif(MuxNotification::WindowRemoved(window_id)))
+            | Ok(Item::Notif(MuxNotification::WindowCreated(window_id)))
+            | Ok(Item::Notif(MuxNotification::WindowInvalidated(window_id))) => {
+                handler.schedule_window_structure_push(window_id);
+            }
+            Ok(Item::Notif(MuxNotification::TabResized(tab_id))) => {
+                handler.schedule_tab_layout_push(tab_id);
+            }
+            Ok(Item::Notif(MuxNotification::TabExited(tab_id))) => {
+                handler.tab_exited(tab_id);
+                let size = Pdu::TabExited(codec::TabExited { tab_id })
+                    .encode_async_framed(&mut stream, 0, &mut framing)
+                    .await?;
+                handler.stats().record_sent(size, &framing.compression);
+                stream.flush().await.context("flushing PDU to client")?;
+            }
             Ok(Item::Notif(MuxNotification::WindowWorkspaceChanged(window_id))) => {
                 let workspace = {
                     let mux = Mux::get().expect("to be running on gui thread");
@@ -106,12 +299,13 @@ where
                         .map(|w| w.get_workspace().to_string())
                 };
                 if let Some(workspace) = workspace {
-                    Pdu::WindowWorkspaceChanged(codec::WindowWorkspaceChanged {
+                    let size = Pdu::WindowWorkspaceChanged(codec::WindowWorkspaceChanged {
                         window_id,
                         workspace,
                     })
-                    .encode_async(&mut stream, 0)
+                    .encode_async_framed(&mut stream, 0, &mut framing)
                     .await?;
+                    handler.stats().record_sent(size, &framing.compression);
                     stream.flush().await.context("flushing PDU to client")?;
                 }
             }
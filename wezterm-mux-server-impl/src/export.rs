@@ -0,0 +1,147 @@
+use termwiz::cell::{Blink, CellAttributes, Intensity, Underline};
+use termwiz::color::{ColorAttribute, ColorSpec};
+use termwiz::escape::csi::{Sgr, CSI};
+use termwiz::surface::Line;
+
+/// Render a sequence of consecutive lines as text.  Lines that were
+/// wrapped are joined with the line that follows them, so that each
+/// logical line ends with a single newline.  Trailing blanks are
+/// removed from each logical line.  If `escapes` is true, SGR escape
+/// sequences are included so that the text retains its colors and
+/// other attributes.
+pub fn lines_to_text(lines: &[Line], escapes: bool) -> String {
+    let mut text = String::new();
+    let mut sgr = String::new();
+
+    for line in lines {
+        let wrapped = line.last_cell_was_wrapped();
+        let cells: Vec<_> = line.visible_cells().map(|(_, cell)| cell).collect();
+        let len = if wrapped {
+            cells.len()
+        } else {
+            cells
+                .iter()
+                .rposition(|cell| {
+                    cell.str() != " "
+                        || (escapes && cell.attrs().background() != ColorAttribute::Default)
+                })
+                .map(|idx| idx + 1)
+                .unwrap_or(0)
+        };
+
+        for cell in &cells[..len] {
+            if escapes {
+                let cell_sgr = attributes_to_sgr(cell.attrs());
+                if cell_sgr != sgr {
+                    text.push_str(&cell_sgr);
+                    sgr = cell_sgr;
+                }
+            }
+            text.push_str(cell.str());
+        }
+
+        if !wrapped {
+            if !sgr.is_empty() {
+                // Don't let the attributes bleed into the rest of
+                // the line if the text is pasted into a terminal
+                text.push_str(&CSI::Sgr(Sgr::Reset).to_string());
+                sgr.clear();
+            }
+            text.push('\n');
+        }
+    }
+
+    if !sgr.is_empty() {
+        text.push_str(&CSI::Sgr(Sgr::Reset).to_string());
+    }
+
+    text
+}
+
+fn color_spec(color: ColorAttribute) -> Option<ColorSpec> {
+    match color {
+        ColorAttribute::TrueColorWithPaletteFallback(color, _)
+        | ColorAttribute::TrueColorWithDefaultFallback(color) => Some(ColorSpec::TrueColor(color)),
+        ColorAttribute::PaletteIndex(idx) => Some(ColorSpec::PaletteIndex(idx)),
+        ColorAttribute::Default => None,
+    }
+}
+
+/// Returns the escape sequence that selects `attrs`, starting from
+/// the default attributes, or an empty string if `attrs` are the
+/// defaults
+fn attributes_to_sgr(attrs: &CellAttributes) -> String {
+    let mut sgrs = vec![];
+    if attrs.intensity() != Intensity::Normal {
+        sgrs.push(Sgr::Intensity(attrs.intensity()));
+    }
+    if attrs.underline() != Underline::None {
+        sgrs.push(Sgr::Underline(attrs.underline()));
+    }
+    if attrs.blink() != Blink::None {
+        sgrs.push(Sgr::Blink(attrs.blink()));
+    }
+    if attrs.italic() {
+        sgrs.push(Sgr::Italic(true));
+    }
+    if attrs.reverse() {
+        sgrs.push(Sgr::Inverse(true));
+    }
+    if attrs.invisible() {
+        sgrs.push(Sgr::Invisible(true));
+    }
+    if attrs.strikethrough() {
+        sgrs.push(Sgr::StrikeThrough(true));
+    }
+    if attrs.overline() {
+        sgrs.push(Sgr::Overline(true));
+    }
+    if let Some(color) = color_spec(attrs.foreground()) {
+        sgrs.push(Sgr::Foreground(color));
+    }
+    if let Some(color) = color_spec(attrs.background()) {
+        sgrs.push(Sgr::Background(color));
+    }
+
+    if sgrs.is_empty() {
+        return String::new();
+    }
+
+    let mut result = CSI::Sgr(Sgr::Reset).to_string();
+    for sgr in sgrs {
+        result.push_str(&CSI::Sgr(sgr).to_string());
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use termwiz::color::AnsiColor;
+    use termwiz::surface::SEQ_ZERO;
+
+    #[test]
+    fn wrapped_lines_are_joined() {
+        let attrs = CellAttributes::default();
+        let mut first = Line::from_text("hello", &attrs, SEQ_ZERO);
+        first.set_last_cell_was_wrapped(true, SEQ_ZERO);
+        let lines = vec![
+            first,
+            Line::from_text("world   ", &attrs, SEQ_ZERO),
+            Line::from_text("", &attrs, SEQ_ZERO),
+        ];
+        assert_eq!(lines_to_text(&lines, false), "helloworld\n\n");
+    }
+
+    #[test]
+    fn escapes_select_attributes() {
+        let mut red = CellAttributes::default();
+        red.set_foreground(AnsiColor::Maroon);
+        let mut line = Line::from_text("ab", &CellAttributes::default(), SEQ_ZERO);
+        line.set_cell(1, termwiz::cell::Cell::new('b', red), SEQ_ZERO);
+        assert_eq!(
+            lines_to_text(&[line], true),
+            "a\u{1b}[0m\u{1b}[31mb\u{1b}[0m\n"
+        );
+    }
+}
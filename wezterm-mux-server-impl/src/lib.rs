@@ -3,10 +3,28 @@ use std::os::unix::net::{UnixListener, UnixStream};
 #[cfg(windows)]
 use uds_windows::{UnixListener, UnixStream};
 
+pub mod acl;
+pub mod audit;
+pub mod auth;
+pub mod barrier;
+pub mod clipboard;
 pub mod dispatch;
+pub mod export;
 pub mod local;
+pub mod mainthread;
+pub mod mouse;
 pub mod pki;
+pub mod reaper;
+#[cfg(feature = "screenshot")]
+mod screenshot;
+pub mod selection;
 pub mod sessionhandler;
+pub mod spawnlimit;
+pub mod stats;
+pub mod tabstate;
+pub mod tail;
+pub mod trickle;
+pub mod writequeue;
 
 lazy_static::lazy_static! {
     pub static ref PKI: pki::Pki = pki::Pki::init().expect("failed to initialize PKI");
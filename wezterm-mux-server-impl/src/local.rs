@@ -1,31 +1,56 @@
+use crate::auth::AuthToken;
 use crate::UnixListener;
 use anyhow::{anyhow, Context as _};
+use codec::EncryptionKey;
 use config::{create_user_owned_dirs, UnixDomain};
 use promise::spawn::spawn_into_main_thread;
+use std::sync::Arc;
 
 pub struct LocalListener {
     listener: UnixListener,
+    auth_token: Option<Arc<AuthToken>>,
+    encryption_key: Option<Arc<EncryptionKey>>,
 }
 
 impl LocalListener {
     pub fn new(listener: UnixListener) -> Self {
-        Self { listener }
+        Self {
+            listener,
+            auth_token: None,
+            encryption_key: None,
+        }
     }
 
     pub fn with_domain(unix_dom: &UnixDomain) -> anyhow::Result<Self> {
+        let auth_token = match &unix_dom.auth_token_file {
+            Some(path) => Some(Arc::new(AuthToken::load(path)?)),
+            None => None,
+        };
+        let encryption_key = match &unix_dom.encryption_key_file {
+            Some(path) => Some(Arc::new(EncryptionKey::load(path)?)),
+            None => None,
+        };
         let listener = safely_create_sock_path(unix_dom)?;
-        Ok(Self::new(listener))
+        Ok(Self {
+            listener,
+            auth_token,
+            encryption_key,
+        })
     }
 
     pub fn run(&mut self) {
         for stream in self.listener.incoming() {
             match stream {
                 Ok(stream) => {
+                    let auth_token = self.auth_token.clone();
+                    let encryption_key = self.encryption_key.clone();
                     spawn_into_main_thread(async move {
-                        crate::dispatch::process(stream).await.map_err(|e| {
-                            log::error!("{:#}", e);
-                            e
-                        })
+                        crate::dispatch::process(stream, auth_token, encryption_key)
+                            .await
+                            .map_err(|e| {
+                                log::error!("{:#}", e);
+                                e
+                            })
                     })
                     .detach();
                 }
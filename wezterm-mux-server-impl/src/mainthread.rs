@@ -0,0 +1,109 @@
+//! Measures how far behind the main thread is with the work that the
+//! sessions have queued up for it.
+//!
+//! The requests of every session are handled on the main thread, and
+//! under load their handlers queue up without limit, adding latency
+//! that nobody can see.  The depth of that queue is the number of tasks
+//! that were spawned with `spawn` and have yet to start running.  While
+//! it is at least `mux_main_thread_busy_threshold`, the server is busy:
+//! the work that can wait, such as pushing the changes of a background
+//! tab, is put off, and clients are told so in their `Pong`s.
+use config::configuration;
+use promise::spawn::{spawn_into_main_thread, Task};
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static QUEUED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts a task from when it is spawned until it starts to run,
+/// or until it is dropped without having run
+struct Queued;
+
+impl Queued {
+    fn new() -> Self {
+        let depth = QUEUED.fetch_add(1, Ordering::Relaxed) + 1;
+        PEAK.fetch_max(depth, Ordering::Relaxed);
+        Self
+    }
+}
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        QUEUED.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Spawn `future` into the main thread, counting it in the queue
+/// depth until it starts to run
+pub fn spawn<F, R>(future: F) -> Task<R>
+where
+    F: Future<Output = R> + Send + 'static,
+    R: Send + 'static,
+{
+    let queued = Queued::new();
+    spawn_into_main_thread(async move {
+        drop(queued);
+        future.await
+    })
+}
+
+/// Returns the number of tasks that are waiting for the main thread
+pub fn queue_depth() -> usize {
+    QUEUED.load(Ordering::Relaxed)
+}
+
+/// Returns the largest queue depth since the server started
+pub fn peak_queue_depth() -> usize {
+    PEAK.load(Ordering::Relaxed)
+}
+
+/// Returns true if the main thread is too far behind for work
+/// that can wait to be queued for it
+pub fn is_busy() -> bool {
+    exceeds(
+        queue_depth(),
+        configuration().mux_main_thread_busy_threshold,
+    )
+}
+
+fn exceeds(depth: usize, threshold: usize) -> bool {
+    threshold != 0 && depth >= threshold
+}
+
+/// The tests that spawn into the main thread take turns, as both the
+/// executor and the queue depth are shared by the whole process
+#[cfg(test)]
+pub(crate) fn lock_for_test() -> std::sync::MutexGuard<'static, ()> {
+    lazy_static::lazy_static! {
+        static ref LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    }
+    LOCK.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tasks_count_until_they_run() {
+        let _lock = lock_for_test();
+        let before = queue_depth();
+        let first = Queued::new();
+        let second = Queued::new();
+        assert_eq!(queue_depth(), before + 2);
+        assert!(peak_queue_depth() >= before + 2);
+
+        drop(first);
+        drop(second);
+        assert_eq!(queue_depth(), before);
+        assert!(peak_queue_depth() >= before + 2);
+    }
+
+    #[test]
+    fn zero_is_never_busy() {
+        assert!(exceeds(256, 256));
+        assert!(!exceeds(255, 256));
+        assert!(!exceeds(1_000_000, 0));
+    }
+}
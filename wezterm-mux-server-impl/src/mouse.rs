@@ -0,0 +1,162 @@
+//! Coalesces the mouse events sent by a session.
+//!
+//! Dragging or hovering produces a flood of motion events, each of
+//! which would otherwise be applied to its pane, and produce a push,
+//! on its own.  The events are instead queued until the main thread
+//! gets to them.  A motion event supersedes an earlier motion event
+//! for the same tab that is still waiting in the queue, unless a
+//! button event for that tab has been queued since; presses and
+//! releases are never dropped, and the remaining events are applied
+//! in the order in which they arrived.
+use mux::pane::PaneId;
+use mux::tab::TabId;
+use std::collections::VecDeque;
+use wezterm_term::input::{MouseEvent, MouseEventKind};
+
+/// A mouse event that is waiting to be applied.  `reply` is used to
+/// complete the request that carried it.
+pub struct QueuedMouseEvent<T> {
+    pub tab_id: Option<TabId>,
+    pub pane_id: PaneId,
+    pub event: MouseEvent,
+    pub reply: T,
+}
+
+/// The outcome of adding an event to the queue
+pub struct Queued<T> {
+    /// The reply of the motion event that the new event superseded
+    pub superseded: Option<T>,
+    /// True if the queue was idle, in which case the caller must
+    /// arrange to `take` the events that it holds
+    pub schedule_flush: bool,
+}
+
+pub struct MouseQueue<T> {
+    events: VecDeque<QueuedMouseEvent<T>>,
+    flush_scheduled: bool,
+}
+
+impl<T> Default for MouseQueue<T> {
+    fn default() -> Self {
+        Self {
+            events: VecDeque::new(),
+            flush_scheduled: false,
+        }
+    }
+}
+
+impl<T> MouseQueue<T> {
+    pub fn push(&mut self, queued: QueuedMouseEvent<T>) -> Queued<T> {
+        let mut superseded = None;
+        if queued.event.kind == MouseEventKind::Move {
+            let previous = self
+                .events
+                .iter()
+                .rposition(|prior| prior.tab_id == queued.tab_id);
+            if let Some(idx) = previous {
+                if self.events[idx].event.kind == MouseEventKind::Move {
+                    superseded = self.events.remove(idx).map(|prior| prior.reply);
+                }
+            }
+        }
+        self.events.push_back(queued);
+
+        let schedule_flush = !self.flush_scheduled;
+        self.flush_scheduled = true;
+        Queued {
+            superseded,
+            schedule_flush,
+        }
+    }
+
+    /// Take the queued events, in the order in which they are to be
+    /// applied.  Events pushed after this schedule a new flush.
+    pub fn take(&mut self) -> Vec<QueuedMouseEvent<T>> {
+        self.flush_scheduled = false;
+        self.events.drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use wezterm_term::input::{KeyModifiers, MouseButton};
+
+    fn event(kind: MouseEventKind, x: usize) -> MouseEvent {
+        MouseEvent {
+            kind,
+            x,
+            y: 0,
+            x_pixel_offset: 0,
+            y_pixel_offset: 0,
+            button: MouseButton::Left,
+            modifiers: KeyModifiers::NONE,
+        }
+    }
+
+    fn push(
+        queue: &mut MouseQueue<usize>,
+        tab_id: TabId,
+        kind: MouseEventKind,
+        reply: usize,
+    ) -> Queued<usize> {
+        queue.push(QueuedMouseEvent {
+            tab_id: Some(tab_id),
+            pane_id: tab_id,
+            event: event(kind, reply),
+            reply,
+        })
+    }
+
+    fn replies(queue: &mut MouseQueue<usize>) -> Vec<usize> {
+        queue
+            .take()
+            .into_iter()
+            .map(|queued| queued.reply)
+            .collect()
+    }
+
+    #[test]
+    fn latest_motion_supersedes_earlier_motion() {
+        let mut queue = MouseQueue::default();
+        let first = push(&mut queue, 1, MouseEventKind::Move, 1);
+        assert!(first.schedule_flush);
+        assert_eq!(first.superseded, None);
+
+        let second = push(&mut queue, 1, MouseEventKind::Move, 2);
+        assert!(!second.schedule_flush);
+        assert_eq!(second.superseded, Some(1));
+
+        // Motion in another tab doesn't supersede it
+        assert_eq!(
+            push(&mut queue, 2, MouseEventKind::Move, 3).superseded,
+            None
+        );
+        assert_eq!(replies(&mut queue), vec![2, 3]);
+
+        // Once taken, the next event needs a new flush
+        assert!(push(&mut queue, 1, MouseEventKind::Move, 4).schedule_flush);
+    }
+
+    #[test]
+    fn button_events_are_kept_in_order() {
+        let mut queue = MouseQueue::default();
+        push(&mut queue, 1, MouseEventKind::Move, 1);
+        push(&mut queue, 1, MouseEventKind::Press, 2);
+        // The press separates this motion from the one before it
+        assert_eq!(
+            push(&mut queue, 1, MouseEventKind::Move, 3).superseded,
+            None
+        );
+        assert_eq!(
+            push(&mut queue, 1, MouseEventKind::Move, 4).superseded,
+            Some(3)
+        );
+        push(&mut queue, 1, MouseEventKind::Release, 5);
+        assert_eq!(
+            push(&mut queue, 1, MouseEventKind::Move, 6).superseded,
+            None
+        );
+        assert_eq!(replies(&mut queue), vec![1, 2, 4, 5, 6]);
+    }
+}
@@ -0,0 +1,216 @@
+//! Removes the tabs that the mux would otherwise keep forever.
+//!
+//! A tab leaks if it is left out of every window once the processes
+//! in its panes have exited, or if it is held open after they have
+//! exited and no session is interested in it any more.  `start` sweeps the mux for such tabs
+//! every `mux_stale_tab_sweep_interval_secs`, removing them and telling
+//! the clients with `TabExited`.
+//!
+//! The policy is conservative, so that a live tab is never reaped:
+//! a tab is removed only once consecutive sweeps have found it stale
+//! for the whole of its grace period, a tab with a running process is
+//! never considered stale, even if it is in no window, and exited tabs
+//! that are in a window are only removed at all if
+//! `mux_reap_exited_tabs_after_secs` is set.
+use config::configuration;
+use mux::tab::TabId;
+use mux::{Mux, MuxNotification};
+use promise::spawn::spawn_into_main_thread;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Why a tab is considered to have been left behind
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Staleness {
+    /// The tab isn't in any window, and has no running processes
+    Orphaned,
+    /// The processes in the panes of the tab have all exited,
+    /// and no session is interested in it
+    Exited,
+}
+
+/// The period for which a tab must remain stale before it is removed
+#[derive(Debug, Clone, Copy)]
+struct GracePeriods {
+    orphaned: Duration,
+    /// None if exited tabs are to be kept
+    exited: Option<Duration>,
+}
+
+impl GracePeriods {
+    fn get(&self, staleness: Staleness) -> Option<Duration> {
+        match staleness {
+            Staleness::Orphaned => Some(self.orphaned),
+            Staleness::Exited => self.exited,
+        }
+    }
+}
+
+/// Remembers when each stale tab was first found to be stale
+#[derive(Default)]
+struct StaleTabs {
+    since: HashMap<TabId, (Staleness, Instant)>,
+}
+
+impl StaleTabs {
+    /// Record the tabs that a sweep at `now` found to be `stale`, and
+    /// return those that have been stale for their grace period.
+    /// A tab that is no longer stale, or is stale for a different
+    /// reason, has to start its grace period over again.
+    fn sweep(
+        &mut self,
+        stale: &[(TabId, Staleness)],
+        now: Instant,
+        grace: GracePeriods,
+    ) -> Vec<TabId> {
+        let previous = std::mem::take(&mut self.since);
+        let mut due = vec![];
+        for &(tab_id, staleness) in stale {
+            let since = match previous.get(&tab_id) {
+                Some(&(prior, since)) if prior == staleness => since,
+                _ => now,
+            };
+            match grace.get(staleness) {
+                Some(grace) if now.duration_since(since) >= grace => due.push(tab_id),
+                _ => {
+                    self.since.insert(tab_id, (staleness, since));
+                }
+            }
+        }
+        due
+    }
+}
+
+/// Returns the tabs of the mux that have been left behind
+fn find_stale_tabs(mux: &Mux) -> Vec<(TabId, Staleness)> {
+    let in_windows: HashSet<TabId> = mux
+        .iter_windows()
+        .into_iter()
+        .filter_map(|window_id| mux.get_window(window_id))
+        .flat_map(|window| window.iter().map(|tab| tab.tab_id()).collect::<Vec<_>>())
+        .collect();
+
+    let mut stale = vec![];
+    for tab in mux.iter_tabs() {
+        let tab_id = tab.tab_id();
+        let panes = tab.iter_panes_ignoring_zoom();
+        let live = panes.iter().any(|pos| pos.pane.exit_status().is_none());
+        if !in_windows.contains(&tab_id) {
+            // A tab that is out of every window may yet be added to
+            // one, and its processes may still be doing useful work
+            if !live {
+                stale.push((tab_id, Staleness::Orphaned));
+            }
+            continue;
+        }
+        let exited = !panes.is_empty() && !live;
+        if exited && !crate::clipboard::has_interested_session(tab_id) {
+            stale.push((tab_id, Staleness::Exited));
+        }
+    }
+    stale
+}
+
+fn sweep(stale_tabs: &Mutex<StaleTabs>, grace: GracePeriods) {
+    let mux = match Mux::get() {
+        Some(mux) => mux,
+        None => return,
+    };
+    let stale = find_stale_tabs(&mux);
+    let due = stale_tabs
+        .lock()
+        .unwrap()
+        .sweep(&stale, Instant::now(), grace);
+    for tab_id in due {
+        log::info!("removing tab {}, which has been left behind", tab_id);
+        mux.remove_tab(tab_id);
+        mux.notify(MuxNotification::TabExited(tab_id));
+    }
+}
+
+/// Start sweeping the mux for stale tabs, unless it is disabled
+/// by `mux_stale_tab_sweep_interval_secs`
+pub fn start() {
+    let config = configuration();
+    if config.mux_stale_tab_sweep_interval_secs == 0 {
+        return;
+    }
+    let interval = Duration::from_secs(config.mux_stale_tab_sweep_interval_secs);
+    // An orphaned tab must be seen by two sweeps, so that a tab that
+    // is between being created and being added to its window is safe
+    let grace = GracePeriods {
+        orphaned: interval,
+        exited: config
+            .mux_reap_exited_tabs_after_secs
+            .map(Duration::from_secs),
+    };
+    let stale_tabs = Arc::new(Mutex::new(StaleTabs::default()));
+
+    smol::spawn(async move {
+        loop {
+            smol::Timer::after(interval).await;
+            let stale_tabs = Arc::clone(&stale_tabs);
+            spawn_into_main_thread(async move {
+                sweep(&stale_tabs, grace);
+            })
+            .detach();
+        }
+    })
+    .detach();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const GRACE: GracePeriods = GracePeriods {
+        orphaned: Duration::from_secs(60),
+        exited: Some(Duration::from_secs(300)),
+    };
+
+    #[test]
+    fn tabs_are_reaped_after_their_grace_period() {
+        let mut stale_tabs = StaleTabs::default();
+        let start = Instant::now();
+        let stale = [(1, Staleness::Orphaned), (2, Staleness::Exited)];
+
+        assert!(stale_tabs.sweep(&stale, start, GRACE).is_empty());
+        assert_eq!(
+            stale_tabs.sweep(&stale, start + Duration::from_secs(60), GRACE),
+            vec![1]
+        );
+        assert_eq!(
+            stale_tabs.sweep(&stale[1..], start + Duration::from_secs(300), GRACE),
+            vec![2]
+        );
+    }
+
+    #[test]
+    fn recovered_tabs_start_over() {
+        let mut stale_tabs = StaleTabs::default();
+        let start = Instant::now();
+        let later = |secs| start + Duration::from_secs(secs);
+
+        stale_tabs.sweep(&[(1, Staleness::Orphaned)], start, GRACE);
+        // The tab was added to a window, and then removed from it
+        stale_tabs.sweep(&[], later(30), GRACE);
+        assert!(stale_tabs
+            .sweep(&[(1, Staleness::Orphaned)], later(60), GRACE)
+            .is_empty());
+        assert_eq!(
+            stale_tabs.sweep(&[(1, Staleness::Orphaned)], later(120), GRACE),
+            vec![1]
+        );
+
+        // Exited tabs are kept unless a grace period is configured
+        let keep = GracePeriods {
+            exited: None,
+            ..GRACE
+        };
+        stale_tabs.sweep(&[(2, Staleness::Exited)], later(200), keep);
+        assert!(stale_tabs
+            .sweep(&[(2, Staleness::Exited)], later(1_000_000), keep)
+            .is_empty());
+    }
+}
@@ -0,0 +1,193 @@
+//! Renders the viewport of a tab to a PNG image without a window,
+//! using the fonts and colors from the configuration.
+//!
+//! The rendering is deliberately simple; each cell is shaped on its
+//! own, so ligatures are not formed, and there is no cursor, selection
+//! or underline.  It is intended for previews and thumbnails rather than
+//! as a faithful reproduction of what the gui would display.
+use anyhow::{anyhow, Context};
+use config::configuration;
+use mux::tab::Tab;
+use std::rc::Rc;
+use termwiz::cell::CellAttributes;
+use wezterm_bidi::Direction;
+use wezterm_font::{FontConfiguration, RasterizedGlyph};
+use wezterm_term::color::{ColorPalette, RgbColor};
+use wezterm_term::StableRowIndex;
+
+/// Used when the configuration doesn't specify the dpi, as there
+/// is no display from which to obtain it
+const DEFAULT_DPI: f64 = 96.;
+
+/// An RGBA image that is drawn into one cell at a time
+struct Canvas {
+    width: usize,
+    height: usize,
+    data: Vec<u8>,
+}
+
+impl Canvas {
+    fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            data: vec![0u8; width * height * 4],
+        }
+    }
+
+    fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: RgbColor) {
+        let (red, green, blue) = color.to_tuple_rgb8();
+        for row in y..(y + height).min(self.height) {
+            for col in x..(x + width).min(self.width) {
+                let idx = (row * self.width + col) * 4;
+                self.data[idx..idx + 4].copy_from_slice(&[red, green, blue, 0xff]);
+            }
+        }
+    }
+
+    /// Composite `glyph` with its top left corner at (x, y).
+    /// Monochrome glyphs are tinted with `fg`; color glyphs are
+    /// drawn as they are.
+    fn draw_glyph(&mut self, x: isize, y: isize, glyph: &RasterizedGlyph, fg: RgbColor) {
+        let (fg_red, fg_green, fg_blue) = fg.to_tuple_rgb8();
+        for glyph_row in 0..glyph.height {
+            let row = y + glyph_row as isize;
+            if row < 0 || row as usize >= self.height {
+                continue;
+            }
+            for glyph_col in 0..glyph.width {
+                let col = x + glyph_col as isize;
+                if col < 0 || col as usize >= self.width {
+                    continue;
+                }
+                let src = (glyph_row * glyph.width + glyph_col) * 4;
+                let alpha = glyph.data[src + 3] as u32;
+                if alpha == 0 {
+                    continue;
+                }
+                let (red, green, blue) = if glyph.has_color {
+                    // The color glyph data is premultiplied
+                    (glyph.data[src], glyph.data[src + 1], glyph.data[src + 2])
+                } else {
+                    (
+                        premultiply(fg_red, alpha),
+                        premultiply(fg_green, alpha),
+                        premultiply(fg_blue, alpha),
+                    )
+                };
+                let dest = (row as usize * self.width + col as usize) * 4;
+                let pixel = &mut self.data[dest..dest + 4];
+                pixel[0] = red.saturating_add(premultiply(pixel[0], 255 - alpha));
+                pixel[1] = green.saturating_add(premultiply(pixel[1], 255 - alpha));
+                pixel[2] = blue.saturating_add(premultiply(pixel[2], 255 - alpha));
+                pixel[3] = 0xff;
+            }
+        }
+    }
+
+    fn encode_png(self) -> anyhow::Result<Vec<u8>> {
+        let image = image::RgbaImage::from_raw(self.width as u32, self.height as u32, self.data)
+            .ok_or_else(|| anyhow!("screenshot buffer has the wrong size"))?;
+        let mut png = vec![];
+        image::DynamicImage::ImageRgba8(image)
+            .write_to(
+                &mut std::io::Cursor::new(&mut png),
+                image::ImageOutputFormat::Png,
+            )
+            .context("encoding screenshot as png")?;
+        Ok(png)
+    }
+}
+
+fn premultiply(value: u8, alpha: u32) -> u8 {
+    ((value as u32 * alpha) / 255) as u8
+}
+
+fn cell_colors(palette: &ColorPalette, attrs: &CellAttributes) -> (RgbColor, RgbColor) {
+    let fg = palette.resolve_fg(attrs.foreground());
+    let bg = palette.resolve_bg(attrs.background());
+    if attrs.reverse() {
+        (bg, fg)
+    } else {
+        (fg, bg)
+    }
+}
+
+/// Render the panes that are visible in `tab` and return the
+/// result encoded as a PNG
+pub fn screenshot_tab(tab: &Rc<Tab>) -> anyhow::Result<Vec<u8>> {
+    let config = configuration();
+    let dpi = config.dpi.unwrap_or(DEFAULT_DPI) as usize;
+    let fonts = FontConfiguration::new(Some(config.clone()), dpi)?;
+    let metrics = fonts.default_font_metrics()?;
+    let cell_width = metrics.cell_width.get().ceil() as usize;
+    let cell_height = metrics.cell_height.get().ceil() as usize;
+    let baseline = (metrics.cell_height + metrics.descender).get();
+
+    let size = tab.get_size();
+    let mut canvas = Canvas::new(
+        size.cols as usize * cell_width,
+        size.rows as usize * cell_height,
+    );
+
+    for pos in tab.iter_panes() {
+        let palette = pos.pane.palette();
+        canvas.fill_rect(
+            pos.left * cell_width,
+            pos.top * cell_height,
+            pos.width * cell_width,
+            pos.height * cell_height,
+            palette.background,
+        );
+
+        let dims = pos.pane.get_dimensions();
+        let (_first_row, lines) = pos
+            .pane
+            .get_lines(dims.physical_top..dims.physical_top + pos.height as StableRowIndex);
+
+        for (line_idx, line) in lines.iter().enumerate().take(pos.height) {
+            let y = (pos.top + line_idx) * cell_height;
+            for (col, cell) in line.visible_cells() {
+                if col >= pos.width {
+                    break;
+                }
+                let attrs = cell.attrs();
+                let (fg, bg) = cell_colors(&palette, attrs);
+                let x = (pos.left + col) * cell_width;
+                let width = cell.width().min(pos.width - col);
+                canvas.fill_rect(x, y, width * cell_width, cell_height, bg);
+
+                let text = cell.str();
+                if attrs.invisible() || text.trim().is_empty() {
+                    continue;
+                }
+
+                let style = fonts.match_style(&config, attrs);
+                let font = fonts.resolve_font(style)?;
+                let glyphs = font.blocking_shape(
+                    text,
+                    Some(cell.presentation()),
+                    Direction::LeftToRight,
+                    None,
+                    None,
+                )?;
+                let mut pen_x = x as f64;
+                for info in glyphs {
+                    if !info.is_space {
+                        let glyph = font.rasterize_glyph(info.glyph_pos, info.font_idx)?;
+                        canvas.draw_glyph(
+                            (pen_x + info.x_offset.get() + glyph.bearing_x.get()).round() as isize,
+                            (y as f64 + baseline - info.y_offset.get() - glyph.bearing_y.get())
+                                .round() as isize,
+                            &glyph,
+                            fg,
+                        );
+                    }
+                    pen_x += info.x_advance.get();
+                }
+            }
+        }
+    }
+
+    canvas.encode_png()
+}
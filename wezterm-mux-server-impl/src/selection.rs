@@ -0,0 +1,30 @@
+use codec::SelectionRange;
+use mux::pane::PaneId;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+lazy_static::lazy_static! {
+    static ref SELECTIONS: Mutex<HashMap<PaneId, SelectionRange>> = Mutex::new(HashMap::new());
+}
+
+/// Set the selection that is shared by all of the sessions that are
+/// viewing `pane_id`, or clear it if `selection` is `None`.
+/// Returns true if the selection was changed.
+pub fn set_selection(pane_id: PaneId, selection: Option<SelectionRange>) -> bool {
+    let mut selections = SELECTIONS.lock().unwrap();
+    let prior = match selection {
+        Some(selection) => selections.insert(pane_id, selection),
+        None => selections.remove(&pane_id),
+    };
+    prior != selection
+}
+
+/// Returns the shared selection for `pane_id`, if any
+pub fn get_selection(pane_id: PaneId) -> Option<SelectionRange> {
+    SELECTIONS.lock().unwrap().get(&pane_id).cloned()
+}
+
+/// Discard the selection for a pane that has been removed
+pub fn forget_pane(pane_id: PaneId) {
+    SELECTIONS.lock().unwrap().remove(&pane_id);
+}
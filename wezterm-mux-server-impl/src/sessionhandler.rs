@@ -1,24 +1,74 @@
+use crate::auth::AuthToken;
+use crate::barrier::RequestTracker;
+use crate::mainthread;
+use crate::mouse::{MouseQueue, QueuedMouseEvent};
+use crate::spawnlimit::SpawnLimiter;
+use crate::stats::{attached_clients, kill_session, list_sessions, session_stats, SessionStats};
+use crate::tail::Tail;
+use crate::trickle::Trickles;
 use crate::PKI;
 use anyhow::{anyhow, Context};
 use codec::*;
+use config::{configuration, ExitBehavior};
 use mux::client::ClientId;
+use mux::domain::{DomainId, DomainState};
 use mux::pane::{Pane, PaneId};
 use mux::renderable::{RenderableDimensions, StableCursorPosition};
-use mux::tab::TabId;
-use mux::Mux;
-use promise::spawn::spawn_into_main_thread;
-use std::collections::HashMap;
+use mux::tab::{PaneLayout, PaneNode, Tab, TabId};
+use mux::window::WindowId;
+use mux::{Mux, MuxNotification};
+use promise::spawn::Task;
+use rangeset::RangeSet;
+use ratelim::RateLimiter;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::ops::Range;
 use std::rc::Rc;
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
-use termwiz::surface::SequenceNo;
+use std::time::{Duration, Instant};
+use termwiz::cell::CellAttributes;
+use termwiz::escape::osc::Progress;
+use termwiz::surface::{Line, SequenceNo};
+use tracing::Instrument;
 use url::Url;
-use wezterm_term::terminal::{Alert, Clipboard, ClipboardSelection};
-use wezterm_term::StableRowIndex;
+use wezterm_term::terminal::{Alert, TerminalModes};
+use wezterm_term::{ClipboardSelection, StableRowIndex};
+
+/// The maximum number of lines sent in a single GetLinesResponse PDU;
+/// larger requests are split into multiple chunks.
+const MAX_LINES_PER_RESPONSE: usize = 256;
+
+/// The algorithm with which we compare the rows that a client
+/// already has; see `GetLines::known_hashes`
+const ROW_HASH: RowHashAlgorithm = RowHashAlgorithm::Fnv1a64;
+
+/// Rows of at least this many cells are sent as a `LineDelta` when
+/// few of their cells have changed, such as when a cursor blinks on
+/// an ultrawide display.  A narrower row is cheap enough to send whole.
+const LINE_DELTA_MIN_COLS: usize = 200;
+
+/// A `LineDelta` is sent in place of a row when it holds no more than
+/// this fraction of the cells of the row
+const LINE_DELTA_MAX_FRACTION: usize = 4;
+
+/// The number of rejected requests that an unauthenticated session
+/// may make before it is disconnected.
+const MAX_AUTH_FAILURES: usize = 3;
+
+/// When the number of PDUs queued for writing to the client reaches
+/// this level, the client is considered to be congested and render
+/// pushes are deferred until the queue has drained.  This is a soft
+/// limit: responses to requests and notifications are always queued,
+/// so the queue itself is unbounded.  The value is large enough to
+/// absorb a burst of output across a handful of panes without
+/// deferring, while limiting how far the render pushes queued for a
+/// slow client can fall behind.
+pub const WRITE_QUEUE_HIGH_WATER: usize = 64;
 
 #[derive(Clone)]
 pub struct PduSender {
     func: Arc<dyn Fn(DecodedPdu) -> anyhow::Result<()> + Send + Sync>,
+    queue_len: Arc<dyn Fn() -> usize + Send + Sync>,
 }
 
 impl PduSender {
@@ -30,107 +80,745 @@ impl PduSender {
     where
         T: Fn(DecodedPdu) -> anyhow::Result<()> + Send + Sync + 'static,
     {
-        Self { func: Arc::new(f) }
+        Self {
+            func: Arc::new(f),
+            queue_len: Arc::new(|| 0),
+        }
+    }
+
+    /// Provide a function that reports the number of PDUs that are
+    /// queued and waiting to be written to the client
+    pub fn with_queue_len<L>(mut self, queue_len: L) -> Self
+    where
+        L: Fn() -> usize + Send + Sync + 'static,
+    {
+        self.queue_len = Arc::new(queue_len);
+        self
+    }
+
+    /// Returns the number of PDUs that are waiting to be
+    /// written to the client
+    pub fn queue_len(&self) -> usize {
+        (self.queue_len)()
+    }
+
+    /// Returns true if the client isn't keeping up with the
+    /// PDUs that we are sending to it
+    pub fn is_congested(&self) -> bool {
+        self.queue_len() >= WRITE_QUEUE_HIGH_WATER
     }
 }
 
+/// Identical desktop notifications from a pane that arrive within
+/// this interval of the last one that was sent are dropped
+const NOTIFICATION_COALESCE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long changes to a pane in a tab that the client isn't
+/// displaying are held back, so that they can be coalesced into
+/// a single push
+const BACKGROUND_PUSH_DELAY: Duration = Duration::from_millis(500);
+
+/// For this long after the client types into a pane, the changes
+/// to the pane are treated as the echo of what it typed, and are
+/// pushed as soon as they are made: they are neither held back as
+/// changes to a background tab nor deferred by congestion
+const ECHO_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The number of rows of scrollback sent in each `ScrollbackChunk`.
+/// This is kept small so that a chunk doesn't keep the client
+/// waiting for long for the render changes queued behind it.
+const TRICKLE_CHUNK_ROWS: usize = 64;
+
+/// How long to wait before sending the next `ScrollbackChunk`, which
+/// gives the requests that the client makes in the meantime a chance
+/// to be answered first
+const TRICKLE_STEP_DELAY: Duration = Duration::from_millis(10);
+
+#[derive(Debug)]
+struct SentNotification {
+    title: Option<String>,
+    body: String,
+    when: Instant,
+}
+
 #[derive(Default, Debug)]
 pub(crate) struct PerPane {
     cursor_position: StableCursorPosition,
     title: String,
+    title_bytes: Option<Vec<u8>>,
     working_dir: Option<Url>,
+    working_dir_bytes: Option<Vec<u8>>,
+    progress: Progress,
     dimensions: RenderableDimensions,
     mouse_grabbed: bool,
+    bracketed_paste: bool,
+    terminal_modes: TerminalModes,
+    selection: Option<SelectionRange>,
     sent_initial_palette: bool,
     seqno: SequenceNo,
     config_generation: usize,
     pub(crate) notifications: Vec<Alert>,
+    push_deferred: bool,
+    acked_images: Arc<Mutex<HashSet<ImageHash>>>,
+    /// Set when the client has asked for the complete state of the
+    /// pane, regardless of what we have previously sent to it
+    full_resync: bool,
+    last_notification: Option<SentNotification>,
+    /// The input serial of the response in which each row was last
+    /// sent, along with the seqno of the row at that time
+    sent_rows: HashMap<StableRowIndex, (InputSerial, SequenceNo)>,
+    /// The content hash of each viewport row as it was last sent,
+    /// when mux_skip_unchanged_rows is enabled
+    row_hashes: HashMap<StableRowIndex, u64>,
+    /// The wide viewport rows as they were last sent, against which
+    /// the next change to each of them is sent as a `LineDelta`
+    sent_lines: HashMap<StableRowIndex, Line>,
+    /// The most recent input serial that the client has applied
+    acked_input_serial: Option<InputSerial>,
+    /// Set while a delayed push is scheduled for a pane that is
+    /// in the background
+    background_push_pending: bool,
+    /// The damage_seqno of the most recent render changes response
+    damage_seqno: u64,
+    /// Set when the client has asked for changes to be sent
+    /// without bonus_lines
+    omit_bonus_lines: bool,
+    /// Set when the client has asked for the next changes to hold
+    /// just the viewport
+    viewport_only: bool,
+    /// The bytes sent to the client for the render changes of
+    /// this pane, and for the lines that it fetched
+    render_changes_bytes: u64,
+    get_lines_bytes: u64,
+    /// The resize_id of the most recent Resize that the client
+    /// sent, and the one that we last sent back to confirm it
+    last_resize_id: Option<u64>,
+    sent_resize_id: Option<u64>,
+    /// When the client last typed into the pane
+    last_key_input: Option<Instant>,
+    /// Set by a key press until the push that carries its echo
+    echo_pending_since: Option<Instant>,
+    /// The number of echoes that were measured, and the total
+    /// time that the client waited for them
+    echoes: u64,
+    echo_latency: Duration,
+    /// Whether the alternate screen was active when changes were
+    /// last sent, or None if none have been sent
+    alt_screen: Option<bool>,
+    /// The pen that was last sent
+    pen: Option<CellAttributes>,
+}
+
+/// The changes to a pane that are to be sent to the client
+enum PaneChanges {
+    /// Nothing but the cursor has changed in response to input
+    CursorOnly(CursorUpdate),
+    Full(GetPaneRenderChangesResponse),
+}
+
+impl PaneChanges {
+    fn into_pdu(self) -> Pdu {
+        match self {
+            Self::CursorOnly(update) => Pdu::CursorUpdate(update),
+            Self::Full(resp) => Pdu::GetPaneRenderChangesResponse(resp),
+        }
+    }
 }
 
 impl PerPane {
+    /// Forget what we have sent to the client, so that the next
+    /// push includes everything about the pane
+    fn invalidate(&mut self) {
+        *self = PerPane {
+            acked_images: Arc::clone(&self.acked_images),
+            notifications: std::mem::take(&mut self.notifications),
+            // The client resyncs after a gap, so the sequence
+            // continues across an invalidation
+            damage_seqno: self.damage_seqno,
+            omit_bonus_lines: self.omit_bonus_lines,
+            render_changes_bytes: self.render_changes_bytes,
+            get_lines_bytes: self.get_lines_bytes,
+            last_resize_id: self.last_resize_id,
+            echoes: self.echoes,
+            echo_latency: self.echo_latency,
+            // The client still holds the rows of that screen
+            alt_screen: self.alt_screen,
+            full_resync: true,
+            ..PerPane::default()
+        };
+    }
+
+    /// Record that the client has applied the changes that were
+    /// sent in response to input up to and including `serial`
+    fn acknowledge_input_serial(&mut self, serial: InputSerial) {
+        if self.acked_input_serial.map_or(true, |acked| serial > acked) {
+            self.acked_input_serial.replace(serial);
+        }
+    }
+
+    /// Returns true if the client already holds `row` as it is at
+    /// `seqno`, because it has acknowledged the response to input
+    /// in which the row was last sent
+    fn client_has_row(&self, row: StableRowIndex, seqno: SequenceNo) -> bool {
+        match (self.acked_input_serial, self.sent_rows.get(&row)) {
+            (Some(acked), Some((serial, sent_seqno))) => *serial <= acked && *sent_seqno == seqno,
+            _ => false,
+        }
+    }
+
+    /// Note that the client typed into the pane at `now`
+    fn record_key_input(&mut self, now: Instant) {
+        self.last_key_input.replace(now);
+        if self.echo_pending_since.is_none() {
+            self.echo_pending_since.replace(now);
+        }
+    }
+
+    /// Returns true if the client typed into the pane within
+    /// ECHO_INTERVAL of `now`
+    fn awaiting_echo(&self, now: Instant) -> bool {
+        self.last_key_input
+            .map_or(false, |at| now.duration_since(at) < ECHO_INTERVAL)
+    }
+
+    /// Record the time that the client waited for the echo of its
+    /// earliest unechoed key press, which is carried by a push that
+    /// is being sent at `now`
+    fn record_echo(&mut self, now: Instant) {
+        if let Some(since) = self.echo_pending_since.take() {
+            let elapsed = now.duration_since(since);
+            // Input that produced no output doesn't have an echo
+            if elapsed < ECHO_INTERVAL {
+                self.echoes += 1;
+                self.echo_latency += elapsed;
+            }
+        }
+    }
+
+    /// Note which screen is active, returning true if that has changed
+    /// since changes were last sent.  The rows that the client holds
+    /// are of the other screen, so what we recorded about them is
+    /// forgotten.
+    fn switch_screen(&mut self, alt_screen: bool) -> bool {
+        let switched = self.alt_screen.map_or(false, |prior| prior != alt_screen);
+        self.alt_screen.replace(alt_screen);
+        if switched {
+            self.sent_rows.clear();
+            self.row_hashes.clear();
+            self.sent_lines.clear();
+        }
+        switched
+    }
+
+    /// Returns true if `line` has the same content as it did when
+    /// `row` was last sent to the client, and records its hash
+    /// for the next comparison
+    fn row_is_unchanged(&mut self, row: StableRowIndex, line: &Line) -> bool {
+        let hash = line.content_hash();
+        self.row_hashes.insert(row, hash) == Some(hash)
+    }
+
+    /// The client is fetching `rows` itself, and will hold whatever
+    /// content they have now, so stop comparing against the hashes
+    /// and lines that we recorded for them
+    fn forget_row_hashes(&mut self, rows: &[Range<StableRowIndex>]) {
+        self.row_hashes
+            .retain(|row, _| !rows.iter().any(|range| range.contains(row)));
+        self.sent_lines
+            .retain(|row, _| !rows.iter().any(|range| range.contains(row)));
+    }
+
+    /// Add a delta to `deltas` for the changes to `line` since `row`
+    /// was last sent, if it is wide and few of its cells have changed,
+    /// returning false if the whole line has to be sent instead.
+    /// Either way, `line` is recorded as what the client holds.
+    fn push_line_delta(
+        &mut self,
+        row: StableRowIndex,
+        line: &Line,
+        deltas: &mut LineDeltas,
+    ) -> bool {
+        let cols = line.cells().len();
+        if cols < LINE_DELTA_MIN_COLS {
+            self.sent_lines.remove(&row);
+            return false;
+        }
+        let pushed = match self.sent_lines.get(&row) {
+            Some(base) => deltas.push(row, base, line, ROW_HASH, cols / LINE_DELTA_MAX_FRACTION),
+            None => false,
+        };
+        self.sent_lines.insert(row, line.clone());
+        pushed
+    }
+
+    /// Returns true if a desktop notification should be sent to the
+    /// client, or false if it is a repeat of the notification that
+    /// was sent moments ago
+    fn should_send_notification(
+        &mut self,
+        title: &Option<String>,
+        body: &str,
+        now: Instant,
+    ) -> bool {
+        if let Some(last) = &self.last_notification {
+            if last.title == *title
+                && last.body == body
+                && now.duration_since(last.when) < NOTIFICATION_COALESCE_INTERVAL
+            {
+                return false;
+            }
+        }
+        self.last_notification.replace(SentNotification {
+            title: title.clone(),
+            body: body.to_string(),
+            when: now,
+        });
+        true
+    }
+
     fn compute_changes(
         &mut self,
         pane: &Rc<dyn Pane>,
         force_with_input_serial: Option<InputSerial>,
-    ) -> Option<GetPaneRenderChangesResponse> {
+    ) -> Option<PaneChanges> {
         let mut changed = false;
         let mouse_grabbed = pane.is_mouse_grabbed();
         if mouse_grabbed != self.mouse_grabbed {
             changed = true;
         }
 
+        let bracketed_paste = pane.is_bracketed_paste_enabled();
+        if bracketed_paste != self.bracketed_paste {
+            changed = true;
+        }
+
+        let terminal_modes = pane.get_terminal_modes();
+        if terminal_modes != self.terminal_modes {
+            changed = true;
+        }
+        let screen_switched =
+            self.switch_screen(terminal_modes.contains(TerminalModes::ALT_SCREEN));
+
+        let selection = crate::selection::get_selection(pane.pane_id());
+        if selection != self.selection {
+            changed = true;
+        }
+
+        // Note that this also catches changes to the scrollback size,
+        // and to physical_top and scrollback_top, which shift as output
+        // scrolls lines into the scrollback or as it gets trimmed.
+        // The client needs those to keep its stable row mapping and
+        // scrollbar accurate.
         let dims = pane.get_dimensions();
         if dims != self.dimensions {
             changed = true;
         }
+        // Confirm a resize even if it left the size as it was
+        if self.last_resize_id != self.sent_resize_id {
+            changed = true;
+        }
 
         let cursor_position = pane.get_cursor_position();
-        if cursor_position != self.cursor_position {
-            changed = true;
+        let cursor_moved = cursor_position != self.cursor_position;
+
+        // The pen is only sent when it has changed, which is rare
+        // compared to the pushes that carry the cursor position
+        let pen = pane.get_pen();
+        let pen_changed = pen != self.pen;
+        if pen_changed {
+            self.pen = pen.clone();
         }
+        let pen = if pen_changed { pen } else { None };
 
-        let title = pane.get_title();
-        if title != self.title {
+        let title = reported_title(pane);
+        // Distinct undecodable titles can have the same lossy
+        // decoding, so compare their bytes too.  They don't apply
+        // to a title that has been pinned or otherwise replaced.
+        let title_bytes = pane
+            .get_title_bytes()
+            .filter(|bytes| String::from_utf8_lossy(bytes) == title);
+        if title != self.title || title_bytes != self.title_bytes {
             changed = true;
         }
 
         let working_dir = pane.get_current_working_dir();
-        if working_dir != self.working_dir {
+        let working_dir_bytes = pane.get_current_working_dir_bytes();
+        if !same_working_dir(&working_dir, &self.working_dir)
+            || working_dir_bytes != self.working_dir_bytes
+        {
             changed = true;
         }
 
-        let mut all_dirty_lines = pane.get_changed_since(
-            0..dims.physical_top + dims.viewport_rows as StableRowIndex,
-            self.seqno,
-        );
+        // A client that has just attached wants the viewport and
+        // nothing else; it fetches the scrollback later on, so the
+        // rows outside of the viewport aren't reported as dirty
+        let viewport_only = std::mem::take(&mut self.viewport_only);
+        let mut all_dirty_lines = if viewport_only {
+            changed = true;
+            RangeSet::new()
+        } else {
+            pane.get_changed_since(
+                dims.scrollback_top..dims.physical_top + dims.viewport_rows as StableRowIndex,
+                self.seqno,
+            )
+        };
+        // Changing the width rewraps the lines, which can move their
+        // content to different stable rows, so the client needs all
+        // of them again.  The wrapped attribute on the last cell of
+        // each line tells it which are continued on the next row.
+        let rewrapped = dims.cols != self.dimensions.cols;
+        if rewrapped {
+            self.sent_rows.clear();
+        }
+        if std::mem::take(&mut self.full_resync) || rewrapped || screen_switched {
+            self.row_hashes.clear();
+            self.sent_lines.clear();
+            if !viewport_only {
+                all_dirty_lines.add_range(
+                    dims.scrollback_top..dims.physical_top + dims.viewport_rows as StableRowIndex,
+                );
+            }
+        }
         if !all_dirty_lines.is_empty() {
             changed = true;
         }
 
-        if !changed && !force_with_input_serial.is_some() {
-            return None;
+        if !changed {
+            match force_with_input_serial {
+                // The text is unchanged, so the position of the cursor,
+                // along with the pen if that has changed, is all that the
+                // client needs in order to reconcile its predictive echo;
+                // skip fetching and serializing lines
+                Some(input_serial) => {
+                    self.cursor_position = cursor_position;
+                    self.seqno = pane.get_current_seqno();
+                    return Some(PaneChanges::CursorOnly(CursorUpdate {
+                        pane_id: pane.pane_id(),
+                        cursor_position,
+                        input_serial,
+                        pen,
+                    }));
+                }
+                None if !cursor_moved && !pen_changed => return None,
+                None => {}
+            }
         }
 
         // Figure out what we're going to send as dirty lines vs bonus lines
         let viewport_range =
             dims.physical_top..dims.physical_top + dims.viewport_rows as StableRowIndex;
 
-        let (first_line, lines) = pane.get_lines(viewport_range);
-        let mut bonus_lines = lines
-            .into_iter()
-            .enumerate()
-            .map(|(idx, line)| {
-                let stable_row = first_line + idx as StableRowIndex;
-                all_dirty_lines.remove(stable_row);
-                (stable_row, line)
-            })
-            .collect::<Vec<_>>();
+        // Send the lines in the viewport as bonus lines, as the client
+        // will almost certainly want them.
+        // The lines are moved into the response, so size the vec up
+        // front to avoid re-allocating it as we push into it.  It can't
+        // be kept in PerPane and reused from one push to the next, as
+        // the response owns it until it has been written to the client,
+        // and get_lines hands us freshly allocated lines regardless.
+        // A client that has opted out of bonus lines gets none at all,
+        // leaving every changed row in the dirty lines.
+        let (first_line, lines) = if self.omit_bonus_lines && !viewport_only {
+            (viewport_range.start, vec![])
+        } else {
+            pane.get_lines(viewport_range.clone())
+        };
+        let mut bonus_lines = Vec::with_capacity(lines.len());
+        let mut line_deltas = LineDeltas::default();
+        // Rows that the client has acknowledged receiving are skipped
+        self.sent_rows.retain(|row, _| viewport_range.contains(row));
+        // Some programs repaint the whole screen when little of it has
+        // changed; when enabled, the rows whose content is the same as
+        // what we last sent are dropped from both the bonus and the
+        // dirty lines, as the client already holds them
+        let skip_unchanged_rows = configuration().mux_skip_unchanged_rows;
+        for (idx, line) in lines.into_iter().enumerate() {
+            let stable_row = first_line + idx as StableRowIndex;
+            if viewport_only {
+                // Seed the hashes as though the row had been compared
+                if skip_unchanged_rows {
+                    self.row_hashes.insert(stable_row, line.content_hash());
+                }
+                bonus_lines.push((stable_row, line));
+                continue;
+            }
+            let is_cursor_row =
+                force_with_input_serial.is_some() && stable_row == cursor_position.y;
+            all_dirty_lines.remove(stable_row);
+            if self.client_has_row(stable_row, line.current_seqno()) {
+                continue;
+            }
+            if skip_unchanged_rows && self.row_is_unchanged(stable_row, &line) && !is_cursor_row {
+                continue;
+            }
+            if let Some(serial) = force_with_input_serial {
+                self.sent_rows
+                    .insert(stable_row, (serial, line.current_seqno()));
+            }
+            if !self.push_line_delta(stable_row, &line, &mut line_deltas) {
+                bonus_lines.push((stable_row, line));
+            }
+        }
+
+        // The cursor is usually within the viewport, in which case it was
+        // sent above and there is no need to fetch it a second time.
+        // Otherwise its row is sent only if it changed, or when responding
+        // to input, so that the client can reconcile its predictive echo;
+        // that keeps the traffic low for programs that move the cursor
+        // around without changing the text.
+        if (force_with_input_serial.is_some() || all_dirty_lines.contains(cursor_position.y))
+            && !self.omit_bonus_lines
+            && !viewport_range.contains(&cursor_position.y)
+        {
+            let (cursor_line, mut lines) = pane.get_lines(cursor_position.y..cursor_position.y + 1);
+            if let Some(line) = lines.pop() {
+                all_dirty_lines.remove(cursor_line);
+                bonus_lines.push((cursor_line, line));
+            }
+        }
 
-        // Always send the cursor's row, as that tends to the busiest and we don't
-        // have a sequencing concept for our idea of the remote state.
-        let (cursor_line, lines) = pane.get_lines(cursor_position.y..cursor_position.y + 1);
-        bonus_lines.push((cursor_line, lines[0].clone()));
+        // The rows that remain dirty are sent without their content,
+        // so the client will discard its copy of them
+        if !self.row_hashes.is_empty() {
+            self.row_hashes
+                .retain(|row, _| viewport_range.contains(row) && !all_dirty_lines.contains(*row));
+        }
+        if !self.sent_lines.is_empty() {
+            self.sent_lines
+                .retain(|row, _| viewport_range.contains(row) && !all_dirty_lines.contains(*row));
+        }
 
         self.cursor_position = cursor_position;
         self.title = title.clone();
+        self.title_bytes = title_bytes.clone();
         self.working_dir = working_dir.clone();
+        self.working_dir_bytes = working_dir_bytes.clone();
         self.dimensions = dims;
         self.mouse_grabbed = mouse_grabbed;
+        self.bracketed_paste = bracketed_paste;
+        self.terminal_modes = terminal_modes;
+        self.selection = selection;
         self.seqno = pane.get_current_seqno();
+        self.sent_resize_id = self.last_resize_id;
 
-        let bonus_lines = bonus_lines.into();
-        Some(GetPaneRenderChangesResponse {
+        let bonus_lines = serialize_lines(bonus_lines, &self.acked_images, false);
+        self.damage_seqno += 1;
+        Some(PaneChanges::Full(GetPaneRenderChangesResponse {
             pane_id: pane.pane_id(),
             mouse_grabbed,
+            bracketed_paste,
+            terminal_modes,
             dirty_lines: all_dirty_lines.iter().cloned().collect(),
             dimensions: dims,
             cursor_position,
             title,
+            title_bytes,
             bonus_lines,
+            line_deltas,
             working_dir: working_dir.map(Into::into),
+            working_dir_bytes,
             input_serial: force_with_input_serial,
             seqno: self.seqno,
-        })
+            selection,
+            damage_seqno: self.damage_seqno,
+            last_resize_id: self.last_resize_id,
+            screen_switched,
+            pen,
+        }))
+    }
+}
+
+/// Returns a copy of `url` with the differences that don't change
+/// which directory it refers to removed: the hostname is lowercased
+/// and any trailing slashes are removed from the path
+fn normalize_working_dir(url: &Url) -> Url {
+    let mut url = url.clone();
+    if let Some(host) = url.host_str() {
+        let host = host.to_ascii_lowercase();
+        // This can only fail for a host that didn't parse in the
+        // first place, in which case leave it as it is
+        let _ = url.set_host(Some(&host));
+    }
+    let path = url.path().trim_end_matches('/');
+    if path.is_empty() {
+        url.set_path("/");
+    } else if path.len() != url.path().len() {
+        let path = path.to_string();
+        url.set_path(&path);
+    }
+    url
+}
+
+/// Returns true if `a` and `b` refer to the same working directory.
+/// Some shells report the working directory on every prompt with
+/// cosmetic differences, which shouldn't cause a push to the client.
+fn same_working_dir(a: &Option<Url>, b: &Option<Url>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a == b || normalize_working_dir(a) == normalize_working_dir(b),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+/// Serialize `lines`, sending references to the images that the
+/// client has told us it holds rather than their data
+fn serialize_lines(
+    lines: Vec<(StableRowIndex, Line)>,
+    acked_images: &Mutex<HashSet<ImageHash>>,
+    pack_text: bool,
+) -> SerializedLines {
+    let mut lines: SerializedLines = lines.into();
+    let acked_images = acked_images.lock().unwrap();
+    lines.omit_image_data(|hash| acked_images.contains(hash));
+    if pack_text {
+        lines.pack_text();
+    }
+    lines
+}
+
+/// Returns the lines of `pane` in `rows`, along with their rows
+fn stable_lines(pane: &Rc<dyn Pane>, rows: Range<StableRowIndex>) -> Vec<(StableRowIndex, Line)> {
+    let (first_row, lines) = pane.get_lines(rows);
+    lines
+        .into_iter()
+        .enumerate()
+        .map(|(idx, line)| (first_row + idx as StableRowIndex, line))
+        .collect()
+}
+
+/// Send the next chunk of the scrollback that is being pushed
+/// with `TrickleScrollback`
+fn send_scrollback_chunk(
+    trickles: &Mutex<Trickles>,
+    acked_images: &Mutex<HashSet<ImageHash>>,
+    sender: &PduSender,
+) -> anyhow::Result<()> {
+    let mux = Mux::get().unwrap();
+    let chunk = trickles
+        .lock()
+        .unwrap()
+        .next_chunk(TRICKLE_CHUNK_ROWS, |pane_id| {
+            mux.get_pane(pane_id)
+                .map(|pane| pane.get_dimensions().scrollback_top)
+        });
+    let chunk = match chunk {
+        Some(chunk) => chunk,
+        None => return Ok(()),
+    };
+    let pane = mux
+        .get_pane(chunk.pane_id)
+        .ok_or_else(|| no_such_pane(chunk.pane_id))?;
+    let lines = stable_lines(&pane, chunk.rows);
+    sender.send(DecodedPdu {
+        pdu: Pdu::ScrollbackChunk(ScrollbackChunk {
+            pane_id: chunk.pane_id,
+            lines: serialize_lines(lines, acked_images, true),
+            done: chunk.done,
+        }),
+        serial: 0,
+    })
+}
+
+fn list_tabs(mux: &Mux) -> Vec<PaneNode> {
+    let mut tabs = vec![];
+    for window_id in mux.iter_windows().into_iter() {
+        tabs.append(&mut window_tabs(mux, window_id));
+    }
+    tabs
+}
+
+fn window_tabs(mux: &Mux, window_id: WindowId) -> Vec<PaneNode> {
+    match mux.get_window(window_id) {
+        Some(window) => window.iter().map(|tab| tab.codec_pane_tree()).collect(),
+        None => vec![],
+    }
+}
+
+fn window_entry(
+    mux: &Mux,
+    window_id: WindowId,
+    tab_activity: &HashMap<TabId, TabActivity>,
+) -> Option<WindowEntry> {
+    let window = mux.get_window(window_id)?;
+    let title = match window.get_title() {
+        "" => window
+            .get_active()
+            .and_then(|tab| tab.get_active_pane().map(|pane| tab.pane_title(&pane)))
+            .unwrap_or_default(),
+        title => title.to_string(),
+    };
+    Some(WindowEntry {
+        window_id,
+        title,
+        workspace: window.get_workspace().to_string(),
+        tab_ids: window.iter().map(|tab| tab.tab_id()).collect(),
+        tab_activity: window
+            .iter()
+            .map(|tab| tab_activity.get(&tab.tab_id()).copied().unwrap_or_default())
+            .collect(),
+    })
+}
+
+/// The title to report for `pane`, honoring the title policy of
+/// the tab that contains it
+fn reported_title(pane: &Rc<dyn Pane>) -> String {
+    let mux = match Mux::get() {
+        Some(mux) => mux,
+        None => return pane.get_title(),
+    };
+    match mux
+        .resolve_pane_id(pane.pane_id())
+        .and_then(|(_domain_id, _window_id, tab_id)| mux.get_tab(tab_id))
+    {
+        Some(tab) => tab.pane_title(pane),
+        None => pane.get_title(),
+    }
+}
+
+/// Answer a barrier whose preceding requests have completed.
+/// This is done from the main thread, so that the response follows
+/// the work that those requests queued there, such as pushes.
+fn answer_barrier(reply: BarrierReply) {
+    mainthread::spawn(async move {
+        (reply.send_response)(Ok(Pdu::BarrierResponse(BarrierResponse {
+            token: reply.token,
+        })));
+    })
+    .detach();
+}
+
+/// Apply the queued mouse events, then push the changes to
+/// each of the panes that received them before responding
+fn apply_mouse_events(mouse_queue: &Mutex<MouseQueue<MouseReply>>, sender: PduSender) {
+    let events = mouse_queue.lock().unwrap().take();
+    let mux = Mux::get().unwrap();
+    let mut panes: Vec<(Rc<dyn Pane>, Arc<Mutex<PerPane>>)> = vec![];
+    let mut replies = vec![];
+    for QueuedMouseEvent {
+        pane_id,
+        event,
+        reply,
+        ..
+    } in events
+    {
+        let result = mux
+            .get_pane(pane_id)
+            .ok_or_else(|| no_such_pane(pane_id))
+            .and_then(|pane| {
+                pane.mouse_event(event)?;
+                if !panes.iter().any(|(p, _)| p.pane_id() == pane_id) {
+                    panes.push((pane, Arc::clone(&reply.per_pane)));
+                }
+                Ok(Pdu::UnitResponse(UnitResponse {}))
+            });
+        replies.push((reply.send_response, result));
+    }
+    for (pane, per_pane) in panes {
+        if let Err(err) = maybe_push_pane_changes(&pane, sender.clone(), per_pane) {
+            log::error!("pushing changes to pane {}: {:#}", pane.pane_id(), err);
+        }
+    }
+    for (send_response, result) in replies {
+        send_response(result);
     }
 }
 
@@ -140,11 +828,22 @@ fn maybe_push_pane_changes(
     per_pane: Arc<Mutex<PerPane>>,
 ) -> anyhow::Result<()> {
     let mut per_pane = per_pane.lock().unwrap();
-    if let Some(resp) = per_pane.compute_changes(pane, None) {
-        sender.send(DecodedPdu {
-            pdu: Pdu::GetPaneRenderChangesResponse(resp),
-            serial: 0,
-        })?;
+    let now = Instant::now();
+    if sender.is_congested() && !per_pane.awaiting_echo(now) {
+        // The client isn't keeping up.  Rather than queue up another
+        // intermediate render push, leave the changes pending in the
+        // pane; they'll be coalesced into a single push once the
+        // write queue has drained.
+        per_pane.push_deferred = true;
+    } else {
+        per_pane.push_deferred = false;
+        if let Some(changes) = per_pane.compute_changes(pane, None) {
+            per_pane.record_echo(now);
+            sender.send(DecodedPdu {
+                pdu: changes.into_pdu(),
+                serial: 0,
+            })?;
+        }
     }
 
     let config = config::configuration();
@@ -162,7 +861,21 @@ fn maybe_push_pane_changes(
         per_pane.notifications.push(Alert::PaletteChanged);
         per_pane.sent_initial_palette = true;
     }
-    for alert in per_pane.notifications.drain(..) {
+    let progress = pane.get_progress();
+    if progress != per_pane.progress {
+        per_pane.progress = progress;
+        sender.send(DecodedPdu {
+            pdu: Pdu::SetProgress(SetProgress {
+                pane_id: pane.pane_id(),
+                state: progress.state,
+                percent: progress.percent,
+            }),
+            serial: 0,
+        })?;
+    }
+
+    let notifications = std::mem::take(&mut per_pane.notifications);
+    for alert in notifications {
         match alert {
             Alert::PaletteChanged => {
                 sender.send(DecodedPdu {
@@ -173,6 +886,19 @@ fn maybe_push_pane_changes(
                     serial: 0,
                 })?;
             }
+            Alert::ToastNotification { title, body, focus } => {
+                if per_pane.should_send_notification(&title, &body, Instant::now()) {
+                    sender.send(DecodedPdu {
+                        pdu: Pdu::Notification(Notification {
+                            pane_id: pane.pane_id(),
+                            title,
+                            body,
+                            focus,
+                        }),
+                        serial: 0,
+                    })?;
+                }
+            }
             alert => {
                 sender.send(DecodedPdu {
                     pdu: Pdu::NotifyAlert(NotifyAlert {
@@ -191,10 +917,85 @@ pub struct SessionHandler {
     to_write_tx: PduSender,
     per_pane: HashMap<TabId, Arc<Mutex<PerPane>>>,
     client_id: Option<Arc<ClientId>>,
+    auth_token: Option<Arc<AuthToken>>,
+    authenticated: bool,
+    auth_failures: usize,
+    read_only: bool,
+    input_limiter: RateLimiter,
+    stats: Arc<SessionStats>,
+    pending_window_changes: Arc<Mutex<HashSet<WindowId>>>,
+    /// The tabs whose layout the client has asked for, and which
+    /// therefore receive a push when it changes
+    layout_tabs: Arc<Mutex<HashSet<TabId>>>,
+    pending_layout_changes: Arc<Mutex<HashSet<TabId>>>,
+    /// The images that the client holds in its cache
+    acked_images: Arc<Mutex<HashSet<ImageHash>>>,
+    /// The tab that the client is displaying, if it has told us
+    focused_tab: Option<TabId>,
+    /// The tabs for which the client has paused pushes
+    frozen_tabs: HashSet<TabId>,
+    /// The chunked pastes that are in progress, by paste_id
+    pastes: Arc<Mutex<HashMap<u64, PasteSession>>>,
+    next_paste_id: u64,
+    /// The tabs that have had activity since the client last viewed them
+    tab_activity: Arc<Mutex<HashMap<TabId, TabActivity>>>,
+    /// The mouse events that are waiting to be applied
+    mouse_queue: Arc<Mutex<MouseQueue<MouseReply>>>,
+    /// The requests that have yet to be answered, and the
+    /// barriers that are waiting for them
+    requests: Arc<Mutex<RequestTracker<BarrierReply>>>,
+    /// The scrollback that is being pushed to the client
+    /// in the background
+    trickles: Arc<Mutex<Trickles>>,
+    /// The tabs that the client is following with `TailTab`
+    tails: HashMap<TabId, Tail>,
+    /// The programs that the client has spawned, which are limited
+    /// by `mux_max_concurrent_spawns`
+    spawns: Arc<Mutex<SpawnLimiter>>,
+}
+
+/// Completes the request that carried a queued mouse event
+struct MouseReply {
+    send_response: Box<dyn Fn(anyhow::Result<Pdu>) + Send>,
+    per_pane: Arc<Mutex<PerPane>>,
+}
+
+/// Completes a `Barrier` request
+struct BarrierReply {
+    token: u64,
+    send_response: Box<dyn Fn(anyhow::Result<Pdu>) + Send>,
+}
+
+/// A paste whose text is arriving in several `PasteChunk`s
+struct PasteSession {
+    pane_id: PaneId,
+    bracketed: bool,
+    /// A carriage return at the end of a chunk is held back until
+    /// the next chunk arrives, so that a CRLF that is split across
+    /// two chunks is canonicalized as a single line ending
+    held_cr: bool,
+}
+
+impl PasteSession {
+    /// Returns the text to send to the pane for `chunk`
+    fn take_text(&mut self, chunk: &str) -> String {
+        let mut text = String::with_capacity(chunk.len() + 1);
+        if std::mem::take(&mut self.held_cr) {
+            text.push('\r');
+        }
+        text.push_str(chunk);
+        if text.ends_with('\r') {
+            text.pop();
+            self.held_cr = true;
+        }
+        text
+    }
 }
 
 impl Drop for SessionHandler {
     fn drop(&mut self) {
+        crate::acl::forget_session(self.stats.session_id());
+        crate::clipboard::forget_session(self.stats.session_id());
         if let Some(client_id) = self.client_id.take() {
             let mux = Mux::get().unwrap();
             mux.unregister_client(&client_id);
@@ -203,65 +1004,548 @@ impl Drop for SessionHandler {
 }
 
 impl SessionHandler {
-    pub fn new(to_write_tx: PduSender) -> Self {
-        // Fixup the clipboard on the empty initial pane that is
-        // spawned into the mux
-        let mux = Mux::get().unwrap();
-        for pane in mux.iter_panes() {
-            let clip: Arc<dyn Clipboard> = Arc::new(RemoteClipboard {
-                pane_id: pane.pane_id(),
-                sender: to_write_tx.clone(),
-            });
-            pane.set_clipboard(&clip);
-        }
+    pub fn new(to_write_tx: PduSender, auth_token: Option<Arc<AuthToken>>) -> Self {
+        crate::clipboard::start();
+
+        let stats = SessionStats::register();
+        crate::clipboard::register_session(stats.session_id(), to_write_tx.clone());
 
         Self {
             to_write_tx,
             per_pane: HashMap::new(),
             client_id: None,
+            authenticated: auth_token.is_none(),
+            auth_token,
+            auth_failures: 0,
+            read_only: false,
+            input_limiter: RateLimiter::new(|config| config.ratelimit_mux_input_pdus_per_second),
+            stats,
+            pending_window_changes: Arc::new(Mutex::new(HashSet::new())),
+            layout_tabs: Arc::new(Mutex::new(HashSet::new())),
+            pending_layout_changes: Arc::new(Mutex::new(HashSet::new())),
+            acked_images: Arc::new(Mutex::new(HashSet::new())),
+            focused_tab: None,
+            frozen_tabs: HashSet::new(),
+            pastes: Arc::new(Mutex::new(HashMap::new())),
+            next_paste_id: 0,
+            tab_activity: Arc::new(Mutex::new(HashMap::new())),
+            mouse_queue: Arc::new(Mutex::new(MouseQueue::default())),
+            requests: Arc::new(Mutex::new(RequestTracker::default())),
+            trickles: Arc::new(Mutex::new(Trickles::default())),
+            tails: HashMap::new(),
+            spawns: Arc::new(Mutex::new(SpawnLimiter::default())),
+        }
+    }
+
+    /// Record `pdu` in the audit log if it is a request that
+    /// changes the state of the server
+    fn audit(&self, pdu: &Pdu) {
+        if !crate::audit::is_enabled() {
+            return;
+        }
+        if let Some(event) = crate::audit::describe(pdu) {
+            let tab_id = event.tab_id.or_else(|| {
+                let mux = Mux::get().unwrap();
+                event
+                    .pane_id
+                    .and_then(|pane_id| mux.resolve_pane_id(pane_id))
+                    .map(|(_domain_id, _window_id, tab_id)| tab_id)
+            });
+            crate::audit::record(crate::audit::AuditRecord {
+                timestamp: chrono::Utc::now(),
+                session_id: self.stats.session_id(),
+                client_label: self.stats.client_label(),
+                request: event.request,
+                pane_id: event.pane_id,
+                tab_id,
+                payload: event.payload,
+            });
         }
     }
 
+    pub fn stats(&self) -> &Arc<SessionStats> {
+        &self.stats
+    }
+
+    /// Returns true if the session is permitted to make requests
+    /// and receive notifications
+    pub fn is_authenticated(&self) -> bool {
+        self.authenticated
+    }
+
+    /// Returns true if the session has made too many failed attempts
+    /// to authenticate and should be closed
+    pub fn should_disconnect(&self) -> bool {
+        self.auth_failures >= MAX_AUTH_FAILURES
+    }
+
     pub(crate) fn per_pane(&mut self, pane_id: PaneId) -> Arc<Mutex<PerPane>> {
-        Arc::clone(
-            self.per_pane
-                .entry(pane_id)
-                .or_insert_with(|| Arc::new(Mutex::new(PerPane::default()))),
-        )
+        Arc::clone(self.per_pane.entry(pane_id).or_insert_with(|| {
+            Arc::new(Mutex::new(PerPane {
+                acked_images: Arc::clone(&self.acked_images),
+                ..PerPane::default()
+            }))
+        }))
+    }
+
+    /// Attribute the `size` bytes that were written for `pdu` to the
+    /// pane that it describes, for the benefit of `GetTabStats`
+    pub fn record_pane_bytes_sent(&self, pdu: &Pdu, size: usize) {
+        let size = size as u64;
+        // Only count against panes that are still tracked; a PDU for
+        // a pane may still be queued after the pane has been removed,
+        // and it shouldn't bring the state of that pane back to life
+        match pdu {
+            Pdu::GetPaneRenderChangesResponse(GetPaneRenderChangesResponse { pane_id, .. })
+            | Pdu::CursorUpdate(CursorUpdate { pane_id, .. }) => {
+                if let Some(per_pane) = self.per_pane.get(pane_id) {
+                    per_pane.lock().unwrap().render_changes_bytes += size;
+                }
+            }
+            Pdu::GetLinesResponse(GetLinesResponse { pane_id, .. })
+            | Pdu::ScrollbackChunk(ScrollbackChunk { pane_id, .. }) => {
+                if let Some(per_pane) = self.per_pane.get(pane_id) {
+                    per_pane.lock().unwrap().get_lines_bytes += size;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Schedule pushes for any panes whose render changes were
+    /// deferred while the write queue was congested
+    pub fn schedule_deferred_pushes(&mut self) {
+        let deferred: Vec<PaneId> = self
+            .per_pane
+            .iter()
+            .filter(|(_, per_pane)| per_pane.lock().unwrap().push_deferred)
+            .map(|(pane_id, _)| *pane_id)
+            .collect();
+        for pane_id in deferred {
+            self.schedule_pane_push(pane_id);
+        }
+
+        let tailed: HashSet<PaneId> = self.tails.values().map(|tail| tail.pane_id).collect();
+        for pane_id in tailed {
+            self.push_tail_lines(pane_id);
+        }
+    }
+
+    /// Start following the active pane of `tab_id`, returning the
+    /// `TailTabResponse` that holds its last `context_lines` lines
+    fn start_tail(&mut self, tab_id: TabId, context_lines: usize) -> anyhow::Result<Pdu> {
+        let mux = Mux::get().unwrap();
+        let tab = mux.get_tab(tab_id).ok_or_else(|| no_such_tab(tab_id))?;
+        let pane = tab
+            .get_active_pane()
+            .ok_or_else(|| anyhow!("tab {} has no panes", tab_id))?;
+        let (tail, context) = Tail::new(
+            pane.pane_id(),
+            pane.get_cursor_position().y,
+            pane.get_dimensions().scrollback_top,
+            context_lines,
+        );
+        self.tails.insert(tab_id, tail);
+        Ok(Pdu::TailTabResponse(TailTabResponse {
+            tab_id,
+            pane_id: pane.pane_id(),
+            lines: serialize_lines(stable_lines(&pane, context), &self.acked_images, true),
+        }))
+    }
+
+    /// Push the lines that `pane_id` has output since they were last
+    /// pushed to the tails that are following it.  This is skipped
+    /// while the client is congested, and caught up once the write
+    /// queue has drained.
+    pub fn push_tail_lines(&mut self, pane_id: PaneId) {
+        if self.tails.is_empty() || self.to_write_tx.is_congested() {
+            return;
+        }
+        let pane = match Mux::get().unwrap().get_pane(pane_id) {
+            Some(pane) => pane,
+            None => return,
+        };
+        let cursor_row = pane.get_cursor_position().y;
+        let scrollback_top = pane.get_dimensions().scrollback_top;
+        for (tab_id, tail) in self.tails.iter_mut() {
+            if tail.pane_id != pane_id {
+                continue;
+            }
+            let rows = match tail.take_appended(cursor_row, scrollback_top) {
+                Some(rows) => rows,
+                None => continue,
+            };
+            let mut start = rows.start;
+            while start < rows.end {
+                let end = (start + MAX_LINES_PER_RESPONSE as StableRowIndex).min(rows.end);
+                let lines = stable_lines(&pane, start..end);
+                self.to_write_tx
+                    .send(DecodedPdu {
+                        pdu: Pdu::TailLines(TailLines {
+                            tab_id: *tab_id,
+                            lines: serialize_lines(lines, &self.acked_images, true),
+                        }),
+                        serial: 0,
+                    })
+                    .ok();
+                start = end;
+            }
+        }
+    }
+
+    /// Schedule the next chunk of the scrollback that is being pushed
+    /// with `TrickleScrollback`, if there is one.  The chunk is only
+    /// sent if the write queue is empty when its turn comes; otherwise
+    /// this is called again once the queue has drained, so that the
+    /// trickle never holds up the rest of what we send to the client.
+    /// Nor is it queued for the main thread while that is busy.
+    pub fn continue_trickle(&mut self) {
+        if !self.trickles.lock().unwrap().schedule_step() {
+            return;
+        }
+        let trickles = Arc::clone(&self.trickles);
+        let acked_images = Arc::clone(&self.acked_images);
+        let sender = self.to_write_tx.clone();
+        smol::spawn(async move {
+            smol::Timer::after(TRICKLE_STEP_DELAY).await;
+            while mainthread::is_busy() {
+                smol::Timer::after(BACKGROUND_PUSH_DELAY).await;
+            }
+            mainthread::spawn(async move {
+                if sender.queue_len() > 0 {
+                    trickles.lock().unwrap().cancel_step();
+                    return Ok(());
+                }
+                send_scrollback_chunk(&trickles, &acked_images, &sender)
+            })
+            .detach();
+        })
+        .detach();
+    }
+
+    /// Returns true if the client has told us which tab it is
+    /// displaying and `pane_id` is not in that tab
+    fn is_background_pane(&self, pane_id: PaneId) -> bool {
+        match self.focused_tab {
+            Some(focused_tab) => Mux::get()
+                .unwrap()
+                .resolve_pane_id(pane_id)
+                .map_or(false, |(_domain_id, _window_id, tab_id)| {
+                    tab_id != focused_tab
+                }),
+            None => false,
+        }
+    }
+
+    /// Returns true if the client has just typed into `pane_id`
+    fn is_awaiting_echo(&self, pane_id: PaneId) -> bool {
+        self.per_pane.get(&pane_id).map_or(false, |per_pane| {
+            per_pane.lock().unwrap().awaiting_echo(Instant::now())
+        })
+    }
+
+    /// Returns true if the client has frozen the tab that
+    /// contains `pane_id`
+    fn is_frozen_pane(&self, pane_id: PaneId) -> bool {
+        if self.frozen_tabs.is_empty() {
+            return false;
+        }
+        Mux::get()
+            .unwrap()
+            .resolve_pane_id(pane_id)
+            .map_or(false, |(_domain_id, _window_id, tab_id)| {
+                self.frozen_tabs.contains(&tab_id)
+            })
     }
 
     pub fn schedule_pane_push(&mut self, pane_id: PaneId) {
+        if self.is_frozen_pane(pane_id) {
+            // The changes accumulate in the pane and are sent
+            // when the tab is unfrozen
+            return;
+        }
+        if self.is_background_pane(pane_id) && !self.is_awaiting_echo(pane_id) {
+            self.schedule_background_pane_push(pane_id);
+            return;
+        }
         let sender = self.to_write_tx.clone();
         let per_pane = self.per_pane(pane_id);
-        spawn_into_main_thread(async move {
+        mainthread::spawn(async move {
             let mux = Mux::get().unwrap();
-            let pane = mux
-                .get_pane(pane_id)
-                .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
+            let pane = mux.get_pane(pane_id).ok_or_else(|| no_such_pane(pane_id))?;
             maybe_push_pane_changes(&pane, sender, per_pane)?;
             Ok::<(), anyhow::Error>(())
         })
         .detach();
     }
 
-    pub fn process_one(&mut self, decoded: DecodedPdu) {
-        let start = Instant::now();
-        let sender = self.to_write_tx.clone();
-        let serial = decoded.serial;
-
-        if let Some(client_id) = &self.client_id {
-            Mux::get().unwrap().client_had_input(client_id);
+    /// Push the changes for a pane that the client isn't displaying
+    /// after BACKGROUND_PUSH_DELAY, coalescing any further changes
+    /// that are made in the meantime into the same push.  The push is
+    /// put off for as long as the main thread is busy.
+    fn schedule_background_pane_push(&mut self, pane_id: PaneId) {
+        let per_pane = self.per_pane(pane_id);
+        {
+            let mut per_pane = per_pane.lock().unwrap();
+            if per_pane.background_push_pending {
+                return;
+            }
+            per_pane.background_push_pending = true;
         }
 
-        let send_response = move |result: anyhow::Result<Pdu>| {
-            let pdu = match result {
-                Ok(pdu) => pdu,
-                Err(err) => Pdu::ErrorResponse(ErrorResponse {
-                    reason: format!("Error: {}", err),
-                }),
-            };
-            log::trace!("{} processing time {:?}", serial, start.elapsed());
+        let sender = self.to_write_tx.clone();
+        smol::spawn(async move {
+            smol::Timer::after(BACKGROUND_PUSH_DELAY).await;
+            while mainthread::is_busy() {
+                smol::Timer::after(BACKGROUND_PUSH_DELAY).await;
+            }
+            mainthread::spawn(async move {
+                per_pane.lock().unwrap().background_push_pending = false;
+                let mux = Mux::get().unwrap();
+                if let Some(pane) = mux.get_pane(pane_id) {
+                    maybe_push_pane_changes(&pane, sender, per_pane)?;
+                }
+                Ok::<(), anyhow::Error>(())
+            })
+            .detach();
+        })
+        .detach();
+    }
+
+    /// Record that a pane has produced output or rung the bell.
+    /// This is ignored for the tab that the client is displaying.
+    /// The client is told about the window when the activity of one
+    /// of its tabs changes, so that it can update its tab bar.
+    pub fn record_tab_activity(&mut self, pane_id: PaneId, update: impl FnOnce(&mut TabActivity)) {
+        let (window_id, tab_id) = match Mux::get().unwrap().resolve_pane_id(pane_id) {
+            Some((_domain_id, window_id, tab_id)) => (window_id, tab_id),
+            None => return,
+        };
+        if self.focused_tab == Some(tab_id) {
+            return;
+        }
+        let changed = {
+            let mut tab_activity = self.tab_activity.lock().unwrap();
+            let activity = tab_activity.entry(tab_id).or_default();
+            let before = *activity;
+            update(activity);
+            *activity != before
+        };
+        if changed {
+            self.schedule_window_structure_push(window_id);
+        }
+    }
+
+    /// Forget the state that the session holds for a pane that has
+    /// been removed, along with that of any tab that went with it.
+    /// Ids are not reused, so this is about releasing the memory
+    /// rather than about confusing the state with that of a new pane.
+    pub fn forget_pane(&mut self, pane_id: PaneId) {
+        self.per_pane.remove(&pane_id);
+        self.trickles.lock().unwrap().stop(pane_id);
+        self.spawns.lock().unwrap().pane_removed(pane_id);
+        self.tails.retain(|_, tail| tail.pane_id != pane_id);
+
+        let mux = Mux::get().unwrap();
+        let is_live = |tab_id: &TabId| mux.get_tab(*tab_id).is_some();
+        self.frozen_tabs.retain(is_live);
+        self.layout_tabs.lock().unwrap().retain(is_live);
+        self.tab_activity
+            .lock()
+            .unwrap()
+            .retain(|tab_id, _| is_live(tab_id));
+        if !self.focused_tab.as_ref().map_or(true, is_live) {
+            self.focused_tab = None;
+        }
+    }
+
+    /// Stop counting the programs that the client spawned into
+    /// a tab that has exited against its limit
+    pub fn tab_exited(&mut self, tab_id: TabId) {
+        self.spawns.lock().unwrap().tab_exited(tab_id);
+    }
+
+    /// Count a spawn against the limit of the session, returning the
+    /// function through which its response is to be sent, which
+    /// records the pane that it produced.  Returns None, having
+    /// refused the spawn, if the session has reached its limit.
+    fn admit_spawn<SND>(&self, send_response: SND) -> Option<impl Fn(anyhow::Result<Pdu>) + 'static>
+    where
+        SND: Fn(anyhow::Result<Pdu>) + 'static,
+    {
+        let max = configuration().mux_max_concurrent_spawns;
+        if !self.spawns.lock().unwrap().admit(max) {
+            send_response(Ok(Pdu::SpawnFailed(SpawnFailed {
+                kind: SpawnErrorKind::ResourceExhausted,
+                reason: format!(
+                    "this session already has {} spawned programs, \
+                     which is the limit set by mux_max_concurrent_spawns",
+                    max
+                ),
+            })));
+            return None;
+        }
+        let spawns = Arc::clone(&self.spawns);
+        Some(move |result: anyhow::Result<Pdu>| {
+            let spawned = match &result {
+                Ok(Pdu::SpawnResponse(SpawnResponse {
+                    pane_id, tab_id, ..
+                }))
+                | Ok(Pdu::SpawnSplitResponse(SpawnSplitResponse {
+                    pane_id, tab_id, ..
+                })) => Some((*pane_id, *tab_id)),
+                _ => None,
+            };
+            // The program may already have exited, and its pane
+            // been removed, in which case it no longer counts
+            let spawned =
+                spawned.filter(|(pane_id, _)| Mux::get().unwrap().get_pane(*pane_id).is_some());
+            spawns.lock().unwrap().completed(spawned);
+            send_response(result);
+        })
+    }
+
+    /// Send the structure of the window that the client is attaching
+    /// to, along with the render changes of its active tab, which is
+    /// treated as though the client had focused it
+    fn prime_initial_window(&mut self, window_id: WindowId) {
+        self.schedule_window_structure_push(window_id);
+        let tab_id = match Mux::get().unwrap().get_active_tab_for_window(window_id) {
+            Some(tab) => tab.tab_id(),
+            None => return,
+        };
+        self.focused_tab.replace(tab_id);
+        self.clear_tab_activity(tab_id);
+        for pane_id in tab_pane_ids(tab_id) {
+            self.schedule_pane_push(pane_id);
+        }
+    }
+
+    /// Clear the activity of a tab that the client has viewed
+    fn clear_tab_activity(&mut self, tab_id: TabId) {
+        if self.tab_activity.lock().unwrap().remove(&tab_id).is_some() {
+            if let Some(window_id) = Mux::get().unwrap().window_containing_tab(tab_id) {
+                self.schedule_window_structure_push(window_id);
+            }
+        }
+    }
+
+    /// Arrange to tell the client that the structure of `window_id`
+    /// has changed.  Changes that are made in quick succession,
+    /// such as when several tabs are spawned or pruned at once,
+    /// are coalesced so that at most one notification is sent per
+    /// window on each pass through the main thread.
+    pub fn schedule_window_structure_push(&mut self, window_id: WindowId) {
+        let first = {
+            let mut pending = self.pending_window_changes.lock().unwrap();
+            let first = pending.is_empty();
+            pending.insert(window_id);
+            first
+        };
+        if !first {
+            // A push is already scheduled and will include this window
+            return;
+        }
+
+        let sender = self.to_write_tx.clone();
+        let pending = Arc::clone(&self.pending_window_changes);
+        let tab_activity = Arc::clone(&self.tab_activity);
+        mainthread::spawn(async move {
+            let window_ids: Vec<WindowId> = pending.lock().unwrap().drain().collect();
+            let mux = Mux::get().unwrap();
+            for window_id in window_ids {
+                let window = window_entry(&mux, window_id, &tab_activity.lock().unwrap());
+                sender.send(DecodedPdu {
+                    pdu: Pdu::WindowStructureChanged(WindowStructureChanged { window_id, window }),
+                    serial: 0,
+                })?;
+            }
+            Ok::<(), anyhow::Error>(())
+        })
+        .detach();
+    }
+
+    pub fn schedule_tab_layout_push(&mut self, tab_id: TabId) {
+        if !self.layout_tabs.lock().unwrap().contains(&tab_id) {
+            return;
+        }
+        let first = {
+            let mut pending = self.pending_layout_changes.lock().unwrap();
+            let first = pending.is_empty();
+            pending.insert(tab_id);
+            first
+        };
+        if !first {
+            // A push is already scheduled and will include this tab
+            return;
+        }
+
+        let sender = self.to_write_tx.clone();
+        let pending = Arc::clone(&self.pending_layout_changes);
+        let layout_tabs = Arc::clone(&self.layout_tabs);
+        mainthread::spawn(async move {
+            let tab_ids: Vec<TabId> = pending.lock().unwrap().drain().collect();
+            let mux = Mux::get().unwrap();
+            for tab_id in tab_ids {
+                let layout = match mux.get_tab(tab_id) {
+                    Some(tab) => tab.pane_layout(),
+                    None => {
+                        layout_tabs.lock().unwrap().remove(&tab_id);
+                        PaneLayout::Empty
+                    }
+                };
+                sender.send(DecodedPdu {
+                    pdu: Pdu::GetPaneLayoutResponse(GetPaneLayoutResponse { tab_id, layout }),
+                    serial: 0,
+                })?;
+            }
+            Ok::<(), anyhow::Error>(())
+        })
+        .detach();
+    }
+
+    pub fn process_one(&mut self, decoded: DecodedPdu) {
+        let start = Instant::now();
+        let sender = self.to_write_tx.clone();
+        let serial = decoded.serial;
+
+        // The span remains open until the handler for the request
+        // has completed on the main thread; spawn_handler and catch
+        // add child spans for the time spent waiting for the main
+        // thread and the time spent in the handler itself
+        let span = tracing::trace_span!(
+            "process_pdu",
+            kind = decoded.pdu.pdu_name(),
+            serial,
+            tab_id = tracing::field::Empty,
+        );
+        if !span.is_disabled() || log::log_enabled!(log::Level::Trace) {
+            if let Some(tab_id) = request_tab_id(&decoded.pdu) {
+                span.record("tab_id", &tab_id);
+            }
+        }
+        let _enter = span.enter();
+
+        if let Some(client_id) = &self.client_id {
+            Mux::get().unwrap().client_had_input(client_id);
+        }
+
+        let stats = Arc::clone(&self.stats);
+        let request_id = self.requests.lock().unwrap().begin();
+        let requests = Arc::clone(&self.requests);
+        let send_response = move |result: anyhow::Result<Pdu>| {
+            let pdu = match result {
+                Ok(pdu) => pdu,
+                Err(err) => error_response(err),
+            };
+            let elapsed = start.elapsed();
+            stats.record_processing_time(elapsed);
+            log::trace!("{} processing time {:?}", serial, elapsed);
             sender.send(DecodedPdu { pdu, serial }).ok();
+            let released = requests.lock().unwrap().complete(request_id);
+            for barrier in released {
+                answer_barrier(barrier);
+            }
         };
 
         fn catch<F, SND>(f: F, send_response: SND)
@@ -269,22 +1553,150 @@ impl SessionHandler {
             F: FnOnce() -> anyhow::Result<Pdu>,
             SND: Fn(anyhow::Result<Pdu>),
         {
+            let span = tracing::trace_span!("handler");
+            let _enter = span.enter();
             send_response(f());
         }
 
+        if !self.authenticated {
+            match &decoded.pdu {
+                Pdu::GetCodecVersion(_) | Pdu::Authenticate(_) => {}
+                _ => {
+                    self.auth_failures += 1;
+                    send_response(Err(request_error(
+                        ErrorCode::PermissionDenied,
+                        "authentication required".to_string(),
+                    )));
+                    return;
+                }
+            }
+        }
+
+        if self.read_only && changes_mux_state(&decoded.pdu) {
+            send_response(Err(request_error(
+                ErrorCode::PermissionDenied,
+                format!(
+                    "this session is read-only and may not send {}",
+                    decoded.pdu.pdu_name()
+                ),
+            )));
+            return;
+        }
+
+        let input_pane_id = match &decoded.pdu {
+            Pdu::WriteToPane(WriteToPane { pane_id, .. })
+            | Pdu::WriteTextToPane(WriteTextToPane { pane_id, .. })
+            | Pdu::TryWriteToPane(TryWriteToPane { pane_id, .. })
+            | Pdu::SendKeyDown(SendKeyDown { pane_id, .. })
+            | Pdu::SendKeyDownBatch(SendKeyDownBatch { pane_id, .. })
+            | Pdu::SendText(SendText { pane_id, .. })
+            | Pdu::SendMouseEvent(SendMouseEvent { pane_id, .. })
+            | Pdu::SendPaste(SendPaste { pane_id, .. })
+            | Pdu::BeginPaste(BeginPaste { pane_id, .. }) => Some(*pane_id),
+            // Each chunk of a paste is counted, so that a long paste
+            // cannot be used to get around the limit
+            Pdu::PasteChunk(PasteChunk { paste_id, .. }) => self
+                .pastes
+                .lock()
+                .unwrap()
+                .get(paste_id)
+                .map(|paste| paste.pane_id),
+            _ => None,
+        };
+        if let Some(pane_id) = input_pane_id {
+            if self.read_only {
+                // A read-only session may still have been granted
+                // access to the tab that contains the pane
+                let mux = Mux::get().unwrap();
+                let allowed = match mux.resolve_pane_id(pane_id) {
+                    Some((_domain_id, _window_id, tab_id)) => {
+                        crate::acl::can_write(self.stats.session_id(), tab_id)
+                    }
+                    None => false,
+                };
+                if !allowed {
+                    send_response(Err(request_error(
+                        ErrorCode::PermissionDenied,
+                        format!(
+                            "this session is read-only and may not send input to pane {}",
+                            pane_id
+                        ),
+                    )));
+                    return;
+                }
+            }
+            // Reject excess input here, rather than queueing it up,
+            // so that a flood from this session cannot monopolize
+            // the main thread at the expense of other sessions
+            if let Err(delay) = self.input_limiter.admit_check(1) {
+                log::trace!("{} input rate limited; retry after {:?}", serial, delay);
+                send_response(Err(request_error(
+                    ErrorCode::RateLimited,
+                    format!("input rate limited; retry after {} ms", delay.as_millis()),
+                )));
+                return;
+            }
+            self.stats.record_input(pane_id);
+            if let Some((_domain_id, _window_id, tab_id)) =
+                Mux::get().unwrap().resolve_pane_id(pane_id)
+            {
+                crate::clipboard::record_interaction(self.stats.session_id(), tab_id);
+            }
+        }
+
+        self.audit(&decoded.pdu);
+
         match decoded.pdu {
-            Pdu::Ping(Ping {}) => send_response(Ok(Pdu::Pong(Pong {}))),
+            Pdu::Barrier(Barrier { token }) => {
+                let reply = BarrierReply {
+                    token,
+                    send_response: Box::new(send_response),
+                };
+                let ready = self.requests.lock().unwrap().wait(request_id, reply);
+                if let Some(reply) = ready {
+                    answer_barrier(reply);
+                }
+            }
+            Pdu::Ping(Ping { timestamp }) => {
+                let queue_depth = timestamp.map(|_| self.to_write_tx.queue_len());
+                send_response(Ok(Pdu::Pong(Pong {
+                    timestamp,
+                    queue_depth,
+                    busy: mainthread::is_busy(),
+                })))
+            }
+            Pdu::Authenticate(Authenticate { token }) => {
+                let valid = match &self.auth_token {
+                    Some(expected) => expected.verify(&token),
+                    None => true,
+                };
+                if valid {
+                    self.authenticated = true;
+                    send_response(Ok(Pdu::UnitResponse(UnitResponse {})))
+                } else {
+                    self.auth_failures += 1;
+                    log::error!(
+                        "authentication failed ({} of {} attempts)",
+                        self.auth_failures,
+                        MAX_AUTH_FAILURES
+                    );
+                    send_response(Err(request_error(
+                        ErrorCode::PermissionDenied,
+                        "authentication failed".to_string(),
+                    )))
+                }
+            }
             Pdu::SetWindowWorkspace(SetWindowWorkspace {
                 window_id,
                 workspace,
             }) => {
-                spawn_into_main_thread(async move {
+                spawn_handler(async move {
                     catch(
                         move || {
                             let mux = Mux::get().unwrap();
                             let mut window = mux
                                 .get_window_mut(window_id)
-                                .ok_or_else(|| anyhow!("window {} is invalid", window_id))?;
+                                .ok_or_else(|| no_such_window(window_id))?;
                             window.set_workspace(&workspace);
                             Ok(Pdu::UnitResponse(UnitResponse {}))
                         },
@@ -296,479 +1708,2947 @@ impl SessionHandler {
             Pdu::SetClientId(SetClientId { client_id }) => {
                 let client_id = Arc::new(client_id);
                 self.client_id.replace(client_id.clone());
-                spawn_into_main_thread(async move {
+                self.stats.set_client_id(client_id.clone());
+                spawn_handler(async move {
                     let mux = Mux::get().unwrap();
                     mux.register_client(client_id);
                 })
                 .detach();
                 send_response(Ok(Pdu::UnitResponse(UnitResponse {})))
             }
-            Pdu::GetClientList(GetClientList) => {
-                spawn_into_main_thread(async move {
+            Pdu::SetClientInfo(info) => {
+                // Once read-only, a session stays that way; otherwise
+                // it could simply send its info again to lift it
+                self.read_only |= info.read_only;
+                self.stats.set_client_info(info);
+                send_response(Ok(Pdu::UnitResponse(UnitResponse {})))
+            }
+            Pdu::SetFocusedTab(SetFocusedTab { tab_id }) => {
+                self.focused_tab.replace(tab_id);
+                self.clear_tab_activity(tab_id);
+                crate::clipboard::record_interaction(self.stats.session_id(), tab_id);
+                // Don't make the client wait for the delayed pushes
+                // of the panes that have just come to the foreground
+                for pane_id in tab_pane_ids(tab_id) {
+                    let pending = self.per_pane.get(&pane_id).map_or(false, |per_pane| {
+                        per_pane.lock().unwrap().background_push_pending
+                    });
+                    if pending {
+                        self.schedule_pane_push(pane_id);
+                    }
+                }
+                send_response(Ok(Pdu::UnitResponse(UnitResponse {})))
+            }
+            Pdu::FreezeTab(FreezeTab { tab_id, frozen }) => {
+                if frozen {
+                    self.frozen_tabs.insert(tab_id);
+                } else if self.frozen_tabs.remove(&tab_id) {
+                    // Catch the client up on what changed while
+                    // the tab was frozen
+                    for pane_id in tab_pane_ids(tab_id) {
+                        self.schedule_pane_push(pane_id);
+                    }
+                }
+                send_response(Ok(Pdu::UnitResponse(UnitResponse {})))
+            }
+            Pdu::SetTabTitlePolicy(SetTabTitlePolicy {
+                tab_id,
+                allow_process_override,
+            }) => {
+                spawn_handler(async move {
                     catch(
                         move || {
                             let mux = Mux::get().unwrap();
-                            let clients = mux.iter_clients();
-                            Ok(Pdu::GetClientListResponse(GetClientListResponse {
-                                clients,
-                            }))
+                            let tab = mux.get_tab(tab_id).ok_or_else(|| no_such_tab(tab_id))?;
+                            tab.set_allow_process_title_override(allow_process_override);
+                            // Re-enabling the override can change the
+                            // reported titles; push them to the clients
+                            for pos in tab.iter_panes_ignoring_zoom() {
+                                mux.notify(MuxNotification::PaneOutput(pos.pane.pane_id()));
+                            }
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
                         },
                         send_response,
                     )
                 })
                 .detach();
             }
-            Pdu::ListPanes(ListPanes {}) => {
-                spawn_into_main_thread(async move {
+            Pdu::ReorderTab(ReorderTab { tab_id, new_index }) => {
+                spawn_handler(async move {
                     catch(
                         move || {
                             let mux = Mux::get().unwrap();
-                            let mut tabs = vec![];
-                            for window_id in mux.iter_windows().into_iter() {
-                                let window = mux.get_window(window_id).unwrap();
-                                for tab in window.iter() {
-                                    tabs.push(tab.codec_pane_tree());
-                                }
-                            }
-                            log::trace!("ListPanes {:#?}", tabs);
-                            Ok(Pdu::ListPanesResponse(ListPanesResponse { tabs }))
+                            let window_id = mux
+                                .window_containing_tab(tab_id)
+                                .ok_or_else(|| anyhow!("tab {} is not in a window", tab_id))?;
+                            let mut window = mux
+                                .get_window_mut(window_id)
+                                .ok_or_else(|| no_such_window(window_id))?;
+                            // Moving the tab invalidates the window, which
+                            // tells the clients that its structure changed
+                            window
+                                .move_tab(tab_id, new_index)
+                                .ok_or_else(|| no_such_tab(tab_id))?;
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
                         },
                         send_response,
                     )
                 })
                 .detach();
             }
-
-            Pdu::WriteToPane(WriteToPane { pane_id, data }) => {
-                let sender = self.to_write_tx.clone();
-                let per_pane = self.per_pane(pane_id);
-                spawn_into_main_thread(async move {
+            Pdu::GetTabScreenshot(GetTabScreenshot { tab_id }) => {
+                spawn_handler(async move {
                     catch(
                         move || {
                             let mux = Mux::get().unwrap();
-                            let pane = mux
-                                .get_pane(pane_id)
-                                .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
-                            pane.writer().write_all(&data)?;
-                            maybe_push_pane_changes(&pane, sender, per_pane)?;
-                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                            let tab = mux.get_tab(tab_id).ok_or_else(|| no_such_tab(tab_id))?;
+                            let png = screenshot_tab(&tab)?;
+                            Ok(Pdu::GetTabScreenshotResponse(GetTabScreenshotResponse {
+                                tab_id,
+                                png,
+                            }))
                         },
                         send_response,
-                    );
+                    )
                 })
                 .detach();
             }
-            Pdu::KillPane(KillPane { pane_id }) => {
-                let sender = self.to_write_tx.clone();
-                let per_pane = self.per_pane(pane_id);
-                spawn_into_main_thread(async move {
+            Pdu::GetPaneLayout(GetPaneLayout { tab_id }) => {
+                let layout_tabs = Arc::clone(&self.layout_tabs);
+                spawn_handler(async move {
                     catch(
                         move || {
                             let mux = Mux::get().unwrap();
-                            let pane = mux
-                                .get_pane(pane_id)
-                                .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
-                            pane.kill();
-                            mux.remove_pane(pane_id);
-                            maybe_push_pane_changes(&pane, sender, per_pane)?;
-                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                            let tab = mux.get_tab(tab_id).ok_or_else(|| no_such_tab(tab_id))?;
+                            layout_tabs.lock().unwrap().insert(tab_id);
+                            Ok(Pdu::GetPaneLayoutResponse(GetPaneLayoutResponse {
+                                tab_id,
+                                layout: tab.pane_layout(),
+                            }))
                         },
                         send_response,
-                    );
+                    )
                 })
                 .detach();
             }
-            Pdu::SendPaste(SendPaste { pane_id, data }) => {
-                let sender = self.to_write_tx.clone();
-                let per_pane = self.per_pane(pane_id);
-                spawn_into_main_thread(async move {
+            Pdu::GetEnv(GetEnv { tab_id })
+                if self.read_only && !crate::acl::can_write(self.stats.session_id(), tab_id) =>
+            {
+                // The environment commonly holds credentials, so it is
+                // only revealed to sessions that could type into the tab
+                send_response(Err(request_error(
+                    ErrorCode::PermissionDenied,
+                    format!(
+                        "a read-only session may not read the environment of tab {}",
+                        tab_id
+                    ),
+                )));
+            }
+            Pdu::GetEnv(GetEnv { tab_id }) => {
+                spawn_handler(async move {
                     catch(
                         move || {
                             let mux = Mux::get().unwrap();
-                            let pane = mux
-                                .get_pane(pane_id)
-                                .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
-                            pane.send_paste(&data)?;
-                            maybe_push_pane_changes(&pane, sender, per_pane)?;
-                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                            let tab = mux.get_tab(tab_id).ok_or_else(|| no_such_tab(tab_id))?;
+                            let env = tab
+                                .get_active_pane()
+                                .and_then(|pane| pane.get_foreground_process_environment());
+                            Ok(Pdu::GetEnvResponse(GetEnvResponse { tab_id, env }))
                         },
                         send_response,
                     )
                 })
                 .detach();
             }
-
-            Pdu::SearchScrollbackRequest(SearchScrollbackRequest { pane_id, pattern }) => {
-                use mux::pane::Pattern;
-
-                async fn do_search(pane_id: TabId, pattern: Pattern) -> anyhow::Result<Pdu> {
-                    let mux = Mux::get().unwrap();
-                    let pane = mux
-                        .get_pane(pane_id)
-                        .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
-
-                    pane.search(pattern).await.map(|results| {
-                        Pdu::SearchScrollbackResponse(SearchScrollbackResponse { results })
-                    })
+            Pdu::ImageAck(ImageAck { ids, evicted }) => {
+                let mut acked_images = self.acked_images.lock().unwrap();
+                acked_images.extend(ids);
+                for hash in evicted {
+                    acked_images.remove(&hash);
                 }
-
-                spawn_into_main_thread(async move {
-                    promise::spawn::spawn(async move {
-                        let result = do_search(pane_id, pattern).await;
-                        send_response(result);
-                    })
-                    .detach();
-                })
-                .detach();
+                send_response(Ok(Pdu::UnitResponse(UnitResponse {})))
             }
-
-            Pdu::SetPaneZoomed(SetPaneZoomed {
-                containing_tab_id,
-                pane_id,
-                zoomed,
+            Pdu::SetWriteAccess(SetWriteAccess {
+                session_id,
+                tab_id,
+                allowed,
             }) => {
-                spawn_into_main_thread(async move {
+                if self.read_only {
+                    send_response(Err(request_error(
+                        ErrorCode::PermissionDenied,
+                        "a read-only session may not change write access".to_string(),
+                    )));
+                } else {
+                    crate::acl::set_write_access(session_id, tab_id, allowed);
+                    send_response(Ok(Pdu::UnitResponse(UnitResponse {})))
+                }
+            }
+            Pdu::ListSessions(ListSessions {}) => {
+                if self.read_only {
+                    send_response(Err(request_error(
+                        ErrorCode::PermissionDenied,
+                        "a read-only session may not list sessions".to_string(),
+                    )));
+                } else {
+                    send_response(Ok(Pdu::ListSessionsResponse(ListSessionsResponse {
+                        sessions: list_sessions(),
+                    })))
+                }
+            }
+            Pdu::KillSession(KillSession { session_id }) => {
+                if self.read_only {
+                    send_response(Err(request_error(
+                        ErrorCode::PermissionDenied,
+                        "a read-only session may not kill sessions".to_string(),
+                    )));
+                } else if kill_session(session_id) {
+                    log::info!(
+                        "session {} killed session {}",
+                        self.stats.session_id(),
+                        session_id
+                    );
+                    send_response(Ok(Pdu::UnitResponse(UnitResponse {})))
+                } else {
+                    send_response(Err(anyhow!("no session with id {}", session_id)))
+                }
+            }
+            Pdu::ListClients(ListClients {}) => {
+                spawn_handler(async move {
                     catch(
                         move || {
                             let mux = Mux::get().unwrap();
-                            let pane = mux
-                                .get_pane(pane_id)
-                                .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
-                            let tab = mux
-                                .get_tab(containing_tab_id)
-                                .ok_or_else(|| anyhow!("no such tab {}", containing_tab_id))?;
-                            tab.set_active_pane(&pane);
-                            tab.set_zoomed(zoomed);
-                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                            let clients = attached_clients(|pane_id| {
+                                mux.resolve_pane_id(pane_id).map(|(_, _, tab_id)| tab_id)
+                            });
+                            Ok(Pdu::ListClientsResponse(ListClientsResponse { clients }))
                         },
                         send_response,
                     )
                 })
                 .detach();
             }
-
-            Pdu::Resize(Resize {
-                containing_tab_id,
-                pane_id,
-                size,
-            }) => {
-                spawn_into_main_thread(async move {
+            Pdu::GetClientList(GetClientList) => {
+                spawn_handler(async move {
                     catch(
                         move || {
                             let mux = Mux::get().unwrap();
-                            let pane = mux
-                                .get_pane(pane_id)
-                                .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
-                            pane.resize(size)?;
-                            let tab = mux
-                                .get_tab(containing_tab_id)
-                                .ok_or_else(|| anyhow!("no such tab {}", containing_tab_id))?;
-                            tab.rebuild_splits_sizes_from_contained_panes();
-                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                            let clients = mux.iter_clients();
+                            Ok(Pdu::GetClientListResponse(GetClientListResponse {
+                                clients,
+                            }))
                         },
                         send_response,
                     )
                 })
                 .detach();
             }
-
-            Pdu::SendKeyDown(SendKeyDown {
-                pane_id,
-                event,
-                input_serial,
-            }) => {
-                let sender = self.to_write_tx.clone();
-                let per_pane = self.per_pane(pane_id);
-                spawn_into_main_thread(async move {
+            Pdu::GetServerStats(GetServerStats { include_sessions }) => {
+                spawn_handler(async move {
                     catch(
                         move || {
                             let mux = Mux::get().unwrap();
-                            let pane = mux
-                                .get_pane(pane_id)
-                                .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
-                            pane.key_down(event.key, event.modifiers)?;
-
-                            // For a key press, we want to always send back the
-                            // cursor position so that the predictive echo doesn't
-                            // leave the cursor in the wrong place
-                            let mut per_pane = per_pane.lock().unwrap();
-                            if let Some(resp) = per_pane.compute_changes(&pane, Some(input_serial))
-                            {
-                                sender.send(DecodedPdu {
-                                    pdu: Pdu::GetPaneRenderChangesResponse(resp),
-                                    serial: 0,
-                                })?;
+                            let windows = mux.iter_windows();
+                            let mut num_tabs = 0;
+                            for window_id in &windows {
+                                if let Some(window) = mux.get_window(*window_id) {
+                                    num_tabs += window.len();
+                                }
                             }
-                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                            let (sessions, totals) = session_stats();
+                            Ok(Pdu::GetServerStatsResponse(GetServerStatsResponse {
+                                num_sessions: sessions.len(),
+                                num_windows: windows.len(),
+                                num_tabs,
+                                num_panes: mux.iter_panes().len(),
+                                totals,
+                                sessions: if include_sessions { sessions } else { vec![] },
+                                main_thread_queue_depth: mainthread::queue_depth(),
+                                peak_main_thread_queue_depth: mainthread::peak_queue_depth(),
+                            }))
                         },
                         send_response,
                     )
                 })
                 .detach();
             }
-            Pdu::SendMouseEvent(SendMouseEvent { pane_id, event }) => {
-                let sender = self.to_write_tx.clone();
-                let per_pane = self.per_pane(pane_id);
-                spawn_into_main_thread(async move {
+            Pdu::GetCapabilities(GetCapabilities {}) => {
+                send_response(Ok(Pdu::GetCapabilitiesResponse(capabilities())))
+            }
+            Pdu::GetTabStats(GetTabStats { tab_id, reset }) => {
+                let per_pane = self.per_pane.clone();
+                spawn_handler(async move {
                     catch(
                         move || {
                             let mux = Mux::get().unwrap();
-                            let pane = mux
-                                .get_pane(pane_id)
-                                .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
-                            pane.mouse_event(event)?;
-                            maybe_push_pane_changes(&pane, sender, per_pane)?;
-                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                            let tab = mux.get_tab(tab_id).ok_or_else(|| no_such_tab(tab_id))?;
+                            let mut render_changes_bytes = 0;
+                            let mut get_lines_bytes = 0;
+                            let mut echoes = 0;
+                            let mut echo_latency = Duration::default();
+                            for pos in tab.iter_panes_ignoring_zoom() {
+                                if let Some(per_pane) = per_pane.get(&pos.pane.pane_id()) {
+                                    let mut per_pane = per_pane.lock().unwrap();
+                                    render_changes_bytes += per_pane.render_changes_bytes;
+                                    get_lines_bytes += per_pane.get_lines_bytes;
+                                    echoes += per_pane.echoes;
+                                    echo_latency += per_pane.echo_latency;
+                                    if reset {
+                                        per_pane.render_changes_bytes = 0;
+                                        per_pane.get_lines_bytes = 0;
+                                        per_pane.echoes = 0;
+                                        per_pane.echo_latency = Duration::default();
+                                    }
+                                }
+                            }
+                            let average_echo_latency = if echoes == 0 {
+                                Duration::default()
+                            } else {
+                                echo_latency / echoes as u32
+                            };
+                            Ok(Pdu::GetTabStatsResponse(GetTabStatsResponse {
+                                tab_id,
+                                render_changes_bytes,
+                                get_lines_bytes,
+                                echoes,
+                                average_echo_latency,
+                            }))
                         },
                         send_response,
                     )
                 })
                 .detach();
             }
-
-            Pdu::SpawnV2(spawn) => {
-                let sender = self.to_write_tx.clone();
-                let client_id = self.client_id.clone();
-                spawn_into_main_thread(async move {
-                    schedule_domain_spawn_v2(spawn, sender, send_response, client_id);
-                })
-                .detach();
+            Pdu::ListPanes(ListPanes { initial_window_id }) => {
+                let mux = Mux::get().unwrap();
+                let initial_window_id =
+                    initial_window_id.filter(|window_id| mux.get_window(*window_id).is_some());
+                let tabs = match initial_window_id {
+                    Some(window_id) => window_tabs(&mux, window_id),
+                    None => list_tabs(&mux),
+                };
+                log::trace!("ListPanes {:#?}", tabs);
+                // Respond before priming the window, so that the client
+                // knows of its panes by the time that their changes arrive
+                send_response(Ok(Pdu::ListPanesResponse(ListPanesResponse { tabs })));
+                if let Some(window_id) = initial_window_id {
+                    self.prime_initial_window(window_id);
+                }
             }
 
-            Pdu::SplitPane(split) => {
-                let sender = self.to_write_tx.clone();
-                let client_id = self.client_id.clone();
-                spawn_into_main_thread(async move {
-                    schedule_split_pane(split, sender, send_response, client_id);
-                })
+            Pdu::GetTab(GetTab { tab_id }) => {
+                spawn_handler(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let tab = mux.get_tab(tab_id).ok_or_else(|| no_such_tab(tab_id))?;
+                            Ok(Pdu::GetTabResponse(GetTabResponse {
+                                tab: tab.codec_pane_tree(),
+                            }))
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
+            }
+
+            Pdu::ListDomains(ListDomains {}) => {
+                spawn_handler(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let domains = mux
+                                .iter_domains()
+                                .into_iter()
+                                .map(|domain| DomainEntry {
+                                    domain_id: domain.domain_id(),
+                                    name: domain.domain_name().to_string(),
+                                    label: domain.domain_label().to_string(),
+                                    state: domain.state(),
+                                    spawnable: domain.spawnable(),
+                                })
+                                .collect();
+                            Ok(Pdu::ListDomainsResponse(ListDomainsResponse { domains }))
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
+            }
+
+            Pdu::DetachDomain(DetachDomain { domain_id }) => {
+                spawn_handler(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let domain = mux
+                                .get_domain(domain_id)
+                                .ok_or_else(|| no_such_domain(domain_id))?;
+                            if domain.state() == DomainState::Attached {
+                                domain.detach()?;
+                            }
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
+            }
+
+            Pdu::AttachDomain(AttachDomain { domain_id }) => {
+                spawn_handler(async move {
+                    schedule_attach_domain(domain_id, send_response);
+                })
+                .detach();
+            }
+
+            Pdu::GetWindows(GetWindows {}) => {
+                let tab_activity = Arc::clone(&self.tab_activity);
+                spawn_handler(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let mut window_ids = mux.iter_windows();
+                            window_ids.sort();
+                            let tab_activity = tab_activity.lock().unwrap();
+                            let windows = window_ids
+                                .into_iter()
+                                .filter_map(|window_id| {
+                                    window_entry(&mux, window_id, &tab_activity)
+                                })
+                                .collect();
+                            Ok(Pdu::GetWindowsResponse(GetWindowsResponse { windows }))
+                        },
+                        send_response,
+                    )
+                })
                 .detach();
             }
 
-            Pdu::GetPaneRenderChanges(GetPaneRenderChanges { pane_id, .. }) => {
+            Pdu::WriteToPane(WriteToPane { pane_id, data }) => {
                 let sender = self.to_write_tx.clone();
                 let per_pane = self.per_pane(pane_id);
-                spawn_into_main_thread(async move {
+                spawn_handler(async move {
                     catch(
                         move || {
                             let mux = Mux::get().unwrap();
-                            let is_alive = match mux.get_pane(pane_id) {
-                                Some(pane) => {
-                                    maybe_push_pane_changes(&pane, sender, per_pane)?;
-                                    true
-                                }
-                                None => false,
+                            let pane =
+                                mux.get_pane(pane_id).ok_or_else(|| no_such_pane(pane_id))?;
+                            pane.writer().write_all(&data)?;
+                            maybe_push_pane_changes(&pane, sender, per_pane)?;
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                        },
+                        send_response,
+                    );
+                })
+                .detach();
+            }
+            Pdu::WriteTextToPane(WriteTextToPane { pane_id, data }) => {
+                let sender = self.to_write_tx.clone();
+                let per_pane = self.per_pane(pane_id);
+                spawn_handler(async move {
+                    catch(
+                        move || {
+                            let text = validate_text(&data)?;
+                            let mux = Mux::get().unwrap();
+                            let pane =
+                                mux.get_pane(pane_id).ok_or_else(|| no_such_pane(pane_id))?;
+                            pane.writer().write_all(text.as_bytes())?;
+                            maybe_push_pane_changes(&pane, sender, per_pane)?;
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                        },
+                        send_response,
+                    );
+                })
+                .detach();
+            }
+            Pdu::TryWriteToPane(TryWriteToPane { pane_id, data }) => {
+                let sender = self.to_write_tx.clone();
+                let per_pane = self.per_pane(pane_id);
+                spawn_handler(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let pane =
+                                mux.get_pane(pane_id).ok_or_else(|| no_such_pane(pane_id))?;
+                            let written = match pane.try_write(&data) {
+                                Ok(written) => written,
+                                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => 0,
+                                Err(err) => return Err(err.into()),
                             };
-                            Ok(Pdu::LivenessResponse(LivenessResponse {
-                                pane_id,
-                                is_alive,
+                            if written > 0 {
+                                maybe_push_pane_changes(&pane, sender, per_pane)?;
+                            }
+                            Ok(Pdu::WriteToPaneResponse(WriteToPaneResponse {
+                                written,
+                                full: written < data.len(),
                             }))
                         },
                         send_response,
-                    )
+                    );
                 })
                 .detach();
             }
-
-            Pdu::GetLines(GetLines { pane_id, lines }) => {
-                spawn_into_main_thread(async move {
+            Pdu::CloseWindow(CloseWindow { window_id }) => {
+                spawn_handler(async move {
                     catch(
                         move || {
                             let mux = Mux::get().unwrap();
-                            let pane = mux
-                                .get_pane(pane_id)
-                                .ok_or_else(|| anyhow!("no such pane {}", pane_id))?;
-                            let mut lines_and_indices = vec![];
-
-                            for range in lines {
-                                let (first_row, lines) = pane.get_lines(range);
-                                for (idx, line) in lines.into_iter().enumerate() {
-                                    let stable_row = first_row + idx as StableRowIndex;
-                                    lines_and_indices.push((stable_row, line));
-                                }
+                            if mux.get_window(window_id).is_none() {
+                                return Err(no_such_window(window_id));
                             }
-                            Ok(Pdu::GetLinesResponse(GetLinesResponse {
-                                pane_id,
-                                lines: lines_and_indices.into(),
+                            // Removes each tab and its panes, which tells the
+                            // clients that they have gone, and then tells them
+                            // that the window has
+                            mux.kill_window(window_id);
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                        },
+                        send_response,
+                    );
+                })
+                .detach();
+            }
+            Pdu::NewWindow(NewWindow { title }) => {
+                spawn_handler(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            // The builder tells the clients of the window
+                            // as it is dropped, by which time it has its title
+                            let window_id = mux.new_empty_window(None);
+                            if let Some(mut window) = mux.get_window_mut(*window_id) {
+                                window.set_awaiting_first_tab();
+                                window.set_title(&title);
+                            }
+                            Ok(Pdu::NewWindowResponse(NewWindowResponse {
+                                window_id: *window_id,
                             }))
                         },
                         send_response,
-                    )
+                    );
                 })
                 .detach();
             }
-
-            Pdu::GetCodecVersion(_) => {
-                match std::env::current_exe().context("resolving current_exe") {
-                    Err(err) => send_response(Err(err)),
-                    Ok(executable_path) => {
-                        send_response(Ok(Pdu::GetCodecVersionResponse(GetCodecVersionResponse {
-                            codec_vers: CODEC_VERSION,
-                            version_string: config::wezterm_version().to_owned(),
-                            executable_path,
-                            config_file_path: std::env::var_os("WEZTERM_CONFIG_FILE")
-                                .map(Into::into),
-                        })))
+            Pdu::SerializeTabState(SerializeTabState { tab_id }) => {
+                spawn_handler(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let tab = mux.get_tab(tab_id).ok_or_else(|| no_such_tab(tab_id))?;
+                            let state = crate::tabstate::capture(&tab)?;
+                            Ok(Pdu::SerializeTabStateResponse(SerializeTabStateResponse {
+                                tab_id,
+                                state: state.encode()?,
+                            }))
+                        },
+                        send_response,
+                    );
+                })
+                .detach();
+            }
+            Pdu::GetCellMetrics(GetCellMetrics { tab_id }) => {
+                spawn_handler(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let tab = mux.get_tab(tab_id).ok_or_else(|| no_such_tab(tab_id))?;
+                            Ok(Pdu::GetCellMetricsResponse(cell_metrics(&tab)))
+                        },
+                        send_response,
+                    );
+                })
+                .detach();
+            }
+            Pdu::SetCellMetrics(SetCellMetrics {
+                tab_id,
+                cell_width_px,
+                cell_height_px,
+            }) => {
+                spawn_handler(async move {
+                    catch(
+                        move || {
+                            if cell_width_px == 0 || cell_height_px == 0 {
+                                anyhow::bail!("cell metrics must not be zero");
+                            }
+                            let mux = Mux::get().unwrap();
+                            let tab = mux.get_tab(tab_id).ok_or_else(|| no_such_tab(tab_id))?;
+                            tab.set_cell_dimensions(cell_width_px, cell_height_px);
+                            Ok(Pdu::GetCellMetricsResponse(cell_metrics(&tab)))
+                        },
+                        send_response,
+                    );
+                })
+                .detach();
+            }
+            Pdu::TrickleScrollback(TrickleScrollback { pane_id, max_rows }) => {
+                match Mux::get().unwrap().get_pane(pane_id) {
+                    Some(pane) => {
+                        let viewport_top = pane.get_dimensions().physical_top;
+                        self.trickles
+                            .lock()
+                            .unwrap()
+                            .start(pane_id, viewport_top, max_rows);
+                        self.continue_trickle();
+                        send_response(Ok(Pdu::UnitResponse(UnitResponse {})))
                     }
+                    None => send_response(Err(no_such_pane(pane_id))),
                 }
             }
-
-            Pdu::GetTlsCreds(_) => {
-                catch(
-                    move || {
-                        let client_cert_pem = PKI.generate_client_cert()?;
-                        let ca_cert_pem = PKI.ca_pem_string()?;
-                        Ok(Pdu::GetTlsCredsResponse(GetTlsCredsResponse {
-                            client_cert_pem,
-                            ca_cert_pem,
-                        }))
-                    },
-                    send_response,
-                );
+            Pdu::TailTab(TailTab {
+                tab_id,
+                context_lines,
+            }) => send_response(self.start_tail(tab_id, context_lines)),
+            Pdu::StopTail(StopTail { tab_id }) => {
+                self.tails.remove(&tab_id);
+                send_response(Ok(Pdu::UnitResponse(UnitResponse {})))
+            }
+            Pdu::SubscribeClipboard(SubscribeClipboard { subscribe }) => {
+                crate::clipboard::subscribe(self.stats.session_id(), subscribe);
+                send_response(Ok(Pdu::UnitResponse(UnitResponse {})))
+            }
+            Pdu::KillPane(KillPane { pane_id }) => {
+                let sender = self.to_write_tx.clone();
+                let per_pane = self.per_pane(pane_id);
+                spawn_handler(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let pane =
+                                mux.get_pane(pane_id).ok_or_else(|| no_such_pane(pane_id))?;
+                            pane.kill();
+                            mux.remove_pane(pane_id);
+                            maybe_push_pane_changes(&pane, sender, per_pane)?;
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                        },
+                        send_response,
+                    );
+                })
+                .detach();
+            }
+            Pdu::SendPaste(SendPaste { pane_id, data }) => {
+                let sender = self.to_write_tx.clone();
+                let per_pane = self.per_pane(pane_id);
+                spawn_handler(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let pane =
+                                mux.get_pane(pane_id).ok_or_else(|| no_such_pane(pane_id))?;
+                            pane.send_paste(&data)?;
+                            maybe_push_pane_changes(&pane, sender, per_pane)?;
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
             }
 
-            Pdu::Invalid { .. } => send_response(Err(anyhow!("invalid PDU {:?}", decoded.pdu))),
-            Pdu::Pong { .. }
-            | Pdu::ListPanesResponse { .. }
-            | Pdu::SetClipboard { .. }
-            | Pdu::NotifyAlert { .. }
-            | Pdu::SetPalette { .. }
-            | Pdu::SpawnResponse { .. }
-            | Pdu::GetPaneRenderChangesResponse { .. }
-            | Pdu::UnitResponse { .. }
-            | Pdu::LivenessResponse { .. }
-            | Pdu::SearchScrollbackResponse { .. }
-            | Pdu::GetLinesResponse { .. }
-            | Pdu::GetCodecVersionResponse { .. }
-            | Pdu::WindowWorkspaceChanged { .. }
-            | Pdu::GetTlsCredsResponse { .. }
-            | Pdu::GetClientListResponse { .. }
-            | Pdu::PaneRemoved { .. }
-            | Pdu::ErrorResponse { .. } => {
-                send_response(Err(anyhow!("expected a request, got {:?}", decoded.pdu)))
+            Pdu::BeginPaste(BeginPaste { pane_id }) => {
+                self.next_paste_id += 1;
+                let paste_id = self.next_paste_id;
+                let pastes = Arc::clone(&self.pastes);
+                spawn_handler(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let pane =
+                                mux.get_pane(pane_id).ok_or_else(|| no_such_pane(pane_id))?;
+                            let bracketed = pane.begin_paste()?;
+                            pastes.lock().unwrap().insert(
+                                paste_id,
+                                PasteSession {
+                                    pane_id,
+                                    bracketed,
+                                    held_cr: false,
+                                },
+                            );
+                            Ok(Pdu::BeginPasteResponse(BeginPasteResponse { paste_id }))
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
             }
-        }
-    }
-}
 
-// Dancing around a little bit here; we can't directly spawn_into_main_thread the domain_spawn
-// function below because the compiler thinks that all of its locals then need to be Send.
-// We need to shimmy through this helper to break that aspect of the compiler flow
-// analysis and allow things to compile.
-fn schedule_domain_spawn_v2<SND>(
-    spawn: SpawnV2,
-    sender: PduSender,
-    send_response: SND,
-    client_id: Option<Arc<ClientId>>,
-) where
-    SND: Fn(anyhow::Result<Pdu>) + 'static,
-{
-    promise::spawn::spawn(
-        async move { send_response(domain_spawn_v2(spawn, sender, client_id).await) },
-    )
-    .detach();
-}
+            Pdu::PasteChunk(PasteChunk { paste_id, data }) => {
+                let sender = self.to_write_tx.clone();
+                let pastes = Arc::clone(&self.pastes);
+                let pane_id = pastes
+                    .lock()
+                    .unwrap()
+                    .get(&paste_id)
+                    .map(|paste| paste.pane_id);
+                let per_pane = pane_id.map(|pane_id| self.per_pane(pane_id));
+                spawn_handler(async move {
+                    catch(
+                        move || {
+                            let (pane_id, bracketed, text) = {
+                                let mut pastes = pastes.lock().unwrap();
+                                let paste = pastes
+                                    .get_mut(&paste_id)
+                                    .ok_or_else(|| anyhow!("no such paste {}", paste_id))?;
+                                (paste.pane_id, paste.bracketed, paste.take_text(&data))
+                            };
+                            let mux = Mux::get().unwrap();
+                            let pane =
+                                mux.get_pane(pane_id).ok_or_else(|| no_such_pane(pane_id))?;
+                            pane.send_paste_part(&text, bracketed)?;
+                            if let Some(per_pane) = per_pane {
+                                maybe_push_pane_changes(&pane, sender, per_pane)?;
+                            }
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
+            }
 
-fn schedule_split_pane<SND>(
-    split: SplitPane,
-    sender: PduSender,
-    send_response: SND,
+            Pdu::EndPaste(EndPaste { paste_id }) => {
+                let pastes = Arc::clone(&self.pastes);
+                spawn_handler(async move {
+                    catch(
+                        move || {
+                            let paste = pastes
+                                .lock()
+                                .unwrap()
+                                .remove(&paste_id)
+                                .ok_or_else(|| anyhow!("no such paste {}", paste_id))?;
+                            let mux = Mux::get().unwrap();
+                            let pane = mux
+                                .get_pane(paste.pane_id)
+                                .ok_or_else(|| no_such_pane(paste.pane_id))?;
+                            if paste.held_cr {
+                                pane.send_paste_part("\r", paste.bracketed)?;
+                            }
+                            pane.end_paste(paste.bracketed)?;
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
+            }
+
+            Pdu::SearchScrollbackRequest(SearchScrollbackRequest { pane_id, pattern }) => {
+                use mux::pane::Pattern;
+
+                async fn do_search(pane_id: TabId, pattern: Pattern) -> anyhow::Result<Pdu> {
+                    let mux = Mux::get().unwrap();
+                    let pane = mux.get_pane(pane_id).ok_or_else(|| no_such_pane(pane_id))?;
+
+                    pane.search(pattern).await.map(|results| {
+                        Pdu::SearchScrollbackResponse(SearchScrollbackResponse { results })
+                    })
+                }
+
+                spawn_handler(async move {
+                    promise::spawn::spawn(async move {
+                        let result = do_search(pane_id, pattern).await;
+                        send_response(result);
+                    })
+                    .detach();
+                })
+                .detach();
+            }
+
+            Pdu::DrainTab(DrainTab { tab_id }) => {
+                async fn drain_tab(tab_id: TabId) -> anyhow::Result<Pdu> {
+                    let mux = Mux::get().unwrap();
+                    let tab = mux.get_tab(tab_id).ok_or_else(|| no_such_tab(tab_id))?;
+                    let progress: Vec<_> = tab
+                        .iter_panes_ignoring_zoom()
+                        .into_iter()
+                        .filter_map(|pos| mux.get_pty_output_progress(pos.pane.pane_id()))
+                        .collect();
+                    for progress in progress {
+                        progress.drained().await;
+                    }
+                    Ok(Pdu::UnitResponse(UnitResponse {}))
+                }
+
+                spawn_handler(async move {
+                    promise::spawn::spawn(async move {
+                        let result = drain_tab(tab_id).await;
+                        send_response(result);
+                    })
+                    .detach();
+                })
+                .detach();
+            }
+
+            Pdu::SetPaneZoomed(SetPaneZoomed {
+                containing_tab_id,
+                pane_id,
+                zoomed,
+            }) => {
+                spawn_handler(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let pane =
+                                mux.get_pane(pane_id).ok_or_else(|| no_such_pane(pane_id))?;
+                            let tab = mux
+                                .get_tab(containing_tab_id)
+                                .ok_or_else(|| no_such_tab(containing_tab_id))?;
+                            tab.set_active_pane(&pane);
+                            tab.set_zoomed(zoomed);
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
+            }
+
+            Pdu::Resize(Resize {
+                containing_tab_id,
+                pane_id,
+                size,
+                resize_id,
+                anchor_row,
+            }) => {
+                let sender = self.to_write_tx.clone();
+                let per_pane = self.per_pane(pane_id);
+                spawn_handler(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let pane =
+                                mux.get_pane(pane_id).ok_or_else(|| no_such_pane(pane_id))?;
+                            let anchor_row = match anchor_row {
+                                Some(anchor) => pane.resize_with_anchor(size, anchor)?,
+                                None => {
+                                    pane.resize(size)?;
+                                    None
+                                }
+                            };
+                            let tab = mux
+                                .get_tab(containing_tab_id)
+                                .ok_or_else(|| no_such_tab(containing_tab_id))?;
+                            tab.rebuild_splits_sizes_from_contained_panes();
+                            // Tell the client that its resize has been
+                            // applied, along with the size that resulted
+                            per_pane.lock().unwrap().last_resize_id = Some(resize_id);
+                            maybe_push_pane_changes(&pane, sender, per_pane)?;
+                            Ok(Pdu::ResizeResponse(ResizeResponse { anchor_row }))
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
+            }
+
+            Pdu::SetSelection(SetSelection { pane_id, selection }) => {
+                spawn_handler(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            mux.get_pane(pane_id).ok_or_else(|| no_such_pane(pane_id))?;
+                            if crate::selection::set_selection(pane_id, selection) {
+                                // Prompt every session viewing the pane
+                                // to push the new selection to its client
+                                mux.notify(MuxNotification::PaneOutput(pane_id));
+                            }
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
+            }
+
+            Pdu::ExportScrollback(ExportScrollback {
+                pane_id,
+                range,
+                escapes,
+            }) => {
+                spawn_handler(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let pane =
+                                mux.get_pane(pane_id).ok_or_else(|| no_such_pane(pane_id))?;
+                            let dims = pane.get_dimensions();
+                            let start = range.start.max(dims.scrollback_top);
+                            let end = range
+                                .end
+                                .min(dims.physical_top + dims.viewport_rows as StableRowIndex);
+                            let (range, text) = if start < end {
+                                let (first, lines) = pane.get_lines(start..end);
+                                (
+                                    first..first + lines.len() as StableRowIndex,
+                                    crate::export::lines_to_text(&lines, escapes),
+                                )
+                            } else {
+                                (start..start, String::new())
+                            };
+                            Ok(Pdu::ExportScrollbackResponse(ExportScrollbackResponse {
+                                pane_id,
+                                range,
+                                text,
+                            }))
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
+            }
+
+            Pdu::TrimScrollback(TrimScrollback { pane_id, max_lines }) => {
+                spawn_handler(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let pane =
+                                mux.get_pane(pane_id).ok_or_else(|| no_such_pane(pane_id))?;
+                            let viewport_rows = pane.get_dimensions().viewport_rows;
+                            if max_lines < viewport_rows {
+                                anyhow::bail!(
+                                    "cannot trim scrollback to {} lines as the viewport has {}",
+                                    max_lines,
+                                    viewport_rows
+                                );
+                            }
+                            pane.trim_scrollback(max_lines - viewport_rows);
+                            // The scrollback_top has moved; make sure that
+                            // every session viewing the pane learns of it
+                            mux.notify(MuxNotification::PaneOutput(pane_id));
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
+            }
+
+            Pdu::ResizeWindow(ResizeWindow { window_id, size }) => {
+                spawn_handler(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let tabs: Vec<Rc<Tab>> = mux
+                                .get_window(window_id)
+                                .ok_or_else(|| no_such_window(window_id))?
+                                .iter()
+                                .cloned()
+                                .collect();
+                            // Resize all of the tabs before any pushes are
+                            // made, so that the client sees a consistent
+                            // layout across the window
+                            for tab in &tabs {
+                                tab.resize(size);
+                            }
+                            for tab in &tabs {
+                                for pos in tab.iter_panes() {
+                                    mux.notify(MuxNotification::PaneOutput(pos.pane.pane_id()));
+                                }
+                            }
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
+            }
+
+            Pdu::InvalidateTab(InvalidateTab { tab_id }) => {
+                let sender = self.to_write_tx.clone();
+                let pane_ids: Vec<PaneId> = Mux::get()
+                    .unwrap()
+                    .get_tab(tab_id)
+                    .map(|tab| {
+                        tab.iter_panes_ignoring_zoom()
+                            .iter()
+                            .map(|pos| pos.pane.pane_id())
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                let per_panes: Vec<_> = pane_ids
+                    .into_iter()
+                    .map(|pane_id| (pane_id, self.per_pane(pane_id)))
+                    .collect();
+                spawn_handler(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            mux.get_tab(tab_id).ok_or_else(|| no_such_tab(tab_id))?;
+                            for (pane_id, per_pane) in per_panes {
+                                let pane = match mux.get_pane(pane_id) {
+                                    Some(pane) => pane,
+                                    None => continue,
+                                };
+                                // Push the full state right away, even if the
+                                // client is congested, as it has explicitly
+                                // asked for it
+                                let mut per_pane = per_pane.lock().unwrap();
+                                per_pane.invalidate();
+                                if let Some(changes) = per_pane.compute_changes(&pane, None) {
+                                    sender.send(DecodedPdu {
+                                        pdu: changes.into_pdu(),
+                                        serial: 0,
+                                    })?;
+                                }
+                                sender.send(DecodedPdu {
+                                    pdu: Pdu::SetPalette(SetPalette {
+                                        pane_id,
+                                        palette: pane.palette(),
+                                    }),
+                                    serial: 0,
+                                })?;
+                                per_pane.sent_initial_palette = true;
+                            }
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
+            }
+
+            Pdu::SendKeyDown(SendKeyDown {
+                pane_id,
+                event,
+                input_serial,
+            }) => {
+                let sender = self.to_write_tx.clone();
+                let per_pane = self.per_pane(pane_id);
+                spawn_handler(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let pane =
+                                mux.get_pane(pane_id).ok_or_else(|| no_such_pane(pane_id))?;
+                            pane.key_down(event.key, event.modifiers)?;
+
+                            // For a key press, we want to always send back the
+                            // cursor position so that the predictive echo doesn't
+                            // leave the cursor in the wrong place
+                            let mut per_pane = per_pane.lock().unwrap();
+                            per_pane.record_key_input(Instant::now());
+                            if let Some(changes) =
+                                per_pane.compute_changes(&pane, Some(input_serial))
+                            {
+                                sender.send(DecodedPdu {
+                                    pdu: changes.into_pdu(),
+                                    serial: 0,
+                                })?;
+                            }
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
+            }
+            Pdu::SendKeyDownBatch(SendKeyDownBatch {
+                pane_id,
+                events,
+                input_serial,
+            }) => {
+                let sender = self.to_write_tx.clone();
+                let per_pane = self.per_pane(pane_id);
+                spawn_handler(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let pane =
+                                mux.get_pane(pane_id).ok_or_else(|| no_such_pane(pane_id))?;
+                            for event in events {
+                                pane.key_down(event.key, event.modifiers)?;
+                            }
+
+                            // As for SendKeyDown, but only for the final
+                            // state after the whole batch has been applied
+                            let mut per_pane = per_pane.lock().unwrap();
+                            per_pane.record_key_input(Instant::now());
+                            if let Some(changes) =
+                                per_pane.compute_changes(&pane, Some(input_serial))
+                            {
+                                sender.send(DecodedPdu {
+                                    pdu: changes.into_pdu(),
+                                    serial: 0,
+                                })?;
+                            }
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
+            }
+            Pdu::SendText(SendText {
+                pane_id,
+                text,
+                input_serial,
+            }) => {
+                let sender = self.to_write_tx.clone();
+                let per_pane = self.per_pane(pane_id);
+                spawn_handler(async move {
+                    catch(
+                        move || {
+                            let text = validate_text(&text)?;
+                            let mux = Mux::get().unwrap();
+                            let pane =
+                                mux.get_pane(pane_id).ok_or_else(|| no_such_pane(pane_id))?;
+                            if text.contains(|c| c == '\n' || c == '\r') {
+                                pane.send_paste(text)?;
+                            } else {
+                                pane.writer().write_all(text.as_bytes())?;
+                            }
+
+                            // As for SendKeyDown, so that the predictive
+                            // echo of the text is reconciled
+                            let mut per_pane = per_pane.lock().unwrap();
+                            per_pane.record_key_input(Instant::now());
+                            if let Some(changes) =
+                                per_pane.compute_changes(&pane, Some(input_serial))
+                            {
+                                sender.send(DecodedPdu {
+                                    pdu: changes.into_pdu(),
+                                    serial: 0,
+                                })?;
+                            }
+                            Ok(Pdu::UnitResponse(UnitResponse {}))
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
+            }
+            Pdu::SendMouseEvent(SendMouseEvent { pane_id, event }) => {
+                let tab_id = Mux::get()
+                    .unwrap()
+                    .resolve_pane_id(pane_id)
+                    .map(|(_domain_id, _window_id, tab_id)| tab_id);
+                let queued = self.mouse_queue.lock().unwrap().push(QueuedMouseEvent {
+                    tab_id,
+                    pane_id,
+                    event,
+                    reply: MouseReply {
+                        send_response: Box::new(send_response),
+                        per_pane: self.per_pane(pane_id),
+                    },
+                });
+                if let Some(superseded) = queued.superseded {
+                    (superseded.send_response)(Ok(Pdu::UnitResponse(UnitResponse {})));
+                }
+                if queued.schedule_flush {
+                    let sender = self.to_write_tx.clone();
+                    let mouse_queue = Arc::clone(&self.mouse_queue);
+                    spawn_handler(async move {
+                        apply_mouse_events(&mouse_queue, sender);
+                    })
+                    .detach();
+                }
+            }
+
+            Pdu::SpawnV2(spawn) => {
+                let send_response = match self.admit_spawn(send_response) {
+                    Some(send_response) => send_response,
+                    None => return,
+                };
+                let session_id = self.stats.session_id();
+                let client_id = self.client_id.clone();
+                spawn_handler(async move {
+                    schedule_domain_spawn_v2(spawn, session_id, send_response, client_id);
+                })
+                .detach();
+            }
+
+            Pdu::ValidateSpawn(ValidateSpawn {
+                domain,
+                command,
+                command_dir,
+            }) => {
+                spawn_handler(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            Ok(Pdu::ValidateSpawnResponse(
+                                match mux.validate_spawn(&domain, command, command_dir) {
+                                    Ok(program) => ValidateSpawnResponse {
+                                        program: program
+                                            .map(|program| program.to_string_lossy().into_owned()),
+                                        failure: None,
+                                    },
+                                    Err(err) => ValidateSpawnResponse {
+                                        program: None,
+                                        failure: Some(SpawnFailed {
+                                            kind: spawn_error_kind(&err),
+                                            reason: format!("{:#}", err),
+                                        }),
+                                    },
+                                },
+                            ))
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
+            }
+
+            Pdu::SplitPane(split) => {
+                let send_response = match self.admit_spawn(send_response) {
+                    Some(send_response) => send_response,
+                    None => return,
+                };
+                let session_id = self.stats.session_id();
+                let client_id = self.client_id.clone();
+                spawn_handler(async move {
+                    schedule_split_pane(split, session_id, send_response, client_id);
+                })
+                .detach();
+            }
+
+            Pdu::SpawnSplit(split) => {
+                let send_response = match self.admit_spawn(send_response) {
+                    Some(send_response) => send_response,
+                    None => return,
+                };
+                let session_id = self.stats.session_id();
+                let client_id = self.client_id.clone();
+                spawn_handler(async move {
+                    schedule_spawn_split(split, session_id, send_response, client_id);
+                })
+                .detach();
+            }
+
+            Pdu::RestoreTabState(restore) => {
+                let send_response = match self.admit_spawn(send_response) {
+                    Some(send_response) => send_response,
+                    None => return,
+                };
+                let session_id = self.stats.session_id();
+                let client_id = self.client_id.clone();
+                spawn_handler(async move {
+                    schedule_restore_tab_state(restore, session_id, send_response, client_id);
+                })
+                .detach();
+            }
+
+            Pdu::GetPaneRenderChanges(GetPaneRenderChanges {
+                pane_id,
+                since_input_serial,
+                include_bonus_lines,
+                viewport_only,
+            }) => {
+                let sender = self.to_write_tx.clone();
+                let per_pane = self.per_pane(pane_id);
+                {
+                    let mut per_pane = per_pane.lock().unwrap();
+                    if let Some(serial) = since_input_serial {
+                        per_pane.acknowledge_input_serial(serial);
+                    }
+                    per_pane.omit_bonus_lines = !include_bonus_lines;
+                    per_pane.viewport_only |= viewport_only;
+                }
+                spawn_handler(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let is_alive = match mux.get_pane(pane_id) {
+                                Some(pane) => {
+                                    maybe_push_pane_changes(&pane, sender, per_pane)?;
+                                    true
+                                }
+                                None => false,
+                            };
+                            Ok(Pdu::LivenessResponse(LivenessResponse {
+                                pane_id,
+                                is_alive,
+                            }))
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
+            }
+
+            Pdu::GetLines(mut request) => {
+                if let Some((_domain_id, _window_id, tab_id)) =
+                    Mux::get().unwrap().resolve_pane_id(request.pane_id)
+                {
+                    self.clear_tab_activity(tab_id);
+                }
+                self.per_pane(request.pane_id)
+                    .lock()
+                    .unwrap()
+                    .forget_row_hashes(&request.lines);
+                let sender = self.to_write_tx.clone();
+                let acked_images = Arc::clone(&self.acked_images);
+                spawn_handler(async move {
+                    catch(
+                        move || {
+                            let mux = Mux::get().unwrap();
+                            let pane_id = request.pane_id;
+                            let pane =
+                                mux.get_pane(pane_id).ok_or_else(|| no_such_pane(pane_id))?;
+                            request.clamp_to_dimensions(&pane.get_dimensions());
+                            let known_hashes: HashMap<StableRowIndex, u64> =
+                                request.known_hashes.into_iter().collect();
+                            let mut lines_and_indices = vec![];
+
+                            for range in request.lines {
+                                let (first_row, lines) = pane.get_lines(range);
+                                for (idx, line) in lines.into_iter().enumerate() {
+                                    let stable_row = first_row + idx as StableRowIndex;
+                                    // The client already has this version of the line
+                                    if known_hashes
+                                        .get(&stable_row)
+                                        .map_or(false, |&hash| hash == ROW_HASH.hash_line(&line))
+                                    {
+                                        continue;
+                                    }
+                                    lines_and_indices.push((stable_row, line));
+
+                                    // Emit full chunks as we go, so that a large
+                                    // request doesn't produce a single huge PDU
+                                    // that monopolizes the writer
+                                    if lines_and_indices.len() >= MAX_LINES_PER_RESPONSE {
+                                        sender.send(DecodedPdu {
+                                            pdu: Pdu::GetLinesResponse(GetLinesResponse {
+                                                pane_id,
+                                                lines: serialize_lines(
+                                                    std::mem::take(&mut lines_and_indices),
+                                                    &acked_images,
+                                                    request.packed,
+                                                ),
+                                                more: true,
+                                            }),
+                                            serial,
+                                        })?;
+                                    }
+                                }
+                            }
+                            Ok(Pdu::GetLinesResponse(GetLinesResponse {
+                                pane_id,
+                                lines: serialize_lines(
+                                    lines_and_indices,
+                                    &acked_images,
+                                    request.packed,
+                                ),
+                                more: false,
+                            }))
+                        },
+                        send_response,
+                    )
+                })
+                .detach();
+            }
+
+            Pdu::GetCodecVersion(GetCodecVersion { codec_vers }) => {
+                // A client that doesn't send its version predates
+                // the range that we support
+                if !codec_vers.map_or(false, is_supported_codec_version) {
+                    log::error!(
+                        "rejecting client with unsupported codec version {:?}",
+                        codec_vers
+                    );
+                    send_response(Ok(Pdu::IncompatibleCodec(IncompatibleCodec {
+                        server_vers: config::wezterm_version().to_owned(),
+                        min_supported: MIN_SUPPORTED_CODEC_VERSION,
+                        max_supported: CODEC_VERSION,
+                    })));
+                    return;
+                }
+                match std::env::current_exe().context("resolving current_exe") {
+                    Err(err) => send_response(Err(err)),
+                    Ok(executable_path) => {
+                        send_response(Ok(Pdu::GetCodecVersionResponse(GetCodecVersionResponse {
+                            codec_vers: CODEC_VERSION,
+                            version_string: config::wezterm_version().to_owned(),
+                            executable_path,
+                            config_file_path: std::env::var_os("WEZTERM_CONFIG_FILE")
+                                .map(Into::into),
+                            row_hash: Some(ROW_HASH),
+                        })))
+                    }
+                }
+            }
+
+            Pdu::GetTlsCreds(_) => {
+                catch(
+                    move || {
+                        let client_cert_pem = PKI.generate_client_cert()?;
+                        let ca_cert_pem = PKI.ca_pem_string()?;
+                        Ok(Pdu::GetTlsCredsResponse(GetTlsCredsResponse {
+                            client_cert_pem,
+                            ca_cert_pem,
+                        }))
+                    },
+                    send_response,
+                );
+            }
+
+            Pdu::NegotiateEncryption { .. }
+            | Pdu::EnableFrameChecksums { .. }
+            | Pdu::EnableBlobChannel { .. }
+            | Pdu::EnableStreamCompression { .. } => {
+                // These change the framing of the stream, so they are
+                // handled by the dispatcher rather than here
+                send_response(Err(request_error(
+                    ErrorCode::Unsupported,
+                    "framing is negotiated by the dispatcher".to_string(),
+                )))
+            }
+
+            Pdu::Invalid { ident, .. } => {
+                // Most likely a newer client; let it know that we don't
+                // support this request so that it can fall back
+                log::warn!("client sent unknown PDU ident {}; skipping it", ident);
+                send_response(Ok(Pdu::UnimplementedResponse(UnimplementedResponse {
+                    serial,
+                    ident,
+                })))
+            }
+            Pdu::Pong { .. }
+            | Pdu::BarrierResponse { .. }
+            | Pdu::ListPanesResponse { .. }
+            | Pdu::GetTabResponse { .. }
+            | Pdu::GetWindowsResponse { .. }
+            | Pdu::NewWindowResponse { .. }
+            | Pdu::GetCapabilitiesResponse { .. }
+            | Pdu::SerializeTabStateResponse { .. }
+            | Pdu::TabExited { .. }
+            | Pdu::GetCellMetricsResponse { .. }
+            | Pdu::ScrollbackChunk { .. }
+            | Pdu::TailTabResponse { .. }
+            | Pdu::ResizeResponse { .. }
+            | Pdu::TailLines { .. }
+            | Pdu::SetClipboard { .. }
+            | Pdu::NotifyAlert { .. }
+            | Pdu::Notification { .. }
+            | Pdu::SetProgress { .. }
+            | Pdu::WriteToPaneResponse { .. }
+            | Pdu::GetTabScreenshotResponse { .. }
+            | Pdu::GetEnvResponse { .. }
+            | Pdu::GetPaneLayoutResponse { .. }
+            | Pdu::BeginPasteResponse { .. }
+            | Pdu::BlobChunk { .. }
+            | Pdu::CursorUpdate { .. }
+            | Pdu::SetPalette { .. }
+            | Pdu::SpawnResponse { .. }
+            | Pdu::SpawnSplitResponse { .. }
+            | Pdu::GetPaneRenderChangesResponse { .. }
+            | Pdu::UnitResponse { .. }
+            | Pdu::UnimplementedResponse { .. }
+            | Pdu::LivenessResponse { .. }
+            | Pdu::SearchScrollbackResponse { .. }
+            | Pdu::GetLinesResponse { .. }
+            | Pdu::GetCodecVersionResponse { .. }
+            | Pdu::IncompatibleCodec { .. }
+            | Pdu::WindowWorkspaceChanged { .. }
+            | Pdu::WindowStructureChanged { .. }
+            | Pdu::GetTlsCredsResponse { .. }
+            | Pdu::GetClientListResponse { .. }
+            | Pdu::ListClientsResponse { .. }
+            | Pdu::ListSessionsResponse { .. }
+            | Pdu::SpawnFailed { .. }
+            | Pdu::ValidateSpawnResponse { .. }
+            | Pdu::ListDomainsResponse { .. }
+            | Pdu::ExportScrollbackResponse { .. }
+            | Pdu::PaneRemoved { .. }
+            | Pdu::NegotiateEncryptionResponse { .. }
+            | Pdu::GetServerStatsResponse { .. }
+            | Pdu::GetTabStatsResponse { .. }
+            | Pdu::ErrorResponse { .. } => send_response(Err(request_error(
+                ErrorCode::Unsupported,
+                format!("expected a request, got {:?}", decoded.pdu),
+            ))),
+        }
+    }
+}
+
+/// Returns the panes in `tab_id`, including those hidden by a zoomed pane
+fn tab_pane_ids(tab_id: TabId) -> Vec<PaneId> {
+    Mux::get()
+        .unwrap()
+        .get_tab(tab_id)
+        .map(|tab| {
+            tab.iter_panes_ignoring_zoom()
+                .iter()
+                .map(|pos| pos.pane.pane_id())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Returns true if `pdu` is a request that changes the panes, tabs
+/// or windows of the mux, and so is denied to a read-only session
+fn changes_mux_state(pdu: &Pdu) -> bool {
+    matches!(
+        pdu,
+        Pdu::KillPane(_)
+            | Pdu::CloseWindow(_)
+            | Pdu::SpawnV2(_)
+            | Pdu::SplitPane(_)
+            | Pdu::SpawnSplit(_)
+            | Pdu::NewWindow(_)
+            | Pdu::RestoreTabState(_)
+            | Pdu::Resize(_)
+            | Pdu::ResizeWindow(_)
+            | Pdu::TrimScrollback(_)
+            | Pdu::SetPaneZoomed(_)
+            | Pdu::ReorderTab(_)
+            | Pdu::SetWindowWorkspace(_)
+            | Pdu::SetTabTitlePolicy(_)
+            | Pdu::AttachDomain(_)
+            | Pdu::DetachDomain(_)
+    )
+}
+
+/// Returns the tab that is the target of the request `pdu`, if any
+fn request_tab_id(pdu: &Pdu) -> Option<TabId> {
+    let pane_id = match pdu {
+        Pdu::SpawnSplit(SpawnSplit { tab_id, .. })
+        | Pdu::SetWriteAccess(SetWriteAccess { tab_id, .. })
+        | Pdu::InvalidateTab(InvalidateTab { tab_id, .. })
+        | Pdu::SetFocusedTab(SetFocusedTab { tab_id, .. })
+        | Pdu::FreezeTab(FreezeTab { tab_id, .. })
+        | Pdu::ReorderTab(ReorderTab { tab_id, .. })
+        | Pdu::SetTabTitlePolicy(SetTabTitlePolicy { tab_id, .. })
+        | Pdu::DrainTab(DrainTab { tab_id })
+        | Pdu::GetTabScreenshot(GetTabScreenshot { tab_id, .. })
+        | Pdu::GetEnv(GetEnv { tab_id, .. })
+        | Pdu::GetPaneLayout(GetPaneLayout { tab_id })
+        | Pdu::GetTab(GetTab { tab_id })
+        | Pdu::GetTabStats(GetTabStats { tab_id, .. })
+        | Pdu::SerializeTabState(SerializeTabState { tab_id })
+        | Pdu::GetCellMetrics(GetCellMetrics { tab_id })
+        | Pdu::TailTab(TailTab { tab_id, .. })
+        | Pdu::StopTail(StopTail { tab_id })
+        | Pdu::SetCellMetrics(SetCellMetrics { tab_id, .. })
+        | Pdu::Resize(Resize {
+            containing_tab_id: tab_id,
+            ..
+        })
+        | Pdu::SetPaneZoomed(SetPaneZoomed {
+            containing_tab_id: tab_id,
+            ..
+        }) => return Some(*tab_id),
+        Pdu::SplitPane(SplitPane { pane_id, .. })
+        | Pdu::KillPane(KillPane { pane_id, .. })
+        | Pdu::WriteToPane(WriteToPane { pane_id, .. })
+        | Pdu::WriteTextToPane(WriteTextToPane { pane_id, .. })
+        | Pdu::TryWriteToPane(TryWriteToPane { pane_id, .. })
+        | Pdu::SendPaste(SendPaste { pane_id, .. })
+        | Pdu::BeginPaste(BeginPaste { pane_id, .. })
+        | Pdu::SendKeyDown(SendKeyDown { pane_id, .. })
+        | Pdu::SendKeyDownBatch(SendKeyDownBatch { pane_id, .. })
+        | Pdu::SendText(SendText { pane_id, .. })
+        | Pdu::SendMouseEvent(SendMouseEvent { pane_id, .. })
+        | Pdu::ExportScrollback(ExportScrollback { pane_id, .. })
+        | Pdu::TrimScrollback(TrimScrollback { pane_id, .. })
+        | Pdu::GetPaneRenderChanges(GetPaneRenderChanges { pane_id, .. })
+        | Pdu::SetSelection(SetSelection { pane_id, .. })
+        | Pdu::GetLines(GetLines { pane_id, .. })
+        | Pdu::TrickleScrollback(TrickleScrollback { pane_id, .. })
+        | Pdu::SearchScrollbackRequest(SearchScrollbackRequest { pane_id, .. }) => *pane_id,
+        _ => return None,
+    };
+    Mux::get()
+        .unwrap()
+        .resolve_pane_id(pane_id)
+        .map(|(_domain_id, _window_id, tab_id)| tab_id)
+}
+
+fn cell_metrics(tab: &Tab) -> GetCellMetricsResponse {
+    let cell = tab.get_cell_dimensions();
+    GetCellMetricsResponse {
+        tab_id: tab.tab_id(),
+        cell_width_px: cell.pixel_width,
+        cell_height_px: cell.pixel_height,
+    }
+}
+
+fn capabilities() -> GetCapabilitiesResponse {
+    let signals = if cfg!(unix) {
+        vec!["SIGHUP".to_string(), "SIGWINCH".to_string()]
+    } else {
+        vec![]
+    };
+    GetCapabilitiesResponse {
+        signals,
+        sixel_images: true,
+        kitty_images: configuration().enable_kitty_graphics,
+        splits: true,
+        clipboard_selections: vec![
+            ClipboardSelection::Clipboard,
+            ClipboardSelection::PrimarySelection,
+        ],
+        screenshots: cfg!(feature = "screenshot"),
+    }
+}
+
+/// Verify that `data` is well formed UTF-8, as required by
+/// `WriteTextToPane` and `SendText`, returning it as a str if so
+fn validate_text(data: &[u8]) -> anyhow::Result<&str> {
+    std::str::from_utf8(data).map_err(|err| {
+        anyhow!(
+            "text is not valid UTF-8; \
+             the sequence at byte offset {} is malformed",
+            err.valid_up_to()
+        )
+    })
+}
+
+#[cfg(feature = "screenshot")]
+fn screenshot_tab(tab: &Rc<Tab>) -> anyhow::Result<Vec<u8>> {
+    crate::screenshot::screenshot_tab(tab)
+}
+
+#[cfg(not(feature = "screenshot"))]
+fn screenshot_tab(_tab: &Rc<Tab>) -> anyhow::Result<Vec<u8>> {
+    Err(request_error(
+        ErrorCode::Unsupported,
+        "this server was built without the screenshot feature".to_string(),
+    ))
+}
+
+/// Spawn the handler for a request into the main thread, where it
+/// counts towards the queue depth of `mainthread` until it runs.
+/// The handler runs within a "main_thread" span that is a child of
+/// the span of the request, so that the time spent waiting for the
+/// main thread is reported as the idle time of that span.
+fn spawn_handler<F, R>(future: F) -> Task<R>
+where
+    F: Future<Output = R> + Send + 'static,
+    R: Send + 'static,
+{
+    mainthread::spawn(future.instrument(tracing::trace_span!("main_thread")))
+}
+
+/// Returns an error that is reported to the client with `code`
+pub(crate) fn request_error(code: ErrorCode, reason: String) -> anyhow::Error {
+    ErrorResponse { code, reason }.into()
+}
+
+fn no_such_pane(pane_id: PaneId) -> anyhow::Error {
+    request_error(ErrorCode::NoSuchPane, format!("no such pane {}", pane_id))
+}
+
+fn no_such_tab(tab_id: TabId) -> anyhow::Error {
+    request_error(ErrorCode::NoSuchTab, format!("no such tab {}", tab_id))
+}
+
+fn no_such_window(window_id: WindowId) -> anyhow::Error {
+    request_error(
+        ErrorCode::NoSuchWindow,
+        format!("no such window {}", window_id),
+    )
+}
+
+fn no_such_domain(domain_id: DomainId) -> anyhow::Error {
+    request_error(
+        ErrorCode::NoSuchDomain,
+        format!("no such domain {}", domain_id),
+    )
+}
+
+/// Describe the failure of a request, classifying it so that the
+/// client can react to it.  Errors that weren't made with
+/// `request_error` are assumed to be internal errors.
+pub(crate) fn error_response(err: anyhow::Error) -> Pdu {
+    Pdu::ErrorResponse(ErrorResponse {
+        code: error_code(&err),
+        reason: format!("Error: {}", err),
+    })
+}
+
+fn error_code(err: &anyhow::Error) -> ErrorCode {
+    for cause in err.chain() {
+        if let Some(response) = cause.downcast_ref::<ErrorResponse>() {
+            return response.code;
+        }
+        if cause.is::<mux::DomainUnavailable>() {
+            return ErrorCode::NoSuchDomain;
+        }
+    }
+    ErrorCode::Internal
+}
+
+/// Describe the failure of a request that spawns a program,
+/// classifying it so that the client can react to it
+fn spawn_failed(err: anyhow::Error) -> Pdu {
+    Pdu::SpawnFailed(SpawnFailed {
+        kind: spawn_error_kind(&err),
+        reason: format!("{:#}", err),
+    })
+}
+
+fn spawn_error_kind(err: &anyhow::Error) -> SpawnErrorKind {
+    for cause in err.chain() {
+        if cause.is::<portable_pty::CommandNotFound>() {
+            return SpawnErrorKind::CommandNotFound;
+        }
+        if cause.is::<mux::DomainUnavailable>() {
+            return SpawnErrorKind::DomainUnavailable;
+        }
+        if cause.is::<mux::DirectoryNotFound>() {
+            return SpawnErrorKind::DirectoryNotFound;
+        }
+        if let Some(err) = cause.downcast_ref::<std::io::Error>() {
+            match err.kind() {
+                std::io::ErrorKind::NotFound => return SpawnErrorKind::CommandNotFound,
+                std::io::ErrorKind::PermissionDenied => return SpawnErrorKind::PermissionDenied,
+                _ => {}
+            }
+        }
+    }
+    SpawnErrorKind::Other
+}
+
+// Dancing around a little bit here; we can't directly spawn_into_main_thread the domain_spawn
+// function below because the compiler thinks that all of its locals then need to be Send.
+// We need to shimmy through this helper to break that aspect of the compiler flow
+// analysis and allow things to compile.
+fn schedule_domain_spawn_v2<SND>(
+    spawn: SpawnV2,
+    session_id: usize,
+    send_response: SND,
     client_id: Option<Arc<ClientId>>,
 ) where
     SND: Fn(anyhow::Result<Pdu>) + 'static,
 {
-    promise::spawn::spawn(async move { send_response(split_pane(split, sender, client_id).await) })
-        .detach();
+    promise::spawn::spawn(
+        async move {
+            send_response(Ok(domain_spawn_v2(spawn, session_id, client_id)
+                .await
+                .unwrap_or_else(spawn_failed)))
+        }
+        .instrument(tracing::trace_span!("handler")),
+    )
+    .detach();
+}
+
+fn schedule_restore_tab_state<SND>(
+    restore: RestoreTabState,
+    session_id: usize,
+    send_response: SND,
+    client_id: Option<Arc<ClientId>>,
+) where
+    SND: Fn(anyhow::Result<Pdu>) + 'static,
+{
+    promise::spawn::spawn(
+        async move {
+            // Refuse a state that can't be decoded before
+            // spawning anything for it
+            let response = match TabState::decode(&restore.state) {
+                Ok(state) => Ok(restore_tab_state(restore, state, session_id, client_id)
+                    .await
+                    .unwrap_or_else(spawn_failed)),
+                Err(err) => Err(err),
+            };
+            send_response(response)
+        }
+        .instrument(tracing::trace_span!("handler")),
+    )
+    .detach();
+}
+
+fn schedule_split_pane<SND>(
+    split: SplitPane,
+    session_id: usize,
+    send_response: SND,
+    client_id: Option<Arc<ClientId>>,
+) where
+    SND: Fn(anyhow::Result<Pdu>) + 'static,
+{
+    promise::spawn::spawn(
+        async move {
+            send_response(Ok(split_pane(split, session_id, client_id)
+                .await
+                .unwrap_or_else(spawn_failed)))
+        }
+        .instrument(tracing::trace_span!("handler")),
+    )
+    .detach();
+}
+
+fn schedule_spawn_split<SND>(
+    split: SpawnSplit,
+    session_id: usize,
+    send_response: SND,
+    client_id: Option<Arc<ClientId>>,
+) where
+    SND: Fn(anyhow::Result<Pdu>) + 'static,
+{
+    promise::spawn::spawn(
+        async move {
+            send_response(Ok(spawn_split(split, session_id, client_id)
+                .await
+                .unwrap_or_else(spawn_failed)))
+        }
+        .instrument(tracing::trace_span!("handler")),
+    )
+    .detach();
+}
+
+async fn split_pane(
+    split: SplitPane,
+    session_id: usize,
+    client_id: Option<Arc<ClientId>>,
+) -> anyhow::Result<Pdu> {
+    let mux = Mux::get().unwrap();
+    let _identity = mux.with_identity(client_id);
+
+    let (_pane_domain_id, window_id, tab_id) = mux
+        .resolve_pane_id(split.pane_id)
+        .ok_or_else(|| no_such_pane(split.pane_id))?;
+
+    let (pane, size) = mux
+        .split_pane(
+            split.pane_id,
+            split.direction,
+            split.command,
+            split.command_dir,
+            split.domain,
+        )
+        .await?;
+
+    crate::clipboard::install(&pane);
+    crate::clipboard::record_interaction(session_id, tab_id);
+
+    Ok::<Pdu, anyhow::Error>(Pdu::SpawnResponse(SpawnResponse {
+        pane_id: pane.pane_id(),
+        tab_id: tab_id,
+        window_id,
+        size,
+        dimensions: pane.get_dimensions(),
+    }))
+}
+
+fn schedule_attach_domain<SND>(domain_id: DomainId, send_response: SND)
+where
+    SND: Fn(anyhow::Result<Pdu>) + 'static,
+{
+    promise::spawn::spawn(
+        async move { send_response(attach_domain(domain_id).await) }
+            .instrument(tracing::trace_span!("handler")),
+    )
+    .detach();
+}
+
+async fn attach_domain(domain_id: DomainId) -> anyhow::Result<Pdu> {
+    let mux = Mux::get().unwrap();
+    let domain = mux
+        .get_domain(domain_id)
+        .ok_or_else(|| no_such_domain(domain_id))?;
+    if domain.state() == DomainState::Detached {
+        domain.attach().await?;
+    }
+    Ok(Pdu::ListPanesResponse(ListPanesResponse {
+        tabs: list_tabs(&mux),
+    }))
+}
+
+async fn spawn_split(
+    split: SpawnSplit,
+    session_id: usize,
+    client_id: Option<Arc<ClientId>>,
+) -> anyhow::Result<Pdu> {
+    let mux = Mux::get().unwrap();
+    let _identity = mux.with_identity(client_id);
+
+    let window_id = mux
+        .window_containing_tab(split.tab_id)
+        .ok_or_else(|| anyhow!("tab_id {} is not in a window", split.tab_id))?;
+
+    let (pane, size) = mux
+        .spawn_split(
+            split.tab_id,
+            split.direction,
+            split.size_percent,
+            split.command,
+        )
+        .await?;
+
+    crate::clipboard::install(&pane);
+    crate::clipboard::record_interaction(session_id, split.tab_id);
+
+    let layout = mux
+        .get_tab(split.tab_id)
+        .ok_or_else(|| no_such_tab(split.tab_id))?
+        .codec_pane_tree();
+
+    Ok::<Pdu, anyhow::Error>(Pdu::SpawnSplitResponse(SpawnSplitResponse {
+        pane_id: pane.pane_id(),
+        tab_id: split.tab_id,
+        window_id,
+        size,
+        layout,
+    }))
+}
+
+async fn domain_spawn_v2(
+    spawn: SpawnV2,
+    session_id: usize,
+    client_id: Option<Arc<ClientId>>,
+) -> anyhow::Result<Pdu> {
+    let mux = Mux::get().unwrap();
+    let _identity = mux.with_identity(client_id);
+
+    let (tab, pane, window_id) = mux
+        .spawn_tab_or_window(
+            spawn.window_id,
+            spawn.domain,
+            spawn.command,
+            spawn.command_dir,
+            spawn.size,
+            None, // optional current pane_id
+            spawn.workspace,
+        )
+        .await?;
+
+    if spawn.hold {
+        pane.set_exit_behavior(ExitBehavior::Hold);
+    }
+
+    crate::clipboard::install(&pane);
+    crate::clipboard::record_interaction(session_id, tab.tab_id());
+
+    Ok::<Pdu, anyhow::Error>(Pdu::SpawnResponse(SpawnResponse {
+        pane_id: pane.pane_id(),
+        tab_id: tab.tab_id(),
+        window_id,
+        size: tab.get_size(),
+        dimensions: pane.get_dimensions(),
+    }))
+}
+
+async fn restore_tab_state(
+    restore: RestoreTabState,
+    state: TabState,
+    session_id: usize,
+    client_id: Option<Arc<ClientId>>,
+) -> anyhow::Result<Pdu> {
+    let mux = Mux::get().unwrap();
+    let _identity = mux.with_identity(client_id);
+
+    let (tab, pane, window_id) = mux
+        .spawn_tab_or_window(
+            restore.window_id,
+            restore.domain,
+            restore.command,
+            restore.command_dir,
+            state.size,
+            None, // optional current pane_id
+            restore.workspace,
+        )
+        .await?;
+
+    // The display is restored before the new process has had a
+    // chance to produce any output, which follows on from it
+    crate::tabstate::restore(&pane, state);
+
+    crate::clipboard::install(&pane);
+    crate::clipboard::record_interaction(session_id, tab.tab_id());
+
+    Ok::<Pdu, anyhow::Error>(Pdu::SpawnResponse(SpawnResponse {
+        pane_id: pane.pane_id(),
+        tab_id: tab.tab_id(),
+        window_id,
+        size: tab.get_size(),
+        dimensions: pane.get_dimensions(),
+    }))
 }
 
-struct RemoteClipboard {
-    sender: PduSender,
-    pane_id: PaneId,
-}
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_trait::async_trait;
+    use config::keyassignment::SpawnTabDomain;
+    use mux::domain::{alloc_domain_id, Domain};
+    use mux::pane::alloc_pane_id;
+    use mux::renderable::{
+        terminal_get_cursor_position, terminal_get_dimensions, terminal_get_dirty_lines,
+        terminal_get_lines,
+    };
+    use portable_pty::{CommandBuilder, PtySize};
+    use promise::spawn::SimpleExecutor;
+    use std::cell::{RefCell, RefMut};
+    use wezterm_term::color::ColorPalette;
+    use wezterm_term::{
+        KeyCode, KeyModifiers, MouseEvent, Terminal, TerminalConfiguration, TerminalSize,
+    };
+
+    #[derive(Debug)]
+    struct TestTermConfig {}
+    impl TerminalConfiguration for TestTermConfig {
+        fn color_palette(&self) -> ColorPalette {
+            ColorPalette::default()
+        }
+    }
+
+    fn terminal(rows: usize, cols: usize) -> Terminal {
+        Terminal::new(
+            TerminalSize {
+                physical_rows: rows,
+                physical_cols: cols,
+                pixel_width: cols * 8,
+                pixel_height: rows * 16,
+            },
+            Arc::new(TestTermConfig {}),
+            "WezTerm",
+            "O_o",
+            Box::new(Vec::new()),
+        )
+    }
+
+    /// A pane whose terminal is fed by the test, rather than by
+    /// a program running in a pty
+    struct FakePane {
+        pane_id: PaneId,
+        terminal: RefCell<Terminal>,
+        writer: RefCell<Vec<u8>>,
+    }
+
+    impl FakePane {
+        fn new(size: PtySize) -> Rc<dyn Pane> {
+            Rc::new(Self {
+                pane_id: alloc_pane_id(),
+                terminal: RefCell::new(terminal(size.rows as usize, size.cols as usize)),
+                writer: RefCell::new(vec![]),
+            })
+        }
+    }
+
+    impl Pane for FakePane {
+        fn pane_id(&self) -> PaneId {
+            self.pane_id
+        }
+
+        fn get_cursor_position(&self) -> StableCursorPosition {
+            terminal_get_cursor_position(&mut self.terminal.borrow_mut())
+        }
+
+        fn get_current_seqno(&self) -> SequenceNo {
+            self.terminal.borrow().current_seqno()
+        }
+
+        fn get_changed_since(
+            &self,
+            lines: Range<StableRowIndex>,
+            seqno: SequenceNo,
+        ) -> RangeSet<StableRowIndex> {
+            terminal_get_dirty_lines(&mut self.terminal.borrow_mut(), lines, seqno)
+        }
+
+        fn get_lines(&self, lines: Range<StableRowIndex>) -> (StableRowIndex, Vec<Line>) {
+            terminal_get_lines(&mut self.terminal.borrow_mut(), lines)
+        }
+
+        fn get_dimensions(&self) -> RenderableDimensions {
+            terminal_get_dimensions(&mut self.terminal.borrow_mut())
+        }
+
+        fn get_title(&self) -> String {
+            self.terminal.borrow_mut().get_title().to_string()
+        }
+        fn send_paste(&self, _text: &str) -> anyhow::Result<()> {
+            Ok(())
+        }
+        fn reader(&self) -> anyhow::Result<Option<Box<dyn std::io::Read + Send>>> {
+            Ok(None)
+        }
+        fn writer(&self) -> RefMut<dyn std::io::Write> {
+            self.writer.borrow_mut()
+        }
+        fn resize(&self, size: PtySize) -> anyhow::Result<()> {
+            self.terminal.borrow_mut().resize(
+                size.rows as usize,
+                size.cols as usize,
+                size.pixel_width as usize,
+                size.pixel_height as usize,
+            );
+            Ok(())
+        }
+
+        fn key_down(&self, _key: KeyCode, _mods: KeyModifiers) -> anyhow::Result<()> {
+            Ok(())
+        }
+        fn key_up(&self, _key: KeyCode, _mods: KeyModifiers) -> anyhow::Result<()> {
+            Ok(())
+        }
+        fn mouse_event(&self, _event: MouseEvent) -> anyhow::Result<()> {
+            Ok(())
+        }
+        fn is_dead(&self) -> bool {
+            false
+        }
+        fn palette(&self) -> ColorPalette {
+            self.terminal.borrow().palette()
+        }
+        fn domain_id(&self) -> DomainId {
+            0
+        }
+        fn is_mouse_grabbed(&self) -> bool {
+            false
+        }
+        fn is_alt_screen_active(&self) -> bool {
+            self.terminal.borrow().is_alt_screen_active()
+        }
+        fn get_current_working_dir(&self) -> Option<Url> {
+            None
+        }
+    }
+
+    /// A domain that spawns `FakePane`s
+    struct FakeDomain {
+        domain_id: DomainId,
+    }
+
+    #[async_trait(?Send)]
+    impl Domain for FakeDomain {
+        async fn spawn_pane(
+            &self,
+            size: PtySize,
+            _command: Option<CommandBuilder>,
+            _command_dir: Option<String>,
+        ) -> anyhow::Result<Rc<dyn Pane>> {
+            Ok(FakePane::new(size))
+        }
 
-impl Clipboard for RemoteClipboard {
-    fn set_contents(
-        &self,
-        selection: ClipboardSelection,
-        clipboard: Option<String>,
-    ) -> anyhow::Result<()> {
-        self.sender.send(DecodedPdu {
-            serial: 0,
-            pdu: Pdu::SetClipboard(SetClipboard {
-                pane_id: self.pane_id,
-                clipboard,
-                selection,
-            }),
-        })?;
-        Ok(())
+        fn domain_id(&self) -> DomainId {
+            self.domain_id
+        }
+
+        fn domain_name(&self) -> &str {
+            "fake"
+        }
+
+        async fn attach(&self) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn detach(&self) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn state(&self) -> DomainState {
+            DomainState::Attached
+        }
     }
-}
 
-async fn split_pane(
-    split: SplitPane,
-    sender: PduSender,
-    client_id: Option<Arc<ClientId>>,
-) -> anyhow::Result<Pdu> {
-    let mux = Mux::get().unwrap();
-    let _identity = mux.with_identity(client_id);
+    /// A session connected to a mux of its own, whose main thread
+    /// is run by the test while it waits for a response
+    struct TestSession {
+        handler: SessionHandler,
+        sender: PduSender,
+        responses: smol::channel::Receiver<DecodedPdu>,
+        notifications: Rc<RefCell<Vec<MuxNotification>>>,
+        serial: u64,
+        executor: SimpleExecutor,
+        _lock: std::sync::MutexGuard<'static, ()>,
+    }
 
-    let (_pane_domain_id, window_id, tab_id) = mux
-        .resolve_pane_id(split.pane_id)
-        .ok_or_else(|| anyhow!("pane_id {} invalid", split.pane_id))?;
+    impl TestSession {
+        fn new(auth_token: Option<&str>) -> Self {
+            let lock = mainthread::lock_for_test();
+            let executor = SimpleExecutor::new();
 
-    let (pane, size) = mux
-        .split_pane(
-            split.pane_id,
-            split.direction,
-            split.command,
-            split.command_dir,
-            split.domain,
-        )
-        .await?;
+            let domain: Arc<dyn Domain> = Arc::new(FakeDomain {
+                domain_id: alloc_domain_id(),
+            });
+            let mux = Rc::new(Mux::new(Some(domain)));
+            Mux::set_mux(&mux);
+            let notifications = Rc::new(RefCell::new(vec![]));
+            mux.subscribe({
+                let notifications = Rc::clone(&notifications);
+                move |notification| {
+                    notifications.borrow_mut().push(notification);
+                    true
+                }
+            });
 
-    let clip: Arc<dyn Clipboard> = Arc::new(RemoteClipboard {
-        pane_id: pane.pane_id(),
-        sender,
-    });
-    pane.set_clipboard(&clip);
+            let (tx, responses) = smol::channel::unbounded::<DecodedPdu>();
+            let sender = PduSender::new({
+                let tx = tx.clone();
+                move |pdu| tx.try_send(pdu).map_err(|e| anyhow!("{:?}", e))
+            })
+            .with_queue_len(move || tx.len());
+            let auth_token = auth_token.map(|token| Arc::new(AuthToken::new(token)));
 
-    Ok::<Pdu, anyhow::Error>(Pdu::SpawnResponse(SpawnResponse {
-        pane_id: pane.pane_id(),
-        tab_id: tab_id,
-        window_id,
-        size,
-    }))
-}
+            Self {
+                handler: SessionHandler::new(sender.clone(), auth_token),
+                sender,
+                responses,
+                notifications,
+                serial: 0,
+                executor,
+                _lock: lock,
+            }
+        }
 
-async fn domain_spawn_v2(
-    spawn: SpawnV2,
-    sender: PduSender,
-    client_id: Option<Arc<ClientId>>,
-) -> anyhow::Result<Pdu> {
-    let mux = Mux::get().unwrap();
-    let _identity = mux.with_identity(client_id);
+        /// Send `pdu`, running the main thread until it is answered
+        fn request(&mut self, pdu: Pdu) -> Pdu {
+            self.serial += 1;
+            let serial = self.serial;
+            self.handler.process_one(DecodedPdu { pdu, serial });
+            loop {
+                while let Ok(response) = self.responses.try_recv() {
+                    if response.serial == serial {
+                        return response.pdu;
+                    }
+                }
+                self.executor.tick().unwrap();
+            }
+        }
 
-    let (tab, pane, window_id) = mux
-        .spawn_tab_or_window(
-            spawn.window_id,
-            spawn.domain,
-            spawn.command,
-            spawn.command_dir,
-            spawn.size,
-            None, // optional current pane_id
-            spawn.workspace,
-        )
-        .await?;
+        /// Replace the session with another that is connected to the
+        /// same mux, returning the original, which remains connected
+        fn reconnect(&mut self) -> SessionHandler {
+            let handler = SessionHandler::new(self.sender.clone(), None);
+            std::mem::replace(&mut self.handler, handler)
+        }
 
-    let clip: Arc<dyn Clipboard> = Arc::new(RemoteClipboard {
-        pane_id: pane.pane_id(),
-        sender,
-    });
-    pane.set_clipboard(&clip);
+        /// Spawn a tab into `window_id`, or into a new window
+        fn spawn(&mut self, window_id: Option<WindowId>) -> Pdu {
+            self.request(Pdu::SpawnV2(SpawnV2 {
+                domain: SpawnTabDomain::DefaultDomain,
+                window_id,
+                command: None,
+                command_dir: None,
+                size: PtySize::default(),
+                workspace: "default".to_string(),
+                hold: false,
+            }))
+        }
 
-    Ok::<Pdu, anyhow::Error>(Pdu::SpawnResponse(SpawnResponse {
-        pane_id: pane.pane_id(),
-        tab_id: tab.tab_id(),
-        window_id,
-        size: tab.get_size(),
-    }))
+        /// Deliver the notifications that the mux has sent since
+        /// the last call to the session, as dispatch does, and
+        /// return them
+        fn deliver_notifications(&mut self) -> Vec<MuxNotification> {
+            let notifications = self.notifications.borrow_mut().split_off(0);
+            for notification in &notifications {
+                match notification {
+                    MuxNotification::PaneRemoved(pane_id) => self.handler.forget_pane(*pane_id),
+                    MuxNotification::TabExited(tab_id) => self.handler.tab_exited(*tab_id),
+                    _ => {}
+                }
+            }
+            notifications
+        }
+    }
+
+    fn spawned(pdu: Pdu) -> SpawnResponse {
+        match pdu {
+            Pdu::SpawnResponse(response) => response,
+            pdu => panic!("unexpected {:?}", pdu),
+        }
+    }
+
+    fn exited_tabs(notifications: &[MuxNotification]) -> Vec<TabId> {
+        notifications
+            .iter()
+            .filter_map(|notification| match notification {
+                MuxNotification::TabExited(tab_id) => Some(*tab_id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn wrap_flags_survive_resize() {
+        let mut term = terminal(4, 10);
+        // Enable auto wrap, then print a line that wraps twice
+        term.advance_bytes("\x1b[?7h");
+        term.advance_bytes("x".repeat(25));
+
+        // Narrowing the terminal rewraps the line over five rows
+        term.resize(4, 5, 40, 64);
+        let lines: Vec<(StableRowIndex, Line)> = term
+            .screen()
+            .all_lines()
+            .into_iter()
+            .enumerate()
+            .map(|(idx, line)| (idx as StableRowIndex, line))
+            .collect();
+        let wrapped = |lines: &[(StableRowIndex, Line)]| -> Vec<bool> {
+            lines
+                .iter()
+                .map(|(_, line)| line.last_cell_was_wrapped())
+                .collect()
+        };
+        let server_wrapped = wrapped(&lines);
+        assert_eq!(&server_wrapped[..5], &[true, true, true, true, false]);
+
+        // Send them to the client as a GetLinesResponse
+        let pdu = Pdu::GetLinesResponse(GetLinesResponse {
+            pane_id: 0,
+            lines: serialize_lines(lines, &Mutex::new(HashSet::new()), false),
+            more: false,
+        });
+        let mut encoded = vec![];
+        pdu.encode(&mut encoded, 1).unwrap();
+        let client_lines = match Pdu::decode(encoded.as_slice()).unwrap().pdu {
+            Pdu::GetLinesResponse(response) => response.lines.lines(),
+            pdu => panic!("unexpected {:?}", pdu),
+        };
+        assert_eq!(wrapped(&client_lines), server_wrapped);
+    }
+
+    #[test]
+    fn repainted_rows_that_are_unchanged_are_skipped() {
+        let rows = 24;
+        // A status display that repaints every row, of which only
+        // `updated` has different content
+        let repaint = |updated: usize| -> String {
+            (0..rows)
+                .map(|row| {
+                    format!(
+                        "\x1b[{};1H\x1b[1;32mservice {:>2}\x1b[0m: {}",
+                        row + 1,
+                        row,
+                        if row == updated {
+                            "restarting"
+                        } else {
+                            "running"
+                        }
+                    )
+                })
+                .collect()
+        };
+        let all_lines = |term: &Terminal| -> Vec<(StableRowIndex, Line)> {
+            term.screen()
+                .all_lines()
+                .into_iter()
+                .enumerate()
+                .map(|(idx, line)| (idx as StableRowIndex, line))
+                .collect()
+        };
+        let encoded_size = |lines: Vec<(StableRowIndex, Line)>| -> usize {
+            let mut encoded = vec![];
+            Pdu::GetLinesResponse(GetLinesResponse {
+                pane_id: 0,
+                lines: serialize_lines(lines, &Mutex::new(HashSet::new()), false),
+                more: false,
+            })
+            .encode(&mut encoded, 1)
+            .unwrap();
+            encoded.len()
+        };
+
+        let mut term = terminal(rows, 80);
+        term.advance_bytes(repaint(rows));
+        let mut per_pane = PerPane::default();
+        for (row, line) in all_lines(&term) {
+            assert!(!per_pane.row_is_unchanged(row, &line));
+        }
+
+        let seqno = term.current_seqno();
+        term.advance_bytes(repaint(5));
+        let dirty: Vec<(StableRowIndex, Line)> = all_lines(&term)
+            .into_iter()
+            .filter(|(_, line)| line.changed_since(seqno))
+            .collect();
+        assert_eq!(dirty.len(), rows);
+
+        let sent: Vec<(StableRowIndex, Line)> = dirty
+            .iter()
+            .filter(|(row, line)| !per_pane.row_is_unchanged(*row, line))
+            .cloned()
+            .collect();
+        assert_eq!(
+            sent.iter().map(|(row, _)| *row).collect::<Vec<_>>(),
+            vec![5]
+        );
+
+        let dirty_size = encoded_size(dirty);
+        let sent_size = encoded_size(sent);
+        assert!(
+            sent_size * 5 < dirty_size,
+            "sent {} bytes of a {} byte repaint",
+            sent_size,
+            dirty_size
+        );
+
+        // Once the client fetches a row itself, it is sent again
+        // regardless of whether it matches the recorded hash
+        per_pane.forget_row_hashes(&[5..6]);
+        let (_, line) = &all_lines(&term)[5];
+        assert!(!per_pane.row_is_unchanged(5, line));
+    }
+
+    #[test]
+    fn blinking_cursor_on_a_wide_row_is_sent_as_a_delta() {
+        let cols = 500;
+        let first_row = |term: &Terminal| term.screen().all_lines().remove(0);
+        let mut term = terminal(4, cols);
+        term.advance_bytes(format!("$ {}", "x".repeat(cols - 3)));
+        let mut per_pane = PerPane::default();
+        let mut deltas = LineDeltas::default();
+        // The client doesn't hold the row yet
+        assert!(!per_pane.push_line_delta(0, &first_row(&term), &mut deltas));
+
+        // The program draws its own cursor in the last column,
+        // alternating between reverse video and a plain space
+        for blink in 0..10 {
+            let cursor = if blink % 2 == 0 {
+                "\x1b[7m \x1b[0m"
+            } else {
+                " "
+            };
+            term.advance_bytes(format!("\x1b[1;{}H{}", cols, cursor));
+            assert!(per_pane.push_line_delta(0, &first_row(&term), &mut deltas));
+        }
+        let deltas = deltas.deltas();
+        assert_eq!(deltas.len(), 10);
+        assert!(deltas.iter().all(|(_, cells)| cells.len() == 1));
+
+        // Once the client fetches the row, it is sent whole again
+        per_pane.forget_row_hashes(&[0..1]);
+        assert!(!per_pane.push_line_delta(0, &first_row(&term), &mut LineDeltas::default()));
+
+        // A narrow row is always sent whole
+        let mut term = terminal(4, 80);
+        term.advance_bytes("$ ls");
+        let mut per_pane = PerPane::default();
+        for _ in 0..2 {
+            assert!(!per_pane.push_line_delta(0, &first_row(&term), &mut LineDeltas::default()));
+        }
+        assert!(per_pane.sent_lines.is_empty());
+    }
+
+    #[test]
+    fn spawn_failures_are_classified() {
+        let not_found: anyhow::Error = portable_pty::CommandNotFound {
+            command: "vim".into(),
+            searched_path: true,
+        }
+        .into();
+        assert_eq!(
+            spawn_error_kind(&not_found.context("spawning in domain local")),
+            SpawnErrorKind::CommandNotFound
+        );
+
+        let denied = anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::PermissionDenied))
+            .context("spawning /usr/local/bin/tool");
+        assert_eq!(spawn_error_kind(&denied), SpawnErrorKind::PermissionDenied);
+
+        assert_eq!(
+            spawn_error_kind(&mux::DomainUnavailable::Detached.into()),
+            SpawnErrorKind::DomainUnavailable
+        );
+        assert_eq!(
+            spawn_error_kind(&mux::DirectoryNotFound("/no/such/dir".to_string()).into()),
+            SpawnErrorKind::DirectoryNotFound
+        );
+        assert_eq!(
+            spawn_error_kind(&anyhow!("window_id 3 not found on this server")),
+            SpawnErrorKind::Other
+        );
+
+        // The human readable message is kept, including its context
+        match spawn_failed(denied) {
+            Pdu::SpawnFailed(SpawnFailed { kind, reason }) => {
+                assert_eq!(kind, SpawnErrorKind::PermissionDenied);
+                assert!(reason.starts_with("spawning /usr/local/bin/tool: "));
+            }
+            pdu => panic!("unexpected {:?}", pdu),
+        }
+    }
+
+    #[test]
+    fn errors_are_classified() {
+        let code = |err: anyhow::Error| match error_response(err) {
+            Pdu::ErrorResponse(ErrorResponse { code, .. }) => code,
+            pdu => panic!("unexpected {:?}", pdu),
+        };
+        assert_eq!(code(no_such_pane(3)), ErrorCode::NoSuchPane);
+        assert_eq!(
+            code(no_such_tab(3).context("moving tab")),
+            ErrorCode::NoSuchTab
+        );
+        assert_eq!(
+            code(mux::DomainUnavailable::NoSuchDomainId(2).into()),
+            ErrorCode::NoSuchDomain
+        );
+        assert_eq!(code(anyhow!("the pty went away")), ErrorCode::Internal);
+
+        // The reason is kept for display
+        match error_response(no_such_window(4)) {
+            Pdu::ErrorResponse(ErrorResponse { code, reason }) => {
+                assert_eq!(code, ErrorCode::NoSuchWindow);
+                assert_eq!(reason, "Error: no such window 4");
+            }
+            pdu => panic!("unexpected {:?}", pdu),
+        }
+    }
+
+    #[test]
+    fn split_crlf_in_paste_is_rejoined() {
+        let mut paste = PasteSession {
+            pane_id: 0,
+            bracketed: false,
+            held_cr: false,
+        };
+        assert_eq!(paste.take_text("one\r"), "one");
+        assert_eq!(paste.take_text("\ntwo"), "\r\ntwo");
+        assert_eq!(paste.take_text("\r"), "");
+        assert!(paste.held_cr);
+    }
+
+    #[test]
+    fn text_must_be_utf8() {
+        assert_eq!(validate_text("héllo".as_bytes()).unwrap(), "héllo");
+
+        // 0xc3 starts a two byte sequence, but 0x28 is not a
+        // continuation byte
+        let err = validate_text(b"ab\xc3\x28").unwrap_err();
+        assert!(err.to_string().contains("byte offset 2"), "{}", err);
+    }
+
+    fn dir(url: &str) -> Option<Url> {
+        Some(Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn working_dir_ignores_trailing_slash() {
+        assert!(same_working_dir(
+            &dir("file://host/home/wez"),
+            &dir("file://host/home/wez/")
+        ));
+        assert!(same_working_dir(
+            &dir("file://host/home/wez//"),
+            &dir("file://host/home/wez")
+        ));
+        assert!(same_working_dir(&dir("file://host/"), &dir("file://host")));
+    }
+
+    #[test]
+    fn working_dir_ignores_hostname_case() {
+        assert!(same_working_dir(
+            &dir("file://MyHost.Example.com/tmp"),
+            &dir("file://myhost.example.com/tmp/")
+        ));
+    }
+
+    #[test]
+    fn working_dir_differences_are_detected() {
+        assert!(!same_working_dir(
+            &dir("file://host/home/wez"),
+            &dir("file://host/home/wezterm")
+        ));
+        // Paths are case sensitive, unlike hostnames
+        assert!(!same_working_dir(
+            &dir("file://host/home/Wez"),
+            &dir("file://host/home/wez")
+        ));
+        assert!(!same_working_dir(
+            &dir("file://host/tmp"),
+            &dir("file://other/tmp")
+        ));
+        assert!(!same_working_dir(
+            &dir("file://host/"),
+            &dir("file://host/tmp")
+        ));
+        assert!(!same_working_dir(&dir("file://host/tmp"), &None));
+        assert!(same_working_dir(&None, &None));
+    }
+
+    #[test]
+    fn slow_consumer_becomes_congested() {
+        let (tx, rx) = smol::channel::unbounded::<DecodedPdu>();
+        let sender = PduSender::new({
+            let tx = tx.clone();
+            move |pdu| tx.try_send(pdu).map_err(|e| anyhow!("{:?}", e))
+        })
+        .with_queue_len(move || tx.len());
+
+        // Nobody is reading from rx, so the queue grows until
+        // we hit the high water mark
+        for _ in 0..WRITE_QUEUE_HIGH_WATER {
+            assert!(!sender.is_congested());
+            sender
+                .send(DecodedPdu {
+                    pdu: Pdu::Pong(Pong::default()),
+                    serial: 0,
+                })
+                .unwrap();
+        }
+        assert!(sender.is_congested());
+
+        // Once the consumer catches up, pushes may resume
+        while rx.try_recv().is_ok() {}
+        assert!(!sender.is_congested());
+    }
+
+    #[test]
+    fn repeated_notifications_are_coalesced() {
+        let mut per_pane = PerPane::default();
+        let title = Some("build".to_string());
+        let start = Instant::now();
+
+        assert!(per_pane.should_send_notification(&title, "done", start));
+        // The same notification fired again straight away is dropped
+        assert!(!per_pane.should_send_notification(&title, "done", start));
+        // but a different one is sent
+        assert!(per_pane.should_send_notification(&title, "failed", start));
+        assert!(per_pane.should_send_notification(&None, "failed", start));
+
+        // Once the interval has passed, a repeat is sent again
+        let later = start + NOTIFICATION_COALESCE_INTERVAL;
+        assert!(per_pane.should_send_notification(&None, "failed", later));
+    }
+
+    #[test]
+    fn switching_screens_forgets_the_sent_rows() {
+        let mut term = terminal(4, 10);
+        let alt_screen =
+            |term: &Terminal| term.terminal_modes().contains(TerminalModes::ALT_SCREEN);
+        let mut per_pane = PerPane::default();
+        // Nothing was sent before the first push
+        assert!(!per_pane.switch_screen(alt_screen(&term)));
+
+        term.advance_bytes("$ vim\r\n");
+        per_pane.sent_rows.insert(0, (InputSerial::now(), 10));
+        per_pane.row_hashes.insert(0, 1);
+        assert!(!per_pane.switch_screen(alt_screen(&term)));
+        assert_eq!(per_pane.sent_rows.len(), 1);
+
+        term.advance_bytes("\x1b[?1049h");
+        assert!(per_pane.switch_screen(alt_screen(&term)));
+        assert!(per_pane.sent_rows.is_empty());
+        assert!(per_pane.row_hashes.is_empty());
+        assert!(!per_pane.switch_screen(alt_screen(&term)));
+
+        // An invalidation doesn't forget which screen the client has
+        per_pane.invalidate();
+        term.advance_bytes("\x1b[?1049l");
+        assert!(per_pane.switch_screen(alt_screen(&term)));
+        assert!(!per_pane.switch_screen(alt_screen(&term)));
+    }
+
+    #[test]
+    fn acknowledged_rows_are_not_resent() {
+        let mut per_pane = PerPane::default();
+        let first = InputSerial::now();
+        per_pane.sent_rows.insert(5, (first, 10));
+        assert!(!per_pane.client_has_row(5, 10));
+
+        per_pane.acknowledge_input_serial(first);
+        assert!(per_pane.client_has_row(5, 10));
+        // The row has changed since it was sent
+        assert!(!per_pane.client_has_row(5, 11));
+        // We never sent this row
+        assert!(!per_pane.client_has_row(6, 10));
+
+        // A stale acknowledgement doesn't go backwards
+        per_pane.acknowledge_input_serial(InputSerial::empty());
+        assert!(per_pane.client_has_row(5, 10));
+    }
+
+    #[test]
+    fn closing_a_window_that_was_emptied_by_moving_its_tab() {
+        let mut session = TestSession::new(None);
+        let first = spawned(session.spawn(None));
+        let second = spawned(session.spawn(None));
+        let mux = Mux::get().unwrap();
+
+        // Move the only tab of the first window into the second
+        let tab = mux.get_tab(first.tab_id).unwrap();
+        mux.get_window_mut(first.window_id)
+            .unwrap()
+            .remove_by_id(first.tab_id);
+        mux.add_tab_to_window(&tab, second.window_id).unwrap();
+        session.deliver_notifications();
+
+        match session.request(Pdu::CloseWindow(CloseWindow {
+            window_id: first.window_id,
+        })) {
+            Pdu::UnitResponse(_) => {}
+            pdu => panic!("unexpected {:?}", pdu),
+        }
+        assert!(mux.get_window(first.window_id).is_none());
+        // The tab lives on in the window that it was moved to
+        assert!(mux.get_tab(first.tab_id).is_some());
+        assert!(exited_tabs(&session.deliver_notifications()).is_empty());
+
+        // Closing that window removes both of its tabs
+        match session.request(Pdu::CloseWindow(CloseWindow {
+            window_id: second.window_id,
+        })) {
+            Pdu::UnitResponse(_) => {}
+            pdu => panic!("unexpected {:?}", pdu),
+        }
+        assert!(mux.get_window(second.window_id).is_none());
+        let mut exited = exited_tabs(&session.deliver_notifications());
+        exited.sort();
+        assert_eq!(exited, vec![first.tab_id, second.tab_id]);
+
+        match session.request(Pdu::CloseWindow(CloseWindow {
+            window_id: second.window_id,
+        })) {
+            Pdu::ErrorResponse(ErrorResponse { code, .. }) => {
+                assert_eq!(code, ErrorCode::NoSuchWindow)
+            }
+            pdu => panic!("unexpected {:?}", pdu),
+        }
+    }
+
+    #[test]
+    fn closing_a_window_makes_room_for_more_spawns() {
+        let mut session = TestSession::new(None);
+        let max = configuration().mux_max_concurrent_spawns;
+        let window_id = spawned(session.spawn(None)).window_id;
+        for _ in 1..max {
+            spawned(session.spawn(Some(window_id)));
+        }
+
+        match session.spawn(None) {
+            Pdu::SpawnFailed(SpawnFailed { kind, .. }) => {
+                assert_eq!(kind, SpawnErrorKind::ResourceExhausted)
+            }
+            pdu => panic!("unexpected {:?}", pdu),
+        }
+
+        match session.request(Pdu::CloseWindow(CloseWindow { window_id })) {
+            Pdu::UnitResponse(_) => {}
+            pdu => panic!("unexpected {:?}", pdu),
+        }
+        session.deliver_notifications();
+        spawned(session.spawn(None));
+    }
+
+    fn permission_denied(pdu: Pdu) -> String {
+        match pdu {
+            Pdu::ErrorResponse(ErrorResponse { code, reason }) => {
+                assert_eq!(code, ErrorCode::PermissionDenied);
+                reason
+            }
+            pdu => panic!("unexpected {:?}", pdu),
+        }
+    }
+
+    #[test]
+    fn requests_are_refused_until_the_session_authenticates() {
+        let mut session = TestSession::new(Some("secret"));
+        let reason = permission_denied(session.request(Pdu::GetWindows(GetWindows {})));
+        assert!(reason.contains("authentication required"), "{}", reason);
+
+        let reason = permission_denied(session.request(Pdu::Authenticate(Authenticate {
+            token: "guess".to_string(),
+        })));
+        assert!(reason.contains("authentication failed"), "{}", reason);
+        assert!(!session.handler.is_authenticated());
+
+        match session.request(Pdu::Authenticate(Authenticate {
+            token: "secret".to_string(),
+        })) {
+            Pdu::UnitResponse(_) => {}
+            pdu => panic!("unexpected {:?}", pdu),
+        }
+        assert!(session.handler.is_authenticated());
+        match session.request(Pdu::GetWindows(GetWindows {})) {
+            Pdu::GetWindowsResponse(_) => {}
+            pdu => panic!("unexpected {:?}", pdu),
+        }
+        assert!(!session.handler.should_disconnect());
+    }
+
+    #[test]
+    fn repeated_failures_to_authenticate_disconnect() {
+        let mut session = TestSession::new(Some("secret"));
+        // A request sent before authenticating counts as a failure,
+        // just as a wrong token does
+        permission_denied(session.request(Pdu::GetWindows(GetWindows {})));
+        for _ in 1..MAX_AUTH_FAILURES {
+            assert!(!session.handler.should_disconnect());
+            permission_denied(session.request(Pdu::Authenticate(Authenticate {
+                token: "guess".to_string(),
+            })));
+        }
+        assert!(session.handler.should_disconnect());
+        assert!(!session.handler.is_authenticated());
+    }
+
+    #[test]
+    fn pushes_deferred_while_congested_are_coalesced() {
+        let mut session = TestSession::new(None);
+        let pane_id = spawned(session.spawn(None)).pane_id;
+        let pane = Mux::get().unwrap().get_pane(pane_id).unwrap();
+        let print = |text: &str| {
+            pane.downcast_ref::<FakePane>()
+                .unwrap()
+                .terminal
+                .borrow_mut()
+                .advance_bytes(text)
+        };
+
+        let (tx, rx) = smol::channel::unbounded::<DecodedPdu>();
+        let sender = PduSender::new({
+            let tx = tx.clone();
+            move |pdu| tx.try_send(pdu).map_err(|e| anyhow!("{:?}", e))
+        })
+        .with_queue_len(move || tx.len());
+        let per_pane = Arc::new(Mutex::new(PerPane::default()));
+        maybe_push_pane_changes(&pane, sender.clone(), Arc::clone(&per_pane)).unwrap();
+        while rx.try_recv().is_ok() {}
+
+        // The client stops reading, so its queue fills up
+        for _ in 0..WRITE_QUEUE_HIGH_WATER {
+            sender
+                .send(DecodedPdu {
+                    pdu: Pdu::Pong(Pong::default()),
+                    serial: 0,
+                })
+                .unwrap();
+        }
+        for text in &["one\r\n", "two\r\n", "three\r\n"] {
+            print(text);
+            maybe_push_pane_changes(&pane, sender.clone(), Arc::clone(&per_pane)).unwrap();
+            assert_eq!(rx.len(), WRITE_QUEUE_HIGH_WATER);
+            assert!(per_pane.lock().unwrap().push_deferred);
+        }
+
+        // Once it catches up, a single push carries all three rows
+        while rx.try_recv().is_ok() {}
+        maybe_push_pane_changes(&pane, sender.clone(), Arc::clone(&per_pane)).unwrap();
+        assert!(!per_pane.lock().unwrap().push_deferred);
+        let mut pushed: Vec<Pdu> = std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|decoded| decoded.pdu)
+            .collect();
+        assert_eq!(pushed.len(), 1, "unexpected {:?}", pushed);
+        match pushed.pop() {
+            Some(Pdu::GetPaneRenderChangesResponse(changes)) => {
+                // The rows are in the viewport, so they are sent as bonus lines
+                let rows: Vec<StableRowIndex> = changes
+                    .bonus_lines
+                    .lines()
+                    .into_iter()
+                    .map(|(row, _line)| row)
+                    .collect();
+                for row in 0..3 {
+                    assert!(
+                        rows.contains(&row),
+                        "row {} is missing from {:?}",
+                        row,
+                        rows
+                    );
+                }
+            }
+            pdu => panic!("unexpected {:?}", pdu),
+        }
+    }
+
+    #[test]
+    fn input_beyond_the_rate_limit_is_refused() {
+        let mut session = TestSession::new(None);
+        let pane_id = spawned(session.spawn(None)).pane_id;
+        let write = || {
+            Pdu::WriteToPane(WriteToPane {
+                pane_id,
+                data: b"x".to_vec(),
+            })
+        };
+
+        // Input is admitted or refused as it arrives, before it is
+        // queued for the main thread, so a flood of it that is sent
+        // faster than the limit runs out of allowance
+        let limit = configuration().ratelimit_mux_input_pdus_per_second as usize;
+        for _ in 0..limit * 2 {
+            session.handler.process_one(DecodedPdu {
+                pdu: write(),
+                serial: 0,
+            });
+        }
+        let limited = std::iter::from_fn(|| session.responses.try_recv().ok())
+            .filter(|decoded| {
+                matches!(
+                    decoded.pdu,
+                    Pdu::ErrorResponse(ErrorResponse {
+                        code: ErrorCode::RateLimited,
+                        ..
+                    })
+                )
+            })
+            .count();
+        assert!(limited > 0);
+
+        // The allowance of another session is its own
+        let _flood = session.reconnect();
+        match session.request(write()) {
+            Pdu::UnitResponse(_) => {}
+            pdu => panic!("unexpected {:?}", pdu),
+        }
+    }
+
+    #[test]
+    fn killed_panes_are_forgotten() {
+        let mut session = TestSession::new(None);
+        let killed = spawned(session.spawn(None));
+        match session.request(Pdu::KillPane(KillPane {
+            pane_id: killed.pane_id,
+        })) {
+            Pdu::UnitResponse(_) => {}
+            pdu => panic!("unexpected {:?}", pdu),
+        }
+        session.deliver_notifications();
+        assert!(!session.handler.per_pane.contains_key(&killed.pane_id));
+
+        // A push that was queued before the pane went away may still
+        // be written afterwards; that mustn't revive its state
+        session.handler.record_pane_bytes_sent(
+            &Pdu::CursorUpdate(CursorUpdate {
+                pane_id: killed.pane_id,
+                cursor_position: StableCursorPosition::default(),
+                input_serial: InputSerial::empty(),
+                pen: None,
+            }),
+            100,
+        );
+        assert!(!session.handler.per_pane.contains_key(&killed.pane_id));
+
+        // The ids of the killed pane and its tab are not handed out
+        // again, so nothing recorded about them can be mistaken for
+        // the new pane
+        let pane = spawned(session.spawn(None));
+        assert_ne!(pane.pane_id, killed.pane_id);
+        assert_ne!(pane.tab_id, killed.tab_id);
+        assert!(!session.handler.per_pane.contains_key(&killed.pane_id));
+        assert_eq!(session.handler.spawns.lock().unwrap().count(), 1);
+    }
+
+    #[test]
+    fn read_only_sessions_cannot_change_the_mux() {
+        let mut session = TestSession::new(None);
+        let pane_id = spawned(session.spawn(None)).pane_id;
+        let set_read_only = |read_only| {
+            Pdu::SetClientInfo(SetClientInfo {
+                label: None,
+                pid: 1,
+                hostname: "localhost".to_string(),
+                read_only,
+            })
+        };
+        session.request(set_read_only(true));
+        permission_denied(session.request(Pdu::KillPane(KillPane { pane_id })));
+        permission_denied(session.spawn(None));
+
+        // Sending the info again doesn't lift the restriction
+        session.request(set_read_only(false));
+        permission_denied(session.request(Pdu::KillPane(KillPane { pane_id })));
+        assert!(Mux::get().unwrap().get_pane(pane_id).is_some());
+    }
+
+    #[test]
+    fn read_only_sessions_need_access_to_read_the_environment() {
+        let mut session = TestSession::new(None);
+        let tab_id = spawned(session.spawn(None)).tab_id;
+        session.request(Pdu::SetClientInfo(SetClientInfo {
+            label: None,
+            pid: 1,
+            hostname: "localhost".to_string(),
+            read_only: true,
+        }));
+        permission_denied(session.request(Pdu::GetEnv(GetEnv { tab_id })));
+
+        crate::acl::set_write_access(session.handler.stats().session_id(), tab_id, true);
+        match session.request(Pdu::GetEnv(GetEnv { tab_id })) {
+            Pdu::GetEnvResponse(response) => assert_eq!(response.tab_id, tab_id),
+            pdu => panic!("unexpected {:?}", pdu),
+        }
+    }
 }
@@ -0,0 +1,92 @@
+//! Limits the number of programs that a session may have spawned at
+//! once, so that a client that floods the server with spawn requests,
+//! whether through a bug or through abuse, can't exhaust the ptys and
+//! processes of the host.
+//!
+//! A spawn counts against the limit of the session from the moment
+//! that it is admitted.  One that fails stops counting when it
+//! completes, and one that succeeds stops counting when its pane is
+//! removed, or when its tab exits.
+use mux::pane::PaneId;
+use mux::tab::TabId;
+use std::collections::HashMap;
+
+#[derive(Default)]
+pub struct SpawnLimiter {
+    /// The spawns that were admitted and have yet to complete
+    in_flight: usize,
+    /// The panes that were spawned, along with their tabs
+    live: HashMap<PaneId, TabId>,
+}
+
+impl SpawnLimiter {
+    /// Admit a spawn, unless `max` spawns are already counted.
+    /// A `max` of 0 means that there is no limit.  `completed` must
+    /// be called once an admitted spawn has completed.
+    pub fn admit(&mut self, max: usize) -> bool {
+        if max != 0 && self.count() >= max {
+            return false;
+        }
+        self.in_flight += 1;
+        true
+    }
+
+    /// Note that an admitted spawn has completed, producing
+    /// the specified pane and tab if it succeeded
+    pub fn completed(&mut self, spawned: Option<(PaneId, TabId)>) {
+        self.in_flight = self.in_flight.saturating_sub(1);
+        if let Some((pane_id, tab_id)) = spawned {
+            self.live.insert(pane_id, tab_id);
+        }
+    }
+
+    pub fn pane_removed(&mut self, pane_id: PaneId) {
+        self.live.remove(&pane_id);
+    }
+
+    pub fn tab_exited(&mut self, tab_id: TabId) {
+        self.live.retain(|_, tab| *tab != tab_id);
+    }
+
+    /// Returns the number of spawns that count against the limit
+    pub fn count(&self) -> usize {
+        self.in_flight + self.live.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn spawns_count_until_their_pane_goes_away() {
+        let mut spawns = SpawnLimiter::default();
+        assert!(spawns.admit(3));
+        assert!(spawns.admit(3));
+        assert!(spawns.admit(3));
+        // The spawns that are still in flight count too
+        assert!(!spawns.admit(3));
+
+        spawns.completed(Some((1, 10)));
+        spawns.completed(Some((2, 10)));
+        // A failed spawn makes room for another
+        spawns.completed(None);
+        assert!(spawns.admit(3));
+        spawns.completed(Some((3, 11)));
+        assert!(!spawns.admit(3));
+
+        spawns.pane_removed(3);
+        assert_eq!(spawns.count(), 2);
+        spawns.tab_exited(10);
+        assert_eq!(spawns.count(), 0);
+    }
+
+    #[test]
+    fn zero_is_unlimited() {
+        let mut spawns = SpawnLimiter::default();
+        for _ in 0..1000 {
+            assert!(spawns.admit(0));
+        }
+        assert_eq!(spawns.count(), 1000);
+    }
+}
@@ -0,0 +1,307 @@
+use chrono::{DateTime, Utc};
+use codec::{AttachedClient, CompressionStats, SessionEntry, SessionStatistics, SetClientInfo};
+use mux::client::ClientId;
+use mux::pane::PaneId;
+use mux::tab::TabId;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+static SESSION_ID: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static::lazy_static! {
+    static ref SESSIONS: Mutex<Vec<Weak<SessionStats>>> = Mutex::new(vec![]);
+}
+
+/// The label shown for a session whose client has not described itself
+pub const ANONYMOUS_CLIENT_LABEL: &str = "anonymous";
+
+/// Closes a session, by waking its dispatcher so that it returns
+pub struct KillSwitch(Box<dyn Fn() + Send>);
+
+impl KillSwitch {
+    pub fn new<F: Fn() + Send + 'static>(kill: F) -> Self {
+        Self(Box::new(kill))
+    }
+}
+
+impl std::fmt::Debug for KillSwitch {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.debug_struct("KillSwitch").finish()
+    }
+}
+
+/// Counters and identifying information maintained for each
+/// connected session.  The counters are updated from both the
+/// dispatcher and the session handler, so use atomics rather
+/// than requiring a lock.
+#[derive(Debug)]
+pub struct SessionStats {
+    session_id: usize,
+    client_id: Mutex<Option<Arc<ClientId>>>,
+    client_info: Mutex<Option<SetClientInfo>>,
+    connected_at: DateTime<Utc>,
+    last_input_pane: Mutex<Option<PaneId>>,
+    /// When the session last received a PDU from its client
+    last_received: Mutex<Instant>,
+    kill_switch: Mutex<Option<KillSwitch>>,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    pdus_processed: AtomicU64,
+    processing_time_us: AtomicU64,
+    /// The totals of the `CompressionStats` of the connection
+    compressed_frames: AtomicU64,
+    compressed_bytes_in: AtomicU64,
+    compressed_bytes_out: AtomicU64,
+    compression_time_us: AtomicU64,
+}
+
+impl SessionStats {
+    /// Create a new set of counters and register them so that
+    /// they will be included in `server_stats`.  They are
+    /// unregistered when the returned Arc is dropped.
+    pub fn register() -> Arc<Self> {
+        let stats = Arc::new(Self {
+            session_id: SESSION_ID.fetch_add(1, Ordering::Relaxed),
+            client_id: Mutex::new(None),
+            client_info: Mutex::new(None),
+            connected_at: Utc::now(),
+            last_input_pane: Mutex::new(None),
+            last_received: Mutex::new(Instant::now()),
+            kill_switch: Mutex::new(None),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            pdus_processed: AtomicU64::new(0),
+            processing_time_us: AtomicU64::new(0),
+            compressed_frames: AtomicU64::new(0),
+            compressed_bytes_in: AtomicU64::new(0),
+            compressed_bytes_out: AtomicU64::new(0),
+            compression_time_us: AtomicU64::new(0),
+        });
+        let mut sessions = SESSIONS.lock().unwrap();
+        sessions.retain(|s| s.strong_count() > 0);
+        sessions.push(Arc::downgrade(&stats));
+        stats
+    }
+
+    /// Identifies the session for the lifetime of the server
+    pub fn session_id(&self) -> usize {
+        self.session_id
+    }
+
+    pub fn set_client_id(&self, client_id: Arc<ClientId>) {
+        self.client_id.lock().unwrap().replace(client_id);
+    }
+
+    pub fn set_client_info(&self, info: SetClientInfo) {
+        self.client_info.lock().unwrap().replace(info);
+    }
+
+    /// The label provided by the client, or ANONYMOUS_CLIENT_LABEL
+    /// if it has not provided one
+    pub fn client_label(&self) -> String {
+        self.client_info
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|info| info.label.clone())
+            .unwrap_or_else(|| ANONYMOUS_CLIENT_LABEL.to_string())
+    }
+
+    /// Set the means by which `kill_session` closes this session
+    pub fn set_kill_switch(&self, kill_switch: KillSwitch) {
+        self.kill_switch.lock().unwrap().replace(kill_switch);
+    }
+
+    /// Note that the session sent input to `pane_id`
+    pub fn record_input(&self, pane_id: PaneId) {
+        self.last_input_pane.lock().unwrap().replace(pane_id);
+    }
+
+    fn attached_client<F>(&self, resolve_tab: F) -> AttachedClient
+    where
+        F: Fn(PaneId) -> Option<TabId>,
+    {
+        let label = self.client_label();
+        let info = self.client_info.lock().unwrap();
+        let last_tab_id = self.last_input_pane.lock().unwrap().and_then(resolve_tab);
+        AttachedClient {
+            session_id: self.session_id,
+            label,
+            pid: info.as_ref().map(|info| info.pid),
+            hostname: info.as_ref().map(|info| info.hostname.clone()),
+            connected_at: self.connected_at,
+            read_only: info.as_ref().map(|info| info.read_only).unwrap_or(false),
+            last_tab_id,
+        }
+    }
+
+    /// Note that `bytes` were sent to the client, and that the work
+    /// done to compress what has been sent so far is `compression`
+    pub fn record_sent(&self, bytes: usize, compression: &CompressionStats) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.compressed_frames
+            .store(compression.frames, Ordering::Relaxed);
+        self.compressed_bytes_in
+            .store(compression.bytes_in, Ordering::Relaxed);
+        self.compressed_bytes_out
+            .store(compression.bytes_out, Ordering::Relaxed);
+        self.compression_time_us
+            .store(compression.time.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_received(&self, bytes: usize) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        *self.last_received.lock().unwrap() = Instant::now();
+    }
+
+    fn session_entry(&self) -> SessionEntry {
+        SessionEntry {
+            session_id: self.session_id,
+            label: self.client_label(),
+            connected_at: self.connected_at,
+            idle: self.last_received.lock().unwrap().elapsed(),
+        }
+    }
+
+    pub fn record_processing_time(&self, elapsed: Duration) {
+        self.pdus_processed.fetch_add(1, Ordering::Relaxed);
+        self.processing_time_us
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> (SessionStatistics, u64) {
+        let pdus_processed = self.pdus_processed.load(Ordering::Relaxed);
+        let processing_time_us = self.processing_time_us.load(Ordering::Relaxed);
+        (
+            SessionStatistics {
+                client_id: self
+                    .client_id
+                    .lock()
+                    .unwrap()
+                    .as_ref()
+                    .map(|id| (**id).clone()),
+                bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+                bytes_received: self.bytes_received.load(Ordering::Relaxed),
+                pdus_processed,
+                average_processing_time: average(processing_time_us, pdus_processed),
+                compression: CompressionStats {
+                    frames: self.compressed_frames.load(Ordering::Relaxed),
+                    bytes_in: self.compressed_bytes_in.load(Ordering::Relaxed),
+                    bytes_out: self.compressed_bytes_out.load(Ordering::Relaxed),
+                    time: Duration::from_micros(self.compression_time_us.load(Ordering::Relaxed)),
+                },
+            },
+            processing_time_us,
+        )
+    }
+}
+
+fn average(total_us: u64, count: u64) -> Duration {
+    if count == 0 {
+        Duration::default()
+    } else {
+        Duration::from_micros(total_us / count)
+    }
+}
+
+fn live_sessions() -> Vec<Arc<SessionStats>> {
+    SESSIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|s| s.upgrade())
+        .collect()
+}
+
+/// Returns the identity of the client of each live session.
+/// `resolve_tab` maps the pane that last received input from
+/// a session to its containing tab.
+pub fn attached_clients<F>(resolve_tab: F) -> Vec<AttachedClient>
+where
+    F: Fn(PaneId) -> Option<TabId>,
+{
+    live_sessions()
+        .iter()
+        .map(|session| session.attached_client(&resolve_tab))
+        .collect()
+}
+
+/// Returns an entry for each live session
+pub fn list_sessions() -> Vec<SessionEntry> {
+    live_sessions()
+        .iter()
+        .map(|session| session.session_entry())
+        .collect()
+}
+
+/// Close the session `session_id`.  Returns false if there is no
+/// such session, or if its dispatcher has yet to start.
+pub fn kill_session(session_id: usize) -> bool {
+    let session = match live_sessions()
+        .into_iter()
+        .find(|session| session.session_id == session_id)
+    {
+        Some(session) => session,
+        None => return false,
+    };
+    let kill_switch = session.kill_switch.lock().unwrap();
+    match kill_switch.as_ref() {
+        Some(KillSwitch(kill)) => {
+            kill();
+            true
+        }
+        None => false,
+    }
+}
+
+/// Returns the statistics for each live session, along with
+/// the totals across all of them.
+pub fn session_stats() -> (Vec<SessionStatistics>, SessionStatistics) {
+    let sessions = live_sessions();
+
+    let mut totals = SessionStatistics::default();
+    let mut total_processing_us = 0;
+    let mut result = vec![];
+    for session in sessions {
+        let (stats, processing_time_us) = session.snapshot();
+        totals.bytes_sent += stats.bytes_sent;
+        totals.bytes_received += stats.bytes_received;
+        totals.pdus_processed += stats.pdus_processed;
+        totals.compression.add(&stats.compression);
+        total_processing_us += processing_time_us;
+        result.push(stats);
+    }
+    totals.average_processing_time = average(total_processing_us, totals.pdus_processed);
+
+    (result, totals)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+
+    #[test]
+    fn kill_session_uses_the_kill_switch() {
+        let stats = SessionStats::register();
+        assert!(!kill_session(stats.session_id()));
+
+        let killed = Arc::new(AtomicBool::new(false));
+        stats.set_kill_switch(KillSwitch::new({
+            let killed = Arc::clone(&killed);
+            move || killed.store(true, Ordering::SeqCst)
+        }));
+        assert!(list_sessions()
+            .iter()
+            .any(|entry| entry.session_id == stats.session_id()
+                && entry.label == ANONYMOUS_CLIENT_LABEL));
+        assert!(kill_session(stats.session_id()));
+        assert!(killed.load(Ordering::SeqCst));
+
+        let session_id = stats.session_id();
+        drop(stats);
+        assert!(!kill_session(session_id));
+    }
+}
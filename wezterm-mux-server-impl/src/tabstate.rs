@@ -0,0 +1,168 @@
+//! Captures the display of a tab as a `TabState`, and replays it into
+//! the pane of a new tab, so that a tab can be moved between servers.
+//!
+//! The state is replayed as the escape sequences that would draw it:
+//! the text of its lines, with their attributes, followed by the
+//! sequences that restore the cursor, the modes and the title.
+use crate::export::lines_to_text;
+use codec::TabState;
+use mux::pane::Pane;
+use mux::renderable::StableCursorPosition;
+use mux::tab::Tab;
+use portable_pty::PtySize;
+use std::rc::Rc;
+use termwiz::escape::csi::{Cursor, DecPrivateMode, DecPrivateModeCode, Mode, CSI};
+use termwiz::escape::osc::OperatingSystemCommand;
+use termwiz::escape::parser::Parser;
+use termwiz::escape::OneBased;
+use termwiz::surface::{CursorVisibility, Line};
+use wezterm_term::{StableRowIndex, TerminalModes};
+
+/// Capture the display of the active pane of `tab`
+pub fn capture(tab: &Rc<Tab>) -> anyhow::Result<TabState> {
+    let pane = tab
+        .get_active_pane()
+        .ok_or_else(|| anyhow::anyhow!("tab {} has no panes", tab.tab_id()))?;
+    let dims = pane.get_dimensions();
+    let (first_row, lines) = pane
+        .get_lines(dims.scrollback_top..dims.physical_top + dims.viewport_rows as StableRowIndex);
+    let lines: Vec<_> = lines
+        .into_iter()
+        .enumerate()
+        .map(|(idx, line)| (first_row + idx as StableRowIndex, line))
+        .collect();
+
+    // The pane may be smaller than the tab; keep the cell size of the tab
+    let tab_size = tab.get_size();
+    let size = PtySize {
+        rows: dims.viewport_rows as u16,
+        cols: dims.cols as u16,
+        pixel_width: (tab_size.pixel_width as usize * dims.cols / tab_size.cols.max(1) as usize)
+            as u16,
+        pixel_height: (tab_size.pixel_height as usize * dims.viewport_rows
+            / tab_size.rows.max(1) as usize) as u16,
+    };
+
+    Ok(TabState {
+        title: tab.pane_title(&pane),
+        size,
+        lines: lines.into(),
+        viewport_top: dims.physical_top,
+        cursor: pane.get_cursor_position(),
+        modes: pane.get_terminal_modes(),
+    })
+}
+
+/// Redraw `state` in `pane`, which is expected to be a new pane
+/// of `state.size`
+pub fn restore(pane: &Rc<dyn Pane>, state: TabState) {
+    let lines: Vec<Line> = state
+        .lines
+        .lines()
+        .into_iter()
+        .map(|(_, line)| line)
+        .collect();
+    let text = state_to_text(
+        &lines,
+        state.viewport_top,
+        &state.cursor,
+        state.modes,
+        &state.title,
+    );
+    pane.perform_actions(Parser::new().parse_as_vec(text.as_bytes()));
+}
+
+fn set_mode(code: DecPrivateModeCode) -> String {
+    CSI::Mode(Mode::SetDecPrivateMode(DecPrivateMode::Code(code))).to_string()
+}
+
+/// Returns the escape sequences that draw `lines`, which end with
+/// the viewport, and then restore the rest of the state
+fn state_to_text(
+    lines: &[Line],
+    viewport_top: StableRowIndex,
+    cursor: &StableCursorPosition,
+    modes: TerminalModes,
+    title: &str,
+) -> String {
+    let mut text = String::new();
+
+    // There is no scrollback on the alternate screen, so the
+    // lines are all the content that it had
+    if modes.contains(TerminalModes::ALT_SCREEN) {
+        text.push_str(&set_mode(DecPrivateModeCode::ClearAndEnableAlternateScreen));
+    }
+
+    // Every line is followed by a newline except for the last, so
+    // that the last line is drawn at the bottom of the viewport
+    let content = lines_to_text(lines, true);
+    let content = content.strip_suffix('\n').unwrap_or(&content);
+    text.push_str(&content.replace('\n', "\r\n"));
+
+    let row = (cursor.y - viewport_top).max(0) as u32;
+    text.push_str(
+        &CSI::Cursor(Cursor::Position {
+            line: OneBased::from_zero_based(row),
+            col: OneBased::from_zero_based(cursor.x as u32),
+        })
+        .to_string(),
+    );
+    if cursor.visibility == CursorVisibility::Hidden {
+        text.push_str(
+            &CSI::Mode(Mode::ResetDecPrivateMode(DecPrivateMode::Code(
+                DecPrivateModeCode::ShowCursor,
+            )))
+            .to_string(),
+        );
+    }
+
+    for (mode, code) in [
+        (
+            TerminalModes::APPLICATION_CURSOR_KEYS,
+            DecPrivateModeCode::ApplicationCursorKeys,
+        ),
+        (TerminalModes::MOUSE_SGR, DecPrivateModeCode::SGRMouse),
+        (
+            TerminalModes::BRACKETED_PASTE,
+            DecPrivateModeCode::BracketedPaste,
+        ),
+    ] {
+        if modes.contains(mode) {
+            text.push_str(&set_mode(code));
+        }
+    }
+
+    if !title.is_empty() {
+        text.push_str(&OperatingSystemCommand::SetWindowTitle(title.to_string()).to_string());
+    }
+
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use termwiz::cell::CellAttributes;
+    use termwiz::surface::SEQ_ZERO;
+
+    #[test]
+    fn state_is_redrawn_from_the_top_of_the_scrollback() {
+        let attrs = CellAttributes::default();
+        let lines = vec![
+            Line::from_text("$ ls", &attrs, SEQ_ZERO),
+            Line::from_text("a  b", &attrs, SEQ_ZERO),
+            Line::from_text("$ ", &attrs, SEQ_ZERO),
+            Line::from_text("", &attrs, SEQ_ZERO),
+        ];
+        let cursor = StableCursorPosition {
+            x: 2,
+            y: 12,
+            ..Default::default()
+        };
+        // The first line is in the scrollback
+        assert_eq!(
+            state_to_text(&lines, 11, &cursor, TerminalModes::BRACKETED_PASTE, "sh"),
+            "$ ls\r\na  b\r\n$\r\n\u{1b}[2;3H\u{1b}[?2004h\u{1b}]2;sh\u{1b}\\"
+        );
+    }
+}
@@ -0,0 +1,83 @@
+//! Tracks the lines of output that have been sent to a session that
+//! is tailing a tab with `TailTab`.
+//!
+//! A tail is append-oriented: it sends each line once, when the cursor
+//! has moved on from it, and never sends a line again if it is changed
+//! later on.  Lines that are scrolled out of the scrollback before they
+//! can be sent are skipped.
+use mux::pane::PaneId;
+use std::ops::Range;
+use wezterm_term::StableRowIndex;
+
+pub struct Tail {
+    pub pane_id: PaneId,
+    /// The row below the last line that was sent
+    next_row: StableRowIndex,
+}
+
+impl Tail {
+    /// Start tailing `pane_id`, returning the tail along with the
+    /// rows of up to `context_lines` lines that precede `cursor_row`,
+    /// which are to be sent first
+    pub fn new(
+        pane_id: PaneId,
+        cursor_row: StableRowIndex,
+        scrollback_top: StableRowIndex,
+        context_lines: usize,
+    ) -> (Self, Range<StableRowIndex>) {
+        let start = (cursor_row - context_lines as StableRowIndex).max(scrollback_top);
+        let start = start.min(cursor_row);
+        (
+            Self {
+                pane_id,
+                next_row: cursor_row,
+            },
+            start..cursor_row,
+        )
+    }
+
+    /// Returns the rows of the lines that have been completed since
+    /// the last call, given the current cursor row and scrollback top
+    /// of the pane, or None if there aren't any
+    pub fn take_appended(
+        &mut self,
+        cursor_row: StableRowIndex,
+        scrollback_top: StableRowIndex,
+    ) -> Option<Range<StableRowIndex>> {
+        let start = self.next_row.max(scrollback_top);
+        if cursor_row <= start {
+            return None;
+        }
+        self.next_row = cursor_row;
+        Some(start..cursor_row)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn only_completed_lines_are_sent() {
+        let (mut tail, context) = Tail::new(1, 20, 0, 5);
+        assert_eq!(context, 15..20);
+        // The line that the cursor is on is still being written
+        assert_eq!(tail.take_appended(20, 0), None);
+        assert_eq!(tail.take_appended(23, 0), Some(20..23));
+        assert_eq!(tail.take_appended(23, 0), None);
+
+        // Moving the cursor back up doesn't send anything again
+        assert_eq!(tail.take_appended(21, 0), None);
+        assert_eq!(tail.take_appended(24, 0), Some(23..24));
+    }
+
+    #[test]
+    fn lines_that_left_the_scrollback_are_skipped() {
+        let (mut tail, context) = Tail::new(1, 3, 0, 10);
+        assert_eq!(context, 0..3);
+        assert_eq!(tail.take_appended(500, 400), Some(400..500));
+
+        let (_, context) = Tail::new(1, 3, 0, 0);
+        assert!(context.is_empty());
+    }
+}
@@ -0,0 +1,160 @@
+//! Tracks the scrollback that a session has asked to be pushed to
+//! it in the background with `TrickleScrollback`.
+//!
+//! The scrollback of a pane is sent in chunks that work upwards from
+//! the top of the viewport, which the client already has, towards the
+//! top of the scrollback.  When several panes are trickling, they take
+//! turns, so that one long scrollback doesn't hold up the others.
+//! Sending a chunk is left to the session, which only does so when
+//! nothing else is waiting to be written to the client.
+use mux::pane::PaneId;
+use std::collections::VecDeque;
+use std::ops::Range;
+use wezterm_term::StableRowIndex;
+
+/// The scrollback of a pane that remains to be sent
+struct Trickle {
+    pane_id: PaneId,
+    /// The row below the last row that is to be sent next
+    next_end: StableRowIndex,
+    /// The number of rows that the client still wants
+    remaining: usize,
+}
+
+/// The rows of a pane that are to be sent in a single chunk
+#[derive(Debug, PartialEq, Eq)]
+pub struct Chunk {
+    pub pane_id: PaneId,
+    pub rows: Range<StableRowIndex>,
+    /// True if this is the last chunk for the pane
+    pub done: bool,
+}
+
+#[derive(Default)]
+pub struct Trickles {
+    panes: VecDeque<Trickle>,
+    step_scheduled: bool,
+}
+
+impl Trickles {
+    /// Send up to `max_rows` rows of the scrollback of `pane_id`,
+    /// starting from the row above `viewport_top`.  This replaces
+    /// any trickle that is already in progress for the pane.
+    pub fn start(&mut self, pane_id: PaneId, viewport_top: StableRowIndex, max_rows: usize) {
+        self.stop(pane_id);
+        if max_rows > 0 {
+            self.panes.push_back(Trickle {
+                pane_id,
+                next_end: viewport_top,
+                remaining: max_rows,
+            });
+        }
+    }
+
+    pub fn stop(&mut self, pane_id: PaneId) {
+        self.panes.retain(|trickle| trickle.pane_id != pane_id);
+    }
+
+    /// Returns true if there is a chunk to send and no step has been
+    /// scheduled to send it, in which case the caller must schedule
+    /// one, and then call `next_chunk`
+    pub fn schedule_step(&mut self) -> bool {
+        if self.panes.is_empty() || self.step_scheduled {
+            return false;
+        }
+        self.step_scheduled = true;
+        true
+    }
+
+    /// Note that a scheduled step won't send a chunk after all
+    pub fn cancel_step(&mut self) {
+        self.step_scheduled = false;
+    }
+
+    /// Take the next chunk of up to `chunk_rows` rows, from the pane
+    /// whose turn it is.  `scrollback_top` returns the top row of the
+    /// scrollback of a pane, or None if the pane has gone away, in
+    /// which case its trickle is dropped.
+    pub fn next_chunk<F>(&mut self, chunk_rows: usize, scrollback_top: F) -> Option<Chunk>
+    where
+        F: Fn(PaneId) -> Option<StableRowIndex>,
+    {
+        self.step_scheduled = false;
+        while let Some(mut trickle) = self.panes.pop_front() {
+            let top = match scrollback_top(trickle.pane_id) {
+                Some(top) => top,
+                None => continue,
+            };
+            let rows = chunk_rows.min(trickle.remaining) as StableRowIndex;
+            let start = (trickle.next_end - rows).max(top);
+            let end = trickle.next_end.max(start);
+            trickle.next_end = start;
+            trickle.remaining -= (end - start) as usize;
+
+            let done = trickle.remaining == 0 || start <= top;
+            let chunk = Chunk {
+                pane_id: trickle.pane_id,
+                rows: start..end,
+                done,
+            };
+            if !done {
+                self.panes.push_back(trickle);
+            }
+            return Some(chunk);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chunk(pane_id: PaneId, rows: Range<StableRowIndex>, done: bool) -> Option<Chunk> {
+        Some(Chunk {
+            pane_id,
+            rows,
+            done,
+        })
+    }
+
+    #[test]
+    fn scrollback_is_sent_upwards_in_turns() {
+        let mut trickles = Trickles::default();
+        trickles.start(1, 100, 1000);
+        trickles.start(2, 50, 15);
+        assert!(trickles.schedule_step());
+        assert!(!trickles.schedule_step());
+
+        let top = |_| Some(0);
+        assert_eq!(trickles.next_chunk(10, top), chunk(1, 90..100, false));
+        assert_eq!(trickles.next_chunk(10, top), chunk(2, 40..50, false));
+        assert_eq!(trickles.next_chunk(10, top), chunk(1, 80..90, false));
+        // The client only wants 15 rows of the second pane
+        assert_eq!(trickles.next_chunk(10, top), chunk(2, 35..40, true));
+
+        // The top of the scrollback has moved, as it was trimmed
+        let trimmed = |_| Some(75);
+        assert_eq!(trickles.next_chunk(10, trimmed), chunk(1, 75..80, true));
+        assert_eq!(trickles.next_chunk(10, trimmed), None);
+        assert!(!trickles.schedule_step());
+    }
+
+    #[test]
+    fn trickles_can_be_replaced_and_stopped() {
+        let mut trickles = Trickles::default();
+        trickles.start(1, 100, 1000);
+        trickles.start(2, 100, 1000);
+        trickles.start(1, 120, 1000);
+        trickles.stop(2);
+
+        let top = |pane_id| if pane_id == 1 { Some(0) } else { None };
+        assert_eq!(trickles.next_chunk(10, top), chunk(1, 110..120, false));
+        trickles.start(1, 120, 0);
+        assert_eq!(trickles.next_chunk(10, top), None);
+
+        // The trickle of a pane that has gone away is dropped
+        trickles.start(3, 100, 1000);
+        assert_eq!(trickles.next_chunk(10, top), None);
+    }
+}
@@ -0,0 +1,447 @@
+use codec::{is_bulk_pdu, split_into_blob_chunks, DecodedPdu, GetLinesResponse, Pdu};
+use mux::pane::PaneId;
+use std::collections::{HashMap, VecDeque};
+
+/// When both are waiting, a low priority PDU is written after this
+/// many normal priority PDUs, so that a busy pane can't starve a
+/// bulk transfer
+const NORMAL_RUN_BEFORE_LOW_TURN: usize = 4;
+
+/// How urgently a PDU needs to be written to the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Bulk transfers, such as fetched lines and exported scrollback
+    Low,
+    /// The render changes, cursor updates and alerts of panes
+    Normal,
+    /// Responses to requests, including the acknowledgement of
+    /// input, and notifications that are not about a pane
+    High,
+}
+
+pub fn priority(pdu: &Pdu) -> Priority {
+    match pdu {
+        Pdu::GetLinesResponse(_) | Pdu::ScrollbackChunk(_) | Pdu::TailLines(_) => Priority::Low,
+        pdu if is_bulk_pdu(pdu) => Priority::Low,
+        pdu if pdu.pane_id().is_some() => Priority::Normal,
+        _ => Priority::High,
+    }
+}
+
+/// Holds the PDUs that are waiting to be written to a client.
+///
+/// PDUs are written in order of their `Priority`, so that the small,
+/// latency sensitive PDUs that the client is waiting on to respond
+/// to input aren't held up by bulk transfers.  Within a priority the
+/// PDUs are kept in the order in which they were queued, and the
+/// render changes and cursor updates of a pane are of the same
+/// priority, so they are never reordered with respect to each other.
+/// A response that completes a chunked response, such as the error
+/// that ends a `GetLines` part way through, is queued behind the
+/// earlier chunks with the same serial, whatever its priority.
+///
+/// A pane that produces a lot of output, or whose scrollback is
+/// being fetched, can queue up many large PDUs.  Rather than
+/// writing everything in the order that it was queued, which
+/// would make a latency sensitive pane wait for the whole backlog
+/// of a noisy pane, the panes take turns: within a priority, each
+/// pane in turn has its next PDU written in round-robin order.
+///
+/// Once the client has enabled the blob channel, large responses
+/// such as an exported scrollback are split into chunks that take
+/// turns with the other low priority PDUs, so that a bulk transfer
+/// doesn't delay the interactive traffic.
+#[derive(Default)]
+pub struct WriteQueue {
+    high: Lane,
+    normal: Lane,
+    low: Lane,
+    /// The number of normal priority PDUs that have been written
+    /// since the last low priority PDU
+    normal_run: usize,
+    len: usize,
+    blob_channel: bool,
+    blobs: VecDeque<DecodedPdu>,
+    next_blob_id: u64,
+    /// Whether the blob channel has the next low priority turn
+    blob_turn: bool,
+}
+
+/// The PDUs of a single priority
+#[derive(Default)]
+struct Lane {
+    /// The PDUs that are not associated with a pane, which
+    /// are written ahead of the pane PDUs
+    control: VecDeque<DecodedPdu>,
+    panes: HashMap<PaneId, VecDeque<DecodedPdu>>,
+    /// The panes with queued PDUs, in the order in which
+    /// they will next be given a turn
+    turns: VecDeque<PaneId>,
+}
+
+impl Lane {
+    fn push(&mut self, pane_id: Option<PaneId>, decoded: DecodedPdu) {
+        match pane_id {
+            Some(pane_id) => {
+                let queue = self.panes.entry(pane_id).or_insert_with(VecDeque::new);
+                if queue.is_empty() {
+                    self.turns.push_back(pane_id);
+                }
+                queue.push_back(decoded);
+            }
+            None => self.control.push_back(decoded),
+        }
+    }
+
+    fn pop(&mut self) -> Option<DecodedPdu> {
+        if let Some(decoded) = self.control.pop_front() {
+            return Some(decoded);
+        }
+        let pane_id = self.turns.pop_front()?;
+        let queue = self.panes.get_mut(&pane_id)?;
+        let decoded = queue.pop_front();
+        if queue.is_empty() {
+            self.panes.remove(&pane_id);
+        } else {
+            self.turns.push_back(pane_id);
+        }
+        decoded
+    }
+
+    fn is_empty(&self) -> bool {
+        self.control.is_empty() && self.turns.is_empty()
+    }
+
+    /// Returns the queue that holds a PDU with `serial`,
+    /// identified as it would be passed to `push`
+    fn find_serial(&self, serial: u64) -> Option<Option<PaneId>> {
+        if self.control.iter().any(|decoded| decoded.serial == serial) {
+            return Some(None);
+        }
+        self.panes
+            .iter()
+            .find(|(_, queue)| queue.iter().any(|decoded| decoded.serial == serial))
+            .map(|(pane_id, _)| Some(*pane_id))
+    }
+}
+
+fn queued_pane_id(pdu: &Pdu) -> Option<PaneId> {
+    match pdu {
+        Pdu::GetLinesResponse(GetLinesResponse { pane_id, .. }) => Some(*pane_id),
+        pdu => pdu.pane_id(),
+    }
+}
+
+impl WriteQueue {
+    /// Send large PDUs in chunks on the blob channel from now on
+    pub fn enable_blob_channel(&mut self) {
+        self.blob_channel = true;
+    }
+
+    /// Queue `decoded` to be written, returning the number of
+    /// PDUs that were queued in order to send it
+    pub fn push(&mut self, decoded: DecodedPdu) -> usize {
+        if self.blob_channel && is_bulk_pdu(&decoded.pdu) {
+            match split_into_blob_chunks(&decoded, self.next_blob_id) {
+                Ok(Some(chunks)) => {
+                    self.next_blob_id += 1;
+                    let num_chunks = chunks.len();
+                    self.blobs.extend(chunks);
+                    return num_chunks;
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    // Encoding it again when it is written will fail in
+                    // the same way, which will be reported there
+                    log::error!("failed to split {} into chunks: {:#}", decoded.serial, err);
+                }
+            }
+        }
+
+        self.len += 1;
+        let mut priority = priority(&decoded.pdu);
+        let mut pane_id = queued_pane_id(&decoded.pdu);
+        if decoded.serial != 0 {
+            // Don't let this overtake the earlier chunks of its response
+            for lower in [Priority::Low, Priority::Normal] {
+                if lower >= priority {
+                    break;
+                }
+                if let Some(queue) = self.lane(lower).find_serial(decoded.serial) {
+                    priority = lower;
+                    pane_id = queue;
+                    break;
+                }
+            }
+        }
+        self.lane(priority).push(pane_id, decoded);
+        1
+    }
+
+    fn lane(&mut self, priority: Priority) -> &mut Lane {
+        match priority {
+            Priority::High => &mut self.high,
+            Priority::Normal => &mut self.normal,
+            Priority::Low => &mut self.low,
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<DecodedPdu> {
+        if let Some(decoded) = self.high.pop() {
+            self.len -= 1;
+            return Some(decoded);
+        }
+
+        let low_turn = self.normal.is_empty() || self.normal_run >= NORMAL_RUN_BEFORE_LOW_TURN;
+        if low_turn {
+            if let Some(decoded) = self.pop_low() {
+                self.normal_run = 0;
+                return Some(decoded);
+            }
+        }
+        if let Some(decoded) = self.normal.pop() {
+            self.len -= 1;
+            self.normal_run += 1;
+            return Some(decoded);
+        }
+        None
+    }
+
+    /// Alternate between the blob channel and the other low priority PDUs
+    fn pop_low(&mut self) -> Option<DecodedPdu> {
+        let blob_turn = self.blob_turn || self.low.is_empty();
+        self.blob_turn = !self.blob_turn;
+        if blob_turn {
+            if let Some(chunk) = self.blobs.pop_front() {
+                return Some(chunk);
+            }
+        }
+        match self.low.pop() {
+            Some(decoded) => {
+                self.len -= 1;
+                Some(decoded)
+            }
+            None => self.blobs.pop_front(),
+        }
+    }
+
+    /// Returns the number of PDUs that are waiting to be written,
+    /// excluding the chunks of the blob channel, which are written
+    /// as and when there is room for them
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0 && self.blobs.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use codec::{
+        CursorUpdate, ErrorCode, ErrorResponse, ExportScrollbackResponse, InputSerial, NotifyAlert,
+        UnitResponse, BLOB_CHUNK_SIZE,
+    };
+    use wezterm_term::Alert;
+
+    fn pane_pdu(pane_id: PaneId) -> DecodedPdu {
+        DecodedPdu {
+            pdu: Pdu::NotifyAlert(NotifyAlert {
+                pane_id,
+                alert: Alert::Bell,
+            }),
+            serial: 0,
+        }
+    }
+
+    #[test]
+    fn interactive_pane_is_not_stuck_behind_noisy_pane() {
+        const NOISY: PaneId = 1;
+        const INTERACTIVE: PaneId = 2;
+        const BACKLOG: usize = 100;
+
+        let mut queue = WriteQueue::default();
+        for _ in 0..BACKLOG {
+            queue.push(pane_pdu(NOISY));
+        }
+        queue.push(pane_pdu(INTERACTIVE));
+        assert_eq!(queue.len(), BACKLOG + 1);
+
+        // With a single FIFO the interactive update would be the
+        // last of BACKLOG + 1 PDUs to be written; here it is written
+        // as soon as the noisy pane has had a single turn
+        let order: Vec<PaneId> = std::iter::from_fn(|| queue.pop())
+            .map(|decoded| decoded.pdu.pane_id().unwrap())
+            .collect();
+        assert_eq!(order.len(), BACKLOG + 1);
+        assert_eq!(order[0], NOISY);
+        assert_eq!(order[1], INTERACTIVE);
+        assert!(order[2..].iter().all(|&pane_id| pane_id == NOISY));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn responses_go_first_and_panes_stay_ordered() {
+        let mut queue = WriteQueue::default();
+        queue.push(pane_pdu(1));
+        queue.push(DecodedPdu {
+            pdu: Pdu::GetLinesResponse(GetLinesResponse {
+                pane_id: 1,
+                lines: vec![].into(),
+                more: false,
+            }),
+            serial: 7,
+        });
+        queue.push(DecodedPdu {
+            pdu: Pdu::UnitResponse(UnitResponse {}),
+            serial: 8,
+        });
+
+        let serials: Vec<u64> = std::iter::from_fn(|| queue.pop())
+            .map(|decoded| decoded.serial)
+            .collect();
+        assert_eq!(serials, vec![8, 0, 7]);
+    }
+
+    fn lines_chunk(pane_id: PaneId, serial: u64) -> DecodedPdu {
+        DecodedPdu {
+            pdu: Pdu::GetLinesResponse(GetLinesResponse {
+                pane_id,
+                lines: vec![].into(),
+                more: true,
+            }),
+            serial,
+        }
+    }
+
+    #[test]
+    fn cursor_updates_are_not_stuck_behind_get_lines() {
+        const PANE: PaneId = 1;
+        const LINES_SERIAL: u64 = 5;
+
+        // A large GetLines for the pane that is being typed into
+        let mut queue = WriteQueue::default();
+        for _ in 0..20 {
+            queue.push(lines_chunk(PANE, LINES_SERIAL));
+        }
+        assert_eq!(queue.pop().unwrap().serial, LINES_SERIAL);
+
+        queue.push(pane_pdu(PANE));
+        queue.push(DecodedPdu {
+            pdu: Pdu::CursorUpdate(CursorUpdate {
+                pane_id: PANE,
+                cursor_position: Default::default(),
+                input_serial: InputSerial::empty(),
+                pen: None,
+            }),
+            serial: 0,
+        });
+
+        // The render changes and cursor update are written next,
+        // in the order in which they were queued
+        let next: Vec<DecodedPdu> = (0..2).map(|_| queue.pop().unwrap()).collect();
+        assert!(matches!(next[0].pdu, Pdu::NotifyAlert(_)));
+        assert!(matches!(next[1].pdu, Pdu::CursorUpdate(_)));
+        assert_eq!(std::iter::from_fn(|| queue.pop()).count(), 19);
+    }
+
+    #[test]
+    fn bulk_transfers_are_not_starved() {
+        let mut queue = WriteQueue::default();
+        queue.push(lines_chunk(1, 5));
+        for _ in 0..10 {
+            queue.push(pane_pdu(2));
+        }
+        let position = std::iter::from_fn(|| queue.pop())
+            .position(|decoded| decoded.serial == 5)
+            .unwrap();
+        assert_eq!(position, NORMAL_RUN_BEFORE_LOW_TURN);
+    }
+
+    #[test]
+    fn responses_stay_behind_their_earlier_chunks() {
+        let mut queue = WriteQueue::default();
+        queue.push(lines_chunk(1, 5));
+        queue.push(lines_chunk(1, 5));
+        // The GetLines failed part way through
+        queue.push(DecodedPdu {
+            pdu: Pdu::ErrorResponse(ErrorResponse {
+                code: ErrorCode::Internal,
+                reason: "Error: the pane went away".to_string(),
+            }),
+            serial: 5,
+        });
+        queue.push(DecodedPdu {
+            pdu: Pdu::UnitResponse(UnitResponse {}),
+            serial: 6,
+        });
+
+        let serials: Vec<u64> = std::iter::from_fn(|| queue.pop())
+            .map(|decoded| decoded.serial)
+            .collect();
+        assert_eq!(serials, vec![6, 5, 5, 5]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn typing_is_not_stuck_behind_export() {
+        const EXPORT_SERIAL: u64 = 100;
+        const KEY_SERIAL: u64 = 101;
+        const TYPING: PaneId = 2;
+
+        // About 5MB of text that doesn't compress well
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let text: String = (0..5 * 1024 * 1024)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (b'!' + (state % 94) as u8) as char
+            })
+            .collect();
+
+        let mut queue = WriteQueue::default();
+        queue.enable_blob_channel();
+        let num_chunks = queue.push(DecodedPdu {
+            pdu: Pdu::ExportScrollbackResponse(ExportScrollbackResponse {
+                pane_id: 1,
+                range: 0..10_000,
+                text,
+            }),
+            serial: EXPORT_SERIAL,
+        });
+        assert!(num_chunks > 10);
+        assert_eq!(queue.len(), 0);
+
+        // Start writing the export, then type a key; the echo is
+        // pushed to the client and the key press is acknowledged
+        let first = queue.pop().unwrap();
+        assert_eq!(first.serial, EXPORT_SERIAL);
+        queue.push(pane_pdu(TYPING));
+        queue.push(DecodedPdu {
+            pdu: Pdu::UnitResponse(UnitResponse {}),
+            serial: KEY_SERIAL,
+        });
+
+        // Both are written after at most one more chunk, rather than
+        // after the remaining megabytes of the export
+        let next: Vec<DecodedPdu> = (0..3).map(|_| queue.pop().unwrap()).collect();
+        assert_eq!(next[0].serial, KEY_SERIAL);
+        let echo_pos = next
+            .iter()
+            .position(|decoded| decoded.pdu.pane_id() == Some(TYPING))
+            .unwrap();
+        assert!(echo_pos <= 2);
+        assert!(next.iter().all(|decoded| match &decoded.pdu {
+            Pdu::BlobChunk(chunk) => chunk.data.len() <= BLOB_CHUNK_SIZE,
+            _ => true,
+        }));
+
+        // And the rest of the export follows
+        let remaining = std::iter::from_fn(|| queue.pop()).count();
+        assert_eq!(remaining + 4, num_chunks + 2);
+        assert!(queue.is_empty());
+    }
+}
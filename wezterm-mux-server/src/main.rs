@@ -184,6 +184,7 @@ fn run() -> anyhow::Result<()> {
         log::error!("problem spawning listeners: {:?}", e);
         e
     })?;
+    wezterm_mux_server_impl::reaper::start();
 
     let activity = Activity::new();
 
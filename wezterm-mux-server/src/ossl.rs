@@ -7,18 +7,25 @@ use promise::spawn::spawn_into_main_thread;
 use std::net::TcpListener;
 use std::path::Path;
 use std::sync::Arc;
+use wezterm_mux_server_impl::auth::AuthToken;
 use wezterm_mux_server_impl::PKI;
 
 struct OpenSSLNetListener {
     acceptor: Arc<SslAcceptor>,
     listener: TcpListener,
+    auth_token: Option<Arc<AuthToken>>,
 }
 
 impl OpenSSLNetListener {
-    pub fn new(listener: TcpListener, acceptor: SslAcceptor) -> Self {
+    pub fn new(
+        listener: TcpListener,
+        acceptor: SslAcceptor,
+        auth_token: Option<Arc<AuthToken>>,
+    ) -> Self {
         Self {
             listener,
             acceptor: Arc::new(acceptor),
+            auth_token,
         }
     }
 
@@ -82,11 +89,15 @@ impl OpenSSLNetListener {
                                 log::error!("problem with peer cert: {}", err);
                                 break;
                             }
+                            let auth_token = self.auth_token.clone();
                             spawn_into_main_thread(async move {
                                 log::error!("Making new AsyncSslStream");
-                                wezterm_mux_server_impl::dispatch::process(AsyncSslStream::new(
-                                    stream,
-                                ))
+                                wezterm_mux_server_impl::dispatch::process(
+                                    AsyncSslStream::new(stream),
+                                    auth_token,
+                                    // TLS already encrypts the session
+                                    None,
+                                )
                                 .await
                                 .map_err(|e| {
                                     log::error!("process: {:?}", e);
@@ -170,6 +181,11 @@ pub fn spawn_tls_listener(tls_server: &TlsDomainServer) -> Result<(), Error> {
 
     let acceptor = acceptor.build();
 
+    let auth_token = match &tls_server.auth_token_file {
+        Some(path) => Some(Arc::new(AuthToken::load(path)?)),
+        None => None,
+    };
+
     log::error!("listening with TLS on {:?}", tls_server.bind_address);
 
     let mut net_listener = OpenSSLNetListener::new(
@@ -180,6 +196,7 @@ pub fn spawn_tls_listener(tls_server: &TlsDomainServer) -> Result<(), Error> {
             )
         })?,
         acceptor,
+        auth_token,
     );
     std::thread::spawn(move || {
         net_listener.run();
@@ -187,6 +187,11 @@ Outputs the pane-id for the newly created pane on success"
         #[structopt(long = "workspace")]
         workspace: Option<String>,
 
+        /// Keep the pane open after the program exits, showing its
+        /// final output, until the pane is explicitly closed
+        #[structopt(long = "hold")]
+        hold: bool,
+
         /// Instead of executing your shell, run PROG.
         /// For example: `wezterm start -- bash -l` will spawn bash
         /// as if it were a login shell.
@@ -510,7 +515,11 @@ async fn run_cli_async(config: config::ConfigHandle, cli: CliCommand) -> anyhow:
                 },
             ];
             let mut data = vec![];
-            let panes = client.list_panes().await?;
+            let panes = client
+                .list_panes(codec::ListPanes {
+                    initial_window_id: None,
+                })
+                .await?;
 
             for tabroot in panes.tabs {
                 let mut cursor = tabroot.into_tree().cursor();
@@ -616,6 +625,7 @@ async fn run_cli_async(config: config::ConfigHandle, cli: CliCommand) -> anyhow:
             window_id,
             new_window,
             workspace,
+            hold,
         } => {
             let window_id = if new_window {
                 None
@@ -636,7 +646,11 @@ async fn run_cli_async(config: config::ConfigHandle, cli: CliCommand) -> anyhow:
                                 .parse()?,
                         };
 
-                        let panes = client.list_panes().await?;
+                        let panes = client
+                            .list_panes(codec::ListPanes {
+                                initial_window_id: None,
+                            })
+                            .await?;
                         let mut window_id = None;
                         'outer: for tabroot in panes.tabs {
                             let mut cursor = tabroot.into_tree().cursor();
@@ -676,6 +690,7 @@ async fn run_cli_async(config: config::ConfigHandle, cli: CliCommand) -> anyhow:
                     command_dir: canon_cwd(cwd)?,
                     size: config::configuration().initial_size(),
                     workspace,
+                    hold,
                 })
                 .await?;
 